@@ -0,0 +1,43 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Append-only JSON-lines access log. `reopen()` is driven by SIGUSR1 so that
+/// logrotate can move the file away and have us start a fresh one.
+pub struct AccessLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AccessLog {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn write(&self, record: &serde_json::Value) {
+        let mut line = record.to_string();
+        line.push('\n');
+        let mut file = self.file.lock().unwrap_or_else(|p| p.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            tracing::error!(path = %self.path.display(), "access log write failed: {}", e);
+        }
+    }
+
+    /// Swap in a new descriptor for the same path. On failure the old descriptor is kept.
+    pub fn reopen(&self) -> io::Result<()> {
+        let new_file = open_append(&self.path)?;
+        let mut file = self.file.lock().unwrap_or_else(|p| p.into_inner());
+        *file = new_file;
+        Ok(())
+    }
+}
+
+fn open_append(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
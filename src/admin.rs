@@ -0,0 +1,290 @@
+use crate::admin_auth::{AdminAuth, Denial};
+use crate::configuration::{GatewayConfig, RouteState};
+use crate::controls::Controls;
+use crate::log_level::LogLevel;
+use crate::metrics::Metrics;
+use crate::openapi::parse_query;
+use crate::reload::Reloader;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use http::{header, Response, StatusCode};
+use pingora::apps::http_app::ServeHttp;
+use pingora::lb::selection::RoundRobin;
+use pingora::lb::LoadBalancer;
+use pingora::protocols::http::ServerSession;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+/// Security blocks kept for the dashboard
+const RECENT_BLOCKS: usize = 50;
+/// Rate limit offenders listed unless the request asks for another `top`
+const DEFAULT_TOP_OFFENDERS: usize = 20;
+
+/// Operator-facing HTTP endpoints, served on a separate listener from proxied traffic.
+pub struct AdminService {
+    pub config: Arc<ArcSwap<GatewayConfig>>,
+    pub reloader: Arc<Reloader>,
+    pub metrics: Arc<Metrics>,
+    pub upstreams: Arc<LoadBalancer<RoundRobin>>,
+    pub recent_blocks: Arc<RecentBlocks>,
+    pub log_level: Arc<LogLevel>,
+    pub auth: Arc<AdminAuth>,
+    pub controls: Arc<Controls>,
+}
+
+/// The latest audit events, newest first.
+#[derive(Default)]
+pub struct RecentBlocks(Mutex<VecDeque<Value>>);
+
+impl RecentBlocks {
+    pub fn push(&self, mut event: Value) {
+        event["time"] = json!(chrono::Utc::now().to_rfc3339());
+        let mut events = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        events.push_front(event);
+        events.truncate(RECENT_BLOCKS);
+    }
+
+    fn snapshot(&self) -> Vec<Value> {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+impl AdminService {
+    /// Counters for the dashboard, which derives rates from successive polls.
+    fn stats(&self) -> Response<Vec<u8>> {
+        let totals = self.metrics.request_totals();
+        let backends = self.upstreams.backends();
+        let upstreams: Vec<Value> = backends
+            .get_backend()
+            .iter()
+            .map(|b| json!({ "addr": b.addr.to_string(), "healthy": backends.ready(b) }))
+            .collect();
+        let body = json!({
+            "requests": totals.requests,
+            "client_errors": totals.client_errors,
+            "server_errors": totals.server_errors,
+            "upstreams": upstreams,
+            "recent_blocks": self.recent_blocks.snapshot(),
+        });
+        json_response(StatusCode::OK, body.to_string().into_bytes())
+    }
+
+    /// Clients refused by the `rate_limit` stage in the last few minutes, most rejections first.
+    fn rate_limit_offenders(&self, query: Option<&str>) -> Response<Vec<u8>> {
+        let top = parse_query(query.unwrap_or_default())
+            .into_iter()
+            .find(|(name, _)| name == "top")
+            .and_then(|(_, value)| value.parse().ok())
+            .unwrap_or(DEFAULT_TOP_OFFENDERS);
+        let offenders: Vec<Value> = self
+            .metrics
+            .rate_limit_offenders(top)
+            .into_iter()
+            .map(|o| {
+                json!({
+                    "ip": o.ip.to_string(),
+                    "rejections": o.rejections,
+                    "last_rejected_secs_ago": o.idle.as_secs(),
+                })
+            })
+            .collect();
+        let body = json!({ "offenders": offenders });
+        json_response(StatusCode::OK, body.to_string().into_bytes())
+    }
+
+    /// The config the running process actually loaded, with secrets masked.
+    fn config_dump(&self) -> Response<Vec<u8>> {
+        let config = self.config.load().redacted();
+        match serde_json::to_vec_pretty(&config) {
+            Ok(body) => json_response(StatusCode::OK, body),
+            Err(e) => text_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("config encode: {}", e),
+            ),
+        }
+    }
+
+    /// Each configured route's state as configured and as in effect.
+    fn routes(&self) -> Response<Vec<u8>> {
+        let routes: Vec<Value> = self
+            .config
+            .load()
+            .routes
+            .iter()
+            .map(|route| {
+                let state = self.controls.route_state(&route.name);
+                json!({
+                    "name": route.name,
+                    "configured": route.state.as_str(),
+                    "state": state.unwrap_or(route.state).as_str(),
+                    "overridden": state.is_some(),
+                })
+            })
+            .collect();
+        json_response(StatusCode::OK, json!(routes).to_string().into_bytes())
+    }
+
+    /// Overrides a route's state with the body (`active`, `maintenance` or `disabled`), or
+    /// with `None` restores the configured one.
+    fn set_route_state(&self, name: &str, state: Option<&str>) -> Response<Vec<u8>> {
+        if !self.config.load().routes.iter().any(|r| r.name == name) {
+            return text_response(StatusCode::NOT_FOUND, "unknown route");
+        }
+        let state = match state.map(|s| RouteState::parse(s.trim())) {
+            Some(None) => {
+                return text_response(
+                    StatusCode::BAD_REQUEST,
+                    "expected active, maintenance or disabled",
+                )
+            }
+            Some(state) => state,
+            None => None,
+        };
+        tracing::warn!(
+            route = name,
+            state = state.map(RouteState::as_str),
+            "route state changed via admin API"
+        );
+        self.controls.set_route_state(name, state);
+        self.routes()
+    }
+
+    fn log_level(&self) -> Response<Vec<u8>> {
+        let body = json!({ "filter": self.log_level.current() });
+        json_response(StatusCode::OK, body.to_string().into_bytes())
+    }
+
+    /// Takes `EnvFilter` directives as the body; an empty body restores the startup filter.
+    fn set_log_level(&self, directives: &str) -> Response<Vec<u8>> {
+        match self.log_level.set(directives) {
+            Ok(()) => self.log_level(),
+            Err(e) => {
+                let body = json!({ "status": "error", "error": e });
+                json_response(StatusCode::BAD_REQUEST, body.to_string().into_bytes())
+            }
+        }
+    }
+
+    /// Same as SIGHUP, but reports the outcome to the caller.
+    fn reload(&self) -> Response<Vec<u8>> {
+        match self.reloader.reload() {
+            Ok(changed) => {
+                tracing::info!(?changed, "configuration reloaded via admin API");
+                let body = serde_json::json!({ "status": "ok", "changed": changed });
+                json_response(StatusCode::OK, body.to_string().into_bytes())
+            }
+            Err(e) => {
+                tracing::error!("admin reload failed: {}. Keeping old config.", e);
+                let body = serde_json::json!({ "status": "error", "error": e.to_string() });
+                json_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    body.to_string().into_bytes(),
+                )
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ServeHttp for AdminService {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        // A dual-stack listener sees IPv4 clients as `::ffff:a.b.c.d`
+        let peer = session
+            .client_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(|addr| addr.ip().to_canonical());
+        let req = session.req_header();
+        let method = req.method.clone();
+        let path = req.uri.path().to_string();
+        let authorization = req.headers.get(header::AUTHORIZATION).map(|v| v.as_bytes());
+        if let Err(denial) = self.auth.check(peer, authorization) {
+            let status = match denial {
+                Denial::Address => StatusCode::FORBIDDEN,
+                Denial::Token => StatusCode::UNAUTHORIZED,
+            };
+            self.auth.audit(
+                "http",
+                peer,
+                method.as_str(),
+                &path,
+                denial.reason(),
+                status.as_u16().into(),
+            );
+            return text_response(status, denial.reason());
+        }
+        let response = self.route(session).await;
+        // Reads, such as the dashboard's polling, aren't actions
+        if method != http::Method::GET {
+            self.auth.audit(
+                "http",
+                peer,
+                method.as_str(),
+                &path,
+                "admin_action",
+                response.status().as_u16().into(),
+            );
+        }
+        response
+    }
+}
+
+impl AdminService {
+    async fn route(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let req = session.req_header();
+        match (req.method.as_str(), req.uri.path()) {
+            ("GET", "/-/dashboard") => {
+                build_response(StatusCode::OK, "text/html", DASHBOARD.as_bytes().to_vec())
+            }
+            ("GET", "/-/stats") => self.stats(),
+            ("GET", "/-/config") => self.config_dump(),
+            ("GET", "/-/rate-limits") => self.rate_limit_offenders(req.uri.query()),
+            ("POST", "/-/reload") => self.reload(),
+            ("GET", "/-/routes") => self.routes(),
+            ("PUT", path) if path.starts_with("/-/routes/") => {
+                let name = path["/-/routes/".len()..].to_string();
+                let body = match session.read_request_body().await {
+                    Ok(body) => body.unwrap_or_default(),
+                    Err(e) => return text_response(StatusCode::BAD_REQUEST, &e.to_string()),
+                };
+                self.set_route_state(&name, Some(&String::from_utf8_lossy(&body)))
+            }
+            ("DELETE", path) if path.starts_with("/-/routes/") => {
+                self.set_route_state(&path["/-/routes/".len()..], None)
+            }
+            ("GET", "/-/log-level") => self.log_level(),
+            ("PUT", "/-/log-level") => {
+                // Directives are a line at most, so one chunk holds them
+                let body = match session.read_request_body().await {
+                    Ok(body) => body.unwrap_or_default(),
+                    Err(e) => return text_response(StatusCode::BAD_REQUEST, &e.to_string()),
+                };
+                self.set_log_level(&String::from_utf8_lossy(&body))
+            }
+            _ => text_response(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+fn json_response(status: StatusCode, body: Vec<u8>) -> Response<Vec<u8>> {
+    build_response(status, "application/json", body)
+}
+
+fn text_response(status: StatusCode, body: &str) -> Response<Vec<u8>> {
+    build_response(status, "text/plain", body.as_bytes().to_vec())
+}
+
+fn build_response(status: StatusCode, content_type: &str, body: Vec<u8>) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .expect("static response parts are valid")
+}
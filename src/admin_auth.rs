@@ -0,0 +1,131 @@
+use crate::configuration::AdminAuthConfig;
+use crate::csrf::constant_time_eq;
+use crate::ip_reputation::{parse_range, PrefixTree};
+use crate::syslog::{EventKind, SyslogSink};
+use pingora::listeners::TlsSettings;
+use pingora::tls::ssl::SslVerifyMode;
+use pingora::tls::x509::X509Name;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// `admin_auth` for the admin and gRPC admin APIs, and their audit trail. Client certificates
+/// are checked in the TLS handshake (see [`tls_settings`]); the rest per request.
+pub struct AdminAuth {
+    token: Option<String>,
+    allowed: Option<PrefixTree>,
+    syslog: Option<Arc<SyslogSink>>,
+}
+
+/// Why a caller was turned away
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Denial {
+    /// Connected from outside `allowed_ips`
+    Address,
+    /// No or the wrong bearer token
+    Token,
+}
+
+impl Denial {
+    pub fn reason(self) -> &'static str {
+        match self {
+            Denial::Address => "admin_ip_denied",
+            Denial::Token => "admin_token_invalid",
+        }
+    }
+}
+
+impl AdminAuth {
+    pub fn new(config: Option<&AdminAuthConfig>, syslog: Option<Arc<SyslogSink>>) -> Self {
+        let allowed = config.filter(|c| !c.allowed_ips.is_empty()).map(|c| {
+            let mut tree = PrefixTree::default();
+            // Entries are checked during config validation
+            for (ip, len) in c.allowed_ips.iter().filter_map(|e| parse_range(e)) {
+                tree.insert(ip, len);
+            }
+            tree
+        });
+        Self {
+            token: config.and_then(|c| c.token.clone()),
+            allowed,
+            syslog,
+        }
+    }
+
+    /// `authorization` is the raw `Authorization` header or metadata value.
+    pub fn check(&self, peer: Option<IpAddr>, authorization: Option<&[u8]>) -> Result<(), Denial> {
+        if let Some(allowed) = &self.allowed {
+            if !peer.is_some_and(|ip| allowed.contains(ip)) {
+                return Err(Denial::Address);
+            }
+        }
+        if let Some(token) = &self.token {
+            // The auth scheme is case-insensitive (RFC 9110)
+            let presented = authorization.and_then(|v| {
+                let (scheme, token) = v.split_at_checked(7)?;
+                scheme.eq_ignore_ascii_case(b"Bearer ").then_some(token)
+            });
+            if !presented.is_some_and(|p| constant_time_eq(p, token.as_bytes())) {
+                return Err(Denial::Token);
+            }
+        }
+        Ok(())
+    }
+
+    /// Records an admin call, to syslog's audit stream when configured and to the log.
+    /// `reason` is `admin_action`, or the denial reason for refused calls.
+    pub fn audit(
+        &self,
+        api: &str,
+        peer: Option<IpAddr>,
+        method: &str,
+        path: &str,
+        reason: &str,
+        status: u32,
+    ) {
+        let client_ip = peer.map(|ip| ip.to_string()).unwrap_or_default();
+        if reason == "admin_action" {
+            tracing::info!(api, client_ip, method, path, reason, status, "admin call");
+        } else {
+            tracing::warn!(
+                api,
+                client_ip,
+                method,
+                path,
+                reason,
+                status,
+                "admin call refused"
+            );
+        }
+        if let Some(syslog) = &self.syslog {
+            let event = serde_json::json!({
+                "reason": reason,
+                "api": api,
+                "client_ip": client_ip,
+                "method": method,
+                "path": path,
+                "status_code": status,
+            });
+            syslog.send(EventKind::Audit, &event);
+        }
+    }
+}
+
+/// TLS for an admin listener requiring a client certificate issued by `client_ca`. Serves the
+/// proxy's own certificate.
+pub fn tls_settings(
+    cert_path: &str,
+    key_path: &str,
+    client_ca: &str,
+    h2: bool,
+) -> pingora::Result<TlsSettings> {
+    let mut settings = TlsSettings::intermediate(cert_path, key_path)?;
+    let tls_error =
+        |e| pingora::Error::because(pingora::ErrorType::InternalError, client_ca.to_string(), e);
+    settings.set_ca_file(client_ca).map_err(tls_error)?;
+    settings.set_client_ca_list(X509Name::load_client_ca_file(client_ca).map_err(tls_error)?);
+    settings.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    if h2 {
+        settings.enable_h2();
+    }
+    Ok(settings)
+}
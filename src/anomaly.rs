@@ -0,0 +1,220 @@
+use crate::configuration::AnomalyDetectionConfig;
+use crate::metrics::Metrics;
+use crate::syslog::{EventKind, SyslogSink};
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// Weight of the newest window in a path's moving average
+const BASELINE_WEIGHT: f64 = 0.2;
+
+/// Background analysis of per-IP and per-path traffic over fixed windows. Each finished
+/// window is checked for request spikes, high error ratios and scanning (many distinct
+/// not-found paths); anomalies become audit events and `anomalies_total`, and with
+/// `penalty` set the offending IP is held to a stricter rate limit for a while.
+pub struct AnomalyDetector {
+    config: AnomalyDetectionConfig,
+    current: ArcSwap<Window>,
+    /// Moving average of each path's requests per window; only the analyzer touches it
+    baselines: Mutex<HashMap<String, f64>>,
+    penalized: DashMap<IpAddr, Penalty>,
+    metrics: Arc<Metrics>,
+    syslog: Option<Arc<SyslogSink>>,
+}
+
+#[derive(Default)]
+struct Window {
+    ips: DashMap<IpAddr, IpStats>,
+    paths: DashMap<String, u64>,
+}
+
+#[derive(Default)]
+struct IpStats {
+    requests: u64,
+    errors: u64,
+    /// Capped at the scan threshold
+    not_found: HashSet<String>,
+}
+
+struct Penalty {
+    until: Instant,
+    second: Instant,
+    count: u32,
+}
+
+impl AnomalyDetector {
+    pub fn start(
+        config: &AnomalyDetectionConfig,
+        metrics: Arc<Metrics>,
+        syslog: Option<Arc<SyslogSink>>,
+    ) -> Arc<Self> {
+        let detector = Arc::new(Self {
+            config: config.clone(),
+            current: ArcSwap::from_pointee(Window::default()),
+            baselines: Mutex::new(HashMap::new()),
+            penalized: DashMap::new(),
+            metrics,
+            syslog,
+        });
+        let weak: Weak<Self> = Arc::downgrade(&detector);
+        let every = Duration::from_secs(config.window_secs);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(every);
+            let Some(detector) = weak.upgrade() else {
+                return;
+            };
+            let window = detector.current.swap(Arc::new(Window::default()));
+            detector.analyze(&window);
+        });
+        detector
+    }
+
+    /// Counts a finished request in the current window.
+    pub fn record(&self, ip: IpAddr, path: &str, status: u16) {
+        let path = path.split('?').next().unwrap_or_default();
+        let window = self.current.load();
+        let max = self.config.max_tracked_keys;
+        if window.ips.len() < max || window.ips.contains_key(&ip) {
+            let mut stats = window.ips.entry(ip).or_default();
+            stats.requests += 1;
+            if status >= 400 {
+                stats.errors += 1;
+            }
+            if status == 404 && stats.not_found.len() < self.config.scan_not_found_paths {
+                stats.not_found.insert(path.to_string());
+            }
+        }
+        if window.paths.len() < max || window.paths.contains_key(path) {
+            *window.paths.entry(path.to_string()).or_default() += 1;
+        }
+    }
+
+    /// False while `ip` is penalized and over the penalty's rate limit.
+    pub fn admit(&self, ip: IpAddr) -> bool {
+        let Some(penalty) = &self.config.penalty else {
+            return true;
+        };
+        let Some(mut state) = self.penalized.get_mut(&ip) else {
+            return true;
+        };
+        let now = Instant::now();
+        if now >= state.until {
+            return true;
+        }
+        if now.duration_since(state.second) >= Duration::from_secs(1) {
+            state.second = now;
+            state.count = 0;
+        }
+        state.count += 1;
+        state.count <= penalty.rate_limit_per_second
+    }
+
+    fn analyze(&self, window: &Window) {
+        let config = &self.config;
+        for entry in window.ips.iter() {
+            let (ip, stats) = entry.pair();
+            let mut kinds = Vec::new();
+            if stats.requests > config.ip_max_requests {
+                kinds.push("ip_request_spike");
+            }
+            if stats.requests >= config.ip_min_requests
+                && stats.errors as f64 / stats.requests as f64 >= config.ip_max_error_ratio
+            {
+                kinds.push("ip_error_ratio");
+            }
+            if stats.not_found.len() >= config.scan_not_found_paths {
+                kinds.push("ip_scanning");
+            }
+            if kinds.is_empty() {
+                continue;
+            }
+            let penalized = self.penalize(*ip);
+            for kind in kinds {
+                self.report(
+                    kind,
+                    serde_json::json!({
+                        "client_ip": ip.to_string(),
+                        "requests": stats.requests,
+                        "errors": stats.errors,
+                        "not_found_paths": stats.not_found.len(),
+                        "penalized": penalized,
+                    }),
+                );
+            }
+        }
+
+        let mut baselines = self.baselines.lock().unwrap_or_else(|e| e.into_inner());
+        for entry in window.paths.iter() {
+            let (path, &requests) = entry.pair();
+            let count = requests as f64;
+            let tracked = baselines.len();
+            match baselines.get_mut(path) {
+                Some(baseline) => {
+                    if requests >= config.path_min_requests
+                        && count > config.path_spike_factor * baseline.max(1.0)
+                    {
+                        self.report(
+                            "path_request_spike",
+                            serde_json::json!({
+                                "path": path,
+                                "requests": requests,
+                                "baseline": (*baseline * 10.0).round() / 10.0,
+                            }),
+                        );
+                    }
+                    *baseline += BASELINE_WEIGHT * (count - *baseline);
+                }
+                // The first window only sets the baseline
+                None if tracked < config.max_tracked_keys => {
+                    baselines.insert(path.clone(), count);
+                }
+                None => {}
+            }
+        }
+        // Paths gone quiet decay toward zero and are dropped
+        baselines.retain(|path, baseline| {
+            if !window.paths.contains_key(path) {
+                *baseline *= 1.0 - BASELINE_WEIGHT;
+            }
+            *baseline >= 0.5
+        });
+        drop(baselines);
+
+        let now = Instant::now();
+        self.penalized.retain(|_, p| p.until > now);
+        self.metrics
+            .set_anomaly_penalized_ips(self.penalized.len() as i64);
+    }
+
+    /// Starts or extends the penalty of `ip`; false if none is configured.
+    fn penalize(&self, ip: IpAddr) -> bool {
+        let Some(penalty) = &self.config.penalty else {
+            return false;
+        };
+        let now = Instant::now();
+        let until = now + Duration::from_secs(penalty.duration_secs);
+        self.penalized
+            .entry(ip)
+            .and_modify(|p| p.until = until)
+            .or_insert(Penalty {
+                until,
+                second: now,
+                count: 0,
+            });
+        true
+    }
+
+    fn report(&self, kind: &str, mut event: serde_json::Value) {
+        tracing::warn!(kind, details = %event, "traffic anomaly");
+        self.metrics.record_anomaly(kind);
+        if let Some(syslog) = &self.syslog {
+            event["reason"] = kind.into();
+            event["mode"] = "anomaly".into();
+            event["window_secs"] = self.config.window_secs.into();
+            syslog.send(EventKind::Audit, &event);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use crate::configuration::{AsnRulesConfig, ConfigError};
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+/// Gap between the search tree and the data section
+const DATA_SEPARATOR: usize = 16;
+
+/// Allow/deny rules by autonomous system number, looked up in a MaxMind-format ASN database
+/// (e.g. GeoLite2-ASN.mmdb). Addresses the database doesn't cover, such as private ranges,
+/// always pass.
+pub struct AsnRules {
+    database: MaxMindDb,
+    allow: HashSet<u32>,
+    deny: HashSet<u32>,
+}
+
+impl AsnRules {
+    pub fn load(config: &AsnRulesConfig) -> Result<Self, ConfigError> {
+        let bytes = std::fs::read(&config.database)
+            .map_err(|e| ConfigError::Io(config.database.clone(), e))?;
+        let database = MaxMindDb::new(bytes).map_err(|e| {
+            ConfigError::Validation(format!("asn_rules.database {}: {}", config.database, e))
+        })?;
+        Ok(Self {
+            database,
+            allow: config.allow.iter().copied().collect(),
+            deny: config.deny.iter().copied().collect(),
+        })
+    }
+
+    /// `Err` carries the client's ASN when it is denied, or outside a non-empty `allow` list.
+    pub fn check(&self, ip: IpAddr) -> Result<(), u32> {
+        let Some(asn) = self.asn(ip) else {
+            return Ok(());
+        };
+        if self.deny.contains(&asn) || (!self.allow.is_empty() && !self.allow.contains(&asn)) {
+            return Err(asn);
+        }
+        Ok(())
+    }
+
+    fn asn(&self, ip: IpAddr) -> Option<u32> {
+        let record = self.database.lookup(ip)?;
+        match record.get("autonomous_system_number")? {
+            Value::Uint(asn) => u32::try_from(*asn).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Reader for the MaxMind DB format: a binary search tree over address bits whose leaves
+/// point into a data section of typed values.
+struct MaxMindDb {
+    bytes: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    data_start: usize,
+    /// Node reached after the 96 leading zero bits of an IPv4 address in an IPv6 tree
+    ipv4_start: usize,
+}
+
+/// Decoded data; types the rules never read are decoded only to be skipped.
+enum Value {
+    Uint(u128),
+    String(String),
+    Map(Vec<(String, Value)>),
+    Other,
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn uint(&self) -> Option<u64> {
+        match self {
+            Value::Uint(v) => u64::try_from(*v).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl MaxMindDb {
+    fn new(bytes: Vec<u8>) -> Result<Self, String> {
+        let marker = bytes
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or("not a MaxMind DB file (no metadata)")?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let (metadata, _) = Decoder {
+            bytes: &bytes,
+            base: metadata_start,
+        }
+        .decode(metadata_start, 0)?;
+        let field = |name| {
+            metadata
+                .get(name)
+                .and_then(Value::uint)
+                .ok_or(format!("metadata has no {}", name))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if !matches!(record_size, 24 | 28 | 32) {
+            return Err(format!("unsupported record size {}", record_size));
+        }
+        let tree_size = node_count * record_size * 2 / 8;
+        if tree_size + DATA_SEPARATOR > marker {
+            return Err("search tree is larger than the file".to_string());
+        }
+        let mut db = Self {
+            bytes,
+            node_count,
+            record_size,
+            ip_version,
+            data_start: tree_size + DATA_SEPARATOR,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = db.record(node, 0)?;
+            }
+            db.ipv4_start = node;
+        }
+        Ok(db)
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<Value> {
+        let (bits, width, mut node) = match ip {
+            IpAddr::V4(v4) => (u128::from(u32::from(v4)), 32, self.ipv4_start),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => (u128::from(u32::from(v4)), 32, self.ipv4_start),
+                None if self.ip_version == 6 => (u128::from(v6), 128, 0),
+                None => return None,
+            },
+        };
+        for i in 0..width {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = ((bits >> (width - 1 - i)) & 1) as usize;
+            node = self.record(node, bit).ok()?;
+        }
+        // Equal to the node count means no data; beyond it, an offset into the data section
+        if node <= self.node_count {
+            return None;
+        }
+        let offset = self.data_start + (node - self.node_count - DATA_SEPARATOR);
+        let decoder = Decoder {
+            bytes: &self.bytes,
+            base: self.data_start,
+        };
+        decoder.decode(offset, 0).ok().map(|(value, _)| value)
+    }
+
+    /// Left (`bit` 0) or right record of a node.
+    fn record(&self, node: usize, bit: usize) -> Result<usize, String> {
+        let size = self.record_size * 2 / 8;
+        let at = node * size;
+        let b = self
+            .bytes
+            .get(at..at + size)
+            .ok_or("search tree truncated")?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0usize, |n, b| n << 8 | *b as usize);
+        Ok(match (self.record_size, bit) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            (28, 0) => (((b[3] & 0xf0) as usize) << 20) | be(&b[0..3]),
+            (28, _) => (((b[3] & 0x0f) as usize) << 24) | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            _ => be(&b[4..8]),
+        })
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    /// Start of the section pointers are relative to
+    base: usize,
+}
+
+/// Nesting allowed in a record, so a corrupt file can't exhaust the stack
+const MAX_DEPTH: usize = 32;
+
+impl Decoder<'_> {
+    /// The value at `at` and the offset just past it.
+    fn decode(&self, at: usize, depth: usize) -> Result<(Value, usize), String> {
+        if depth > MAX_DEPTH {
+            return Err("data nested too deeply".to_string());
+        }
+        let control = *self.bytes.get(at).ok_or("data truncated")?;
+        let mut at = at + 1;
+        let mut kind = control >> 5;
+        if kind == 1 {
+            // Pointer: the value lives elsewhere, decoding continues after the pointer
+            let (target, next) = self.pointer(control, at)?;
+            let (value, _) = self.decode(target, depth + 1)?;
+            return Ok((value, next));
+        }
+        if kind == 0 {
+            let extended = *self.bytes.get(at).ok_or("data truncated")?;
+            kind = extended.checked_add(7).ok_or("unsupported data type")?;
+            at += 1;
+        }
+        let (size, at) = self.size(control & 0x1f, at)?;
+        let take = |len: usize| {
+            self.bytes
+                .get(at..at + len)
+                .ok_or_else(|| "data truncated".to_string())
+        };
+        let be = |bytes: &[u8]| bytes.iter().fold(0u128, |n, b| n << 8 | *b as u128);
+        Ok(match kind {
+            2 => (
+                Value::String(String::from_utf8_lossy(take(size)?).into_owned()),
+                at + size,
+            ),
+            3 => (Value::Other, at + take(8)?.len()),
+            4 => (Value::Other, at + take(size)?.len()),
+            5 | 6 | 9 | 10 => (Value::Uint(be(take(size)?)), at + size),
+            7 => {
+                let mut entries = Vec::with_capacity(size.min(64));
+                let mut next = at;
+                for _ in 0..size {
+                    let (key, after_key) = self.decode(next, depth + 1)?;
+                    let Value::String(key) = key else {
+                        return Err("map key is not a string".to_string());
+                    };
+                    let (value, after_value) = self.decode(after_key, depth + 1)?;
+                    entries.push((key, value));
+                    next = after_value;
+                }
+                (Value::Map(entries), next)
+            }
+            8 => (Value::Other, at + take(size)?.len()),
+            11 => {
+                let mut next = at;
+                for _ in 0..size {
+                    next = self.decode(next, depth + 1)?.1;
+                }
+                (Value::Other, next)
+            }
+            14 => (Value::Other, at),
+            15 => (Value::Other, at + take(4)?.len()),
+            other => return Err(format!("unsupported data type {}", other)),
+        })
+    }
+
+    fn size(&self, size: u8, at: usize) -> Result<(usize, usize), String> {
+        let extra = |len: usize| {
+            self.bytes
+                .get(at..at + len)
+                .map(|b| b.iter().fold(0usize, |n, b| n << 8 | *b as usize))
+                .ok_or_else(|| "data truncated".to_string())
+        };
+        Ok(match size {
+            29 => (29 + extra(1)?, at + 1),
+            30 => (285 + extra(2)?, at + 2),
+            31 => (65_821 + extra(3)?, at + 3),
+            size => (size as usize, at),
+        })
+    }
+
+    fn pointer(&self, control: u8, at: usize) -> Result<(usize, usize), String> {
+        let len = ((control >> 3) & 0x3) as usize + 1;
+        let b = self.bytes.get(at..at + len).ok_or("data truncated")?;
+        let be = b.iter().fold(0usize, |n, b| n << 8 | *b as usize);
+        let high = (control & 0x7) as usize;
+        let offset = match len {
+            1 => (high << 8) | be,
+            2 => ((high << 16) | be) + 2048,
+            3 => ((high << 24) | be) + 526_336,
+            _ => be,
+        };
+        Ok((self.base + offset, at + len))
+    }
+}
@@ -0,0 +1,237 @@
+use crate::configuration::ConfigError;
+use crate::sigv4::{self, Credentials, Payload, SignRequest};
+use serde_json::{json, Value};
+use serde_yaml::Value as Yaml;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// `aws-sm:<secret id or ARN>[#json-key]` — a Secrets Manager secret
+const SECRETS_MANAGER: &str = "aws-sm:";
+/// `aws-ssm:<parameter name or ARN>` — an SSM Parameter Store parameter, decrypted
+const PARAMETER_STORE: &str = "aws-ssm:";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const IMDS: &str = "http://169.254.169.254/latest";
+
+/// Replaces every string in the config that references an AWS secret with its value.
+/// Runs on each load, so a reload picks up rotated secrets.
+pub fn resolve(config: &mut Yaml) -> Result<(), ConfigError> {
+    let mut references = Vec::new();
+    collect(config, &mut references);
+    if references.is_empty() {
+        return Ok(());
+    }
+    // Reload runs on runtime workers; the blocking client must not run on the async context
+    tokio::task::block_in_place(|| {
+        let client = AwsClient::new()?;
+        references.sort();
+        references.dedup();
+        let mut values = HashMap::new();
+        for reference in references {
+            let value = client.fetch(&reference)?;
+            values.insert(reference, value);
+        }
+        substitute(config, &values);
+        Ok(())
+    })
+}
+
+fn collect(value: &Yaml, out: &mut Vec<String>) {
+    match value {
+        Yaml::String(s) if s.starts_with(SECRETS_MANAGER) || s.starts_with(PARAMETER_STORE) => {
+            out.push(s.clone())
+        }
+        Yaml::Sequence(items) => items.iter().for_each(|v| collect(v, out)),
+        Yaml::Mapping(map) => map.values().for_each(|v| collect(v, out)),
+        _ => {}
+    }
+}
+
+fn substitute(value: &mut Yaml, values: &HashMap<String, String>) {
+    match value {
+        Yaml::String(s) => {
+            if let Some(resolved) = values.get(s.as_str()) {
+                *s = resolved.clone();
+            }
+        }
+        Yaml::Sequence(items) => items.iter_mut().for_each(|v| substitute(v, values)),
+        Yaml::Mapping(map) => map.values_mut().for_each(|v| substitute(v, values)),
+        _ => {}
+    }
+}
+
+struct AwsClient {
+    http: reqwest::blocking::Client,
+    credentials: Credentials,
+    default_region: Option<String>,
+    /// `AWS_ENDPOINT_URL`, for VPC endpoints and local emulators
+    endpoint: Option<String>,
+}
+
+impl AwsClient {
+    fn new() -> Result<Self, ConfigError> {
+        let http = reqwest::blocking::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| secret_error(format!("aws client: {}", e)))?;
+        let credentials = match Credentials::from_env() {
+            Some(credentials) => credentials,
+            None => instance_credentials(&http)?,
+        };
+        Ok(Self {
+            http,
+            credentials,
+            default_region: std::env::var("AWS_REGION")
+                .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+                .ok(),
+            endpoint: std::env::var("AWS_ENDPOINT_URL").ok(),
+        })
+    }
+
+    fn fetch(&self, reference: &str) -> Result<String, ConfigError> {
+        if let Some(id) = reference.strip_prefix(SECRETS_MANAGER) {
+            let (id, key) = match id.split_once('#') {
+                Some((id, key)) => (id, Some(key)),
+                None => (id, None),
+            };
+            let response = self.call(
+                "secretsmanager",
+                "secretsmanager.GetSecretValue",
+                id,
+                json!({ "SecretId": id }),
+            )?;
+            let secret = response
+                .get("SecretString")
+                .and_then(Value::as_str)
+                .ok_or_else(|| secret_error(format!("{}: secret has no SecretString", id)))?;
+            match key {
+                None => Ok(secret.to_string()),
+                Some(key) => serde_json::from_str::<Value>(secret)
+                    .ok()
+                    .and_then(|v| v.get(key).and_then(Value::as_str).map(str::to_string))
+                    .ok_or_else(|| secret_error(format!("{}: no JSON key {}", id, key))),
+            }
+        } else {
+            let name = reference.trim_start_matches(PARAMETER_STORE);
+            let response = self.call(
+                "ssm",
+                "AmazonSSM.GetParameter",
+                name,
+                json!({ "Name": name, "WithDecryption": true }),
+            )?;
+            response
+                .pointer("/Parameter/Value")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| secret_error(format!("{}: parameter has no value", name)))
+        }
+    }
+
+    /// One JSON-protocol API call, in the region named by an ARN id or the default region.
+    fn call(
+        &self,
+        service: &str,
+        target: &str,
+        id: &str,
+        body: Value,
+    ) -> Result<Value, ConfigError> {
+        let region = match id.strip_prefix("arn:") {
+            Some(arn) => arn.split(':').nth(2).map(str::to_string),
+            None => self.default_region.clone(),
+        }
+        .ok_or_else(|| secret_error(format!("{}: set AWS_REGION or use an ARN", id)))?;
+        let url = match &self.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://{}.{}.amazonaws.com", service, region),
+        };
+        let host = url
+            .split_once("://")
+            .map_or(url.as_str(), |(_, rest)| rest)
+            .to_string();
+        let payload = body.to_string();
+        let content_type = "application/x-amz-json-1.1";
+        let signed = sigv4::sign(
+            &self.credentials,
+            &region,
+            service,
+            &SignRequest {
+                method: "POST",
+                host: &host,
+                path: "/",
+                query: "",
+                headers: &[("content-type", content_type), ("x-amz-target", target)],
+                payload: Payload::Bytes(payload.as_bytes()),
+            },
+            chrono::Utc::now(),
+        );
+        let mut request = self
+            .http
+            .post(format!("{}/", url))
+            .header("content-type", content_type)
+            .header("x-amz-target", target)
+            .body(payload);
+        for (name, value) in signed {
+            request = request.header(name, value);
+        }
+        let fail = |e: String| secret_error(format!("{}: {}", id, e));
+        let response = request.send().map_err(|e| fail(e.to_string()))?;
+        let status = response.status();
+        let text = response.text().map_err(|e| fail(e.to_string()))?;
+        let body: Option<Value> = serde_json::from_str(&text).ok();
+        if !status.is_success() {
+            let kind = body
+                .as_ref()
+                .and_then(|b| b.get("__type"))
+                .and_then(Value::as_str)
+                .unwrap_or("unknown error");
+            return Err(fail(format!("status {} ({})", status.as_u16(), kind)));
+        }
+        body.ok_or_else(|| fail("response is not JSON".to_string()))
+    }
+}
+
+/// Role credentials from the EC2 instance metadata service (IMDSv2).
+pub fn instance_credentials(http: &reqwest::blocking::Client) -> Result<Credentials, ConfigError> {
+    let fail = |e: String| {
+        secret_error(format!(
+            "no AWS credentials in env or instance metadata: {}",
+            e
+        ))
+    };
+    let get = |request: reqwest::blocking::RequestBuilder| {
+        request
+            .timeout(Duration::from_secs(2))
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.text())
+            .map_err(|e| fail(e.to_string()))
+    };
+    let token = get(http
+        .put(format!("{}/api/token", IMDS))
+        .header("X-aws-ec2-metadata-token-ttl-seconds", "60"))?;
+    let roles_url = format!("{}/meta-data/iam/security-credentials/", IMDS);
+    let role = get(http
+        .get(&roles_url)
+        .header("X-aws-ec2-metadata-token", &token))?;
+    let role = role.lines().next().unwrap_or_default();
+    let body = get(http
+        .get(format!("{}{}", roles_url, role))
+        .header("X-aws-ec2-metadata-token", &token))?;
+    let creds: Value = serde_json::from_str(&body).map_err(|e| fail(e.to_string()))?;
+    let field = |name: &str| {
+        creds
+            .get(name)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| fail(format!("missing {}", name)))
+    };
+    Ok(Credentials {
+        access_key_id: field("AccessKeyId")?,
+        secret_access_key: field("SecretAccessKey")?,
+        session_token: field("Token").ok(),
+    })
+}
+
+fn secret_error(msg: String) -> ConfigError {
+    ConfigError::Secret(msg)
+}
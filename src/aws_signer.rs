@@ -0,0 +1,123 @@
+use crate::aws_secrets::instance_credentials;
+use crate::configuration::{AwsSigV4Config, ConfigError};
+use crate::sigv4::{self, Credentials, Payload, SignRequest};
+use pingora::http::RequestHeader;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+/// Instance role credentials are rotated well ahead of expiry; re-reading them this often
+/// always catches the new set before the old one lapses.
+const INSTANCE_CREDENTIALS_TTL: Duration = Duration::from_secs(240);
+
+/// Signs upstream requests of routes with `aws_sigv4`, using credentials from the environment
+/// or the instance role. Credentials are loaded on first use, so routes can be added by a
+/// reload.
+pub struct AwsSigner {
+    http: reqwest::blocking::Client,
+    default_region: Option<String>,
+    /// With when they were fetched; `None` for static credentials from the environment
+    cached: RwLock<Option<(Credentials, Option<Instant>)>>,
+}
+
+impl AwsSigner {
+    pub fn new() -> Self {
+        Self {
+            http: reqwest::blocking::Client::new(),
+            default_region: std::env::var("AWS_REGION")
+                .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+                .ok(),
+            cached: RwLock::new(None),
+        }
+    }
+
+    /// Replaces the request's `authorization` with a SigV4 signature over the method, path,
+    /// query and host. Bodies are streamed, so they're sent as `UNSIGNED-PAYLOAD`; services
+    /// that insist on a payload hash only accept bodyless requests this way.
+    pub fn sign(&self, config: &AwsSigV4Config, req: &mut RequestHeader) -> Result<(), String> {
+        let region = config
+            .region
+            .as_ref()
+            .or(self.default_region.as_ref())
+            .ok_or("no region: set aws_sigv4.region or AWS_REGION")?;
+        let credentials = self.credentials().map_err(|e| e.to_string())?;
+        let has_body = req
+            .headers
+            .get(http::header::CONTENT_LENGTH)
+            .is_some_and(|v| v.as_bytes() != b"0")
+            || req.headers.contains_key(http::header::TRANSFER_ENCODING);
+        let payload = if has_body {
+            Payload::Unsigned
+        } else {
+            Payload::Bytes(b"")
+        };
+        let payload_hash = payload.hash();
+        let host = req
+            .headers
+            .get(http::header::HOST)
+            .and_then(|v| v.to_str().ok())
+            .or_else(|| req.uri.authority().map(|a| a.as_str()))
+            .unwrap_or_default()
+            .to_string();
+        let path = canonical_path(req.uri.path(), &config.service);
+        let signed = sigv4::sign(
+            &credentials,
+            region,
+            &config.service,
+            &SignRequest {
+                method: req.method.as_str(),
+                host: &host,
+                path: &path,
+                query: req.uri.query().unwrap_or_default(),
+                headers: &[("x-amz-content-sha256", &payload_hash)],
+                payload,
+            },
+            chrono::Utc::now(),
+        );
+        req.insert_header("x-amz-content-sha256", payload_hash)
+            .map_err(|e| e.to_string())?;
+        for (name, value) in signed {
+            req.insert_header(name, value).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn credentials(&self) -> Result<Credentials, ConfigError> {
+        if let Ok(cached) = self.cached.read() {
+            if let Some((credentials, fetched)) = cached.as_ref() {
+                if fetched.is_none_or(|at| at.elapsed() < INSTANCE_CREDENTIALS_TTL) {
+                    return Ok(credentials.clone());
+                }
+            }
+        }
+        let fresh = match Credentials::from_env() {
+            Some(credentials) => (credentials, None),
+            // Runs on a runtime worker; the blocking client must not run on the async context
+            None => (
+                tokio::task::block_in_place(|| instance_credentials(&self.http))?,
+                Some(Instant::now()),
+            ),
+        };
+        if let Ok(mut cached) = self.cached.write() {
+            *cached = Some(fresh.clone());
+        }
+        Ok(fresh.0)
+    }
+}
+
+/// The path as it goes into the canonical request. It's already encoded on the wire; every
+/// service but S3 wants it encoded once more.
+fn canonical_path(path: &str, service: &str) -> String {
+    if service == "s3" {
+        return path.to_string();
+    }
+    let mut out = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
@@ -0,0 +1,383 @@
+use crate::configuration::{GatewayConfig, LoadBalancing};
+use crate::health::SlowStart;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::lb::selection::RoundRobin;
+use pingora::lb::{Backend, Backends, LoadBalancer};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::future::Future;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::task::{Context, Waker};
+use std::time::{Duration, Instant};
+
+/// How fast old latency samples fade: a sample's weight halves roughly every 7s
+const EWMA_DECAY: Duration = Duration::from_secs(10);
+/// Charged as the latency of an attempt that never got a response, so a backend that fails
+/// fast doesn't look like the quickest one
+const FAILURE_PENALTY: Duration = Duration::from_secs(1);
+
+/// Picks the upstream for a request from the HTTP pool, layering slow-start and zone
+/// preference over round robin or power-of-two-choices.
+pub struct Balancer {
+    lb: Arc<LoadBalancer<RoundRobin>>,
+    slow_start: Option<Arc<SlowStart>>,
+    local_zone: Option<LocalZone>,
+    /// Per-backend load; only kept in P2C mode
+    load: Option<LoadStats>,
+    /// Backends that asked to be left alone with `Retry-After`, until when
+    backed_off: DashMap<Backend, Instant>,
+    upstreams: Arc<Upstreams>,
+}
+
+impl Balancer {
+    pub fn new(
+        lb: Arc<LoadBalancer<RoundRobin>>,
+        upstreams: Arc<Upstreams>,
+        algorithm: LoadBalancing,
+        slow_start: Option<Arc<SlowStart>>,
+        local_zone: Option<LocalZone>,
+    ) -> Self {
+        Self {
+            lb,
+            upstreams,
+            slow_start,
+            local_zone,
+            load: (algorithm == LoadBalancing::P2c).then(Default::default),
+            backed_off: DashMap::new(),
+        }
+    }
+}
+
+/// The `upstream_ips` this instance connects to: all of them, or its `upstream_subset`.
+pub fn configured(config: &GatewayConfig) -> Vec<String> {
+    match &config.upstream_subset {
+        Some(subset) => subset_of(&config.upstream_ips, subset.size, subset.instance_index),
+        None => config.upstream_ips.clone(),
+    }
+}
+
+/// The HTTP pool as last configured, serving as the load balancer's service discovery so a
+/// reload can change it. Backends dropped from it drain: they get no new requests, and
+/// requests still running on them are cut once `upstream_drain_secs` have passed.
+#[derive(Default)]
+pub struct Upstreams {
+    backends: ArcSwap<BTreeSet<Backend>>,
+    /// Backends dropped from the pool, until when their requests may run
+    draining: DashMap<Backend, Instant>,
+    /// Updated as soon as the pool changes
+    lb: OnceLock<Weak<LoadBalancer<RoundRobin>>>,
+}
+
+impl Upstreams {
+    pub fn backends(&self) -> Arc<BTreeSet<Backend>> {
+        self.backends.load_full()
+    }
+
+    /// Replaces the pool. Returns the backends that started draining.
+    pub fn set(&self, backends: BTreeSet<Backend>, drain: Duration) -> Vec<Backend> {
+        let now = Instant::now();
+        self.draining
+            .retain(|backend, until| *until > now && !backends.contains(backend));
+        let removed: Vec<Backend> = self
+            .backends
+            .load()
+            .difference(&backends)
+            .cloned()
+            .collect();
+        for backend in &removed {
+            self.draining.insert(backend.clone(), now + drain);
+        }
+        self.backends.store(Arc::new(backends));
+        if let Some(lb) = self.lb.get().and_then(Weak::upgrade) {
+            update_now(&lb);
+        }
+        removed
+    }
+
+    /// A load balancer over the current pool, for `attach` once it's shared.
+    pub fn load_balancer(self: &Arc<Self>) -> LoadBalancer<RoundRobin> {
+        let lb = LoadBalancer::from_backends(Backends::new(Box::new(Discovery(self.clone()))));
+        update_now(&lb);
+        lb
+    }
+
+    /// Has `set` update `lb` right away.
+    pub fn attach(&self, lb: &Arc<LoadBalancer<RoundRobin>>) {
+        let _ = self.lb.set(Arc::downgrade(lb));
+    }
+
+    fn is_draining(&self, backend: &Backend) -> bool {
+        !self.draining.is_empty() && self.draining.contains_key(backend)
+    }
+
+    /// Whether `backend` was dropped from the pool and its drain period is over.
+    pub fn drain_expired(&self, backend: &Backend) -> bool {
+        if self.draining.is_empty() {
+            return false;
+        }
+        self.draining
+            .get(backend)
+            .is_some_and(|until| *until <= Instant::now())
+    }
+}
+
+/// Runs discovery on `lb`. `Discovery` never waits, so the update completes in one poll.
+fn update_now(lb: &LoadBalancer<RoundRobin>) {
+    let update = std::pin::pin!(lb.update());
+    let _ = update.poll(&mut Context::from_waker(Waker::noop()));
+}
+
+struct Discovery(Arc<Upstreams>);
+
+#[async_trait]
+impl ServiceDiscovery for Discovery {
+    async fn discover(&self) -> pingora::Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        Ok((BTreeSet::clone(&self.0.backends.load()), HashMap::new()))
+    }
+}
+
+/// The `size` upstreams this instance connects to, by deterministic subsetting: instances
+/// are dealt disjoint subsets from a shuffle of the list, and each round of
+/// `upstreams / size` consecutive instance indexes gets a new shuffle. With sequential indexes
+/// every upstream serves about the same number of instances.
+fn subset_of(upstreams: &[String], size: usize, instance_index: usize) -> Vec<String> {
+    if size >= upstreams.len() {
+        return upstreams.to_vec();
+    }
+    let subset_count = upstreams.len() / size;
+    let round = instance_index / subset_count;
+    let mut shuffled = upstreams.to_vec();
+    // Sorted first so every instance shuffles the same list, whatever the config order
+    shuffled.sort();
+    shuffled.shuffle(&mut StdRng::seed_from_u64(round as u64));
+    let start = (instance_index % subset_count) * size;
+    shuffled[start..start + size].to_vec()
+}
+
+/// The upstreams sharing this instance's zone. Others, including untagged ones, only get
+/// traffic when no local upstream is healthy or a local one refused the connection.
+pub struct LocalZone {
+    addrs: HashSet<SocketAddr>,
+}
+
+impl LocalZone {
+    /// Resolves `upstreams` the same way the load balancer does.
+    pub fn resolve(upstreams: &[String]) -> std::io::Result<Self> {
+        let mut addrs = HashSet::new();
+        for upstream in upstreams {
+            addrs.extend(upstream.to_socket_addrs()?);
+        }
+        Ok(Self { addrs })
+    }
+
+    fn contains(&self, backend: &Backend) -> bool {
+        backend
+            .addr
+            .as_inet()
+            .is_some_and(|addr| self.addrs.contains(addr))
+    }
+}
+
+impl Balancer {
+    /// With `spill` set the zone preference is dropped, e.g. to retry after a local upstream
+    /// failed.
+    /// Backed-off backends are only picked when every healthy one is.
+    pub fn select(&self, spill: bool) -> Option<Backend> {
+        let available = |b: &Backend| !self.is_backed_off(b);
+        if let Some(zone) = self.local_zone.as_ref().filter(|_| !spill) {
+            if let Some(backend) = self.pick(|b| zone.contains(b) && available(b)) {
+                return Some(backend);
+            }
+        }
+        self.pick(available).or_else(|| self.pick(|_| true))
+    }
+
+    /// Keeps `backend` out of rotation for `duration`.
+    pub fn back_off(&self, backend: &Backend, duration: Duration) {
+        self.backed_off
+            .insert(backend.clone(), Instant::now() + duration);
+    }
+
+    fn is_backed_off(&self, backend: &Backend) -> bool {
+        if self.backed_off.is_empty() {
+            return false;
+        }
+        let Some(until) = self.backed_off.get(backend).map(|u| *u) else {
+            return false;
+        };
+        if until <= Instant::now() {
+            self.backed_off.remove(backend);
+            return false;
+        }
+        true
+    }
+
+    /// Whether requests on `backend` should be cut: it was dropped from the pool and its drain
+    /// period is over.
+    pub fn drain_expired(&self, backend: &Backend) -> bool {
+        self.upstreams.drain_expired(backend)
+    }
+
+    /// Whether a failed connection should be retried in another zone.
+    pub fn can_spill(&self) -> bool {
+        self.local_zone.is_some()
+    }
+
+    /// Counts a request in flight to `backend` until the lease is dropped. Only P2C mode
+    /// tracks load.
+    pub fn lease(&self, backend: &Backend) -> Option<Lease> {
+        self.load.as_ref().map(|load| load.lease(backend))
+    }
+
+    fn pick(&self, eligible: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        // Covers a request picking between a reload's `set` and its load balancer update
+        let eligible = |b: &Backend| !self.upstreams.is_draining(b) && eligible(b);
+        if let Some(load) = &self.load {
+            return self.pick_p2c(load, eligible);
+        }
+        let candidate = |b: &Backend, healthy: bool| healthy && eligible(b);
+        match &self.slow_start {
+            // Skipping every candidate leaves the plain round-robin pick
+            Some(slow_start) => self
+                .lb
+                .select_with(b"", 256, |b, healthy| {
+                    candidate(b, healthy) && slow_start.accept(b)
+                })
+                .or_else(|| self.lb.select_with(b"", 256, candidate)),
+            None => self.lb.select_with(b"", 256, candidate),
+        }
+    }
+
+    /// Power of two choices: of two random healthy candidates, the one with the lower load
+    /// score wins.
+    fn pick_p2c(&self, load: &LoadStats, eligible: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        let backends = self.lb.backends();
+        let all = backends.get_backend();
+        let mut candidates: Vec<&Backend> = all
+            .iter()
+            .filter(|b| backends.ready(b) && eligible(b))
+            .collect();
+        if let Some(slow_start) = &self.slow_start {
+            let warm: Vec<&Backend> = candidates
+                .iter()
+                .copied()
+                .filter(|b| slow_start.accept(b))
+                .collect();
+            // Skipping every candidate leaves them all in play
+            if !warm.is_empty() {
+                candidates = warm;
+            }
+        }
+        let mut rng = rand::thread_rng();
+        let picked = match candidates.choose_multiple(&mut rng, 2).collect::<Vec<_>>()[..] {
+            [a, b] if load.score(b) < load.score(a) => b,
+            [a, ..] => a,
+            [] => return None,
+        };
+        Some((*picked).clone())
+    }
+}
+
+/// Outstanding requests and EWMA response latency per backend.
+#[derive(Default)]
+pub struct LoadStats {
+    backends: DashMap<Backend, Arc<BackendLoad>>,
+}
+
+struct BackendLoad {
+    outstanding: AtomicUsize,
+    /// (EWMA latency in seconds, time of the last sample); `None` until the first sample
+    latency: Mutex<Option<(f64, Instant)>>,
+}
+
+impl LoadStats {
+    fn get(&self, backend: &Backend) -> Arc<BackendLoad> {
+        if let Some(load) = self.backends.get(backend) {
+            return load.clone();
+        }
+        self.backends
+            .entry(backend.clone())
+            .or_insert_with(|| {
+                Arc::new(BackendLoad {
+                    outstanding: AtomicUsize::new(0),
+                    latency: Mutex::new(None),
+                })
+            })
+            .clone()
+    }
+
+    /// Expected latency for one more request: the EWMA scaled by the queue it would join.
+    /// Backends without samples yet score by their queue alone.
+    fn score(&self, backend: &Backend) -> f64 {
+        let load = self.get(backend);
+        let ewma = load
+            .latency
+            .lock()
+            .ok()
+            .and_then(|l| l.map(|(ewma, _)| ewma))
+            .unwrap_or_default();
+        let outstanding = load.outstanding.load(Ordering::Relaxed) as f64;
+        (ewma.max(f64::EPSILON)) * (outstanding + 1.0)
+    }
+
+    fn lease(&self, backend: &Backend) -> Lease {
+        let load = self.get(backend);
+        load.outstanding.fetch_add(1, Ordering::Relaxed);
+        Lease {
+            load,
+            start: Instant::now(),
+            observed: false,
+        }
+    }
+}
+
+/// One request in flight to a backend.
+pub struct Lease {
+    load: Arc<BackendLoad>,
+    start: Instant,
+    observed: bool,
+}
+
+impl Lease {
+    /// Records the time to the response header as a latency sample.
+    pub fn observe(&mut self) {
+        if !self.observed {
+            self.observed = true;
+            self.load.sample(self.start.elapsed());
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if !self.observed {
+            self.load.sample(self.start.elapsed().max(FAILURE_PENALTY));
+        }
+        self.load.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl BackendLoad {
+    fn sample(&self, latency: Duration) {
+        let Ok(mut state) = self.latency.lock() else {
+            return;
+        };
+        let now = Instant::now();
+        let latency = latency.as_secs_f64();
+        let ewma = match *state {
+            Some((ewma, last)) => {
+                let weight = (-(now - last).as_secs_f64() / EWMA_DECAY.as_secs_f64()).exp();
+                ewma * weight + latency * (1.0 - weight)
+            }
+            None => latency,
+        };
+        *state = Some((ewma, now));
+    }
+}
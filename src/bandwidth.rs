@@ -0,0 +1,95 @@
+use crate::configuration::BandwidthLimitConfig;
+use http::HeaderName;
+use pingora::http::RequestHeader;
+use std::time::{Duration, Instant};
+
+/// `bandwidth_limits`: transfer rate caps chosen per request by route and client class.
+pub struct BandwidthLimits(Vec<Limit>);
+
+struct Limit {
+    routes: Vec<String>,
+    class: Option<(HeaderName, Vec<String>)>,
+    download: Option<u64>,
+    upload: Option<u64>,
+    burst: Option<u64>,
+}
+
+/// A token bucket on bytes for one direction of one request.
+pub struct Throttle {
+    bytes_per_sec: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl BandwidthLimits {
+    pub fn new(config: &[BandwidthLimitConfig]) -> Option<Self> {
+        if config.is_empty() {
+            return None;
+        }
+        let limits = config
+            .iter()
+            .map(|c| Limit {
+                routes: c.routes.clone(),
+                // Names are checked during config validation
+                class: c.header.as_ref().and_then(|h| {
+                    let name = HeaderName::from_bytes(h.as_bytes()).ok()?;
+                    Some((name, c.values.clone()))
+                }),
+                download: c.download_kb_per_sec.map(|kb| kb * 1024),
+                upload: c.upload_kb_per_sec.map(|kb| kb * 1024),
+                burst: c.burst_kb.map(|kb| kb * 1024),
+            })
+            .collect();
+        Some(Self(limits))
+    }
+
+    /// Upload and download throttles for a request, from the first entry covering its route
+    /// and client class.
+    pub fn select(
+        &self,
+        route: Option<&str>,
+        req: &RequestHeader,
+    ) -> (Option<Throttle>, Option<Throttle>) {
+        let covers = |limit: &&Limit| {
+            let route_matches = limit.routes.is_empty()
+                || route.is_some_and(|name| limit.routes.iter().any(|r| r == name));
+            let class_matches = limit.class.as_ref().is_none_or(|(header, values)| {
+                req.headers
+                    .get(header)
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|v| values.iter().any(|class| class == v.trim()))
+            });
+            route_matches && class_matches
+        };
+        match self.0.iter().find(covers) {
+            Some(limit) => (
+                limit.upload.map(|rate| Throttle::new(rate, limit.burst)),
+                limit.download.map(|rate| Throttle::new(rate, limit.burst)),
+            ),
+            None => (None, None),
+        }
+    }
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u64, burst: Option<u64>) -> Self {
+        let burst = burst.unwrap_or(bytes_per_sec) as f64;
+        Self {
+            bytes_per_sec: bytes_per_sec as f64,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes `len` bytes from the bucket; how long to wait before passing them on, if the
+    /// bucket ran dry.
+    pub fn take(&mut self, len: usize) -> Option<Duration> {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.bytes_per_sec;
+        self.last = now;
+        self.tokens = (self.tokens + refill).min(self.burst) - len as f64;
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.bytes_per_sec))
+    }
+}
@@ -0,0 +1,1044 @@
+use crate::configuration::{CacheConfig, ContentEncoding, DiskCacheConfig, PrecompressConfig};
+use crate::metrics::CacheMetrics;
+use bytes::Bytes;
+use dashmap::DashMap;
+use http::header;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::proxy::Session;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+use tokio::sync::watch;
+
+/// Chunk size when streaming a body from the disk tier
+const DISK_READ_CHUNK: usize = 64 * 1024;
+/// Middling levels: most of the size win for a fraction of the CPU of the maximum
+const GZIP_LEVEL: u32 = 6;
+const BROTLI_QUALITY: u32 = 5;
+/// Types worth compressing besides `text/*` and `+json`/`+xml` suffixes
+const COMPRESSIBLE_TYPES: &[&str] = &[
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Headers copied from the stored response onto a 304 (RFC 9110 §15.4.5).
+const NOT_MODIFIED_HEADERS: &[header::HeaderName] = &[
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::CACHE_CONTROL,
+    header::EXPIRES,
+    header::VARY,
+    header::CONTENT_LOCATION,
+];
+
+/// Cache of complete upstream responses for routes that opt in with `cache: true`. Fresh
+/// entries answer `GET`/`HEAD` directly and conditional requests with a 304.
+///
+/// Responses up to `max_body_kb` are kept in memory. With a disk tier, larger ones are
+/// streamed to files instead, which outlive restarts.
+pub struct ResponseCache {
+    memory: MemoryTier,
+    default_ttl: Duration,
+    max_body_bytes: usize,
+    disk: Option<DiskTier>,
+    /// Error statuses stored with their TTL, when negative caching is on
+    negative: Option<(Vec<u16>, Duration)>,
+    precompress: Option<PrecompressConfig>,
+    /// How long a miss waits for a concurrent fill of the same key, when coalescing
+    coalesce_wait: Option<Duration>,
+    /// Keys being filled, closed when the fill ends
+    in_flight: Arc<DashMap<String, watch::Receiver<()>>>,
+    metrics: CacheMetrics,
+}
+
+/// Memory tier split into independently locked shards by key hash. Each shard gets an equal
+/// part of the entry and byte budgets and evicts least recently used entries to stay in them.
+struct MemoryTier {
+    shards: Box<[Mutex<Shard>]>,
+    hasher: RandomState,
+    max_entries: usize,
+    max_bytes: usize,
+    /// Logical clock for recency, shared so shards can't drift apart
+    clock: AtomicU64,
+    metrics: CacheMetrics,
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<String, MemoryEntry>,
+    bytes: usize,
+}
+
+struct MemoryEntry {
+    response: Arc<CachedResponse>,
+    size: usize,
+    last_used: u64,
+}
+
+pub struct CachedResponse {
+    /// Upstream response header as received, before per-request response filters ran
+    header: ResponseHeader,
+    body: CachedBody,
+    stored: Instant,
+    expires: Instant,
+    /// Request `Accept-Encoding` the entry was stored under, for `Vary: Accept-Encoding`
+    accept_encoding: Option<Option<String>>,
+    /// Precompressed copies of an uncompressed body, in order of preference
+    variants: Vec<(ContentEncoding, Bytes)>,
+}
+
+enum CachedBody {
+    Memory(Bytes),
+    Disk(PathBuf),
+}
+
+pub enum Lookup {
+    /// Holds the flight when this request is the one filling the key
+    Miss(Option<Flight>),
+    Hit(Arc<CachedResponse>, HitBody),
+    /// With the encoding of the variant the validator was for
+    NotModified(Arc<CachedResponse>, Option<ContentEncoding>),
+}
+
+/// Marks a key as being filled; concurrent misses on it wait until this is dropped, then
+/// look again. Drop it once the response is stored or turns out not to be storable.
+pub struct Flight {
+    key: String,
+    in_flight: Arc<DashMap<String, watch::Receiver<()>>>,
+    _done: watch::Sender<()>,
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        self.in_flight.remove(&self.key);
+    }
+}
+
+/// The body of a hit, opened at lookup so a concurrent eviction can't pull it away.
+pub enum HitBody {
+    /// With the `Content-Encoding` of a precompressed variant
+    Memory(Bytes, Option<ContentEncoding>),
+    File(tokio::fs::File),
+}
+
+/// A response being buffered on its way to the client, stored once complete.
+pub struct CacheFill {
+    key: String,
+    header: ResponseHeader,
+    body: FillBody,
+    len: usize,
+    /// FNV-1a of the body so far, for a generated ETag
+    hash: u64,
+    ttl: Duration,
+    accept_encoding: Option<Option<String>>,
+}
+
+enum FillBody {
+    Memory(Vec<u8>),
+    /// Spilled to a temporary file in the disk tier; removed on drop unless stored
+    Disk(File, Option<PathBuf>),
+}
+
+impl Drop for FillBody {
+    fn drop(&mut self) {
+        if let FillBody::Disk(_, Some(tmp)) = self {
+            let _ = std::fs::remove_file(tmp);
+        }
+    }
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig, metrics: CacheMetrics) -> io::Result<Self> {
+        let disk = config
+            .disk
+            .as_ref()
+            .map(|d| DiskTier::open(d, metrics.clone()))
+            .transpose()?;
+        Ok(Self {
+            memory: MemoryTier::new(config, metrics.clone()),
+            default_ttl: Duration::from_secs(config.default_ttl_secs),
+            max_body_bytes: config.max_body_kb * 1024,
+            disk,
+            negative: config
+                .negative_ttl_secs
+                .map(|secs| (config.negative_statuses.clone(), Duration::from_secs(secs))),
+            precompress: config.precompress.clone(),
+            coalesce_wait: config
+                .coalesce_misses
+                .then(|| Duration::from_millis(config.coalesce_wait_ms)),
+            in_flight: Arc::new(DashMap::new()),
+            metrics,
+        })
+    }
+
+    /// Largest body that can be stored in either tier.
+    fn max_storable(&self) -> usize {
+        self.disk.as_ref().map_or(self.max_body_bytes, |d| {
+            d.max_body_bytes.max(self.max_body_bytes)
+        })
+    }
+
+    /// Cache key for a request, or `None` if it must bypass the cache entirely.
+    pub fn key(req: &RequestHeader) -> Option<String> {
+        if req.method != http::Method::GET && req.method != http::Method::HEAD {
+            return None;
+        }
+        if has_directive(&req.headers, "no-store") {
+            return None;
+        }
+        let host = req
+            .headers
+            .get(header::HOST)
+            .and_then(|v| v.to_str().ok())
+            .or_else(|| req.uri.host())
+            .unwrap_or("");
+        let path = req.uri.path_and_query().map_or("/", |p| p.as_str());
+        Some(format!("{}{}", host.to_ascii_lowercase(), path))
+    }
+
+    /// With coalescing, the first miss on a key leads the fill and later ones wait for it
+    /// instead of going upstream too. A waiter that still misses afterwards, e.g. because the
+    /// response wasn't storable, goes upstream on its own.
+    pub async fn lookup(&self, key: &str, req: &RequestHeader) -> Lookup {
+        let lookup = self.find(key, req);
+        if !matches!(lookup, Lookup::Miss(_)) {
+            self.metrics.record_lookup(lookup.result());
+            return lookup;
+        }
+        let Some(wait) = self
+            .coalesce_wait
+            .filter(|_| !has_directive(&req.headers, "no-cache"))
+        else {
+            self.metrics.record_lookup("miss");
+            return lookup;
+        };
+        let mut done = match self.in_flight.entry(key.to_string()) {
+            dashmap::Entry::Vacant(entry) => {
+                let (tx, rx) = watch::channel(());
+                entry.insert(rx);
+                self.metrics.record_lookup("miss");
+                return Lookup::Miss(Some(Flight {
+                    key: key.to_string(),
+                    in_flight: self.in_flight.clone(),
+                    _done: tx,
+                }));
+            }
+            dashmap::Entry::Occupied(entry) => entry.get().clone(),
+        };
+        // Nothing is ever sent; this returns once the leader drops its flight
+        let _ = tokio::time::timeout(wait, done.changed()).await;
+        let lookup = self.find(key, req);
+        self.metrics.record_lookup(match lookup {
+            Lookup::Miss(_) => "miss",
+            _ => "coalesced",
+        });
+        lookup
+    }
+
+    fn find(&self, key: &str, req: &RequestHeader) -> Lookup {
+        // `no-cache` asks us to revalidate, so go upstream (and refresh the entry on the way back)
+        if has_directive(&req.headers, "no-cache") {
+            return Lookup::Miss(None);
+        }
+        let entry = match self.memory.get(key) {
+            Some(entry) => entry,
+            None => match self.disk.as_ref().and_then(|d| d.get(key)) {
+                Some(entry) => entry,
+                None => return Lookup::Miss(None),
+            },
+        };
+        if let Some(stored) = &entry.accept_encoding {
+            if stored.as_deref() != header_str(&req.headers, header::ACCEPT_ENCODING) {
+                return Lookup::Miss(None);
+            }
+        }
+        let variant = entry.variant(req);
+        let encoding = variant.map(|(encoding, _)| *encoding);
+        if entry.header.status == http::StatusCode::OK
+            && not_modified(req, &entry.header, entry.etag(encoding).as_deref())
+        {
+            return Lookup::NotModified(entry, encoding);
+        }
+        let body = match &entry.body {
+            CachedBody::Memory(bytes) => match variant {
+                Some((encoding, variant)) => HitBody::Memory(variant.clone(), Some(*encoding)),
+                None => HitBody::Memory(bytes.clone(), None),
+            },
+            CachedBody::Disk(path) => match File::open(path) {
+                Ok(file) => HitBody::File(tokio::fs::File::from_std(file)),
+                Err(_) => return Lookup::Miss(None),
+            },
+        };
+        Lookup::Hit(entry, body)
+    }
+
+    /// Starts buffering a response if it is storable (RFC 9111 §3).
+    pub fn start_fill(
+        &self,
+        key: String,
+        req: &RequestHeader,
+        resp: &ResponseHeader,
+    ) -> Option<CacheFill> {
+        if req.method != http::Method::GET {
+            return None;
+        }
+        let negative_ttl = match &self.negative {
+            Some((statuses, ttl)) if statuses.contains(&resp.status.as_u16()) => Some(*ttl),
+            _ => None,
+        };
+        if resp.status != http::StatusCode::OK && negative_ttl.is_none() {
+            return None;
+        }
+        if resp.headers.contains_key(header::SET_COOKIE)
+            || has_directive(&resp.headers, "no-store")
+            || has_directive(&resp.headers, "private")
+            || has_directive(&resp.headers, "no-cache")
+        {
+            return None;
+        }
+        // Responses to authenticated requests are only shared when the origin says so (§3.5)
+        if req.headers.contains_key(header::AUTHORIZATION)
+            && !has_directive(&resp.headers, "public")
+            && !has_directive(&resp.headers, "s-maxage")
+            && !has_directive(&resp.headers, "must-revalidate")
+        {
+            return None;
+        }
+        if let Some(len) = header_str(&resp.headers, header::CONTENT_LENGTH) {
+            if len
+                .parse::<usize>()
+                .map_or(true, |l| l > self.max_storable())
+            {
+                return None;
+            }
+        }
+        let accept_encoding = match vary(resp) {
+            Vary::None => None,
+            Vary::AcceptEncoding => {
+                Some(header_str(&req.headers, header::ACCEPT_ENCODING).map(str::to_string))
+            }
+            Vary::Other => return None,
+        };
+        // Errors are kept no longer than the negative TTL, sooner if the origin says so
+        let ttl = match negative_ttl {
+            Some(ttl) => self.explicit_freshness(resp).map_or(ttl, |f| f.min(ttl)),
+            None => self.freshness(resp),
+        };
+        if ttl.is_zero() {
+            return None;
+        }
+        Some(CacheFill {
+            key,
+            header: resp.clone(),
+            body: FillBody::Memory(Vec::new()),
+            len: 0,
+            hash: FNV_OFFSET,
+            ttl,
+            accept_encoding,
+        })
+    }
+
+    /// Returns false once the body outgrows the limit; the fill should then be dropped.
+    pub fn fill_body(&self, fill: &mut CacheFill, chunk: &[u8]) -> bool {
+        let len = fill.len + chunk.len();
+        if len > self.max_storable() {
+            return false;
+        }
+        if let (FillBody::Memory(buf), Some(disk)) = (&fill.body, &self.disk) {
+            if len > self.max_body_bytes {
+                match disk.spill(buf) {
+                    Ok(body) => fill.body = body,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "cache disk tier write failed");
+                        return false;
+                    }
+                }
+            }
+        }
+        let written = match &mut fill.body {
+            FillBody::Memory(buf) => {
+                buf.extend_from_slice(chunk);
+                true
+            }
+            FillBody::Disk(file, _) => file.write_all(chunk).is_ok(),
+        };
+        fill.len = len;
+        fill.hash = fnv1a(fill.hash, chunk);
+        written
+    }
+
+    pub fn finish_fill(&self, mut fill: CacheFill, generate_etag: bool) {
+        let mut header = fill.header.clone();
+        if generate_etag
+            && header.status == http::StatusCode::OK
+            && !header.headers.contains_key(header::ETAG)
+        {
+            let _ = header.insert_header(header::ETAG, format!("\"{:016x}\"", fill.hash));
+        }
+        let now = Instant::now();
+        let key = std::mem::take(&mut fill.key);
+        let mut response = CachedResponse {
+            header,
+            body: CachedBody::Memory(Bytes::new()),
+            stored: now,
+            expires: now + fill.ttl,
+            accept_encoding: fill.accept_encoding.take(),
+            variants: Vec::new(),
+        };
+        match (&mut fill.body, &self.disk) {
+            (FillBody::Memory(buf), _) => {
+                let body = Bytes::from(std::mem::take(buf));
+                if let Some(config) = &self.precompress {
+                    if precompressible(&response.header, body.len(), config) {
+                        // Runs on a runtime worker; compression must not stall its other tasks
+                        response.variants =
+                            tokio::task::block_in_place(|| compress_variants(&body, config));
+                    }
+                }
+                response.body = CachedBody::Memory(body);
+                self.memory.insert(key, response);
+            }
+            (FillBody::Disk(file, tmp), Some(disk)) => {
+                if let Some(tmp) = tmp.take() {
+                    if let Err(e) = disk.store(key, response, file, tmp, fill.len as u64) {
+                        tracing::warn!(error = %e, "cache disk tier write failed");
+                    }
+                }
+            }
+            (FillBody::Disk(..), None) => {}
+        }
+    }
+
+    /// Origin freshness, or `default_ttl` when it sets none.
+    fn freshness(&self, resp: &ResponseHeader) -> Duration {
+        self.explicit_freshness(resp).unwrap_or(self.default_ttl)
+    }
+
+    /// Freshness the origin set with `Cache-Control` or `Expires`, if any.
+    fn explicit_freshness(&self, resp: &ResponseHeader) -> Option<Duration> {
+        let directive_secs =
+            |name: &str| directive_value(&resp.headers, name).and_then(|v| v.parse::<u64>().ok());
+        if let Some(secs) = directive_secs("s-maxage").or_else(|| directive_secs("max-age")) {
+            return Some(Duration::from_secs(secs));
+        }
+        let expires = header_str(&resp.headers, header::EXPIRES)?;
+        Some(
+            parse_http_date(expires)
+                .and_then(|e| (e - chrono::Utc::now()).to_std().ok())
+                .unwrap_or_default(),
+        )
+    }
+}
+
+impl CachedResponse {
+    /// Approximate memory held by the entry.
+    fn size(&self) -> usize {
+        let variants: usize = self.variants.iter().map(|(_, v)| v.len()).sum();
+        let body = variants
+            + match &self.body {
+                CachedBody::Memory(bytes) => bytes.len(),
+                CachedBody::Disk(path) => path.as_os_str().len(),
+            };
+        let header: usize = self
+            .header
+            .headers
+            .iter()
+            .map(|(n, v)| n.as_str().len() + v.len())
+            .sum();
+        body + header
+    }
+
+    /// Full response header for a hit, with `Age` set and adjusted to a precompressed body.
+    pub fn hit_header(&self, body: &HitBody) -> ResponseHeader {
+        let mut header = self.header.clone();
+        let _ = header.insert_header(header::AGE, self.stored.elapsed().as_secs().to_string());
+        if let HitBody::Memory(bytes, Some(encoding)) = body {
+            let _ = header.insert_header(header::CONTENT_ENCODING, encoding.as_str());
+            let _ = header.insert_header(header::CONTENT_LENGTH, bytes.len().to_string());
+            if let Some(etag) = self.etag(Some(*encoding)) {
+                let _ = header.insert_header(header::ETAG, etag);
+            }
+        }
+        if !self.variants.is_empty() && !matches!(vary(&self.header), Vary::AcceptEncoding) {
+            let _ = header.append_header(header::VARY, "Accept-Encoding");
+        }
+        header
+    }
+
+    /// The stored validator, tagged with the encoding for a variant since a different
+    /// representation needs its own.
+    fn etag(&self, encoding: Option<ContentEncoding>) -> Option<String> {
+        let etag = header_str(&self.header.headers, header::ETAG)?;
+        Some(match (encoding, etag.strip_suffix('"')) {
+            (Some(encoding), Some(open)) => format!("{}-{}\"", open, encoding.as_str()),
+            _ => etag.to_string(),
+        })
+    }
+
+    /// The preferred stored variant the request accepts.
+    fn variant(&self, req: &RequestHeader) -> Option<&(ContentEncoding, Bytes)> {
+        if self.variants.is_empty() {
+            return None;
+        }
+        let accepted = header_str(&req.headers, header::ACCEPT_ENCODING)?;
+        self.variants
+            .iter()
+            .find(|(encoding, _)| accepts(accepted, encoding.as_str()))
+    }
+
+    pub fn not_modified_header(
+        &self,
+        encoding: Option<ContentEncoding>,
+    ) -> pingora::Result<ResponseHeader> {
+        let mut header = ResponseHeader::build(304, Some(NOT_MODIFIED_HEADERS.len() + 1))?;
+        for name in NOT_MODIFIED_HEADERS {
+            if let Some(value) = self.header.headers.get(name) {
+                header.insert_header(name.clone(), value.clone())?;
+            }
+        }
+        if let (Some(etag), Some(_)) = (self.etag(encoding), encoding) {
+            header.insert_header(header::ETAG, etag)?;
+        }
+        if !self.variants.is_empty() && !matches!(vary(&self.header), Vary::AcceptEncoding) {
+            header.append_header(header::VARY, "Accept-Encoding")?;
+        }
+        header.insert_header(header::AGE, self.stored.elapsed().as_secs().to_string())?;
+        Ok(header)
+    }
+}
+
+impl Lookup {
+    fn result(&self) -> &'static str {
+        match self {
+            Lookup::Miss(_) => "miss",
+            Lookup::Hit(..) => "hit",
+            Lookup::NotModified(..) => "not_modified",
+        }
+    }
+}
+
+impl HitBody {
+    pub async fn write(self, session: &mut Session) -> pingora::Result<()> {
+        let mut file = match self {
+            HitBody::Memory(bytes, _) => {
+                return session.write_response_body(Some(bytes), true).await
+            }
+            HitBody::File(file) => file,
+        };
+        let mut buf = vec![0; DISK_READ_CHUNK];
+        loop {
+            let n = file.read(&mut buf).await.map_err(|e| {
+                pingora::Error::because(pingora::ErrorType::ReadError, "cached body", e)
+            })?;
+            if n == 0 {
+                return session.write_response_body(None, true).await;
+            }
+            session
+                .write_response_body(Some(Bytes::copy_from_slice(&buf[..n])), false)
+                .await?;
+        }
+    }
+}
+
+impl MemoryTier {
+    fn new(config: &CacheConfig, metrics: CacheMetrics) -> Self {
+        let shards = config.shards.max(1);
+        Self {
+            shards: (0..shards).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+            max_entries: config.max_entries.div_ceil(shards),
+            max_bytes: (config.max_memory_mb * 1024 * 1024 / shards as u64) as usize,
+            clock: AtomicU64::new(0),
+            metrics,
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<Shard> {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    fn get(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        let mut shard = self.shard(key).lock().ok()?;
+        let entry = shard.entries.get_mut(key)?;
+        if entry.response.expires <= Instant::now() {
+            self.remove(&mut shard, key);
+            return None;
+        }
+        entry.last_used = self.clock.fetch_add(1, Ordering::Relaxed);
+        Some(entry.response.clone())
+    }
+
+    /// Stores `response` unless it alone exceeds a shard's byte budget.
+    fn insert(&self, key: String, response: CachedResponse) {
+        let size = response.size() + key.len();
+        let Ok(mut shard) = self.shard(&key).lock() else {
+            return;
+        };
+        self.remove(&mut shard, &key);
+        if size > self.max_bytes {
+            return;
+        }
+        let full =
+            |s: &Shard| s.entries.len() >= self.max_entries || s.bytes + size > self.max_bytes;
+        if full(&shard) {
+            let now = Instant::now();
+            let expired: Vec<String> = shard
+                .entries
+                .iter()
+                .filter(|(_, e)| e.response.expires <= now)
+                .map(|(k, _)| k.clone())
+                .collect();
+            for key in expired {
+                self.remove(&mut shard, &key);
+            }
+        }
+        while full(&shard) {
+            let Some(victim) = shard
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone())
+            else {
+                break;
+            };
+            self.remove(&mut shard, &victim);
+            self.metrics.record_eviction("memory");
+        }
+        shard.bytes += size;
+        self.metrics.add_stored("memory", 1, size as i64);
+        shard.entries.insert(
+            key,
+            MemoryEntry {
+                response: Arc::new(response),
+                size,
+                last_used: self.clock.fetch_add(1, Ordering::Relaxed),
+            },
+        );
+    }
+
+    fn remove(&self, shard: &mut Shard, key: &str) {
+        if let Some(entry) = shard.entries.remove(key) {
+            shard.bytes -= entry.size;
+            self.metrics.add_stored("memory", -1, -(entry.size as i64));
+        }
+    }
+}
+
+/// Size-capped directory of cached responses, evicted least recently used first. Each entry
+/// is a body file plus a JSON metadata file named after a digest of the key; the index is
+/// rebuilt from the metadata on startup.
+struct DiskTier {
+    dir: PathBuf,
+    max_size: u64,
+    max_body_bytes: usize,
+    entries: DashMap<String, DiskEntry>,
+    size: AtomicU64,
+    /// Logical clock for recency
+    clock: AtomicU64,
+    metrics: CacheMetrics,
+}
+
+struct DiskEntry {
+    response: Arc<CachedResponse>,
+    name: String,
+    size: u64,
+    last_used: AtomicU64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiskMeta {
+    key: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Unix seconds
+    stored: u64,
+    expires: u64,
+    accept_encoding: Option<Option<String>>,
+}
+
+impl DiskTier {
+    fn open(config: &DiskCacheConfig, metrics: CacheMetrics) -> io::Result<Self> {
+        let dir = PathBuf::from(&config.path);
+        std::fs::create_dir_all(&dir)?;
+        let tier = Self {
+            dir,
+            max_size: config.max_size_mb * 1024 * 1024,
+            max_body_bytes: config.max_body_mb * 1024 * 1024,
+            entries: DashMap::new(),
+            size: AtomicU64::new(0),
+            clock: AtomicU64::new(0),
+            metrics,
+        };
+        let mut loaded = Vec::new();
+        for file in std::fs::read_dir(&tier.dir)? {
+            let path = file?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("meta") => {}
+                Some("body") => continue,
+                // Leftovers of fills cut short by a restart
+                _ => {
+                    let _ = std::fs::remove_file(&path);
+                    continue;
+                }
+            }
+            let loaded_entry = std::fs::read(&path)
+                .ok()
+                .and_then(|bytes| serde_json::from_slice::<DiskMeta>(&bytes).ok())
+                .and_then(|meta| {
+                    let name = path.file_stem()?.to_str()?.to_string();
+                    let size = std::fs::metadata(tier.body_path(&name)).ok()?.len();
+                    Some((meta, name, size))
+                });
+            match loaded_entry {
+                Some(entry) => loaded.push(entry),
+                None => {
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+        // Oldest first, so recency survives the restart roughly
+        loaded.sort_by_key(|(meta, ..)| meta.stored);
+        let count = loaded.len();
+        for (meta, name, size) in loaded {
+            match tier.restore(meta, &name) {
+                Some((key, response)) => tier.index(key, response, name, size),
+                None => tier.remove_files(&name),
+            }
+        }
+        tracing::info!(dir = %tier.dir.display(), entries = count, "cache disk tier opened");
+        Ok(tier)
+    }
+
+    fn body_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.body", name))
+    }
+
+    fn meta_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.meta", name))
+    }
+
+    fn remove_files(&self, name: &str) {
+        let _ = std::fs::remove_file(self.meta_path(name));
+        let _ = std::fs::remove_file(self.body_path(name));
+    }
+
+    /// Moves a body buffered in memory to a temporary file.
+    fn spill(&self, buffered: &[u8]) -> io::Result<FillBody> {
+        let tmp = self
+            .dir
+            .join(format!("fill-{:016x}.tmp", rand::random::<u64>()));
+        let mut file = File::create(&tmp)?;
+        let body = FillBody::Disk(file.try_clone()?, Some(tmp));
+        file.write_all(buffered)?;
+        Ok(body)
+    }
+
+    fn get(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        let entry = self.entries.get(key)?;
+        if entry.response.expires <= Instant::now() {
+            drop(entry);
+            self.remove(key);
+            return None;
+        }
+        entry.last_used.store(
+            self.clock.fetch_add(1, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        Some(entry.response.clone())
+    }
+
+    fn store(
+        &self,
+        key: String,
+        mut response: CachedResponse,
+        file: &mut File,
+        tmp: PathBuf,
+        size: u64,
+    ) -> io::Result<()> {
+        if size > self.max_size {
+            let _ = std::fs::remove_file(&tmp);
+            return Ok(());
+        }
+        if let Err(e) = file.flush() {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
+        let name = hex::encode(&digest::digest(&digest::SHA256, key.as_bytes()).as_ref()[..16]);
+        self.remove(&key);
+        while self.size.load(Ordering::Relaxed) + size > self.max_size && self.evict_one() {}
+        let meta = DiskMeta {
+            key: key.clone(),
+            status: response.header.status.as_u16(),
+            headers: response
+                .header
+                .headers
+                .iter()
+                .filter_map(|(n, v)| Some((n.to_string(), v.to_str().ok()?.to_string())))
+                .collect(),
+            stored: unix_secs(SystemTime::now()),
+            expires: unix_secs(
+                SystemTime::now() + response.expires.saturating_duration_since(Instant::now()),
+            ),
+            accept_encoding: response.accept_encoding.clone(),
+        };
+        if let Err(e) = std::fs::rename(&tmp, self.body_path(&name)) {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
+        std::fs::write(self.meta_path(&name), serde_json::to_vec(&meta)?)?;
+        response.body = CachedBody::Disk(self.body_path(&name));
+        self.index(key, response, name, size);
+        Ok(())
+    }
+
+    fn restore(&self, meta: DiskMeta, name: &str) -> Option<(String, CachedResponse)> {
+        let now = SystemTime::now();
+        let expires_at = UNIX_EPOCH + Duration::from_secs(meta.expires);
+        let remaining = expires_at.duration_since(now).ok()?;
+        let age = now
+            .duration_since(UNIX_EPOCH + Duration::from_secs(meta.stored))
+            .unwrap_or_default();
+        let mut header = ResponseHeader::build(meta.status, Some(meta.headers.len())).ok()?;
+        for (name, value) in meta.headers {
+            header.append_header(name, value).ok()?;
+        }
+        let instant = Instant::now();
+        Some((
+            meta.key,
+            CachedResponse {
+                header,
+                body: CachedBody::Disk(self.body_path(name)),
+                stored: instant.checked_sub(age).unwrap_or(instant),
+                expires: instant + remaining,
+                accept_encoding: meta.accept_encoding,
+                variants: Vec::new(),
+            },
+        ))
+    }
+
+    fn index(&self, key: String, response: CachedResponse, name: String, size: u64) {
+        self.size.fetch_add(size, Ordering::Relaxed);
+        self.metrics.add_stored("disk", 1, size as i64);
+        self.entries.insert(
+            key,
+            DiskEntry {
+                response: Arc::new(response),
+                name,
+                size,
+                last_used: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+            },
+        );
+    }
+
+    fn remove(&self, key: &str) {
+        if let Some((_, entry)) = self.entries.remove(key) {
+            self.size.fetch_sub(entry.size, Ordering::Relaxed);
+            self.metrics.add_stored("disk", -1, -(entry.size as i64));
+            self.remove_files(&entry.name);
+        }
+    }
+
+    /// Drops the least recently used entry; false if there was none.
+    fn evict_one(&self) -> bool {
+        let victim = self
+            .entries
+            .iter()
+            .min_by_key(|e| e.last_used.load(Ordering::Relaxed))
+            .map(|e| e.key().clone());
+        match victim {
+            Some(key) => {
+                self.remove(&key);
+                self.metrics.record_eviction("disk");
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Validator for static content derived from size and modification time, like nginx's.
+/// Only applied when the upstream sent both and no ETag of its own.
+pub fn ensure_etag(resp: &mut ResponseHeader) {
+    if resp.headers.contains_key(header::ETAG) {
+        return;
+    }
+    let len = header_str(&resp.headers, header::CONTENT_LENGTH).and_then(|l| l.parse::<u64>().ok());
+    let modified = header_str(&resp.headers, header::LAST_MODIFIED).and_then(parse_http_date);
+    if let (Some(len), Some(modified)) = (len, modified) {
+        let _ = resp.insert_header(
+            header::ETAG,
+            format!("\"{:x}-{:x}\"", modified.timestamp(), len),
+        );
+    }
+}
+
+/// FNV-1a over the body as it streams in, the strong validator for stored responses that
+/// had none.
+fn fnv1a(hash: u64, chunk: &[u8]) -> u64 {
+    chunk
+        .iter()
+        .fold(hash, |h, b| (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// RFC 9110 §13.1: `If-None-Match` wins over `If-Modified-Since`.
+/// `etag` is the validator of the representation that would be sent.
+fn not_modified(req: &RequestHeader, stored: &ResponseHeader, etag: Option<&str>) -> bool {
+    if let Some(candidates) = header_str(&req.headers, header::IF_NONE_MATCH) {
+        let Some(etag) = etag else {
+            return false;
+        };
+        return candidates
+            .split(',')
+            .map(str::trim)
+            .any(|c| c == "*" || weak_match(c, etag));
+    }
+    let since = header_str(&req.headers, header::IF_MODIFIED_SINCE).and_then(parse_http_date);
+    let modified = header_str(&stored.headers, header::LAST_MODIFIED).and_then(parse_http_date);
+    matches!((since, modified), (Some(since), Some(modified)) if modified <= since)
+}
+
+fn weak_match(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+enum Vary {
+    None,
+    AcceptEncoding,
+    Other,
+}
+
+fn vary(resp: &ResponseHeader) -> Vary {
+    let mut result = Vary::None;
+    for value in resp.headers.get_all(header::VARY) {
+        for field in value.to_str().unwrap_or("*").split(',').map(str::trim) {
+            if field.eq_ignore_ascii_case("accept-encoding") {
+                result = Vary::AcceptEncoding;
+            } else if !field.is_empty() {
+                return Vary::Other;
+            }
+        }
+    }
+    result
+}
+
+/// Whether an `Accept-Encoding` value allows `coding`, i.e. lists it without `q=0`.
+fn accepts(accept_encoding: &str, coding: &str) -> bool {
+    accept_encoding.split(',').any(|item| {
+        let mut params = item.split(';').map(str::trim);
+        params
+            .next()
+            .is_some_and(|c| c.eq_ignore_ascii_case(coding))
+            && !params.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            })
+    })
+}
+
+/// Stored responses get variants only when they're sent as-is, text-like and big enough.
+fn precompressible(resp: &ResponseHeader, len: usize, config: &PrecompressConfig) -> bool {
+    if resp.status != http::StatusCode::OK
+        || len < config.min_bytes
+        || resp.headers.contains_key(header::CONTENT_ENCODING)
+        || has_directive(&resp.headers, "no-transform")
+    {
+        return false;
+    }
+    let Some(content_type) = header_str(&resp.headers, header::CONTENT_TYPE) else {
+        return false;
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || COMPRESSIBLE_TYPES.contains(&mime.as_str())
+}
+
+/// Variants that came out smaller than the body, in the configured order.
+fn compress_variants(body: &[u8], config: &PrecompressConfig) -> Vec<(ContentEncoding, Bytes)> {
+    config
+        .encodings
+        .iter()
+        .filter_map(|encoding| {
+            let compressed = match encoding {
+                ContentEncoding::Gzip => {
+                    let mut encoder = flate2::write::GzEncoder::new(
+                        Vec::new(),
+                        flate2::Compression::new(GZIP_LEVEL),
+                    );
+                    encoder.write_all(body).ok()?;
+                    encoder.finish().ok()?
+                }
+                ContentEncoding::Br => {
+                    let mut out = Vec::new();
+                    let mut encoder =
+                        brotli::CompressorWriter::new(&mut out, 4096, BROTLI_QUALITY, 22);
+                    encoder.write_all(body).ok()?;
+                    drop(encoder);
+                    out
+                }
+            };
+            (compressed.len() < body.len()).then(|| (*encoding, Bytes::from(compressed)))
+        })
+        .collect()
+}
+
+fn header_str(headers: &http::HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn cache_control(headers: &http::HeaderMap) -> impl Iterator<Item = (String, Option<String>)> + '_ {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| {
+            let (name, value) = match d.split_once('=') {
+                Some((n, v)) => (n, Some(v.trim().trim_matches('"').to_string())),
+                None => (d, None),
+            };
+            (name.trim().to_ascii_lowercase(), value)
+        })
+}
+
+fn has_directive(headers: &http::HeaderMap, name: &str) -> bool {
+    cache_control(headers).any(|(n, _)| n == name)
+}
+
+fn directive_value(headers: &http::HeaderMap, name: &str) -> Option<String> {
+    cache_control(headers)
+        .find(|(n, _)| n == name)
+        .and_then(|(_, v)| v)
+}
+
+fn parse_http_date(value: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    chrono::DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|d| d.with_timezone(&chrono::Utc))
+}
@@ -0,0 +1,125 @@
+use crate::access_log::AccessLog;
+use crate::configuration::BodyCaptureConfig;
+use pingora::http::{RequestHeader, ResponseHeader};
+use serde_json::json;
+use std::io;
+
+/// Headers whose values never end up in a capture file.
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "set-cookie",
+    "proxy-authorization",
+];
+
+/// Debug facility that records headers plus the first N bytes of request and response
+/// bodies for a sample of traffic, written to its own JSON-lines file.
+pub struct BodyCapture {
+    sink: AccessLog,
+    sample_rate: f64,
+    path_prefix: Option<String>,
+    max_body_bytes: usize,
+}
+
+/// Per-request capture buffer, carried in the request context while a request is sampled.
+#[derive(Default)]
+pub struct CaptureRecord {
+    request_headers: Vec<(String, String)>,
+    request_body: Vec<u8>,
+    request_truncated: bool,
+    response_status: u16,
+    response_headers: Vec<(String, String)>,
+    response_body: Vec<u8>,
+    response_truncated: bool,
+}
+
+impl BodyCapture {
+    pub fn new(config: &BodyCaptureConfig) -> io::Result<Self> {
+        Ok(Self {
+            sink: AccessLog::open(&config.path)?,
+            sample_rate: config.sample_rate,
+            path_prefix: config.path_prefix.clone(),
+            max_body_bytes: config.max_body_kb * 1024,
+        })
+    }
+
+    pub fn sink(&self) -> &AccessLog {
+        &self.sink
+    }
+
+    /// Starts a capture if the request matches the filter and wins the sampling draw.
+    pub fn start(&self, req: &RequestHeader) -> Option<CaptureRecord> {
+        if let Some(prefix) = &self.path_prefix {
+            if !req.uri.path().starts_with(prefix.as_str()) {
+                return None;
+            }
+        }
+        if self.sample_rate < 1.0 && rand::random::<f64>() >= self.sample_rate {
+            return None;
+        }
+        Some(CaptureRecord {
+            request_headers: header_pairs(req.headers.iter()),
+            ..Default::default()
+        })
+    }
+
+    pub fn request_body(&self, record: &mut CaptureRecord, chunk: &[u8]) {
+        record.request_truncated |=
+            append_bounded(&mut record.request_body, chunk, self.max_body_bytes);
+    }
+
+    pub fn response_header(&self, record: &mut CaptureRecord, resp: &ResponseHeader) {
+        record.response_status = resp.status.as_u16();
+        record.response_headers = header_pairs(resp.headers.iter());
+    }
+
+    pub fn response_body(&self, record: &mut CaptureRecord, chunk: &[u8]) {
+        record.response_truncated |=
+            append_bounded(&mut record.response_body, chunk, self.max_body_bytes);
+    }
+
+    /// `status` covers responses generated by the proxy itself, which skip `response_header`.
+    pub fn finish(&self, record: CaptureRecord, method: &str, path: &str, status: u16) {
+        let status = match record.response_status {
+            0 => status,
+            s => s,
+        };
+        self.sink.write(&json!({
+            "method": method,
+            "path": path,
+            "request": {
+                "headers": record.request_headers,
+                "body": String::from_utf8_lossy(&record.request_body),
+                "truncated": record.request_truncated,
+            },
+            "response": {
+                "status": status,
+                "headers": record.response_headers,
+                "body": String::from_utf8_lossy(&record.response_body),
+                "truncated": record.response_truncated,
+            },
+        }));
+    }
+}
+
+/// Returns true if anything had to be dropped.
+fn append_bounded(buf: &mut Vec<u8>, chunk: &[u8], limit: usize) -> bool {
+    let room = limit.saturating_sub(buf.len());
+    buf.extend_from_slice(&chunk[..chunk.len().min(room)]);
+    chunk.len() > room
+}
+
+fn header_pairs<'a>(
+    headers: impl Iterator<Item = (&'a http::HeaderName, &'a http::HeaderValue)>,
+) -> Vec<(String, String)> {
+    headers
+        .map(|(name, value)| {
+            let value = if SENSITIVE_HEADERS.contains(&name.as_str()) {
+                "<redacted>".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
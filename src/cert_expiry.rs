@@ -0,0 +1,119 @@
+use crate::configuration::GatewayConfig;
+use crate::metrics::Metrics;
+use crate::vault::Vault;
+use pingora::tls::nid::Nid;
+use pingora::tls::x509::X509;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Exports when each loaded certificate expires and warns ahead of it. Files are read once,
+/// as the listener only loads them on startup; a Vault certificate is checked as currently
+/// served.
+pub struct CertExpiry {
+    /// Source label and the certificates it holds
+    files: Vec<(&'static str, Vec<X509>)>,
+    vault: Option<Arc<Vault>>,
+    warn_before: Duration,
+    interval: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl CertExpiry {
+    pub fn new(config: &GatewayConfig, vault: Option<Arc<Vault>>, metrics: Arc<Metrics>) -> Self {
+        let vault = vault.filter(|v| v.serves_tls());
+        let mut paths = Vec::new();
+        if vault.is_none() {
+            paths.push(("listener", config.tls_cert_path.as_str()));
+        }
+        if let Some(ca) = config
+            .admin_auth
+            .as_ref()
+            .and_then(|a| a.client_ca.as_deref())
+        {
+            paths.push(("admin_client_ca", ca));
+        }
+        let files = paths
+            .into_iter()
+            .filter_map(|(source, path)| {
+                let certs = std::fs::read(path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|pem| X509::stack_from_pem(&pem).map_err(|e| e.to_string()));
+                match certs {
+                    Ok(certs) => Some((source, certs)),
+                    Err(e) => {
+                        tracing::warn!(source, path, error = %e, "cannot read certificates for expiry checks");
+                        None
+                    }
+                }
+            })
+            .collect();
+        Self {
+            files,
+            vault,
+            warn_before: Duration::from_secs(config.cert_expiry.warn_days * 86_400),
+            interval: Duration::from_secs(config.cert_expiry.check_interval_secs),
+            metrics,
+        }
+    }
+
+    /// Checks now and then every `check_interval_secs`, for the life of the process.
+    pub fn spawn(self) {
+        std::thread::spawn(move || loop {
+            self.check();
+            std::thread::sleep(self.interval);
+        });
+    }
+
+    fn check(&self) {
+        let vault = self.vault.as_ref().map(|v| ("listener", v.certificates()));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let mut expiries = Vec::new();
+        for (source, certs) in self.files.iter().chain(vault.iter()) {
+            for cert in certs {
+                let Some(at) = expiry(cert) else {
+                    continue;
+                };
+                let subject = subject(cert);
+                let left = at - now;
+                if left <= 0 {
+                    tracing::error!(source, subject, "certificate has expired");
+                } else if left <= self.warn_before.as_secs() as i64 {
+                    tracing::warn!(
+                        source,
+                        subject,
+                        days_left = left / 86_400,
+                        "certificate expires soon"
+                    );
+                }
+                expiries.push((*source, subject, at));
+            }
+        }
+        self.metrics.set_cert_expiries(&expiries);
+    }
+}
+
+/// `notAfter` as Unix time, from OpenSSL's rendering of it (`Jan  2 03:04:05 2027 GMT`).
+fn expiry(cert: &X509) -> Option<i64> {
+    let not_after = cert.not_after().to_string();
+    chrono::NaiveDateTime::parse_from_str(&not_after, "%b %e %H:%M:%S %Y GMT")
+        .ok()
+        .map(|at| at.and_utc().timestamp())
+}
+
+/// The common name, or the whole subject if there is none.
+fn subject(cert: &X509) -> String {
+    let name = cert.subject_name();
+    name.entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|cn| cn.data().as_utf8().ok())
+        .map(|cn| cn.to_string())
+        .unwrap_or_else(|| {
+            name.entries()
+                .filter_map(|e| e.data().as_utf8().ok().map(|v| v.to_string()))
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+}
@@ -0,0 +1,283 @@
+use crate::configuration::{CaptchaConfig, CaptchaProvider, ChallengeConfig, ConfigError};
+use crate::middleware::matches_pattern;
+use crate::openapi::parse_query;
+use http::HeaderName;
+use pingora::http::RequestHeader;
+use ring::hmac;
+use serde_json::Value;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interstitial for suspicious clients, run by the `challenge` stage: a page whose script sets
+/// a signed cookie and reloads. Browsers pass after one round trip. The cookie value is in the
+/// page itself, so this only stops clients that never look at the page, such as naive
+/// scrapers and scanners; a bot that parses it can lift the cookie without running any
+/// script. Use `captcha` where that matters. The cookie is bound to the client's address and
+/// user agent, so it can't be harvested once and shared across a bot fleet.
+///
+/// With `captcha` set the page shows the provider's widget instead, and the cookie is only
+/// issued by the verify endpoint after the provider confirms the solution.
+pub struct Challenge {
+    key: hmac::Key,
+    cookie_name: String,
+    ttl_secs: u64,
+    paths: Vec<String>,
+    /// Lowercase substrings
+    user_agents: Vec<String>,
+    missing_headers: Vec<HeaderName>,
+    tor_exit_nodes: bool,
+    captcha: Option<Captcha>,
+}
+
+struct Captcha {
+    provider: CaptchaProvider,
+    site_key: String,
+    secret_key: String,
+    verify_url: String,
+    verify_path: String,
+    client: reqwest::blocking::Client,
+}
+
+/// A solved CAPTCHA: where to send the client back to, with its cookie.
+pub struct Clearance {
+    pub location: String,
+    pub set_cookie: String,
+}
+
+impl Challenge {
+    pub fn new(config: &ChallengeConfig) -> Result<Self, ConfigError> {
+        Ok(Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, config.secret.as_bytes()),
+            cookie_name: config.cookie_name.clone(),
+            ttl_secs: config.ttl_secs,
+            paths: config.paths.clone(),
+            user_agents: config
+                .user_agents
+                .iter()
+                .map(|ua| ua.to_lowercase())
+                .collect(),
+            missing_headers: config
+                .missing_headers
+                .iter()
+                .map(|h| {
+                    HeaderName::from_bytes(h.as_bytes()).map_err(|e| {
+                        ConfigError::Validation(format!("challenge.missing_headers: {}", e))
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+            tor_exit_nodes: config.tor_exit_nodes,
+            captcha: config.captcha.as_ref().map(Captcha::new).transpose()?,
+        })
+    }
+
+    /// `Some` for requests to the CAPTCHA verify path, with the cookie to issue or the reason
+    /// the solution was refused.
+    pub fn verify(
+        &self,
+        req: &RequestHeader,
+        client: &str,
+    ) -> Option<Result<Clearance, &'static str>> {
+        let captcha = self.captcha.as_ref()?;
+        if req.uri.path() != captcha.verify_path {
+            return None;
+        }
+        let query = parse_query(req.uri.query().unwrap_or(""));
+        let param = |name| {
+            query
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        let Some(token) = param("token").filter(|t| !t.is_empty()) else {
+            return Some(Err("captcha_missing"));
+        };
+        if let Err(e) = captcha.check(token, client) {
+            tracing::warn!(client_ip = %client, error = %e, "CAPTCHA verification failed");
+            return Some(Err("captcha_failed"));
+        }
+        // Only local paths, so the endpoint can't be used as an open redirect
+        let location = param("return")
+            .filter(|r| r.starts_with('/') && !r.starts_with("//") && !r.starts_with("/\\"))
+            .filter(|r| !r.contains(|c: char| c.is_control()))
+            .filter(|r| !r.starts_with(&captcha.verify_path))
+            .unwrap_or("/");
+        Some(Ok(Clearance {
+            location: location.to_string(),
+            set_cookie: format!("{}; HttpOnly", self.cookie_value(req, client)),
+        }))
+    }
+
+    /// Whether any criterion matches; `tor_exit` says whether the client is a known exit node.
+    pub fn suspicious(&self, req: &RequestHeader, tor_exit: bool) -> bool {
+        let user_agent = req
+            .headers
+            .get(http::header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_lowercase();
+        (self.tor_exit_nodes && tor_exit)
+            || self
+                .paths
+                .iter()
+                .any(|p| matches_pattern(p, req.uri.path()))
+            || self.user_agents.iter().any(|ua| user_agent.contains(ua))
+            || self
+                .missing_headers
+                .iter()
+                .any(|h| !req.headers.contains_key(h))
+    }
+
+    /// Whether the request carries an unexpired cookie issued to this client.
+    pub fn passed(&self, req: &RequestHeader, client: &str) -> bool {
+        let Some((expires, signature)) = self.cookie(req).and_then(|c| c.split_once('.')) else {
+            return false;
+        };
+        let (Ok(expires), Ok(signature)) = (expires.parse::<u64>(), hex::decode(signature)) else {
+            return false;
+        };
+        expires > unix_now()
+            && hmac::verify(
+                &self.key,
+                message(expires, client, user_agent(req)).as_bytes(),
+                &signature,
+            )
+            .is_ok()
+    }
+
+    /// HTML of the interstitial: the CAPTCHA widget, or a script setting a cookie freshly
+    /// signed for this client.
+    pub fn page(&self, req: &RequestHeader, client: &str) -> String {
+        if let Some(captcha) = &self.captcha {
+            return captcha.page();
+        }
+        let cookie = self.cookie_value(req, client);
+        // Without the reload guard a browser refusing cookies would loop forever
+        format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Checking your browser</title></head>\
+             <body><p>Checking your browser&hellip;</p><noscript>Enable JavaScript to continue.</noscript>\
+             <script>document.cookie = \"{}\";\
+             if (document.cookie.indexOf(\"{}=\") < 0) {{ document.body.textContent = \"Enable cookies to continue.\"; }}\
+             else {{ location.reload(); }}</script></body></html>\n",
+            cookie, self.cookie_name
+        )
+    }
+
+    /// `Set-Cookie` value of a cookie signed for this client.
+    fn cookie_value(&self, req: &RequestHeader, client: &str) -> String {
+        let expires = unix_now() + self.ttl_secs;
+        let tag = hmac::sign(
+            &self.key,
+            message(expires, client, user_agent(req)).as_bytes(),
+        );
+        format!(
+            "{}={}.{}; Path=/; Max-Age={}; SameSite=Lax; Secure",
+            self.cookie_name,
+            expires,
+            hex::encode(tag.as_ref()),
+            self.ttl_secs
+        )
+    }
+
+    fn cookie<'a>(&self, req: &'a RequestHeader) -> Option<&'a str> {
+        req.headers
+            .get_all(http::header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == self.cookie_name)
+            .map(|(_, value)| value)
+    }
+}
+
+impl Captcha {
+    fn new(config: &CaptchaConfig) -> Result<Self, ConfigError> {
+        let client = tokio::task::block_in_place(|| {
+            reqwest::blocking::Client::builder()
+                .timeout(VERIFY_TIMEOUT)
+                .build()
+        })
+        .map_err(|e| ConfigError::Secret(format!("captcha client: {}", e)))?;
+        let default_url = match config.provider {
+            CaptchaProvider::Turnstile => {
+                "https://challenges.cloudflare.com/turnstile/v0/siteverify"
+            }
+            CaptchaProvider::Hcaptcha => "https://api.hcaptcha.com/siteverify",
+        };
+        Ok(Self {
+            provider: config.provider,
+            site_key: config.site_key.clone(),
+            secret_key: config.secret_key.clone(),
+            verify_url: config
+                .verify_url
+                .clone()
+                .unwrap_or_else(|| default_url.to_string()),
+            verify_path: config.verify_path.clone(),
+            client,
+        })
+    }
+
+    /// Server-side check of a widget token with the provider.
+    fn check(&self, token: &str, client: &str) -> Result<(), String> {
+        let mut form = vec![("secret", self.secret_key.as_str()), ("response", token)];
+        if !client.is_empty() {
+            form.push(("remoteip", client));
+        }
+        let body: Value = tokio::task::block_in_place(|| {
+            self.client
+                .post(&self.verify_url)
+                .form(&form)
+                .send()
+                .and_then(|r| r.error_for_status())
+                .and_then(|r| r.json())
+        })
+        .map_err(|e| e.to_string())?;
+        match body.get("success").and_then(Value::as_bool) {
+            Some(true) => Ok(()),
+            _ => Err(format!("rejected by provider: {}", body)),
+        }
+    }
+
+    fn page(&self) -> String {
+        let (script, class) = match self.provider {
+            CaptchaProvider::Turnstile => (
+                "https://challenges.cloudflare.com/turnstile/v0/api.js",
+                "cf-turnstile",
+            ),
+            CaptchaProvider::Hcaptcha => ("https://js.hcaptcha.com/1/api.js", "h-captcha"),
+        };
+        format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Checking your browser</title>\
+             <script src=\"{}\" async defer></script></head>\
+             <body><p>Confirm you are human to continue.</p>\
+             <div class=\"{}\" data-sitekey=\"{}\" data-callback=\"solved\"></div>\
+             <script>function solved(token) {{ location.href = \"{}?token=\" + encodeURIComponent(token) + \
+             \"&return=\" + encodeURIComponent(location.pathname + location.search); }}</script></body></html>\n",
+            script, class, self.site_key, self.verify_path
+        )
+    }
+}
+
+fn user_agent(req: &RequestHeader) -> &[u8] {
+    req.headers
+        .get(http::header::USER_AGENT)
+        .map(|v| v.as_bytes())
+        .unwrap_or_default()
+}
+
+fn message(expires: u64, client: &str, user_agent: &[u8]) -> String {
+    format!(
+        "{}\n{}\n{}",
+        expires,
+        client,
+        String::from_utf8_lossy(user_agent)
+    )
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
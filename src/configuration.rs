@@ -1,23 +1,1909 @@
-use serde::Deserialize;
+use crate::aws_secrets;
+use crate::ip_reputation::parse_range;
+use crate::metrics::VARIABLE_LABELS;
+use crate::middleware::{RULE_STAGES, STAGE_NAMES};
+use crate::upload_filter::FILE_TYPES;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct GatewayConfig {
+    /// Port of the HTTPS listener. Changing it, the TLS settings or `http2` on reload hands
+    /// the listeners over to a new process of the proxy.
     pub listen_port: u16,
+    /// Address the HTTPS listener binds; `::` takes IPv6 clients and, on systems that map
+    /// them (Linux by default), IPv4 ones too. Changing it on reload hands the listeners over
+    /// like `listen_port`.
+    #[serde(default = "default_listen_host")]
+    pub listen_host: String,
+    /// Upstreams dropped from the list on reload drain, see `upstream_drain_secs`
     pub upstream_ips: Vec<String>,
+    /// How long requests already running on an upstream dropped on reload may continue
+    /// before they're cut. Checked as response data arrives.
+    #[serde(default = "default_upstream_drain_secs")]
+    pub upstream_drain_secs: u64,
+    /// How requests are spread over `upstream_ips`. Requires restart to change.
+    #[serde(default)]
+    pub load_balancing: LoadBalancing,
+    /// Connect to only a subset of `upstream_ips`, bounding connection counts for large
+    /// pools. Requires restart to change.
+    #[serde(default)]
+    pub upstream_subset: Option<UpstreamSubsetConfig>,
+    /// How hostnames in `upstream_ips` are resolved and kept current. Requires restart to
+    /// change.
+    #[serde(default)]
+    pub upstream_dns: UpstreamDnsConfig,
+    /// What to do with `Retry-After` on upstream 429 and 503 responses. Passed through
+    /// untouched if unset.
+    #[serde(default)]
+    pub upstream_retry_after: Option<RetryAfterConfig>,
+    /// Zone (e.g. availability zone) this instance runs in. Upstreams tagged with the same
+    /// zone in `upstream_zones` are preferred. Requires restart to change.
+    #[serde(default)]
+    pub zone: Option<String>,
+    /// Zone name to the `upstream_ips` entries in it. Requires restart to change.
+    #[serde(default)]
+    pub upstream_zones: BTreeMap<String, Vec<String>>,
+    /// May be omitted when `vault.tls` supplies the certificate
+    #[serde(default)]
     pub tls_cert_path: String,
+    #[serde(default)]
     pub tls_key_path: String,
+    #[serde(default)]
+    pub tls_profile: TlsProfile,
+    /// Offer HTTP/2 to clients via ALPN; HTTP/1.1 only when false
+    #[serde(default = "default_true")]
+    pub http2: bool,
+    /// Per-request checks on the client's TLS handshake, enforced by the `tls` stage
+    #[serde(default)]
+    pub tls_policy: Option<TlsPolicyConfig>,
     pub rate_limit_per_second: u32,
-    /// Secret key for validating JWT signatures (HS256)
+    /// IPv6 clients are rate limited per network of this prefix length, since one host
+    /// usually holds a whole /64
+    #[serde(default = "default_rate_limit_ipv6_prefix")]
+    pub rate_limit_ipv6_prefix: u8,
+    /// Daily/monthly request quotas per authenticated JWT subject or HMAC key id, enforced by
+    /// the `quota` stage. Requires restart to change.
+    #[serde(default)]
+    pub quota: Option<QuotaConfig>,
+    /// Background analysis of per-IP and per-path traffic, reporting spikes, error bursts
+    /// and scanning. Requires restart to change.
+    #[serde(default)]
+    pub anomaly_detection: Option<AnomalyDetectionConfig>,
+    /// Webhook posted a JSON alert when an upstream turns unhealthy or healthy, or asks to be
+    /// backed off. Requires restart to change.
+    #[serde(default)]
+    pub alert_webhook: Option<AlertWebhookConfig>,
+    /// Shared-secret request signing checked by the opt-in `hmac` stage, with replay
+    /// protection. Requires restart to change.
+    #[serde(default)]
+    pub hmac_auth: Option<HmacAuthConfig>,
+    /// Front proxies or load balancers, as addresses or CIDR ranges, whose
+    /// `client_ip_header` is trusted for the client's address. Every per-client check (rate
+    /// limits, bans, IP feeds, ASN rules) then applies to that address.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// Header trusted proxies report the client's address in. For `X-Forwarded-For` the
+    /// rightmost address not in `trusted_proxies` is used; other headers (e.g.
+    /// `CF-Connecting-IP`) hold a single address.
+    #[serde(default = "default_client_ip_header")]
+    pub client_ip_header: String,
+    /// Header the request ID is sent upstream in, and whether one the client sent is kept
+    #[serde(default)]
+    pub request_id: RequestIdConfig,
+    /// Remote IP blocklists by feed name; listed clients get 403 on every route
+    #[serde(default)]
+    pub ip_feeds: BTreeMap<String, IpFeedConfig>,
+    /// Blocks or tags requests from Tor exit nodes, using the Tor Project's published list
+    #[serde(default)]
+    pub tor_exit_nodes: Option<TorExitConfig>,
+    /// Allow/deny rules by the client's autonomous system number
+    #[serde(default)]
+    pub asn_rules: Option<AsnRulesConfig>,
+    /// JavaScript cookie challenge served by the `challenge` stage to clients matching any of
+    /// its criteria
+    #[serde(default)]
+    pub challenge: Option<ChallengeConfig>,
+    /// Per-tenant caps keyed by hostname (port ignored), shared by all of that tenant's
+    /// clients so one tenant's spike can't starve the others
+    #[serde(default)]
+    pub tenant_limits: BTreeMap<String, TenantLimitConfig>,
+    /// Secret key for validating JWT signatures (HS256). May be omitted when
+    /// `vault.jwt_secret` supplies it.
+    #[serde(default)]
     pub jwt_secret: String,
+    /// Claims of a validated JWT copied onto the upstream request, claim name to header name
+    /// (e.g. `sub: X-User-Id`). Client-supplied copies of these headers are always removed.
+    #[serde(default)]
+    pub jwt_claim_headers: BTreeMap<String, String>,
+    /// Tenants validating tokens against their own issuer, keyed by hostname (port ignored).
+    /// Requests for other hosts are checked against `jwt_secret`.
+    #[serde(default)]
+    pub jwt_tenants: BTreeMap<String, JwtTenantConfig>,
+    /// Replace the client's validated JWT with one minted by the proxy before forwarding, so
+    /// edge tokens never reach internal services
+    #[serde(default)]
+    pub upstream_token: Option<UpstreamTokenConfig>,
+    /// Address of the admin listener (e.g. "127.0.0.1:9901"). Disabled when unset.
+    #[serde(default)]
+    pub admin_listen_addr: Option<String>,
+    /// Address of the gRPC control-plane API (`proto/admin.proto`), plaintext HTTP/2 unless
+    /// `admin_auth.client_ca` is set. Disabled when unset. Requires restart to change.
+    #[serde(default)]
+    pub admin_grpc_listen_addr: Option<String>,
+    /// Who may call the admin and gRPC admin APIs, independent of the proxied traffic's auth.
+    /// Calls that change state, and refused calls, go to the audit log. Requires restart to
+    /// change.
+    #[serde(default)]
+    pub admin_auth: Option<AdminAuthConfig>,
+    /// JSON-lines access log file. Reopened on SIGUSR1 for logrotate. Requires restart to change.
+    #[serde(default)]
+    pub access_log_path: Option<String>,
+    /// Log filter SIGUSR2 switches to; a second SIGUSR2 restores the startup one. `PUT
+    /// /-/log-level` on the admin API sets any filter.
+    #[serde(default = "default_debug_log_filter")]
+    pub debug_log_filter: String,
+    /// Ships access and audit events to a syslog collector. Requires restart to change.
+    #[serde(default)]
+    pub syslog: Option<SyslogConfig>,
+    /// Sampled header/body capture for production troubleshooting. Requires restart to change.
+    #[serde(default)]
+    pub body_capture: Option<BodyCaptureConfig>,
+    /// WebAssembly filter plugins (proxy-wasm ABI subset), run in order. Requires restart to change.
+    #[serde(default)]
+    pub wasm_plugins: Vec<WasmPluginConfig>,
+    /// Lua scripts with `on_request` / `on_response` hooks, run in order after the wasm
+    /// plugins. Requires restart to change.
+    #[serde(default)]
+    pub lua_scripts: Vec<LuaScriptConfig>,
+    /// Routes with their own middleware chain, matched in order; unmatched requests run every
+    /// stage in the default order.
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Per-rule settings keyed by stage name (`rate_limit`, `path_filter`, `schedule`, `waf`,
+    /// `user_agent`, `tls`), e.g. `waf: { mode: monitor }` to trial a rule without blocking
+    #[serde(default)]
+    pub security_rules: BTreeMap<String, SecurityRuleConfig>,
+    /// Paths reachable only inside, or blocked inside, a weekly time window. Checked by the
+    /// `schedule` stage in order; the first rule matching the path applies.
+    #[serde(default)]
+    pub schedule_rules: Vec<ScheduleRuleConfig>,
+    /// Query parameters removed before caching and forwarding, e.g. `[utm_*, fbclid]`; a
+    /// trailing `*` matches any suffix
+    #[serde(default)]
+    pub strip_query_params: Vec<String>,
+    /// Health-check and monitoring paths kept out of the request metrics, e.g.
+    /// `[/healthz, /metrics]`; a trailing `*` matches any suffix
+    #[serde(default)]
+    pub metrics_internal_paths: Vec<String>,
+    /// Count `metrics_internal_paths` under a single `internal` path label instead of
+    /// dropping them
+    #[serde(default)]
+    pub metrics_internal_bucket: bool,
+    /// Add `X-FlashProxy-Route` and `X-FlashProxy-Upstream` to responses, naming the route and
+    /// backend that handled the request. For staging: it tells clients about the backends.
+    #[serde(default)]
+    pub debug_headers: bool,
+    /// Constant labels added to every metric, e.g. `{env: prod, region: eu-west-1}`, so
+    /// federated Prometheus setups can tell instances apart. Requires restart to change.
+    #[serde(default)]
+    pub metrics_labels: BTreeMap<String, String>,
+    /// Pushes all metrics on an interval to a Pushgateway or remote-write endpoint, for
+    /// instances Prometheus can't scrape. Requires restart to change.
+    #[serde(default)]
+    pub metrics_push: Option<MetricsPushConfig>,
+    /// Methods accepted on routes without their own `methods` list, unmatched requests
+    /// included, e.g. everything but TRACE; any method if unset
+    #[serde(default)]
+    pub allowed_methods: Option<Vec<String>>,
+    /// Fixed responses served by the `synthetic` stage, e.g. `/robots.txt`
+    #[serde(default)]
+    pub synthetic_responses: Vec<SyntheticResponseConfig>,
+    /// Translate browser gRPC-Web calls to native gRPC (HTTP/2) toward the upstream.
+    /// Requires restart to change.
+    #[serde(default)]
+    pub grpc_web: bool,
+    /// Client connection tuning. Requires restart to change.
+    #[serde(default)]
+    pub downstream: DownstreamConfig,
+    /// Upstream connection reuse tuning. Requires restart to change.
+    #[serde(default)]
+    pub upstream_keepalive: UpstreamKeepaliveConfig,
+    /// Process-level server options. Requires restart to change.
+    #[serde(default)]
+    pub server: ServerOptionsConfig,
+    /// Body of the responses to requests the proxy refuses itself. Requires restart to change.
+    #[serde(default)]
+    pub error_responses: ErrorResponsesConfig,
+    /// Header name casing and repeated headers in requests sent upstream and responses sent
+    /// to clients, for legacy servers picky about either. Requires restart to change.
+    #[serde(default)]
+    pub header_normalization: HeaderNormalizationConfig,
+    /// Hold refused scanners and abusive clients on a slow drip instead of answering at once.
+    /// Requires restart to change.
+    #[serde(default)]
+    pub tarpit: Option<TarpitConfig>,
+    /// Expiry checks of the listener certificate and the admin client CA. Requires restart to
+    /// change.
+    #[serde(default)]
+    pub cert_expiry: CertExpiryConfig,
+    /// Per-request transfer rate caps for routes or client classes; the first matching entry
+    /// applies. Requires restart to change.
+    #[serde(default)]
+    pub bandwidth_limits: Vec<BandwidthLimitConfig>,
+    /// HTTP/1 requests whose target is a full URL (`GET http://host/path`), as sent to
+    /// forward proxies and by open-proxy probes. Requires restart to change.
+    #[serde(default)]
+    pub absolute_form: AbsoluteFormPolicy,
+    /// CONNECT requests; the proxy never opens tunnels. Requires restart to change.
+    #[serde(default)]
+    pub connect_requests: ConnectPolicy,
+    /// Negotiate HTTP/2 with upstreams so response trailers are forwarded. pingora can't write
+    /// HTTP/1.1 trailers, so only HTTP/2 clients receive them. Requires restart to change.
+    #[serde(default)]
+    pub forward_trailers: bool,
+    /// Protocol and certificate checks for upstream connections; routes can set their own
+    #[serde(default)]
+    pub upstream_connection: UpstreamConnectionConfig,
+    /// Response cache used by routes with `cache: true`, in memory with an optional disk tier.
+    /// Requires restart to change.
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+    /// Blocks multipart file uploads by extension or content type. Requires restart to change.
+    #[serde(default)]
+    pub upload_filter: Option<UploadFilterConfig>,
+    /// Frame, message and rate limits for proxied WebSocket connections; a connection breaking
+    /// one is closed. Requires restart to change.
+    #[serde(default)]
+    pub websocket: Option<WebSocketConfig>,
+    /// External content scanner reached over ICAP. Requires restart to change.
+    #[serde(default)]
+    pub icap: Option<IcapConfig>,
+    /// Dial the upstream pool through a forward proxy (HTTP CONNECT or SOCKS5). Requires
+    /// restart to change.
+    #[serde(default)]
+    pub egress_proxy: Option<EgressProxyConfig>,
+    /// Raw TCP services proxied next to HTTP, each with its own listener, upstreams and health
+    /// checks. Requires restart to change.
+    #[serde(default)]
+    pub tcp_services: Vec<TcpServiceConfig>,
+    /// Active health check scheduling, shared by the HTTP and TCP upstream pools. Requires
+    /// restart to change.
+    #[serde(default)]
+    pub health_check: HealthCheckConfig,
+    /// Fetch the JWT secret and TLS certificate from HashiCorp Vault instead of the file.
+    /// Requires restart to change.
+    #[serde(default)]
+    pub vault: Option<VaultConfig>,
+    /// Present an X.509 SVID from the local SPIRE agent on upstream TLS connections. Requires
+    /// restart to change.
+    #[serde(default)]
+    pub spiffe: Option<SpiffeConfig>,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SpiffeConfig {
+    /// Workload API socket of the SPIRE agent
+    #[serde(default = "default_spiffe_socket_path")]
+    pub socket_path: String,
+    /// Only accept upstream certificates issued by the trust domain's bundle
+    #[serde(default = "default_true")]
+    pub verify_upstream: bool,
+}
+
+fn default_spiffe_socket_path() -> String {
+    "/tmp/spire-agent/public/api.sock".to_string()
+}
+
+fn default_debug_log_filter() -> String {
+    "info,reverse_proxy=debug".to_string()
+}
+
+fn default_upstream_drain_secs() -> u64 {
+    30
+}
+
+fn default_client_ip_header() -> String {
+    "X-Forwarded-For".to_string()
+}
+
+fn default_listen_host() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_rate_limit_ipv6_prefix() -> u8 {
+    64
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VaultConfig {
+    /// e.g. "https://vault.internal:8200"
+    pub addr: String,
+    /// File holding the Vault token (e.g. a Vault Agent sink); falls back to `VAULT_TOKEN`
+    #[serde(default)]
+    pub token_path: Option<String>,
+    /// PEM bundle to trust for Vault's own certificate, when it isn't publicly signed
+    #[serde(default)]
+    pub ca_cert_path: Option<String>,
+    /// KV secret holding the JWT secret; replaces `jwt_secret`
+    #[serde(default)]
+    pub jwt_secret: Option<VaultSecretRef>,
+    /// Where the listener certificate comes from; replaces `tls_cert_path` / `tls_key_path`
+    #[serde(default)]
+    pub tls: Option<VaultTlsConfig>,
+    /// How often the token is renewed and secrets are re-read
+    #[serde(default = "default_vault_refresh_secs")]
+    pub refresh_interval_secs: u64,
+}
+
+fn default_vault_refresh_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VaultSecretRef {
+    /// API path under /v1, e.g. "secret/data/flashproxy" for KV v2
+    pub path: String,
+    pub field: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VaultTlsConfig {
+    /// A PKI issue endpoint (e.g. "pki/issue/flashproxy") when `common_name` is set, otherwise
+    /// a KV secret with `certificate` and `private_key` fields
+    pub path: String,
+    #[serde(default)]
+    pub common_name: Option<String>,
+    /// Requested lifetime for PKI certificates, e.g. "72h"; the role's default when unset
+    #[serde(default)]
+    pub ttl: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SyntheticResponseConfig {
+    /// Exact request path, query ignored
+    pub path: String,
+    /// Match only this host (port ignored); any host if unset
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default = "default_synthetic_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: Option<String>,
+    /// Read once per load; use instead of `body`
+    #[serde(default)]
+    pub body_file: Option<String>,
+}
+
+fn default_synthetic_status() -> u16 {
+    200
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QuotaConfig {
+    /// No longer supported: an API key header isn't validated by any stage, so anyone could
+    /// mint identities with it. Quotas are charged to the subject the `jwt` or `hmac` stage
+    /// authenticated; requests reaching the `quota` stage without one are refused.
+    #[serde(default)]
+    pub header: Option<String>,
+    #[serde(default)]
+    pub daily: Option<u64>,
+    #[serde(default)]
+    pub monthly: Option<u64>,
+    /// JSON file the counters persist in across restarts
+    pub store_path: String,
+    #[serde(default = "default_quota_flush_interval")]
+    pub flush_interval_secs: u64,
+    /// Identities counted per month; new ones beyond this are refused until the month ends
+    #[serde(default = "default_quota_max_identities")]
+    pub max_identities: usize,
+}
+
+fn default_quota_flush_interval() -> u64 {
+    10
+}
+
+fn default_quota_max_identities() -> usize {
+    100_000
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MetricsPushConfig {
+    #[serde(default)]
+    pub mode: MetricsPushMode,
+    /// Pushgateway base URL (e.g. `http://pushgateway:9091`), or the full remote-write URL
+    pub url: String,
+    /// `job` grouping label
+    #[serde(default = "default_metrics_push_job")]
+    pub job: String,
+    /// `instance` grouping label; the machine's hostname if unset
+    #[serde(default)]
+    pub instance: Option<String>,
+    #[serde(default = "default_metrics_push_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_metrics_push_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Extra request headers, e.g. an `Authorization` token for a hosted endpoint
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// `pushgateway` replaces the instance's group with the text format on each push;
+/// `remote_write` sends one sample per series in the Prometheus remote-write protocol.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricsPushMode {
+    #[default]
+    Pushgateway,
+    RemoteWrite,
+}
+
+fn default_metrics_push_job() -> String {
+    "reverse_proxy".to_string()
+}
+
+fn default_metrics_push_interval_secs() -> u64 {
+    15
+}
+
+fn default_metrics_push_timeout_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AlertWebhookConfig {
+    pub url: String,
+    /// Extra request headers, e.g. an `Authorization` token for the paging service
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default = "default_alert_webhook_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Deliveries tried per alert, with exponential backoff in between
+    #[serde(default = "default_alert_webhook_max_attempts")]
+    pub max_attempts: u32,
+}
+
+fn default_alert_webhook_timeout_secs() -> u64 {
+    5
+}
+
+fn default_alert_webhook_max_attempts() -> u32 {
+    3
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AnomalyDetectionConfig {
+    /// Length of each analyzed window
+    #[serde(default = "default_anomaly_window_secs")]
+    pub window_secs: u64,
+    /// Requests from one IP in a window above which it is flagged
+    #[serde(default = "default_anomaly_ip_max_requests")]
+    pub ip_max_requests: u64,
+    /// Share of an IP's responses with status 400 or above that flags it
+    #[serde(default = "default_anomaly_ip_max_error_ratio")]
+    pub ip_max_error_ratio: f64,
+    /// Requests an IP needs in a window before its error ratio counts
+    #[serde(default = "default_anomaly_ip_min_requests")]
+    pub ip_min_requests: u64,
+    /// Distinct paths answered 404 to one IP in a window that count as scanning
+    #[serde(default = "default_anomaly_scan_not_found_paths")]
+    pub scan_not_found_paths: usize,
+    /// A path is flagged when its window count exceeds its moving average by this factor
+    #[serde(default = "default_anomaly_path_spike_factor")]
+    pub path_spike_factor: f64,
+    /// Requests a path needs in a window before it can be flagged
+    #[serde(default = "default_anomaly_path_min_requests")]
+    pub path_min_requests: u64,
+    /// IPs and paths tracked per window; beyond this new ones are ignored until the next
+    #[serde(default = "default_anomaly_max_tracked_keys")]
+    pub max_tracked_keys: usize,
+    /// Stricter rate limit applied to flagged IPs; report only if unset
+    #[serde(default)]
+    pub penalty: Option<AnomalyPenaltyConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AnomalyPenaltyConfig {
+    /// Excess requests from a penalized IP get 429
+    pub rate_limit_per_second: u32,
+    /// How long the penalty lasts; flagged again while penalized extends it
+    #[serde(default = "default_anomaly_penalty_duration_secs")]
+    pub duration_secs: u64,
+}
+
+fn default_anomaly_window_secs() -> u64 {
+    10
+}
+
+fn default_anomaly_ip_max_requests() -> u64 {
+    1000
+}
+
+fn default_anomaly_ip_max_error_ratio() -> f64 {
+    0.5
+}
+
+fn default_anomaly_ip_min_requests() -> u64 {
+    20
+}
+
+fn default_anomaly_scan_not_found_paths() -> usize {
+    20
+}
+
+fn default_anomaly_path_spike_factor() -> f64 {
+    5.0
+}
+
+fn default_anomaly_path_min_requests() -> u64 {
+    100
+}
+
+fn default_anomaly_max_tracked_keys() -> usize {
+    100_000
+}
+
+fn default_anomaly_penalty_duration_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HmacAuthConfig {
+    /// Secrets by key id, as sent in `X-Signature-Key-Id`
+    pub keys: BTreeMap<String, String>,
+    /// How far a request's timestamp may be from now; nonces are remembered this long
+    #[serde(default = "default_hmac_max_skew")]
+    pub max_skew_secs: u64,
+    /// Nonces remembered at once; beyond this those closest to expiry are forgotten first
+    #[serde(default = "default_hmac_max_nonces")]
+    pub max_nonces: usize,
+}
+
+fn default_hmac_max_skew() -> u64 {
+    300
+}
+
+fn default_hmac_max_nonces() -> usize {
+    100_000
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IpFeedConfig {
+    /// Plain-text list with one address or CIDR range per line, e.g. Spamhaus DROP;
+    /// anything after `;` or `#` is ignored
+    pub url: String,
+    /// How often the list is re-fetched
+    #[serde(default = "default_ip_feed_refresh_secs")]
+    pub refresh_secs: u64,
+}
+
+fn default_ip_feed_refresh_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TorExitConfig {
+    #[serde(default)]
+    pub action: TorExitAction,
+    /// One exit address per line
+    #[serde(default = "default_tor_exit_url")]
+    pub url: String,
+    #[serde(default = "default_tor_exit_refresh_secs")]
+    pub refresh_secs: u64,
+    /// Upstream header set to `true` on tagged requests; client-supplied copies are removed
+    #[serde(default = "default_tor_exit_header")]
+    pub header: String,
+}
+
+/// `block` answers 403; `tag` forwards the request with `header` set and logs it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TorExitAction {
+    #[default]
+    Block,
+    Tag,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AsnRulesConfig {
+    /// MaxMind-format ASN database, e.g. GeoLite2-ASN.mmdb; re-read on reload
+    pub database: String,
+    /// When non-empty, clients from any other AS get 403
+    #[serde(default)]
+    pub allow: Vec<u32>,
+    /// Clients from these ASes get 403, e.g. hosting providers
+    #[serde(default)]
+    pub deny: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChallengeConfig {
+    /// Signs the challenge cookie; share it between instances so a client passes once
+    pub secret: String,
+    #[serde(default = "default_challenge_cookie_name")]
+    pub cookie_name: String,
+    /// How long a passed challenge lasts
+    #[serde(default = "default_challenge_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Paths always challenged, e.g. `[/login, /signup*]`; a trailing `*` matches any suffix
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Case-insensitive substrings of challenged user agents
+    #[serde(default)]
+    pub user_agents: Vec<String>,
+    /// Headers every browser sends, e.g. `[accept-language]`; requests lacking one are challenged
+    #[serde(default)]
+    pub missing_headers: Vec<String>,
+    /// Challenge clients on the `tor_exit_nodes` list
+    #[serde(default)]
+    pub tor_exit_nodes: bool,
+    /// Show a CAPTCHA instead of the script-only check; the cookie is only issued once the
+    /// provider confirms the solution
+    #[serde(default)]
+    pub captcha: Option<CaptchaConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CaptchaConfig {
+    pub provider: CaptchaProvider,
+    /// Public key embedded in the widget
+    pub site_key: String,
+    /// Key for server-side verification of solutions
+    pub secret_key: String,
+    /// Verification endpoint; the provider's public `siteverify` URL if unset
+    #[serde(default)]
+    pub verify_url: Option<String>,
+    /// Path the solved widget sends its token to; answered by the proxy itself
+    #[serde(default = "default_captcha_verify_path")]
+    pub verify_path: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptchaProvider {
+    Turnstile,
+    Hcaptcha,
+}
+
+fn default_captcha_verify_path() -> String {
+    "/.well-known/captcha-verify".to_string()
+}
+
+fn default_challenge_cookie_name() -> String {
+    "proxy_challenge".to_string()
+}
+
+fn default_challenge_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_tor_exit_url() -> String {
+    "https://check.torproject.org/torbulkexitlist".to_string()
+}
+
+fn default_tor_exit_refresh_secs() -> u64 {
+    1800
+}
+
+fn default_tor_exit_header() -> String {
+    "X-Tor-Exit".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TenantLimitConfig {
+    /// Requests per second across the tenant; excess gets 429
+    #[serde(default)]
+    pub rate_limit_per_second: Option<u32>,
+    /// Requests in flight at once; excess gets 503
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JwtTenantConfig {
+    /// HS256 secret; set `jwks_url` instead for an issuer publishing asymmetric keys
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    /// How often the JWKS is re-fetched
+    #[serde(default = "default_jwks_refresh_secs")]
+    pub jwks_refresh_secs: u64,
+    /// Required `iss` claim
+    #[serde(default)]
+    pub issuer: Option<String>,
+    /// Required `aud` claim
+    #[serde(default)]
+    pub audience: Option<String>,
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UpstreamTokenConfig {
+    /// HS256 signing key; set `private_key_path` instead to sign with RS256
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// PEM RSA private key for RS256
+    #[serde(default)]
+    pub private_key_path: Option<String>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    pub audience: String,
+    /// Lifetime of minted tokens, capped at the edge token's own expiry
+    #[serde(default = "default_upstream_token_ttl")]
+    pub ttl_secs: u64,
+    /// Claims copied from the edge token
+    #[serde(default = "default_upstream_token_claims")]
+    pub claims: Vec<String>,
+}
+
+fn default_upstream_token_ttl() -> u64 {
+    300
+}
+
+fn default_upstream_token_claims() -> Vec<String> {
+    vec!["sub".to_string()]
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TcpServiceConfig {
+    pub name: String,
+    /// e.g. "0.0.0.0:5432"
+    pub listen: String,
+    /// Backends for every connection, or with `sni_routes`, for TLS clients whose server name
+    /// matches no route. May be empty when `sni_routes` is set.
+    #[serde(default)]
+    pub upstreams: Vec<String>,
+    /// TLS passthrough: route each connection by the SNI in its ClientHello, without
+    /// terminating TLS
+    #[serde(default)]
+    pub sni_routes: Vec<SniRouteConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SniRouteConfig {
+    /// Exact names, or "*.example.com" for any subdomain
+    pub server_names: Vec<String>,
+    pub upstreams: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EgressProxyConfig {
+    /// Forward proxy address, e.g. "10.0.0.3:3128" or "10.0.0.3:1080"
+    pub addr: String,
+    #[serde(default)]
+    pub protocol: EgressProtocol,
+    /// `Proxy-Authorization: Basic` for HTTP, username/password auth (RFC 1929) for SOCKS5
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Local Unix socket bridging pingora to the proxy; must be writable by the process
+    #[serde(default = "default_egress_socket_path")]
+    pub socket_path: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EgressProtocol {
+    #[default]
+    Http,
+    Socks5,
+}
+
+fn default_egress_socket_path() -> String {
+    "/tmp/flashproxy-egress.sock".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IcapConfig {
+    /// Scanner address, e.g. "10.0.0.7:1344"
+    pub addr: String,
+    /// Service that scans request bodies (REQMOD), e.g. "avscan"; request bodies aren't
+    /// scanned if unset
+    #[serde(default)]
+    pub reqmod_service: Option<String>,
+    /// Service that scans response bodies (RESPMOD); response bodies aren't scanned if unset
+    #[serde(default)]
+    pub respmod_service: Option<String>,
+    #[serde(default = "default_icap_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Bodies are held in memory while scanned; larger ones count as unscannable
+    #[serde(default = "default_icap_max_body_kb")]
+    pub max_body_kb: usize,
+    /// Let traffic through when the scanner is unreachable or a body is too large to scan.
+    /// Off by default: with a regulatory scanning requirement, unscanned means blocked.
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+fn default_icap_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_icap_max_body_kb() -> usize {
+    10 * 1024
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WebSocketConfig {
+    #[serde(default = "default_websocket_max_frame_kb")]
+    pub max_frame_kb: u64,
+    /// Across all frames of a fragmented message
+    #[serde(default = "default_websocket_max_message_kb")]
+    pub max_message_kb: u64,
+    /// Sustained rate of messages from the client per connection; unlimited if unset
+    #[serde(default)]
+    pub max_messages_per_sec: Option<u32>,
+    /// Messages the client may send at once above the rate; defaults to the rate
+    #[serde(default)]
+    pub message_burst: Option<u32>,
+}
+
+fn default_websocket_max_frame_kb() -> u64 {
+    1024
+}
+
+fn default_websocket_max_message_kb() -> u64 {
+    4096
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UploadFilterConfig {
+    /// e.g. `[exe, dll, bat, ps1]`, matched case-insensitively
+    #[serde(default)]
+    pub blocked_extensions: Vec<String>,
+    /// Types detected from a file's first bytes, whatever its name: `exe`, `elf`, `macho`,
+    /// `script`, `msi`, `zip`, `pdf`
+    #[serde(default)]
+    pub blocked_types: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CacheConfig {
+    /// Freshness for responses without `Cache-Control: max-age` or `Expires`
+    #[serde(default = "default_cache_ttl")]
+    pub default_ttl_secs: u64,
+    #[serde(default = "default_cache_max_entries")]
+    pub max_entries: usize,
+    /// Memory budget for stored responses, headers included. Least recently used entries are
+    /// evicted to stay within it.
+    #[serde(default = "default_cache_max_memory_mb")]
+    pub max_memory_mb: u64,
+    /// Number of independently locked parts of the memory tier; each gets an equal share of
+    /// `max_entries` and `max_memory_mb`
+    #[serde(default = "default_cache_shards")]
+    pub shards: usize,
+    /// Larger responses are passed through without being stored
+    #[serde(default = "default_cache_max_body_kb")]
+    pub max_body_kb: usize,
+    /// Disk tier for responses larger than `max_body_kb`
+    #[serde(default)]
+    pub disk: Option<DiskCacheConfig>,
+    /// Also store responses with `negative_statuses` for up to this long, so a burst of
+    /// requests for a missing or failing resource reaches the upstream once. Off when unset.
+    #[serde(default)]
+    pub negative_ttl_secs: Option<u64>,
+    #[serde(default = "default_cache_negative_statuses")]
+    pub negative_statuses: Vec<u16>,
+    /// Concurrent misses on one key send a single request upstream; the rest wait for its
+    /// response to be stored and are served from the cache
+    #[serde(default = "default_true")]
+    pub coalesce_misses: bool,
+    /// Longest a coalesced miss waits before going upstream itself
+    #[serde(default = "default_cache_coalesce_wait_ms")]
+    pub coalesce_wait_ms: u64,
+    /// Compress text-like responses the upstream sent uncompressed once when they're stored,
+    /// and serve the variant a client accepts on hits. Memory tier only.
+    #[serde(default)]
+    pub precompress: Option<PrecompressConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PrecompressConfig {
+    /// Variants to store; when a client accepts several, the first listed wins
+    #[serde(default = "default_precompress_encodings")]
+    pub encodings: Vec<ContentEncoding>,
+    /// Smaller bodies are served as stored
+    #[serde(default = "default_precompress_min_bytes")]
+    pub min_bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentEncoding {
+    Br,
+    Gzip,
+}
+
+impl ContentEncoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ContentEncoding::Br => "br",
+            ContentEncoding::Gzip => "gzip",
+        }
+    }
+}
+
+fn default_precompress_encodings() -> Vec<ContentEncoding> {
+    vec![ContentEncoding::Br, ContentEncoding::Gzip]
+}
+
+fn default_precompress_min_bytes() -> usize {
+    1024
+}
+
+fn default_cache_coalesce_wait_ms() -> u64 {
+    5000
+}
+
+fn default_cache_negative_statuses() -> Vec<u16> {
+    vec![404, 500, 502, 503, 504]
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DiskCacheConfig {
+    /// Directory for the cache files; created if missing. Entries in it are reused after a
+    /// restart.
+    pub path: String,
+    /// Least recently used entries are removed beyond this
+    #[serde(default = "default_disk_cache_max_size_mb")]
+    pub max_size_mb: u64,
+    /// Larger responses are passed through without being stored
+    #[serde(default = "default_disk_cache_max_body_mb")]
+    pub max_body_mb: usize,
+}
+
+fn default_disk_cache_max_size_mb() -> u64 {
+    1024
+}
+
+fn default_disk_cache_max_body_mb() -> usize {
+    100
+}
+
+fn default_cache_ttl() -> u64 {
+    60
+}
+
+fn default_cache_max_entries() -> usize {
+    10_000
+}
+
+fn default_cache_max_memory_mb() -> u64 {
+    256
+}
+
+fn default_cache_shards() -> usize {
+    16
+}
+
+fn default_cache_max_body_kb() -> usize {
+    1024
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RetryAfterConfig {
+    /// Take the upstream out of rotation until the time it asked for
+    #[serde(default = "default_true")]
+    pub back_off: bool,
+    /// Cap on how long one response can bench an upstream
+    #[serde(default = "default_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+    /// Forward the header to the client; stripped otherwise
+    #[serde(default = "default_true")]
+    pub propagate: bool,
+}
+
+fn default_max_backoff_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UpstreamSubsetConfig {
+    /// Upstreams per instance
+    pub size: usize,
+    /// This instance's position in the fleet, e.g. a StatefulSet ordinal. Indexes should be
+    /// consecutive from 0 for the load to spread evenly.
+    pub instance_index: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UpstreamDnsConfig {
+    /// Servers asked for upstream hostnames, e.g. `["10.0.0.2:53"]`; those in
+    /// `/etc/resolv.conf` if empty
+    #[serde(default)]
+    pub nameservers: Vec<String>,
+    /// Record TTLs are raised to at least this, bounding the query rate
+    #[serde(default = "default_dns_min_ttl_secs")]
+    pub min_ttl_secs: u64,
+    /// Record TTLs are lowered to at most this, bounding how long a moved upstream is missed
+    #[serde(default = "default_dns_max_ttl_secs")]
+    pub max_ttl_secs: u64,
+}
+
+impl Default for UpstreamDnsConfig {
+    fn default() -> Self {
+        Self {
+            nameservers: Vec::new(),
+            min_ttl_secs: default_dns_min_ttl_secs(),
+            max_ttl_secs: default_dns_max_ttl_secs(),
+        }
+    }
+}
+
+fn default_dns_min_ttl_secs() -> u64 {
+    5
+}
+
+fn default_dns_max_ttl_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestIdConfig {
+    /// e.g. `X-Correlation-ID`. With `traceparent` the ID is the W3C trace ID, and a kept
+    /// header is passed upstream unchanged.
+    #[serde(default = "default_request_id_header")]
+    pub header: String,
+    #[serde(default)]
+    pub client_ids: ClientRequestIds,
+    /// Clients, as addresses or CIDR ranges, whose IDs are kept under `internal`. The
+    /// address is the one `trusted_proxies` resolves.
+    #[serde(default)]
+    pub internal_cidrs: Vec<String>,
+}
+
+impl Default for RequestIdConfig {
+    fn default() -> Self {
+        Self {
+            header: default_request_id_header(),
+            client_ids: ClientRequestIds::default(),
+            internal_cidrs: Vec::new(),
+        }
+    }
+}
+
+fn default_request_id_header() -> String {
+    "X-Request-ID".to_string()
+}
+
+/// What happens to a request ID the client sent. Kept IDs must be at most 128 printable
+/// ASCII characters (or a valid `traceparent`); others are replaced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientRequestIds {
+    /// Replaced with a generated ID
+    #[default]
+    Overwrite,
+    Trust,
+    /// Kept from clients in `internal_cidrs`, replaced for others
+    Internal,
+}
+
+/// `intermediate` accepts TLS 1.2 and 1.3 with Mozilla's intermediate ciphers; `modern`
+/// only TLS 1.3.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsProfile {
+    #[default]
+    Intermediate,
+    Modern,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TlsPolicyConfig {
+    /// Lowest protocol version accepted; unlike `tls_profile` the request is refused after the
+    /// handshake, so the rule can run in monitor mode and attempts show up in the audit trail
+    #[serde(default)]
+    pub min_version: Option<TlsVersion>,
+    /// OpenSSL cipher names refused, e.g. `[ECDHE-RSA-AES128-SHA, AES*]`; a trailing `*`
+    /// matches any suffix
+    #[serde(default)]
+    pub deny_ciphers: Vec<String>,
+    /// Answer 421 when the SNI name differs from the request's host, e.g. domain fronting.
+    /// Only checked on HTTP/1.1 connections, the only ones pingora exposes the SNI of.
+    #[serde(default)]
+    pub sni_must_match_host: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Deserialize, Serialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancing {
+    #[default]
+    RoundRobin,
+    /// Power of two choices: of two random healthy upstreams, take the one with the lower
+    /// EWMA latency times outstanding requests
+    P2c,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
+    /// Each probe waits a random delay up to this long, spreading probes over the interval
+    /// instead of firing them all at once. Must be shorter than the interval.
+    #[serde(default = "default_health_check_jitter_ms")]
+    pub jitter_ms: u64,
+    /// Probes in flight at once across all pools; the rest wait their turn
+    #[serde(default = "default_max_concurrent_probes")]
+    pub max_concurrent_probes: usize,
+    /// When an upstream of the HTTP pool turns healthy again, ramp its share of traffic up
+    /// over this many seconds instead of giving it a full share at once. Off if unset.
+    #[serde(default)]
+    pub slow_start_secs: Option<u64>,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_health_check_interval_secs(),
+            jitter_ms: default_health_check_jitter_ms(),
+            max_concurrent_probes: default_max_concurrent_probes(),
+            slow_start_secs: None,
+        }
+    }
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    1
+}
+
+fn default_health_check_jitter_ms() -> u64 {
+    200
+}
+
+fn default_max_concurrent_probes() -> usize {
+    32
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ServerOptionsConfig {
+    /// Worker threads of each service (the proxy, the admin API, each TCP service);
+    /// pingora's default (1) if unset
+    #[serde(default)]
+    pub threads: Option<usize>,
+    /// Detach from the terminal and run in the background
+    #[serde(default)]
+    pub daemon: bool,
+    /// File receiving stdout and stderr, logs included, when `daemon` is set; discarded if
+    /// unset
+    #[serde(default)]
+    pub error_log: Option<String>,
+    /// Written with the process ID once started, e.g. for systemd's `PIDFile=`
+    #[serde(default)]
+    pub pid_file: Option<String>,
+    /// Unix socket over which a new process receives the listeners in a listener handoff
+    #[serde(default = "default_upgrade_sock")]
+    pub upgrade_sock: String,
+    /// Hand the listeners to a new process when a reload changes listener settings; those
+    /// changes wait for a restart when false
+    #[serde(default = "default_true")]
+    pub listener_handoff: bool,
+    /// Switch to this user, with its groups, once every listener is bound, so privileged
+    /// ports can be bound as root
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Switch to this group once every listener is bound; the user's primary group if unset
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+impl Default for ServerOptionsConfig {
+    fn default() -> Self {
+        Self {
+            threads: None,
+            daemon: false,
+            error_log: None,
+            pid_file: None,
+            upgrade_sock: default_upgrade_sock(),
+            listener_handoff: true,
+            user: None,
+            group: None,
+        }
+    }
+}
+
+fn default_upgrade_sock() -> String {
+    "/tmp/pingora_upgrade.sock".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ErrorResponsesConfig {
+    /// Answer refused requests with a JSON body; an empty error page when false
+    #[serde(default = "default_true")]
+    pub json: bool,
+    /// Shape of the JSON body. In string values `{error}` is replaced by the reason code (e.g.
+    /// `rate_limited`), `{status}` by the status code and `{request_id}` by the request's ID;
+    /// a string that is only `{status}` becomes a number. Stages with their own error body
+    /// (e.g. `json_schema`) send that instead, with a `request_id` field added.
+    #[serde(default = "default_error_template")]
+    pub template: serde_json::Value,
+}
+
+impl Default for ErrorResponsesConfig {
+    fn default() -> Self {
+        Self {
+            json: true,
+            template: default_error_template(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AdminAuthConfig {
+    /// Callers must send `Authorization: Bearer <token>` (gRPC: `authorization` metadata)
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Callers must connect from one of these addresses or CIDR ranges
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+    /// PEM file of the CAs issuing admin client certificates. Set, the admin listeners serve
+    /// TLS with `tls_cert_path` and refuse clients without such a certificate.
+    #[serde(default)]
+    pub client_ca: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TarpitConfig {
+    /// Refusal reasons answered through the tarpit, `*` suffix for prefixes
+    #[serde(default = "default_tarpit_reasons")]
+    pub reasons: Vec<String>,
+    /// How long a client is held before it gets the refusal
+    #[serde(default = "default_tarpit_duration_secs")]
+    pub duration_secs: u64,
+    /// One byte of padding goes out this often, keeping the client from timing out
+    #[serde(default = "default_tarpit_interval_ms")]
+    pub interval_ms: u64,
+    /// Clients held at once; further refusals are answered right away
+    #[serde(default = "default_tarpit_max_clients")]
+    pub max_clients: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CertExpiryConfig {
+    /// Warn this many days ahead of a certificate's expiry
+    #[serde(default = "default_cert_warn_days")]
+    pub warn_days: u64,
+    #[serde(default = "default_cert_check_interval_secs")]
+    pub check_interval_secs: u64,
+}
+
+impl Default for CertExpiryConfig {
+    fn default() -> Self {
+        Self {
+            warn_days: default_cert_warn_days(),
+            check_interval_secs: default_cert_check_interval_secs(),
+        }
+    }
+}
+
+fn default_cert_warn_days() -> u64 {
+    21
+}
+
+fn default_cert_check_interval_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct BandwidthLimitConfig {
+    /// Route names this entry covers; every route if empty
+    #[serde(default)]
+    pub routes: Vec<String>,
+    /// Request header naming the client's class, e.g. `X-Plan` filled from a token claim by
+    /// `jwt_claim_headers`; any client if unset
+    #[serde(default)]
+    pub header: Option<String>,
+    /// Classes this entry covers, e.g. `[free]`; needs `header`
+    #[serde(default)]
+    pub values: Vec<String>,
+    /// Response body rate
+    #[serde(default)]
+    pub download_kb_per_sec: Option<u64>,
+    /// Request body rate
+    #[serde(default)]
+    pub upload_kb_per_sec: Option<u64>,
+    /// Bytes that may go out at full speed before the cap applies; one second's worth if unset
+    #[serde(default)]
+    pub burst_kb: Option<u64>,
+}
+
+fn default_tarpit_reasons() -> Vec<String> {
+    [
+        "ip_banned",
+        "ip_reputation",
+        "asn_denied",
+        "tor_exit_node",
+        "blocked_path",
+        "blocked_user_agent",
+        "waf_*",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_tarpit_duration_secs() -> u64 {
+    30
+}
+
+fn default_tarpit_interval_ms() -> u64 {
+    1000
+}
+
+fn default_tarpit_max_clients() -> usize {
+    1000
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct HeaderNormalizationConfig {
+    #[serde(default)]
+    pub casing: HeaderCasing,
+    /// Exact spelling of specific names, e.g. `[SOAPAction, X-API-Key]`; overrides `casing`
+    #[serde(default)]
+    pub names: Vec<String>,
+    /// Combine repeated headers into one comma-separated header (`Cookie` with `; `).
+    /// `Set-Cookie` is never combined.
+    #[serde(default)]
+    pub merge_duplicates: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderCasing {
+    /// Names as the other side sent them; HTTP/2 peers send them lowercase
+    #[default]
+    Preserve,
+    /// `X-Api-Key` style
+    Title,
+}
+
+fn default_error_template() -> serde_json::Value {
+    serde_json::json!({"error": "{error}", "request_id": "{request_id}"})
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct UpstreamKeepaliveConfig {
+    /// Idle connections kept for reuse, across all upstreams. pingora's default (128) if unset.
+    #[serde(default)]
+    pub pool_size: Option<usize>,
+    /// Idle pooled connections are closed after this long; kept until the upstream closes
+    /// them if unset
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DownstreamConfig {
+    /// HTTP/1.1 idle keepalive timeout; 0 disables keepalive. pingora's default (no timeout)
+    /// if unset.
+    #[serde(default)]
+    pub keepalive_timeout_secs: Option<u64>,
+    /// Close an HTTP/1.1 connection after this many requests
+    #[serde(default)]
+    pub max_requests_per_connection: Option<usize>,
+    /// Streams beyond this many in flight on one HTTP/2 connection are refused with a 503
+    #[serde(default)]
+    pub h2_max_concurrent_streams: Option<usize>,
+    /// Request bodies larger than this are refused with a 413
+    #[serde(default)]
+    pub max_request_body_kb: Option<u64>,
+    /// Upload rate cap per client connection, shared by its HTTP/2 streams. Reading from the
+    /// client pauses while over the rate, so a fast uploader is held back by TCP flow control.
+    #[serde(default)]
+    pub max_upload_kb_per_sec: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RouteConfig {
+    pub name: String,
+    /// Match only this host (port ignored); any host if unset
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    /// Ordered stage names, e.g. `[metrics, rate_limit, jwt]`; the default chain if unset
+    #[serde(default)]
+    pub middleware: Option<Vec<String>>,
+    /// Opt-in stages (e.g. `waf`) added to the default chain for this route
+    #[serde(default)]
+    pub enable: Vec<String>,
+    /// Stages dropped from the default chain for this route, e.g. `[jwt]`
+    #[serde(default)]
+    pub disable: Vec<String>,
+    /// Serve this route from the response cache (needs the top-level `cache` section)
+    #[serde(default)]
+    pub cache: bool,
+    /// Static content: add an ETag when the upstream doesn't send one
+    #[serde(default)]
+    pub generate_etag: bool,
+    /// Server-Sent Events: `text/event-stream` responses skip the cache and body capture and are
+    /// marked so intermediaries don't buffer them either
+    #[serde(default)]
+    pub streaming: bool,
+    /// Fail the upstream read after this long without data; no limit if unset
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+    /// Accepted methods, e.g. `[GET]`; others get 405. Falls back to the top-level
+    /// `allowed_methods` when unset. Allowing GET allows HEAD too.
+    #[serde(default)]
+    pub methods: Option<Vec<String>>,
+    /// Sign upstream requests with AWS SigV4, for S3, API Gateway or OpenSearch upstreams.
+    /// Replaces any `Authorization` header, exchanged tokens included.
+    #[serde(default)]
+    pub aws_sigv4: Option<AwsSigV4Config>,
+    /// Reject requests that don't conform to this OpenAPI 3 spec; runs as the `openapi` stage,
+    /// which `security_rules` can switch to monitor mode
+    #[serde(default)]
+    pub openapi: Option<OpenApiConfig>,
+    /// Limit the GraphQL operations this route accepts; runs as the `graphql` stage, which
+    /// `security_rules` can switch to monitor mode
+    #[serde(default)]
+    pub graphql: Option<GraphQlConfig>,
+    /// Reject JSON request bodies that don't match this JSON Schema with 422; runs as the
+    /// `json_schema` stage, which `security_rules` can switch to monitor mode
+    #[serde(default)]
+    pub json_schema: Option<JsonSchemaConfig>,
+    /// CSRF protection for cookie-authenticated routes; runs as the `csrf` stage, which
+    /// `security_rules` can switch to monitor mode
+    #[serde(default)]
+    pub csrf: Option<CsrfConfig>,
+    /// Replaces the top-level `upstream_connection` for this route
+    #[serde(default)]
+    pub upstream_connection: Option<UpstreamConnectionConfig>,
+    /// `maintenance` answers 503 and `disabled` 404 without reaching the upstream. The admin
+    /// API can override it until the next restart.
+    #[serde(default)]
+    pub state: RouteState,
+    /// Sent instead of the standard error while the route is in maintenance
+    #[serde(default)]
+    pub maintenance_response: Option<MaintenanceResponseConfig>,
+    /// Whether response bodies are collected before going to the client or passed on as they
+    /// arrive; unset leaves it to each feature
+    #[serde(default)]
+    pub response_buffering: Option<ResponseBufferingConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ResponseBufferingConfig {
+    pub mode: ResponseBufferingMode,
+    /// With `buffered`, bodies larger than this are streamed from the point they outgrow it
+    #[serde(default = "default_max_buffer_kb")]
+    pub max_buffer_kb: u64,
+}
+
+fn default_max_buffer_kb() -> u64 {
+    1024
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseBufferingMode {
+    /// Forward the body once complete, so it can be inspected or transformed as a whole
+    Buffered,
+    /// Forward each chunk at once; the cache, body capture and ICAP response scans are skipped
+    Streaming,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AbsoluteFormPolicy {
+    /// 400
+    #[default]
+    Reject,
+    /// Close the connection without an answer
+    Drop,
+    /// Serve the request as if only the path had been sent; the URL's host must match Host
+    OriginForm,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectPolicy {
+    /// 405
+    #[default]
+    Reject,
+    /// Close the connection without an answer
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteState {
+    #[default]
+    Active,
+    Maintenance,
+    Disabled,
+}
+
+impl RouteState {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "active" => Some(Self::Active),
+            "maintenance" => Some(Self::Maintenance),
+            "disabled" => Some(Self::Disabled),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Active => "active",
+            Self::Maintenance => "maintenance",
+            Self::Disabled => "disabled",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MaintenanceResponseConfig {
+    pub body: String,
+    #[serde(default = "default_maintenance_content_type")]
+    pub content_type: String,
+    /// Sent as `Retry-After`
+    #[serde(default)]
+    pub retry_after_secs: Option<u64>,
+}
+
+fn default_maintenance_content_type() -> String {
+    "application/json".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UpstreamConnectionConfig {
+    /// HTTP version spoken to the upstream; unset keeps h1, or auto with `forward_trailers`.
+    /// gRPC-Web requests always use h2.
+    #[serde(default)]
+    pub protocol: Option<UpstreamProtocol>,
+    /// Off for plaintext internal pools. Without TLS there is no ALPN, so `h2` means h2c and
+    /// `auto` means h1.
+    #[serde(default = "default_true")]
+    pub tls: bool,
+    #[serde(default)]
+    pub verify: UpstreamVerify,
+    /// PEM bundle of the CAs upstream certificates must chain to instead of the system
+    /// store; read on startup and reload
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    /// SHA-256 fingerprints (hex, colons allowed) of the upstream certificates to accept; the
+    /// leaf must match one of them, on top of the `verify` checks
+    #[serde(default)]
+    pub pin_sha256: Vec<String>,
+}
+
+impl Default for UpstreamConnectionConfig {
+    fn default() -> Self {
+        Self {
+            protocol: None,
+            tls: true,
+            verify: UpstreamVerify::default(),
+            ca_file: None,
+            pin_sha256: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamProtocol {
+    H1,
+    H2,
+    /// h2 if the upstream offers it through ALPN, else h1
+    Auto,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamVerify {
+    /// Certificate chain and host name
+    #[default]
+    Full,
+    /// Certificate chain only, for upstreams whose certificate doesn't name the SNI
+    SkipHostname,
+    /// Accept any certificate; for staging upstreams only
+    None,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AwsSigV4Config {
+    /// Signing name of the service, e.g. `s3`, `execute-api`, `es`
+    pub service: String,
+    /// `AWS_REGION` if unset
+    #[serde(default)]
+    pub region: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OpenApiConfig {
+    /// Spec file, YAML or JSON; read on startup and reload
+    pub spec: String,
+    /// Prefix stripped from request paths before matching the spec's paths; the path of the
+    /// first `servers` URL if unset
+    #[serde(default)]
+    pub base_path: Option<String>,
+    /// Forward requests for paths or methods the spec doesn't describe instead of answering
+    /// 404/405
+    #[serde(default)]
+    pub allow_unknown_operations: bool,
+    /// Forward query parameters the operation doesn't declare instead of answering 400
+    #[serde(default = "default_true")]
+    pub allow_unknown_query_params: bool,
+    /// Check JSON request bodies against the operation's schema; bodies are buffered to do so
+    #[serde(default = "default_true")]
+    pub validate_body: bool,
+    /// Larger bodies are refused with 413 rather than buffered for validation
+    #[serde(default = "default_openapi_max_body_kb")]
+    pub max_body_kb: usize,
+}
+
+fn default_openapi_max_body_kb() -> usize {
+    1024
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GraphQlConfig {
+    /// Deepest field nesting, fragments expanded
+    #[serde(default = "default_graphql_max_depth")]
+    pub max_depth: usize,
+    /// Most fields an operation may select, fragments expanded
+    #[serde(default = "default_graphql_max_complexity")]
+    pub max_complexity: u64,
+    #[serde(default = "default_graphql_max_aliases")]
+    pub max_aliases: u64,
+    /// Refuse `__schema` and `__type` queries, e.g. in production
+    #[serde(default)]
+    pub block_introspection: bool,
+    /// Larger POST bodies are refused with 413 rather than buffered for parsing
+    #[serde(default = "default_graphql_max_body_kb")]
+    pub max_body_kb: usize,
+}
+
+fn default_graphql_max_depth() -> usize {
+    15
+}
+
+fn default_graphql_max_complexity() -> u64 {
+    1000
+}
+
+fn default_graphql_max_aliases() -> u64 {
+    30
+}
+
+fn default_graphql_max_body_kb() -> usize {
+    256
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JsonSchemaConfig {
+    /// Schema file, JSON or YAML; read on startup and reload. `$ref`s resolve within it.
+    pub schema: String,
+    /// Larger bodies are refused with 413 rather than buffered for validation
+    #[serde(default = "default_json_schema_max_body_kb")]
+    pub max_body_kb: usize,
+}
+
+fn default_json_schema_max_body_kb() -> usize {
+    1024
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CsrfConfig {
+    #[serde(default)]
+    pub mode: CsrfMode,
+    /// Double-submit cookie holding the token; issued on safe requests that lack it
+    #[serde(default = "default_csrf_cookie_name")]
+    pub cookie_name: String,
+    /// Header unsafe requests must repeat the cookie's token in
+    #[serde(default = "default_csrf_header_name")]
+    pub header_name: String,
+    /// Cross-origin callers to accept besides the request's own host in `origin` mode,
+    /// e.g. `https://app.example.com`
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+/// `origin` checks `Origin` (or `Referer`) against the request's host; `double_submit`
+/// requires a header matching the token cookie.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CsrfMode {
+    #[default]
+    Origin,
+    DoubleSubmit,
+}
+
+fn default_csrf_cookie_name() -> String {
+    "csrf_token".to_string()
+}
+
+fn default_csrf_header_name() -> String {
+    "X-CSRF-Token".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SecurityRuleConfig {
+    #[serde(default)]
+    pub mode: RuleMode,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ScheduleRuleConfig {
+    /// Request path; a trailing `*` matches any suffix
+    pub path: String,
+    pub action: ScheduleAction,
+    /// Days the window is open, e.g. `[mon, tue, wed, thu, fri]`; every day if empty. A
+    /// window past midnight belongs to the day it starts on.
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// Window start, `HH:MM`
+    pub from: String,
+    /// Window end, `HH:MM` (exclusive); earlier than `from` for windows past midnight, equal
+    /// for whole days
+    pub to: String,
+    /// `UTC` or a fixed offset such as `+02:00` that `from`/`to` are in. Offsets don't follow
+    /// daylight saving time.
+    #[serde(default = "default_schedule_timezone")]
+    pub timezone: String,
+}
+
+/// `allow` admits the path only inside the window; `deny` blocks it inside the window.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleAction {
+    Allow,
+    Deny,
+}
+
+fn default_schedule_timezone() -> String {
+    "UTC".to_string()
+}
+
+/// `monitor` evaluates the rule but only logs and counts requests it would have blocked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleMode {
+    #[default]
+    Enforce,
+    Monitor,
+}
+
+fn default_path_prefix() -> String {
+    "/".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WasmPluginConfig {
+    pub name: String,
+    /// Path to the compiled `.wasm` module
+    pub path: String,
+    /// Opaque string handed to the plugin through `proxy_on_configure`
+    #[serde(default)]
+    pub configuration: Option<String>,
+    /// Number of independent VM instances; requests are spread across them
+    #[serde(default = "default_wasm_instances")]
+    pub instances: usize,
+}
+
+fn default_wasm_instances() -> usize {
+    4
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LuaScriptConfig {
+    pub name: String,
+    pub path: String,
+    /// Number of independent Lua states; requests are spread across them
+    #[serde(default = "default_wasm_instances")]
+    pub instances: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BodyCaptureConfig {
+    /// JSON-lines output file, separate from the access log
+    pub path: String,
+    /// Fraction of matching requests to capture, 0.0 - 1.0
+    #[serde(default = "default_capture_sample_rate")]
+    pub sample_rate: f64,
+    /// Only capture requests whose path starts with this prefix
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    /// Bytes kept per body, in KiB
+    #[serde(default = "default_capture_max_body_kb")]
+    pub max_body_kb: usize,
+}
+
+fn default_capture_sample_rate() -> f64 {
+    0.01
+}
+
+fn default_capture_max_body_kb() -> usize {
+    4
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SyslogConfig {
+    /// Collector address, e.g. "10.0.0.5:514"
+    pub addr: String,
+    #[serde(default)]
+    pub protocol: SyslogProtocol,
+    #[serde(default)]
+    pub format: SyslogFormat,
+    /// Syslog facility number; defaults to 16 (local0)
+    #[serde(default = "default_syslog_facility")]
+    pub facility: u8,
+    #[serde(default = "default_syslog_app_name")]
+    pub app_name: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogProtocol {
+    #[default]
+    Udp,
+    Tcp,
+}
+
+/// `rfc5424` carries the event as JSON; `cef` maps it to ArcSight CEF extensions.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFormat {
+    #[default]
+    Rfc5424,
+    Cef,
+}
+
+fn default_syslog_facility() -> u8 {
+    16
+}
+
+fn default_syslog_app_name() -> String {
+    "flashproxy".to_string()
+}
+
+const REDACTED: &str = "<redacted>";
+
 impl GatewayConfig {
+    /// Read and validate the config file; used at startup and on every reload.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let config = Self::from_file(path)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parses the file, resolving `aws-sm:` / `aws-ssm:` secret references on the way.
     pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
         let path = path.as_ref();
         let contents = std::fs::read_to_string(path)
             .map_err(|e| ConfigError::Io(path.display().to_string(), e))?;
-        serde_yaml::from_str(&contents).map_err(ConfigError::Parse)
+        let mut value: serde_yaml::Value =
+            serde_yaml::from_str(&contents).map_err(ConfigError::Parse)?;
+        aws_secrets::resolve(&mut value)?;
+        serde_yaml::from_value(value).map_err(ConfigError::Parse)
     }
 
     pub fn validate(&self) -> Result<(), ConfigError> {
@@ -31,13 +1917,935 @@ impl GatewayConfig {
                 "rate_limit_per_second must be greater than 0".into(),
             ));
         }
-        if self.jwt_secret.is_empty() {
+        if !(1..=128).contains(&self.rate_limit_ipv6_prefix) {
+            return Err(ConfigError::Validation(
+                "rate_limit_ipv6_prefix must be between 1 and 128".into(),
+            ));
+        }
+        if self.listen_host.parse::<IpAddr>().is_err() {
+            return Err(ConfigError::Validation(format!(
+                "listen_host: '{}' is not an IP address",
+                self.listen_host
+            )));
+        }
+        if let Some(syslog) = &self.syslog {
+            if syslog.facility > 23 {
+                return Err(ConfigError::Validation(
+                    "syslog.facility must be between 0 and 23".into(),
+                ));
+            }
+        }
+        if let Some(capture) = &self.body_capture {
+            if !(0.0..=1.0).contains(&capture.sample_rate) {
+                return Err(ConfigError::Validation(
+                    "body_capture.sample_rate must be between 0.0 and 1.0".into(),
+                ));
+            }
+        }
+        if self.downstream.max_requests_per_connection == Some(0)
+            || self.downstream.h2_max_concurrent_streams == Some(0)
+            || self.downstream.max_request_body_kb == Some(0)
+            || self.downstream.max_upload_kb_per_sec == Some(0)
+        {
+            return Err(ConfigError::Validation(
+                "downstream limits must be greater than 0".into(),
+            ));
+        }
+        let dns = &self.upstream_dns;
+        if dns.min_ttl_secs == 0 || dns.min_ttl_secs > dns.max_ttl_secs {
+            return Err(ConfigError::Validation(
+                "upstream_dns: min_ttl_secs must be greater than 0 and at most max_ttl_secs".into(),
+            ));
+        }
+        if let Some(server) = dns
+            .nameservers
+            .iter()
+            .find(|s| s.parse::<std::net::SocketAddr>().is_err())
+        {
+            return Err(ConfigError::Validation(format!(
+                "upstream_dns.nameservers: '{}' is not an address with port",
+                server
+            )));
+        }
+        if self.upstream_subset.as_ref().is_some_and(|s| s.size == 0) {
+            return Err(ConfigError::Validation(
+                "upstream_subset.size must be greater than 0".into(),
+            ));
+        }
+        for (zone, upstreams) in &self.upstream_zones {
+            if let Some(upstream) = upstreams.iter().find(|u| !self.upstream_ips.contains(u)) {
+                return Err(ConfigError::Validation(format!(
+                    "upstream_zones.{}: '{}' is not in upstream_ips",
+                    zone, upstream
+                )));
+            }
+        }
+        if let Some(zone) = &self.zone {
+            if !self.upstream_zones.contains_key(zone) {
+                return Err(ConfigError::Validation(format!(
+                    "zone '{}' has no upstreams in upstream_zones",
+                    zone
+                )));
+            }
+        }
+        if self.health_check.interval_secs == 0
+            || self.health_check.max_concurrent_probes == 0
+            || self.health_check.slow_start_secs == Some(0)
+        {
+            return Err(ConfigError::Validation(
+                "health_check: interval_secs, max_concurrent_probes and slow_start_secs must be greater than 0"
+                    .into(),
+            ));
+        }
+        if self.health_check.jitter_ms >= self.health_check.interval_secs * 1000 {
+            return Err(ConfigError::Validation(
+                "health_check: jitter_ms must be shorter than interval_secs".into(),
+            ));
+        }
+        if let Some(ws) = &self.websocket {
+            if ws.max_frame_kb == 0
+                || ws.max_message_kb == 0
+                || ws.max_messages_per_sec == Some(0)
+                || ws.message_burst == Some(0)
+            {
+                return Err(ConfigError::Validation(
+                    "websocket: limits must be greater than 0".into(),
+                ));
+            }
+        }
+        if let Some(filter) = &self.upload_filter {
+            for name in &filter.blocked_types {
+                if !FILE_TYPES.iter().any(|(t, _)| t == name) {
+                    return Err(ConfigError::Validation(format!(
+                        "upload_filter: unknown blocked type '{}'",
+                        name
+                    )));
+                }
+            }
+        }
+        if let Some(icap) = &self.icap {
+            if icap.reqmod_service.is_none() && icap.respmod_service.is_none() {
+                return Err(ConfigError::Validation(
+                    "icap: set reqmod_service, respmod_service or both".into(),
+                ));
+            }
+        }
+        if let Some(egress) = &self.egress_proxy {
+            let too_long = |v: &Option<String>| v.as_ref().is_some_and(|v| v.len() > 255);
+            if egress.protocol == EgressProtocol::Socks5
+                && (too_long(&egress.username) || too_long(&egress.password))
+            {
+                return Err(ConfigError::Validation(
+                    "egress_proxy: SOCKS5 username and password are limited to 255 bytes".into(),
+                ));
+            }
+        }
+        for service in &self.tcp_services {
+            if service.upstreams.is_empty() && service.sni_routes.is_empty() {
+                return Err(ConfigError::Validation(format!(
+                    "tcp_services.{}: at least one upstream is required",
+                    service.name
+                )));
+            }
+            for route in &service.sni_routes {
+                if route.server_names.is_empty() || route.upstreams.is_empty() {
+                    return Err(ConfigError::Validation(format!(
+                        "tcp_services.{}: each sni route needs server_names and upstreams",
+                        service.name
+                    )));
+                }
+            }
+        }
+        if let Some(cache) = &self.cache {
+            if cache.negative_ttl_secs == Some(0) {
+                return Err(ConfigError::Validation(
+                    "cache: negative_ttl_secs must be greater than 0".into(),
+                ));
+            }
+            if let Some(status) = cache
+                .negative_statuses
+                .iter()
+                .find(|s| !(400..600).contains(*s))
+            {
+                return Err(ConfigError::Validation(format!(
+                    "cache: negative_statuses must be 4xx or 5xx, got {}",
+                    status
+                )));
+            }
+            if cache.shards == 0 || cache.max_memory_mb == 0 {
+                return Err(ConfigError::Validation(
+                    "cache: shards and max_memory_mb must be greater than 0".into(),
+                ));
+            }
+            if (cache.max_body_kb as u64) * 1024 * (cache.shards as u64)
+                > cache.max_memory_mb * 1024 * 1024
+            {
+                return Err(ConfigError::Validation(
+                    "cache: max_body_kb must fit in each shard's share of max_memory_mb".into(),
+                ));
+            }
+        }
+        if let Some(disk) = self.cache.as_ref().and_then(|c| c.disk.as_ref()) {
+            if disk.max_size_mb == 0 || disk.max_body_mb == 0 {
+                return Err(ConfigError::Validation(
+                    "cache.disk: max_size_mb and max_body_mb must be greater than 0".into(),
+                ));
+            }
+        }
+        for route in &self.routes {
+            if route.cache && self.cache.is_none() {
+                return Err(ConfigError::Validation(format!(
+                    "routes.{}: cache is enabled but there is no top-level cache section",
+                    route.name
+                )));
+            }
+            if route.idle_timeout_secs == Some(0) {
+                return Err(ConfigError::Validation(format!(
+                    "routes.{}: idle_timeout_secs must be greater than 0",
+                    route.name
+                )));
+            }
+            if let Some(buffering) = &route.response_buffering {
+                if buffering.max_buffer_kb == 0 {
+                    return Err(ConfigError::Validation(format!(
+                        "routes.{}: response_buffering.max_buffer_kb must be greater than 0",
+                        route.name
+                    )));
+                }
+                if buffering.mode == ResponseBufferingMode::Streaming && route.cache {
+                    return Err(ConfigError::Validation(format!(
+                        "routes.{}: streamed responses can't be cached",
+                        route.name
+                    )));
+                }
+            }
+            if let Some(response) = &route.maintenance_response {
+                if http::HeaderValue::from_str(&response.content_type).is_err() {
+                    return Err(ConfigError::Validation(format!(
+                        "routes.{}: maintenance_response.content_type is not a valid header value",
+                        route.name
+                    )));
+                }
+            }
+            if route.middleware.is_some() && !(route.enable.is_empty() && route.disable.is_empty())
+            {
+                return Err(ConfigError::Validation(format!(
+                    "routes.{}: use either middleware or enable/disable, not both",
+                    route.name
+                )));
+            }
+            let stages = route
+                .middleware
+                .iter()
+                .flatten()
+                .chain(&route.enable)
+                .chain(&route.disable);
+            for stage in stages {
+                if !STAGE_NAMES.contains(&stage.as_str()) {
+                    return Err(ConfigError::Validation(format!(
+                        "routes.{}: unknown middleware '{}' (expected one of {})",
+                        route.name,
+                        stage,
+                        STAGE_NAMES.join(", ")
+                    )));
+                }
+            }
+            let configured = [
+                ("openapi", route.openapi.is_some()),
+                ("graphql", route.graphql.is_some()),
+                ("json_schema", route.json_schema.is_some()),
+                ("csrf", route.csrf.is_some()),
+            ];
+            for (stage, _) in configured.iter().filter(|(_, set)| *set) {
+                let listed = route
+                    .middleware
+                    .as_ref()
+                    .is_none_or(|names| names.iter().any(|n| n == stage));
+                if !listed || route.disable.iter().any(|n| n == stage) {
+                    return Err(ConfigError::Validation(format!(
+                        "routes.{}: {} is set but the {} stage isn't in the chain",
+                        route.name, stage, stage
+                    )));
+                }
+            }
+            if let Some(graphql) = &route.graphql {
+                if graphql.max_depth == 0 || graphql.max_body_kb == 0 {
+                    return Err(ConfigError::Validation(format!(
+                        "routes.{}: graphql.max_depth and max_body_kb must be greater than 0",
+                        route.name
+                    )));
+                }
+            }
+            if let Some(csrf) = &route.csrf {
+                if http::HeaderName::from_bytes(csrf.header_name.as_bytes()).is_err()
+                    || csrf.cookie_name.is_empty()
+                    || csrf.cookie_name.contains([';', '=', ' '])
+                {
+                    return Err(ConfigError::Validation(format!(
+                        "routes.{}: csrf.cookie_name or header_name is not a valid name",
+                        route.name
+                    )));
+                }
+            }
+            if route
+                .json_schema
+                .as_ref()
+                .is_some_and(|s| s.max_body_kb == 0)
+            {
+                return Err(ConfigError::Validation(format!(
+                    "routes.{}: json_schema.max_body_kb must be greater than 0",
+                    route.name
+                )));
+            }
+            if let Some(openapi) = &route.openapi {
+                if openapi.max_body_kb == 0 {
+                    return Err(ConfigError::Validation(format!(
+                        "routes.{}: openapi.max_body_kb must be greater than 0",
+                        route.name
+                    )));
+                }
+            }
+        }
+        for label in self.metrics_labels.keys() {
+            let valid = label.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && !label.starts_with("__");
+            if !valid || VARIABLE_LABELS.contains(&label.as_str()) {
+                return Err(ConfigError::Validation(format!(
+                    "metrics_labels: invalid or reserved label name '{}'",
+                    label
+                )));
+            }
+        }
+        if self.server.threads == Some(0) {
+            return Err(ConfigError::Validation(
+                "server.threads must be greater than 0".into(),
+            ));
+        }
+        if self.server.error_log.is_some() && !self.server.daemon {
+            return Err(ConfigError::Validation(
+                "server.error_log only applies with server.daemon".into(),
+            ));
+        }
+        if self.server.upgrade_sock.is_empty() {
+            return Err(ConfigError::Validation(
+                "server.upgrade_sock must not be empty".into(),
+            ));
+        }
+        if let Some(push) = &self.metrics_push {
+            if !push.url.starts_with("http://") && !push.url.starts_with("https://") {
+                return Err(ConfigError::Validation(
+                    "metrics_push.url must be http:// or https://".into(),
+                ));
+            }
+            if push.interval_secs == 0 || push.timeout_secs == 0 {
+                return Err(ConfigError::Validation(
+                    "metrics_push: interval_secs and timeout_secs must be greater than 0".into(),
+                ));
+            }
+            let mut grouping = std::iter::once(&push.job).chain(push.instance.as_ref());
+            if grouping.any(|v| v.is_empty() || v.contains('/')) {
+                return Err(ConfigError::Validation(
+                    "metrics_push: job and instance must be non-empty and contain no '/'".into(),
+                ));
+            }
+            let invalid = push.headers.iter().any(|(name, value)| {
+                http::HeaderName::from_bytes(name.as_bytes()).is_err()
+                    || http::HeaderValue::from_str(value).is_err()
+            });
+            if invalid {
+                return Err(ConfigError::Validation(
+                    "metrics_push.headers has an invalid header".into(),
+                ));
+            }
+        }
+        for rule in self.security_rules.keys() {
+            if !RULE_STAGES.contains(&rule.as_str()) {
+                return Err(ConfigError::Validation(format!(
+                    "security_rules: unknown rule '{}' (expected one of {})",
+                    rule,
+                    RULE_STAGES.join(", ")
+                )));
+            }
+        }
+        for response in &self.synthetic_responses {
+            if response.body.is_some() && response.body_file.is_some() {
+                return Err(ConfigError::Validation(format!(
+                    "synthetic_responses {}: set body or body_file, not both",
+                    response.path
+                )));
+            }
+            if !(100..=599).contains(&response.status) {
+                return Err(ConfigError::Validation(format!(
+                    "synthetic_responses {}: invalid status {}",
+                    response.path, response.status
+                )));
+            }
+        }
+        let method_lists = self
+            .routes
+            .iter()
+            .filter_map(|r| r.methods.as_ref())
+            .chain(&self.allowed_methods);
+        for method in method_lists.flatten() {
+            if http::Method::from_bytes(method.as_bytes()).is_err() {
+                return Err(ConfigError::Validation(format!(
+                    "invalid method '{}'",
+                    method
+                )));
+            }
+        }
+        for header in self.jwt_claim_headers.values() {
+            if http::HeaderName::from_bytes(header.as_bytes()).is_err() {
+                return Err(ConfigError::Validation(format!(
+                    "jwt_claim_headers: invalid header name '{}'",
+                    header
+                )));
+            }
+        }
+        if let Some(token) = &self.upstream_token {
+            if token.secret.is_some() == token.private_key_path.is_some() {
+                return Err(ConfigError::Validation(
+                    "upstream_token: set exactly one of secret and private_key_path".into(),
+                ));
+            }
+            if token.ttl_secs == 0 {
+                return Err(ConfigError::Validation(
+                    "upstream_token.ttl_secs must be greater than 0".into(),
+                ));
+            }
+        }
+        if let Some(quota) = &self.quota {
+            if quota.daily.is_none() && quota.monthly.is_none() {
+                return Err(ConfigError::Validation(
+                    "quota: set daily, monthly or both".into(),
+                ));
+            }
+            if quota.flush_interval_secs == 0 {
+                return Err(ConfigError::Validation(
+                    "quota.flush_interval_secs must be greater than 0".into(),
+                ));
+            }
+            if quota.header.is_some() {
+                return Err(ConfigError::Validation(
+                    "quota.header is no longer supported; quotas are charged to the subject the jwt or hmac stage authenticated".into(),
+                ));
+            }
+            if quota.max_identities == 0 {
+                return Err(ConfigError::Validation(
+                    "quota.max_identities must be greater than 0".into(),
+                ));
+            }
+        }
+        if let Some(webhook) = &self.alert_webhook {
+            if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                return Err(ConfigError::Validation(
+                    "alert_webhook.url must be http:// or https://".into(),
+                ));
+            }
+            if webhook.timeout_secs == 0 || webhook.max_attempts == 0 {
+                return Err(ConfigError::Validation(
+                    "alert_webhook: timeout_secs and max_attempts must be greater than 0".into(),
+                ));
+            }
+            let invalid = webhook.headers.iter().any(|(name, value)| {
+                http::HeaderName::from_bytes(name.as_bytes()).is_err()
+                    || http::HeaderValue::from_str(value).is_err()
+            });
+            if invalid {
+                return Err(ConfigError::Validation(
+                    "alert_webhook.headers has an invalid header".into(),
+                ));
+            }
+        }
+        if let Some(anomaly) = &self.anomaly_detection {
+            if anomaly.window_secs == 0
+                || anomaly.max_tracked_keys == 0
+                || anomaly.scan_not_found_paths == 0
+            {
+                return Err(ConfigError::Validation(
+                    "anomaly_detection: window_secs, max_tracked_keys and scan_not_found_paths must be greater than 0".into(),
+                ));
+            }
+            if !(anomaly.ip_max_error_ratio > 0.0 && anomaly.ip_max_error_ratio <= 1.0) {
+                return Err(ConfigError::Validation(
+                    "anomaly_detection.ip_max_error_ratio must be in (0, 1]".into(),
+                ));
+            }
+            if anomaly.path_spike_factor.is_nan() || anomaly.path_spike_factor <= 1.0 {
+                return Err(ConfigError::Validation(
+                    "anomaly_detection.path_spike_factor must be greater than 1".into(),
+                ));
+            }
+            if anomaly
+                .penalty
+                .as_ref()
+                .is_some_and(|p| p.rate_limit_per_second == 0 || p.duration_secs == 0)
+            {
+                return Err(ConfigError::Validation(
+                    "anomaly_detection.penalty: limits must be greater than 0".into(),
+                ));
+            }
+        }
+        if let Some(hmac) = &self.hmac_auth {
+            if hmac.keys.is_empty() || hmac.keys.values().any(|secret| secret.is_empty()) {
+                return Err(ConfigError::Validation(
+                    "hmac_auth: set at least one key, with a non-empty secret".into(),
+                ));
+            }
+            if hmac.max_skew_secs == 0 || hmac.max_nonces == 0 {
+                return Err(ConfigError::Validation(
+                    "hmac_auth: max_skew_secs and max_nonces must be greater than 0".into(),
+                ));
+            }
+        }
+        if let Some(entry) = self
+            .trusted_proxies
+            .iter()
+            .find(|entry| parse_range(entry).is_none())
+        {
+            return Err(ConfigError::Validation(format!(
+                "trusted_proxies: '{}' is not an address or CIDR range",
+                entry
+            )));
+        }
+        if let Some(auth) = &self.admin_auth {
+            if auth.token.is_none() && auth.allowed_ips.is_empty() && auth.client_ca.is_none() {
+                return Err(ConfigError::Validation(
+                    "admin_auth: set at least one of token, allowed_ips and client_ca".into(),
+                ));
+            }
+            if auth.token.as_ref().is_some_and(|t| t.is_empty()) {
+                return Err(ConfigError::Validation(
+                    "admin_auth.token must not be empty".into(),
+                ));
+            }
+            if let Some(entry) = auth.allowed_ips.iter().find(|e| parse_range(e).is_none()) {
+                return Err(ConfigError::Validation(format!(
+                    "admin_auth.allowed_ips: '{}' is not an address or CIDR range",
+                    entry
+                )));
+            }
+            if auth.client_ca.is_some()
+                && (self.tls_cert_path.is_empty() || self.tls_key_path.is_empty())
+            {
+                return Err(ConfigError::Validation(
+                    "admin_auth.client_ca needs tls_cert_path and tls_key_path".into(),
+                ));
+            }
+        }
+        if http::HeaderName::from_bytes(self.client_ip_header.as_bytes()).is_err() {
+            return Err(ConfigError::Validation(
+                "client_ip_header is not a valid header name".into(),
+            ));
+        }
+        if http::HeaderName::from_bytes(self.request_id.header.as_bytes()).is_err() {
+            return Err(ConfigError::Validation(
+                "request_id.header is not a valid header name".into(),
+            ));
+        }
+        if let Some(entry) = self
+            .request_id
+            .internal_cidrs
+            .iter()
+            .find(|entry| parse_range(entry).is_none())
+        {
+            return Err(ConfigError::Validation(format!(
+                "request_id.internal_cidrs: '{}' is not an address or CIDR range",
+                entry
+            )));
+        }
+        if self.request_id.client_ids == ClientRequestIds::Internal
+            && self.request_id.internal_cidrs.is_empty()
+        {
+            return Err(ConfigError::Validation(
+                "request_id: client_ids internal needs internal_cidrs".into(),
+            ));
+        }
+        for (name, feed) in &self.ip_feeds {
+            if !feed.url.starts_with("http://") && !feed.url.starts_with("https://") {
+                return Err(ConfigError::Validation(format!(
+                    "ip_feeds.{}: url must be http:// or https://",
+                    name
+                )));
+            }
+            if feed.refresh_secs == 0 {
+                return Err(ConfigError::Validation(format!(
+                    "ip_feeds.{}: refresh_secs must be greater than 0",
+                    name
+                )));
+            }
+        }
+        if let Some(tor) = &self.tor_exit_nodes {
+            if !tor.url.starts_with("http://") && !tor.url.starts_with("https://") {
+                return Err(ConfigError::Validation(
+                    "tor_exit_nodes.url must be http:// or https://".into(),
+                ));
+            }
+            if tor.refresh_secs == 0 {
+                return Err(ConfigError::Validation(
+                    "tor_exit_nodes.refresh_secs must be greater than 0".into(),
+                ));
+            }
+            if http::HeaderName::from_bytes(tor.header.as_bytes()).is_err() {
+                return Err(ConfigError::Validation(
+                    "tor_exit_nodes.header is not a valid header name".into(),
+                ));
+            }
+        }
+        if let Some(challenge) = &self.challenge {
+            if challenge.secret.is_empty() || challenge.ttl_secs == 0 {
+                return Err(ConfigError::Validation(
+                    "challenge: secret must be set and ttl_secs greater than 0".into(),
+                ));
+            }
+            if challenge.cookie_name.is_empty() || challenge.cookie_name.contains([';', '=', ' ']) {
+                return Err(ConfigError::Validation(
+                    "challenge.cookie_name is not a valid name".into(),
+                ));
+            }
+            if challenge
+                .missing_headers
+                .iter()
+                .any(|h| http::HeaderName::from_bytes(h.as_bytes()).is_err())
+            {
+                return Err(ConfigError::Validation(
+                    "challenge.missing_headers has an invalid header name".into(),
+                ));
+            }
+            if let Some(captcha) = &challenge.captcha {
+                if captcha.site_key.is_empty() || captcha.secret_key.is_empty() {
+                    return Err(ConfigError::Validation(
+                        "challenge.captcha: site_key and secret_key must be set".into(),
+                    ));
+                }
+                if !captcha.verify_path.starts_with('/') {
+                    return Err(ConfigError::Validation(
+                        "challenge.captcha.verify_path must start with /".into(),
+                    ));
+                }
+            }
+            if challenge.tor_exit_nodes && self.tor_exit_nodes.is_none() {
+                return Err(ConfigError::Validation(
+                    "challenge.tor_exit_nodes requires tor_exit_nodes".into(),
+                ));
+            }
+            let criteria = challenge.paths.len()
+                + challenge.user_agents.len()
+                + challenge.missing_headers.len()
+                + usize::from(challenge.tor_exit_nodes);
+            if criteria == 0 {
+                return Err(ConfigError::Validation(
+                    "challenge: set at least one of paths, user_agents, missing_headers and tor_exit_nodes".into(),
+                ));
+            }
+        }
+        if let Some(name) = self
+            .header_normalization
+            .names
+            .iter()
+            .find(|name| http::HeaderName::from_bytes(name.as_bytes()).is_err())
+        {
+            return Err(ConfigError::Validation(format!(
+                "header_normalization.names: invalid header name '{}'",
+                name
+            )));
+        }
+        if self.cert_expiry.check_interval_secs == 0 {
+            return Err(ConfigError::Validation(
+                "cert_expiry.check_interval_secs must be greater than 0".into(),
+            ));
+        }
+        for (i, limit) in self.bandwidth_limits.iter().enumerate() {
+            if limit.download_kb_per_sec.is_none() && limit.upload_kb_per_sec.is_none() {
+                return Err(ConfigError::Validation(format!(
+                    "bandwidth_limits[{}]: set download_kb_per_sec or upload_kb_per_sec",
+                    i
+                )));
+            }
+            if [
+                limit.download_kb_per_sec,
+                limit.upload_kb_per_sec,
+                limit.burst_kb,
+            ]
+            .contains(&Some(0))
+            {
+                return Err(ConfigError::Validation(format!(
+                    "bandwidth_limits[{}]: rates and burst_kb must be greater than 0",
+                    i
+                )));
+            }
+            match &limit.header {
+                Some(header) if http::HeaderName::from_bytes(header.as_bytes()).is_err() => {
+                    return Err(ConfigError::Validation(format!(
+                        "bandwidth_limits[{}]: invalid header name '{}'",
+                        i, header
+                    )));
+                }
+                Some(_) if limit.values.is_empty() => {
+                    return Err(ConfigError::Validation(format!(
+                        "bandwidth_limits[{}]: header needs values",
+                        i
+                    )));
+                }
+                None if !limit.values.is_empty() => {
+                    return Err(ConfigError::Validation(format!(
+                        "bandwidth_limits[{}]: values need a header",
+                        i
+                    )));
+                }
+                _ => {}
+            }
+            if let Some(route) = limit
+                .routes
+                .iter()
+                .find(|name| !self.routes.iter().any(|r| &r.name == *name))
+            {
+                return Err(ConfigError::Validation(format!(
+                    "bandwidth_limits[{}]: unknown route '{}'",
+                    i, route
+                )));
+            }
+        }
+        if self.tarpit.as_ref().is_some_and(|t| t.interval_ms == 0) {
+            return Err(ConfigError::Validation(
+                "tarpit.interval_ms must be greater than 0".into(),
+            ));
+        }
+        if !self.error_responses.template.is_object() {
+            return Err(ConfigError::Validation(
+                "error_responses.template must be a JSON object".into(),
+            ));
+        }
+        if let Some(policy) = &self.tls_policy {
+            if policy.min_version.is_none()
+                && policy.deny_ciphers.is_empty()
+                && !policy.sni_must_match_host
+            {
+                return Err(ConfigError::Validation(
+                    "tls_policy: set min_version, deny_ciphers or sni_must_match_host".into(),
+                ));
+            }
+        }
+        if let Some(asn) = &self.asn_rules {
+            if asn.allow.is_empty() && asn.deny.is_empty() {
+                return Err(ConfigError::Validation(
+                    "asn_rules: set allow or deny".into(),
+                ));
+            }
+        }
+        for (host, limits) in &self.tenant_limits {
+            if limits.rate_limit_per_second == Some(0) || limits.max_concurrent == Some(0) {
+                return Err(ConfigError::Validation(format!(
+                    "tenant_limits.{}: limits must be greater than 0",
+                    host
+                )));
+            }
+        }
+        for (host, tenant) in &self.jwt_tenants {
+            if tenant.secret.is_some() == tenant.jwks_url.is_some() {
+                return Err(ConfigError::Validation(format!(
+                    "jwt_tenants.{}: set exactly one of secret and jwks_url",
+                    host
+                )));
+            }
+            if tenant.jwks_refresh_secs == 0 {
+                return Err(ConfigError::Validation(format!(
+                    "jwt_tenants.{}.jwks_refresh_secs must be greater than 0",
+                    host
+                )));
+            }
+        }
+        let vault = self.vault.as_ref();
+        if self.jwt_secret.is_empty() && vault.and_then(|v| v.jwt_secret.as_ref()).is_none() {
             return Err(ConfigError::Validation(
                 "jwt_secret must not be empty".into(),
             ));
         }
+        if (self.tls_cert_path.is_empty() || self.tls_key_path.is_empty())
+            && vault.and_then(|v| v.tls.as_ref()).is_none()
+        {
+            return Err(ConfigError::Validation(
+                "tls_cert_path and tls_key_path are required unless vault.tls is set".into(),
+            ));
+        }
+        if vault.is_some_and(|v| v.refresh_interval_secs == 0) {
+            return Err(ConfigError::Validation(
+                "vault.refresh_interval_secs must be greater than 0".into(),
+            ));
+        }
         Ok(())
     }
+
+    /// Address of the HTTPS listener.
+    pub fn listen_addr(&self) -> String {
+        SocketAddr::new(
+            self.listen_host
+                .parse()
+                .unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
+            self.listen_port,
+        )
+        .to_string()
+    }
+
+    /// Every listener's address, as pingora keys its socket.
+    pub fn listen_addrs(&self) -> Vec<String> {
+        let mut addrs = vec![self.listen_addr()];
+        addrs.extend(self.tcp_services.iter().map(|tcp| tcp.listen.clone()));
+        addrs.extend(self.admin_listen_addr.clone());
+        addrs.extend(self.admin_grpc_listen_addr.clone());
+        addrs.extend(self.egress_proxy.iter().map(|e| e.socket_path.clone()));
+        addrs
+    }
+
+    /// Every field that differs between `self` and `other`, by dotted path (list items by
+    /// index). Changes are found on the real values but reported with values from the
+    /// redacted configs, so a rotated secret shows up as modified without leaking either side.
+    pub fn diff(&self, other: &Self) -> Vec<FieldChange> {
+        let values = |config: &Self| {
+            (
+                serde_json::to_value(config).unwrap_or(Value::Null),
+                serde_json::to_value(config.redacted()).unwrap_or(Value::Null),
+            )
+        };
+        let (old, old_redacted) = values(self);
+        let (new, new_redacted) = values(other);
+        let mut paths = Vec::new();
+        diff_values(&mut Vec::new(), &old, &new, &mut paths);
+        paths
+            .into_iter()
+            .map(|(path, kind)| FieldChange {
+                field: path.join("."),
+                kind,
+                old: lookup(&old_redacted, &path),
+                new: lookup(&new_redacted, &path),
+            })
+            .collect()
+    }
+
+    /// Copy of the config with secrets masked, safe to expose over the admin API.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        config.jwt_secret = REDACTED.to_string();
+        for tenant in config.jwt_tenants.values_mut() {
+            if tenant.secret.is_some() {
+                tenant.secret = Some(REDACTED.to_string());
+            }
+        }
+        if let Some(hmac) = config.hmac_auth.as_mut() {
+            for secret in hmac.keys.values_mut() {
+                *secret = REDACTED.to_string();
+            }
+        }
+        if let Some(token) = config.upstream_token.as_mut() {
+            if token.secret.is_some() {
+                token.secret = Some(REDACTED.to_string());
+            }
+        }
+        if let Some(webhook) = config.alert_webhook.as_mut() {
+            for value in webhook.headers.values_mut() {
+                *value = REDACTED.to_string();
+            }
+        }
+        if let Some(push) = config.metrics_push.as_mut() {
+            for value in push.headers.values_mut() {
+                *value = REDACTED.to_string();
+            }
+        }
+        if let Some(challenge) = config.challenge.as_mut() {
+            challenge.secret = REDACTED.to_string();
+            if let Some(captcha) = challenge.captcha.as_mut() {
+                captcha.secret_key = REDACTED.to_string();
+            }
+        }
+        if let Some(egress) = config.egress_proxy.as_mut() {
+            if egress.password.is_some() {
+                egress.password = Some(REDACTED.to_string());
+            }
+        }
+        if let Some(auth) = config.admin_auth.as_mut() {
+            if auth.token.is_some() {
+                auth.token = Some(REDACTED.to_string());
+            }
+        }
+        config
+    }
+}
+
+/// One difference found by [`GatewayConfig::diff`].
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub kind: ChangeKind,
+    /// Redacted; null when added
+    pub old: Value,
+    /// Redacted; null when removed
+    pub new: Value,
+}
+
+/// An unset optional field counts as absent, so setting one is `added`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+impl ChangeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Modified => "modified",
+        }
+    }
+}
+
+fn diff_values(
+    path: &mut Vec<String>,
+    old: &Value,
+    new: &Value,
+    out: &mut Vec<(Vec<String>, ChangeKind)>,
+) {
+    let mut child = |key: String, o: Option<&Value>, n: Option<&Value>| {
+        path.push(key);
+        diff_values(
+            path,
+            o.unwrap_or(&Value::Null),
+            n.unwrap_or(&Value::Null),
+            out,
+        );
+        path.pop();
+    };
+    match (old, new) {
+        (Value::Object(old_map), Value::Object(new_map)) => {
+            let keys: BTreeSet<&String> = old_map.keys().chain(new_map.keys()).collect();
+            for key in keys {
+                child(key.clone(), old_map.get(key), new_map.get(key));
+            }
+        }
+        (Value::Array(old_items), Value::Array(new_items)) => {
+            for i in 0..old_items.len().max(new_items.len()) {
+                child(i.to_string(), old_items.get(i), new_items.get(i));
+            }
+        }
+        _ if old == new => {}
+        (Value::Null, _) => out.push((path.clone(), ChangeKind::Added)),
+        (_, Value::Null) => out.push((path.clone(), ChangeKind::Removed)),
+        _ => out.push((path.clone(), ChangeKind::Modified)),
+    }
+}
+
+fn lookup(value: &Value, path: &[String]) -> Value {
+    path.iter()
+        .try_fold(value, |v, key| match v {
+            Value::Object(map) => map.get(key),
+            Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => None,
+        })
+        .cloned()
+        .unwrap_or(Value::Null)
 }
 
 #[derive(Debug)]
@@ -45,6 +2853,8 @@ pub enum ConfigError {
     Io(String, std::io::Error),
     Parse(serde_yaml::Error),
     Validation(String),
+    /// An external secret store couldn't supply a referenced secret
+    Secret(String),
 }
 
 impl std::fmt::Display for ConfigError {
@@ -53,6 +2863,7 @@ impl std::fmt::Display for ConfigError {
             ConfigError::Io(path, e) => write!(f, "config io error at {}: {}", path, e),
             ConfigError::Parse(e) => write!(f, "config parse error: {}", e),
             ConfigError::Validation(s) => write!(f, "config validation: {}", s),
+            ConfigError::Secret(s) => write!(f, "config secret: {}", s),
         }
     }
 }
@@ -62,7 +2873,7 @@ impl std::error::Error for ConfigError {
         match self {
             ConfigError::Io(_, e) => Some(e),
             ConfigError::Parse(e) => Some(e),
-            ConfigError::Validation(_) => None,
+            ConfigError::Validation(_) | ConfigError::Secret(_) => None,
         }
     }
 }
@@ -0,0 +1,69 @@
+use crate::configuration::RouteState;
+use dashmap::{DashMap, DashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Runtime switches flipped through the admin API. They aren't part of the config, so a
+/// reload keeps them and a restart clears them.
+#[derive(Default)]
+pub struct Controls {
+    banned: DashSet<IpAddr>,
+    maintenance: AtomicBool,
+    draining: AtomicBool,
+    /// Per-route overrides of the configured `state`, by route name
+    route_states: DashMap<String, RouteState>,
+}
+
+impl Controls {
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        !self.banned.is_empty() && self.banned.contains(ip)
+    }
+
+    pub fn ban(&self, ip: IpAddr) {
+        self.banned.insert(ip);
+    }
+
+    pub fn unban(&self, ip: &IpAddr) {
+        self.banned.remove(ip);
+    }
+
+    pub fn banned(&self) -> Vec<IpAddr> {
+        let mut ips: Vec<IpAddr> = self.banned.iter().map(|ip| *ip).collect();
+        ips.sort();
+        ips
+    }
+
+    /// Every proxied request gets 503 while set.
+    pub fn maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
+
+    pub fn set_maintenance(&self, enabled: bool) {
+        self.maintenance.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Requests are still served, but HTTP/1 connections are closed after each response so
+    /// clients move to other instances before this one is stopped.
+    pub fn draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    pub fn route_state(&self, route: &str) -> Option<RouteState> {
+        if self.route_states.is_empty() {
+            return None;
+        }
+        self.route_states.get(route).map(|state| *state)
+    }
+
+    /// `None` goes back to the configured state.
+    pub fn set_route_state(&self, route: &str, state: Option<RouteState>) {
+        match state {
+            Some(state) => self.route_states.insert(route.to_string(), state),
+            None => self.route_states.remove(route).map(|(_, state)| state),
+        };
+    }
+}
@@ -0,0 +1,124 @@
+use crate::configuration::{CsrfConfig, CsrfMode};
+use crate::middleware::request_host;
+use pingora::http::RequestHeader;
+use rand::RngCore;
+
+/// Cross-site request forgery checks for routes authenticated by session cookies. Safe
+/// methods and requests without cookies pass, since a forged request only matters when the
+/// browser attaches the victim's session.
+pub struct Csrf {
+    mode: CsrfMode,
+    cookie_name: String,
+    header_name: String,
+    /// Lowercase, without trailing slashes
+    allowed_origins: Vec<String>,
+}
+
+impl Csrf {
+    pub fn new(config: &CsrfConfig) -> Self {
+        Self {
+            mode: config.mode,
+            cookie_name: config.cookie_name.clone(),
+            header_name: config.header_name.clone(),
+            allowed_origins: config
+                .allowed_origins
+                .iter()
+                .map(|o| o.trim_end_matches('/').to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// `Err` carries the reason for the audit trail.
+    pub fn check(&self, req: &RequestHeader) -> Result<(), &'static str> {
+        if is_safe(&req.method) || !req.headers.contains_key(http::header::COOKIE) {
+            return Ok(());
+        }
+        match self.mode {
+            CsrfMode::Origin => self.check_origin(req),
+            CsrfMode::DoubleSubmit => {
+                let cookie = self.cookie(req).ok_or("csrf_token_missing")?;
+                let header = req
+                    .headers
+                    .get(self.header_name.as_str())
+                    .ok_or("csrf_token_missing")?;
+                match constant_time_eq(cookie.as_bytes(), header.as_bytes()) {
+                    true => Ok(()),
+                    false => Err("csrf_token_mismatch"),
+                }
+            }
+        }
+    }
+
+    /// A fresh token cookie for a safe request that arrived without one, so the page it loads
+    /// can echo the token back in the header.
+    pub fn issue(&self, req: &RequestHeader) -> Option<String> {
+        if self.mode != CsrfMode::DoubleSubmit
+            || !is_safe(&req.method)
+            || self.cookie(req).is_some()
+        {
+            return None;
+        }
+        let mut token = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut token);
+        // Readable from scripts by design: the page must copy it into the header
+        Some(format!(
+            "{}={}; Path=/; Secure; SameSite=Strict",
+            self.cookie_name,
+            hex::encode(token)
+        ))
+    }
+
+    fn check_origin(&self, req: &RequestHeader) -> Result<(), &'static str> {
+        let header = |name| req.headers.get(name).and_then(|v| v.to_str().ok());
+        // Referer is the fallback for browsers that leave Origin off same-origin requests
+        let origin = match (header(http::header::ORIGIN), header(http::header::REFERER)) {
+            (Some(origin), _) => origin.trim_end_matches('/').to_ascii_lowercase(),
+            (None, Some(referer)) => origin_of(referer).ok_or("csrf_origin_mismatch")?,
+            (None, None) => return Err("csrf_origin_missing"),
+        };
+        if self.allowed_origins.contains(&origin) {
+            return Ok(());
+        }
+        let host = origin
+            .split_once("://")
+            .map(|(_, authority)| authority)
+            .and_then(|authority| match authority.strip_prefix('[') {
+                Some(v6) => v6.split(']').next(),
+                None => authority.split(':').next(),
+            });
+        match (host, request_host(req)) {
+            (Some(origin_host), Some(host)) if origin_host == host => Ok(()),
+            _ => Err("csrf_origin_mismatch"),
+        }
+    }
+
+    fn cookie<'a>(&self, req: &'a RequestHeader) -> Option<&'a str> {
+        req.headers
+            .get_all(http::header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == self.cookie_name)
+            .map(|(_, value)| value)
+            .filter(|value| !value.is_empty())
+    }
+}
+
+fn is_safe(method: &http::Method) -> bool {
+    matches!(
+        *method,
+        http::Method::GET | http::Method::HEAD | http::Method::OPTIONS | http::Method::TRACE
+    )
+}
+
+/// `scheme://authority` of a URL, lowercased.
+fn origin_of(url: &str) -> Option<String> {
+    let (scheme, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    Some(format!("{}://{}", scheme, authority).to_ascii_lowercase())
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,151 @@
+use crate::configuration::{ConfigError, ServerOptionsConfig};
+use async_trait::async_trait;
+use nix::unistd::{Gid, Group, Uid, User};
+use pingora::server::{ListenFds, ShutdownWatch};
+use pingora::services::Service;
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+const BIND_POLL: Duration = Duration::from_millis(50);
+
+/// Detaches from the terminal, sending stdout and stderr to `error_log` if set. Only the
+/// calling thread survives the fork, so this must run before any other thread starts.
+pub fn daemonize(config: &ServerOptionsConfig) -> Result<(), String> {
+    let log = config
+        .error_log
+        .as_ref()
+        .map(|path| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("server.error_log {}: {}", path, e))
+        })
+        .transpose()?;
+    nix::unistd::daemon(true, false).map_err(|e| format!("daemonizing: {}", e))?;
+    if let Some(log) = log {
+        for fd in [1, 2] {
+            nix::unistd::dup2(log.as_raw_fd(), fd)
+                .map_err(|e| format!("server.error_log: {}", e))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_pid_file(path: &str) -> Result<(), String> {
+    std::fs::write(path, format!("{}\n", std::process::id()))
+        .map_err(|e| format!("server.pid_file {}: {}", path, e))
+}
+
+/// Switches to `server.user`/`server.group` once every listener has its socket, so ports
+/// below 1024 can be bound as root. Listeners accept as soon as they bind and the sockets are
+/// polled every `BIND_POLL`, so connections arriving in between are served as root until the
+/// switch. Files opened before the switch (access log, cache) stay usable; reopening them on
+/// rotation, or a listener handoff re-reading the config and TLS files, needs them readable
+/// or writable by the new user. The pid file is handed to it.
+pub struct PrivilegeDrop {
+    user: Option<User>,
+    gid: Option<Gid>,
+    addrs: Vec<String>,
+    pid_file: Option<String>,
+}
+
+impl PrivilegeDrop {
+    /// `None` when neither user nor group is configured.
+    pub fn new(
+        config: &ServerOptionsConfig,
+        addrs: Vec<String>,
+    ) -> Result<Option<Self>, ConfigError> {
+        let user = match &config.user {
+            Some(name) => Some(User::from_name(name).ok().flatten().ok_or_else(|| {
+                ConfigError::Validation(format!("server.user: unknown user '{}'", name))
+            })?),
+            None => None,
+        };
+        let group = match &config.group {
+            Some(name) => Some(
+                Group::from_name(name)
+                    .ok()
+                    .flatten()
+                    .ok_or_else(|| {
+                        ConfigError::Validation(format!("server.group: unknown group '{}'", name))
+                    })?
+                    .gid,
+            ),
+            None => None,
+        };
+        if user.is_none() && group.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            gid: group.or(user.as_ref().map(|u| u.gid)),
+            user,
+            addrs,
+            pid_file: config.pid_file.clone(),
+        }))
+    }
+
+    fn drop_privileges(&self) -> nix::Result<()> {
+        let gid = self.gid.expect("user or group is set");
+        // After a listener handoff from an already unprivileged process there is nothing to do
+        if self.user.as_ref().map_or(Uid::effective(), |u| u.uid) == Uid::effective()
+            && gid == Gid::effective()
+        {
+            return Ok(());
+        }
+        match &self.user {
+            Some(user) => {
+                let name = CString::new(user.name.as_str()).map_err(|_| nix::Error::EINVAL)?;
+                nix::unistd::initgroups(&name, gid)?;
+            }
+            None => nix::unistd::setgroups(&[gid])?,
+        }
+        if let Some(path) = &self.pid_file {
+            nix::unistd::chown(path.as_str(), self.user.as_ref().map(|u| u.uid), Some(gid))?;
+        }
+        nix::unistd::setgid(gid)?;
+        if let Some(user) = &self.user {
+            nix::unistd::setuid(user.uid)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Service for PrivilegeDrop {
+    async fn start_service(&mut self, fds: Option<ListenFds>, _shutdown: ShutdownWatch) {
+        if let Some(fds) = fds {
+            // Listeners add their socket to the table as they bind
+            loop {
+                let bound = fds.lock().await;
+                if self.addrs.iter().all(|addr| bound.get(addr).is_some()) {
+                    break;
+                }
+                drop(bound);
+                tokio::time::sleep(BIND_POLL).await;
+            }
+        }
+        match self.drop_privileges() {
+            Ok(()) => tracing::info!(
+                user = ?self.user.as_ref().map(|u| &u.name),
+                gid = %self.gid.expect("user or group is set"),
+                "listeners bound, dropped privileges"
+            ),
+            Err(e) => {
+                // Carrying on as root would defeat the point
+                tracing::error!("failed to drop privileges: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "privilege drop"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
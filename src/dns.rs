@@ -0,0 +1,322 @@
+use crate::balancer::{self, Upstreams};
+use crate::configuration::{GatewayConfig, UpstreamDnsConfig};
+use crate::metrics::Metrics;
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use pingora::lb::Backend;
+use std::collections::{BTreeSet, HashSet};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+/// Resolves upstream hostnames and keeps each answer for its record TTL, clamped to
+/// `upstream_dns`. Names the DNS servers don't answer, such as `/etc/hosts` entries or ones
+/// needing a search domain, fall back to the system resolver and are kept for
+/// `min_ttl_secs`. A failed lookup keeps serving the last addresses.
+pub struct UpstreamDns {
+    nameservers: Vec<SocketAddr>,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    cache: DashMap<String, Answer>,
+    metrics: Arc<Metrics>,
+}
+
+struct Answer {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+impl UpstreamDns {
+    pub fn new(config: &UpstreamDnsConfig, metrics: Arc<Metrics>) -> Self {
+        let nameservers = if config.nameservers.is_empty() {
+            system_nameservers()
+        } else {
+            config
+                .nameservers
+                .iter()
+                .filter_map(|s| s.parse().ok())
+                .collect()
+        };
+        Self {
+            nameservers,
+            min_ttl: Duration::from_secs(config.min_ttl_secs),
+            max_ttl: Duration::from_secs(config.max_ttl_secs),
+            cache: DashMap::new(),
+            metrics,
+        }
+    }
+
+    /// Resolves `upstreams` (`host:port`) to backends, from the cache while answers are fresh.
+    pub fn resolve(&self, upstreams: &[String]) -> io::Result<BTreeSet<Backend>> {
+        let mut backends = BTreeSet::new();
+        for upstream in upstreams {
+            let (host, port) = split_host_port(upstream)?;
+            let addrs = match host.parse::<IpAddr>() {
+                Ok(ip) => vec![ip],
+                Err(_) => self.lookup(host)?,
+            };
+            backends.extend(addrs.into_iter().map(|ip| Backend {
+                addr: pingora::protocols::l4::socket::SocketAddr::Inet(SocketAddr::new(ip, port)),
+                weight: 1,
+            }));
+        }
+        Ok(backends)
+    }
+
+    /// Re-resolves the configured upstreams as their answers expire, for the life of the
+    /// process. Changed addresses replace the pool, and dropped ones drain as on reload.
+    pub fn spawn_refresh(
+        self: Arc<Self>,
+        config: Arc<ArcSwap<GatewayConfig>>,
+        upstreams: Arc<Upstreams>,
+    ) {
+        std::thread::spawn(move || loop {
+            let next = self.cache.iter().map(|e| e.expires).min();
+            let wait = next.map_or(self.max_ttl, |at| {
+                at.saturating_duration_since(Instant::now())
+            });
+            std::thread::sleep(wait.max(Duration::from_secs(1)));
+
+            let config = config.load_full();
+            let configured = balancer::configured(&config);
+            // Hosts a reload dropped are no longer looked up
+            let hosts: HashSet<&str> = configured
+                .iter()
+                .filter_map(|u| split_host_port(u).ok())
+                .map(|(host, _)| host)
+                .collect();
+            self.cache.retain(|host, _| hosts.contains(host.as_str()));
+            let backends = match self.resolve(&configured) {
+                Ok(backends) => backends,
+                Err(e) => {
+                    tracing::warn!(error = %e, "cannot re-resolve upstreams");
+                    continue;
+                }
+            };
+            if backends == *upstreams.backends() {
+                continue;
+            }
+            tracing::info!(upstreams = backends.len(), "upstream addresses changed");
+            for backend in upstreams.set(backends, Duration::from_secs(config.upstream_drain_secs))
+            {
+                tracing::info!(upstream = %backend.addr, drain_secs = config.upstream_drain_secs, "upstream address gone, draining");
+            }
+        });
+    }
+
+    fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let now = Instant::now();
+        if let Some(answer) = self.cache.get(host).filter(|a| a.expires > now) {
+            return Ok(answer.addrs.clone());
+        }
+        // The system resolver reads /etc/hosts before asking DNS; so do we
+        let from_dns = if listed_in_hosts_file(host) {
+            Err(io::Error::new(ErrorKind::NotFound, "listed in /etc/hosts"))
+        } else {
+            self.query(host)
+        };
+        let (result, answer) = match from_dns {
+            Ok((addrs, ttl)) if !addrs.is_empty() => {
+                ("ok", Ok((addrs, ttl.clamp(self.min_ttl, self.max_ttl))))
+            }
+            _ => match (host, 0).to_socket_addrs() {
+                Ok(addrs) => {
+                    let addrs: Vec<IpAddr> = addrs.map(|a| a.ip()).collect();
+                    if addrs.is_empty() {
+                        (
+                            "failed",
+                            Err(io::Error::new(ErrorKind::NotFound, "no addresses")),
+                        )
+                    } else {
+                        ("system", Ok((addrs, self.min_ttl)))
+                    }
+                }
+                Err(e) => ("failed", Err(e)),
+            },
+        };
+        self.metrics.record_upstream_dns_lookup(host, result);
+        match answer {
+            Ok((addrs, ttl)) => {
+                self.cache.insert(
+                    host.to_string(),
+                    Answer {
+                        addrs: addrs.clone(),
+                        expires: now + ttl,
+                    },
+                );
+                Ok(addrs)
+            }
+            Err(e) => match self.cache.get_mut(host) {
+                Some(mut answer) => {
+                    tracing::warn!(host, error = %e, "upstream lookup failed, keeping the last addresses");
+                    answer.expires = now + self.min_ttl;
+                    Ok(answer.addrs.clone())
+                }
+                None => Err(io::Error::new(e.kind(), format!("{}: {}", host, e))),
+            },
+        }
+    }
+
+    /// A and AAAA records from the first server that answers, with the lowest TTL among them.
+    fn query(&self, host: &str) -> io::Result<(Vec<IpAddr>, Duration)> {
+        let mut last_err = io::Error::new(ErrorKind::NotFound, "no nameservers");
+        for server in &self.nameservers {
+            let answer = ask(*server, host, TYPE_A).and_then(|(mut addrs, ttl)| {
+                let (v6, ttl6) = ask(*server, host, TYPE_AAAA)?;
+                addrs.extend(v6);
+                Ok((addrs, ttl.min(ttl6)))
+            });
+            match answer {
+                Ok((addrs, ttl)) => return Ok((addrs, Duration::from_secs(ttl.into()))),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+}
+
+fn system_nameservers() -> Vec<SocketAddr> {
+    std::fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect()
+}
+
+fn listed_in_hosts_file(host: &str) -> bool {
+    std::fs::read_to_string("/etc/hosts")
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .any(|line| {
+            line.split_whitespace()
+                .skip(1)
+                .any(|name| name.eq_ignore_ascii_case(host))
+        })
+}
+
+/// `host:port` or `[v6]:port`
+fn split_host_port(upstream: &str) -> io::Result<(&str, u16)> {
+    upstream
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host.trim_matches(['[', ']']), port.parse().ok()?)))
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{}: expected host:port", upstream),
+            )
+        })
+}
+
+/// One query over UDP. Returns the addresses of type `qtype` and the lowest TTL in the
+/// answer section; a name without records gives no addresses.
+fn ask(server: SocketAddr, host: &str, qtype: u16) -> io::Result<(Vec<IpAddr>, u32)> {
+    let id: u16 = rand::random();
+    let mut query = Vec::with_capacity(host.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid hostname {}", host),
+            ));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+
+    let bind: SocketAddr = if server.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    socket.send(&query)?;
+    let mut buf = [0u8; 1500];
+    loop {
+        let len = socket.recv(&mut buf)?;
+        let response = &buf[..len];
+        // Stray or late answers to other queries
+        if len < 12 || response[..2] != id.to_be_bytes() || response[2] & 0x80 == 0 {
+            continue;
+        }
+        return parse_response(response, qtype)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed DNS response"))?;
+    }
+}
+
+fn parse_response(msg: &[u8], qtype: u16) -> Option<io::Result<(Vec<IpAddr>, u32)>> {
+    let u16_at = |pos: usize| Some(u16::from_be_bytes(msg.get(pos..pos + 2)?.try_into().ok()?));
+    let flags = u16_at(2)?;
+    if flags & 0x0200 != 0 {
+        return Some(Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "DNS response truncated",
+        )));
+    }
+    match flags & 0x000f {
+        0 => {}
+        // NXDOMAIN
+        3 => return Some(Ok((Vec::new(), u32::MAX))),
+        rcode => {
+            return Some(Err(io::Error::other(format!(
+                "DNS server answered rcode {}",
+                rcode
+            ))))
+        }
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+    let mut addrs = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        pos = skip_name(msg, pos)?;
+        let rtype = u16_at(pos)?;
+        let record_ttl = u32::from_be_bytes(msg.get(pos + 4..pos + 8)?.try_into().ok()?);
+        let rdlen = u16_at(pos + 8)? as usize;
+        let rdata = msg.get(pos + 10..pos + 10 + rdlen)?;
+        pos += 10 + rdlen;
+        // CNAMEs on the way count towards the TTL too
+        ttl = ttl.min(record_ttl);
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) if rtype == qtype => {
+                addrs.push(IpAddr::from(<[u8; 4]>::try_from(rdata).ok()?))
+            }
+            (TYPE_AAAA, 16) if rtype == qtype => {
+                addrs.push(IpAddr::from(<[u8; 16]>::try_from(rdata).ok()?))
+            }
+            _ => {}
+        }
+    }
+    Some(Ok((addrs, ttl)))
+}
+
+/// Position after the name at `pos`, which may end in a compression pointer.
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l => pos += 1 + l,
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use crate::configuration::DownstreamConfig;
+use crate::metrics::Metrics;
+use dashmap::DashMap;
+use pingora::protocols::SocketDigest;
+use pingora::proxy::Session;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// Per-connection keepalive and concurrency limits for client connections.
+///
+/// Connections are identified by their socket digest, which pingora shares across every request
+/// (HTTP/1.1) or stream (HTTP/2) on the same connection. Entries are keyed by the digest's
+/// address and hold a weak reference to it, which keeps the address from being reused by a new
+/// connection until the entry is swept. The sweep also counts the open connections for the
+/// `active_connections` gauges, so a connection is counted from its first request.
+///
+/// pingora 0.3 doesn't let a proxy service supply its own HTTP/2 settings, so the stream limit
+/// is enforced here by refusing excess streams with a 503 rather than advertised to the client.
+pub struct DownstreamLimits {
+    keepalive_timeout_secs: Option<u64>,
+    max_requests_per_connection: Option<usize>,
+    h2_max_concurrent_streams: Option<usize>,
+    max_request_body_bytes: Option<u64>,
+    upload_bytes_per_sec: Option<u64>,
+    connections: DashMap<usize, Connection>,
+}
+
+struct Connection {
+    socket: Weak<SocketDigest>,
+    requests: AtomicUsize,
+    active_streams: AtomicUsize,
+    /// Time at which the connection's upload budget is next free
+    upload_free_at: Mutex<Instant>,
+    /// Route of the latest request
+    route: Mutex<Option<String>>,
+}
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+impl DownstreamLimits {
+    pub fn new(config: &DownstreamConfig) -> Self {
+        Self {
+            keepalive_timeout_secs: config.keepalive_timeout_secs,
+            max_requests_per_connection: config.max_requests_per_connection,
+            h2_max_concurrent_streams: config.h2_max_concurrent_streams,
+            max_request_body_bytes: config.max_request_body_kb.map(|kb| kb * 1024),
+            upload_bytes_per_sec: config.max_upload_kb_per_sec.map(|kb| kb * 1024),
+            connections: DashMap::new(),
+        }
+    }
+
+    /// Called once per request. Returns `Err(status)` if the request must be refused; `Ok(true)`
+    /// if it was counted and `on_done` has to be called when it finishes.
+    pub fn on_request(&self, session: &mut Session) -> Result<bool, u16> {
+        let is_h2 = session.is_http2();
+        if !is_h2 {
+            if let Some(secs) = self.keepalive_timeout_secs {
+                // 0 turns keepalive off: every response carries `Connection: close`
+                session.set_keepalive((secs > 0).then_some(secs));
+            }
+        }
+
+        let Some(socket) = socket_digest(session) else {
+            return Ok(false);
+        };
+
+        let key = Arc::as_ptr(&socket) as usize;
+        let conn = self
+            .connections
+            .entry(key)
+            .or_insert_with(|| Connection::new(&socket));
+
+        let requests = conn.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        let active = conn.active_streams.fetch_add(1, Ordering::Relaxed) + 1;
+        drop(conn);
+
+        if is_h2 {
+            if self
+                .h2_max_concurrent_streams
+                .is_some_and(|max| active > max)
+            {
+                self.release(key);
+                return Err(503);
+            }
+        } else if self
+            .max_requests_per_connection
+            .is_some_and(|max| requests >= max)
+        {
+            session.set_keepalive(None);
+        }
+        Ok(true)
+    }
+
+    /// Refuses a request whose body is, or has grown, too large. `received` counts the body
+    /// bytes read so far; the declared Content-Length is checked up front.
+    pub fn check_body_size(&self, session: &Session, received: u64) -> Result<(), u16> {
+        let Some(max) = self.max_request_body_bytes else {
+            return Ok(());
+        };
+        let declared = session
+            .req_header()
+            .headers
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0);
+        if declared.max(received) > max {
+            return Err(413);
+        }
+        Ok(())
+    }
+
+    /// How long to hold a body chunk of `len` bytes back so the connection stays within its
+    /// upload rate. The first chunk after an idle spell passes straight through.
+    pub fn upload_delay(&self, session: &Session, len: usize) -> Option<Duration> {
+        let rate = self.upload_bytes_per_sec?;
+        let socket = socket_digest(session)?;
+        let conn = self.connections.get(&(Arc::as_ptr(&socket) as usize))?;
+        let now = Instant::now();
+        let mut free_at = conn
+            .upload_free_at
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let start = (*free_at).max(now);
+        *free_at = start + Duration::from_secs_f64(len as f64 / rate as f64);
+        let delay = start - now;
+        (!delay.is_zero()).then_some(delay)
+    }
+
+    pub fn set_route(&self, session: &Session, route: &str) {
+        let Some(socket) = socket_digest(session) else {
+            return;
+        };
+        if let Some(conn) = self.connections.get(&(Arc::as_ptr(&socket) as usize)) {
+            *conn.route.lock().unwrap_or_else(|e| e.into_inner()) = Some(route.to_string());
+        }
+    }
+
+    pub fn on_done(&self, session: &Session) {
+        if let Some(socket) = socket_digest(session) {
+            self.release(Arc::as_ptr(&socket) as usize);
+        }
+    }
+
+    fn release(&self, key: usize) {
+        if let Some(conn) = self.connections.get(&key) {
+            let _ = conn
+                .active_streams
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+        }
+    }
+
+    /// Drops entries for closed connections every second and exports the count of open ones,
+    /// for the life of the process.
+    pub fn spawn_sweep(self: Arc<Self>, metrics: Arc<Metrics>) {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(SWEEP_INTERVAL);
+            self.connections.retain(|_, c| c.socket.strong_count() > 0);
+            let mut by_route: HashMap<String, i64> = HashMap::new();
+            for conn in self.connections.iter() {
+                if let Some(route) = conn
+                    .route
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .as_ref()
+                {
+                    *by_route.entry(route.clone()).or_default() += 1;
+                }
+            }
+            metrics.set_active_connections(self.connections.len() as i64, &by_route);
+        });
+    }
+}
+
+impl Connection {
+    fn new(socket: &Arc<SocketDigest>) -> Self {
+        Self {
+            socket: Arc::downgrade(socket),
+            requests: AtomicUsize::new(0),
+            active_streams: AtomicUsize::new(0),
+            upload_free_at: Mutex::new(Instant::now()),
+            route: Mutex::new(None),
+        }
+    }
+}
+
+fn socket_digest(session: &Session) -> Option<Arc<SocketDigest>> {
+    session.digest()?.socket_digest.clone()
+}
@@ -0,0 +1,246 @@
+use crate::configuration::{EgressProtocol, EgressProxyConfig};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use pingora::apps::ServerApp;
+use pingora::connectors::TransportConnector;
+use pingora::lb::health_check::HealthCheck;
+use pingora::lb::Backend;
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::protocols::Stream;
+use pingora::server::ShutdownWatch;
+use pingora::upstreams::peer::{HttpPeer, Proxy};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upstream connections tunnelled through an egress proxy.
+///
+/// pingora 0.3 can establish upstream connections through CONNECT, but only to a proxy it
+/// reaches over a Unix socket. [`EgressBridge`] listens on that socket: for an HTTP proxy it
+/// pipes every connection through, so pingora's CONNECT handshake runs against the real proxy
+/// unchanged; for SOCKS5 it answers the CONNECT itself after dialing through the proxy.
+pub struct Egress {
+    socket_path: PathBuf,
+    headers: BTreeMap<String, Vec<u8>>,
+}
+
+impl Egress {
+    pub fn new(config: &EgressProxyConfig) -> Self {
+        let mut headers = BTreeMap::new();
+        if let (EgressProtocol::Http, Some(username)) = (config.protocol, &config.username) {
+            let password = config.password.as_deref().unwrap_or("");
+            let credentials = STANDARD.encode(format!("{}:{}", username, password));
+            headers.insert(
+                "Proxy-Authorization".to_string(),
+                format!("Basic {}", credentials).into_bytes(),
+            );
+        }
+        Self {
+            socket_path: PathBuf::from(&config.socket_path),
+            headers,
+        }
+    }
+
+    /// Tunnel settings for a connection to `addr`; `None` for Unix socket backends.
+    pub fn proxy(&self, addr: &SocketAddr) -> Option<Proxy> {
+        let SocketAddr::Inet(addr) = addr else {
+            return None;
+        };
+        Some(Proxy {
+            next_hop: self.socket_path.clone().into_boxed_path(),
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            headers: self.headers.clone(),
+        })
+    }
+}
+
+/// Unix socket listener that relays each connection to the egress proxy.
+pub struct EgressBridge {
+    proxy_addr: String,
+    protocol: EgressProtocol,
+    credentials: Option<(String, String)>,
+}
+
+impl EgressBridge {
+    pub fn new(config: &EgressProxyConfig) -> Self {
+        Self {
+            proxy_addr: config.addr.clone(),
+            protocol: config.protocol,
+            credentials: config
+                .username
+                .as_ref()
+                .map(|u| (u.clone(), config.password.clone().unwrap_or_default())),
+        }
+    }
+
+    /// Reads pingora's CONNECT request and opens the same tunnel through the SOCKS5 proxy.
+    async fn socks5_tunnel(&self, session: &mut Stream, proxy: &mut TcpStream) -> io::Result<()> {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() > 8192 || session.read_buf(&mut head).await? == 0 {
+                return Err(invalid("incomplete CONNECT request"));
+            }
+        }
+        let request = String::from_utf8_lossy(&head);
+        let target = request
+            .split_whitespace()
+            .nth(1)
+            .and_then(|t| t.rsplit_once(':'))
+            .ok_or_else(|| invalid("malformed CONNECT request"))?;
+        let host = target.0.trim_start_matches('[').trim_end_matches(']');
+        let port: u16 = target.1.parse().map_err(|_| invalid("bad CONNECT port"))?;
+
+        match self.socks5_connect(proxy, host, port).await {
+            Ok(()) => {
+                session
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await?;
+                session.flush().await
+            }
+            Err(e) => {
+                let _ = session
+                    .write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")
+                    .await;
+                let _ = session.flush().await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Client side of RFC 1928 `CONNECT`, with RFC 1929 username/password auth if configured.
+    async fn socks5_connect(&self, proxy: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let method = if self.credentials.is_some() {
+            0x02
+        } else {
+            0x00
+        };
+        proxy.write_all(&[0x05, 0x01, method]).await?;
+        let mut reply = [0u8; 2];
+        proxy.read_exact(&mut reply).await?;
+        if reply != [0x05, method] {
+            return Err(invalid("socks5 proxy refused the auth method"));
+        }
+        if let Some((username, password)) = &self.credentials {
+            let mut auth = vec![0x01, username.len() as u8];
+            auth.extend_from_slice(username.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            proxy.write_all(&auth).await?;
+            proxy.read_exact(&mut reply).await?;
+            if reply[1] != 0x00 {
+                return Err(invalid("socks5 authentication failed"));
+            }
+        }
+
+        let mut request = vec![0x05, 0x01, 0x00];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                request.extend_from_slice(&[0x03, host.len() as u8]);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        proxy.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        proxy.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(invalid(&format!(
+                "socks5 connect failed with code {}",
+                reply[1]
+            )));
+        }
+        // Skip the bound address the proxy reports
+        let addr_len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => proxy.read_u8().await? as usize,
+            _ => return Err(invalid("socks5 reply with unknown address type")),
+        };
+        let mut bound = vec![0u8; addr_len + 2];
+        proxy.read_exact(&mut bound).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ServerApp for EgressBridge {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut session: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let connect = TcpStream::connect(&self.proxy_addr);
+        let mut proxy = match tokio::time::timeout(CONNECT_TIMEOUT, connect).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                tracing::error!(addr = %self.proxy_addr, error = %e, "egress proxy connect failed");
+                return None;
+            }
+            Err(_) => {
+                tracing::error!(addr = %self.proxy_addr, "egress proxy connect timed out");
+                return None;
+            }
+        };
+        if self.protocol == EgressProtocol::Socks5 {
+            if let Err(e) = self.socks5_tunnel(&mut session, &mut proxy).await {
+                tracing::error!(addr = %self.proxy_addr, error = %e, "socks5 tunnel failed");
+                return None;
+            }
+        }
+        if let Err(e) = tokio::io::copy_bidirectional(&mut session, &mut proxy).await {
+            tracing::debug!(error = %e, "egress tunnel closed");
+        }
+        None
+    }
+}
+
+/// TCP health check that goes through the egress proxy, since backends aren't reachable
+/// directly.
+pub struct EgressHealthCheck {
+    egress: Arc<Egress>,
+    connector: TransportConnector,
+}
+
+impl EgressHealthCheck {
+    pub fn new(egress: Arc<Egress>) -> Self {
+        Self {
+            egress,
+            connector: TransportConnector::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for EgressHealthCheck {
+    async fn check(&self, target: &Backend) -> pingora::Result<()> {
+        let mut peer = HttpPeer::new(target.addr.clone(), false, String::new());
+        peer.proxy = self.egress.proxy(&target.addr);
+        self.connector.new_stream(&peer).await.map(|_| {})
+    }
+
+    fn health_threshold(&self, _success: bool) -> usize {
+        1
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
@@ -0,0 +1,53 @@
+use crate::configuration::ErrorResponsesConfig;
+use serde_json::Value;
+
+/// JSON bodies for requests the proxy refuses itself, filled in from the `error_responses`
+/// template.
+pub struct ErrorResponses {
+    /// `None` when refused requests get an empty error page
+    template: Option<Value>,
+}
+
+impl ErrorResponses {
+    pub fn new(config: &ErrorResponsesConfig) -> Self {
+        Self {
+            template: config.json.then(|| config.template.clone()),
+        }
+    }
+
+    /// Body for a request refused with `reason`; `None` when JSON errors are off.
+    pub fn body(&self, status: u16, reason: &str, request_id: &str) -> Option<Value> {
+        let template = self.template.as_ref()?;
+        Some(fill(template, status, reason, request_id))
+    }
+
+    /// Tags a stage's own error body with the request ID.
+    pub fn annotate(&self, mut response: Value, request_id: &str) -> Value {
+        if self.template.is_some() {
+            if let Some(fields) = response.as_object_mut() {
+                fields.insert("request_id".into(), request_id.into());
+            }
+        }
+        response
+    }
+}
+
+fn fill(template: &Value, status: u16, reason: &str, request_id: &str) -> Value {
+    match template {
+        Value::String(s) if s == "{status}" => status.into(),
+        Value::String(s) => s
+            .replace("{error}", reason)
+            .replace("{status}", &status.to_string())
+            .replace("{request_id}", request_id)
+            .into(),
+        Value::Array(items) => items
+            .iter()
+            .map(|v| fill(v, status, reason, request_id))
+            .collect(),
+        Value::Object(fields) => fields
+            .iter()
+            .map(|(k, v)| (k.clone(), fill(v, status, reason, request_id)))
+            .collect(),
+        other => other.clone(),
+    }
+}
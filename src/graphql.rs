@@ -0,0 +1,600 @@
+use crate::configuration::GraphQlConfig;
+use crate::middleware::{media_type, Held, HeldBody, Rejection};
+use crate::openapi::parse_query;
+use bytes::Bytes;
+use pingora::http::RequestHeader;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Nesting the parser and fragment expansion follow before giving up, whatever `max_depth` is,
+/// so hostile documents can't exhaust the stack.
+const MAX_NESTING: usize = 256;
+
+/// Limits on GraphQL operations, checked by parsing the query at the edge. Queries come from
+/// `?query=` on GET, or a POSTed `application/json` body (batches included) or
+/// `application/graphql` body.
+///
+/// Every field costs 1 toward complexity. Fragments are expanded where they're spread, with
+/// each fragment measured once, so fragment bombs are counted at full size without being
+/// walked at full size.
+pub struct GraphQlLimits {
+    max_depth: usize,
+    max_complexity: u64,
+    max_aliases: u64,
+    block_introspection: bool,
+    max_body_bytes: usize,
+}
+
+/// A POSTed query held back until it can be parsed whole.
+pub struct BodyCheck {
+    json: bool,
+    held: HeldBody,
+}
+
+impl GraphQlLimits {
+    pub fn new(config: &GraphQlConfig) -> Self {
+        Self {
+            max_depth: config.max_depth,
+            max_complexity: config.max_complexity,
+            max_aliases: config.max_aliases,
+            block_introspection: config.block_introspection,
+            max_body_bytes: config.max_body_kb * 1024,
+        }
+    }
+
+    /// Checks a GET's query string, or returns a `BodyCheck` for a POST.
+    pub fn check(&self, req: &RequestHeader) -> Result<Option<BodyCheck>, Rejection> {
+        if req.method == http::Method::GET || req.method == http::Method::HEAD {
+            let mut queries = parse_query(req.uri.query().unwrap_or_default())
+                .into_iter()
+                .filter(|(name, _)| name == "query")
+                .map(|(_, value)| value);
+            let query = queries.next();
+            // The origin may read a different one than we checked
+            if queries.next().is_some() {
+                let detail = "more than one query parameter".to_string();
+                return Err(Rejection::new(400, "graphql_malformed", detail));
+            }
+            return match query {
+                Some(query) => self.check_query(&query).map(|()| None),
+                None => Ok(None),
+            };
+        }
+        let content_type = media_type(req).unwrap_or_default();
+        let json = match content_type.as_str() {
+            "application/json" => true,
+            "application/graphql" => false,
+            _ => {
+                return Err(Rejection::new(
+                    415,
+                    "graphql_unsupported_media_type",
+                    content_type,
+                ))
+            }
+        };
+        Ok(Some(BodyCheck {
+            json,
+            held: HeldBody::default(),
+        }))
+    }
+
+    /// Parses the query once the whole body is in.
+    pub fn check_body(
+        &self,
+        check: &mut BodyCheck,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        monitor: bool,
+    ) -> Result<(), Rejection> {
+        let result = match check.held.hold(body, end_of_stream, self.max_body_bytes) {
+            Held::Pending => return Ok(()),
+            Held::TooLarge => Err(Rejection::new(
+                413,
+                "graphql_body_too_large",
+                format!("body exceeds {} bytes", self.max_body_bytes),
+            )),
+            Held::Complete(bytes) if check.json => self.check_json(bytes),
+            Held::Complete(bytes) => self.check_query(&String::from_utf8_lossy(bytes)),
+        };
+        check.held.settle(body, result, monitor)
+    }
+
+    fn check_json(&self, body: &[u8]) -> Result<(), Rejection> {
+        let malformed = |detail: String| Rejection::new(400, "graphql_malformed", detail);
+        let value: Value = serde_json::from_slice(body).map_err(|e| malformed(e.to_string()))?;
+        let requests = match &value {
+            Value::Array(batch) => batch.iter().collect(),
+            _ => vec![&value],
+        };
+        for request in requests {
+            // Persisted-query requests may carry only a hash; the upstream resolves those
+            if let Some(query) = request.get("query").and_then(Value::as_str) {
+                self.check_query(query)?;
+            } else if !request.is_object() {
+                return Err(malformed("expected a request object".into()));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_query(&self, query: &str) -> Result<(), Rejection> {
+        let document = Parser::new(query)
+            .document()
+            .map_err(|e| Rejection::new(400, "graphql_malformed", e))?;
+        let mut measured = HashMap::new();
+        for operation in &document.operations {
+            let cost = measure(operation, &document.fragments, &mut measured, 0)
+                .map_err(|e| Rejection::new(400, "graphql_malformed", e))?;
+            if cost.depth > self.max_depth {
+                let detail = format!("depth {} exceeds {}", cost.depth, self.max_depth);
+                return Err(Rejection::new(400, "graphql_too_deep", detail));
+            }
+            if cost.fields > self.max_complexity {
+                let detail = format!("complexity {} exceeds {}", cost.fields, self.max_complexity);
+                return Err(Rejection::new(400, "graphql_too_complex", detail));
+            }
+            if cost.aliases > self.max_aliases {
+                let detail = format!("{} aliases exceed {}", cost.aliases, self.max_aliases);
+                return Err(Rejection::new(400, "graphql_too_many_aliases", detail));
+            }
+            if self.block_introspection && cost.introspection {
+                return Err(Rejection::new(403, "graphql_introspection", String::new()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the checks need from a parsed document; field arguments and variables are skipped.
+struct Document {
+    operations: Vec<SelectionSet>,
+    fragments: HashMap<String, SelectionSet>,
+}
+
+#[derive(Default)]
+struct SelectionSet {
+    fields: Vec<Field>,
+    /// Named fragments spread directly in this set
+    spreads: Vec<String>,
+    /// Inline fragments, merged into this set's level
+    inline: Vec<SelectionSet>,
+}
+
+struct Field {
+    name: String,
+    aliased: bool,
+    selections: Option<SelectionSet>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Cost {
+    depth: usize,
+    fields: u64,
+    aliases: u64,
+    introspection: bool,
+}
+
+impl Cost {
+    fn add(&mut self, other: Cost) {
+        self.depth = self.depth.max(other.depth);
+        self.fields = self.fields.saturating_add(other.fields);
+        self.aliases = self.aliases.saturating_add(other.aliases);
+        self.introspection |= other.introspection;
+    }
+}
+
+/// Cost of a selection set, with `depth` counting its own level.
+fn measure(
+    set: &SelectionSet,
+    fragments: &HashMap<String, SelectionSet>,
+    measured: &mut HashMap<String, Option<Cost>>,
+    nesting: usize,
+) -> Result<Cost, String> {
+    if nesting > MAX_NESTING {
+        return Err("document nests too deeply".into());
+    }
+    let mut cost = Cost {
+        depth: 1,
+        ..Cost::default()
+    };
+    for field in &set.fields {
+        let mut field_cost = Cost {
+            depth: 1,
+            fields: 1,
+            aliases: u64::from(field.aliased),
+            introspection: field.name == "__schema" || field.name == "__type",
+        };
+        if let Some(selections) = &field.selections {
+            let mut inner = measure(selections, fragments, measured, nesting + 1)?;
+            inner.depth += 1;
+            field_cost.add(inner);
+        }
+        cost.add(field_cost);
+    }
+    for inline in &set.inline {
+        cost.add(measure(inline, fragments, measured, nesting + 1)?);
+    }
+    for name in &set.spreads {
+        let fragment = match measured.get(name) {
+            Some(Some(fragment)) => *fragment,
+            Some(None) => return Err(format!("fragment '{}' spreads itself", name)),
+            None => {
+                let set = fragments
+                    .get(name)
+                    .ok_or_else(|| format!("unknown fragment '{}'", name))?;
+                // Marks the fragment in progress to catch cycles
+                measured.insert(name.clone(), None);
+                let fragment = measure(set, fragments, measured, nesting + 1)?;
+                measured.insert(name.clone(), Some(fragment));
+                fragment
+            }
+        };
+        cost.add(fragment);
+    }
+    Ok(cost)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token<'a> {
+    Punct(char),
+    Spread,
+    Name(&'a str),
+    /// Numbers and strings; only ever skipped
+    Value,
+}
+
+/// Recursive-descent parser for GraphQL executable documents (October 2021 spec), keeping
+/// only the selection structure.
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    peeked: Option<Token<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            input,
+            pos: 0,
+            peeked: None,
+        }
+    }
+
+    fn document(mut self) -> Result<Document, String> {
+        let mut document = Document {
+            operations: Vec::new(),
+            fragments: HashMap::new(),
+        };
+        while let Some(token) = self.peek()? {
+            match token {
+                Token::Punct('{') => {
+                    let set = self.selection_set(0)?;
+                    document.operations.push(set);
+                }
+                Token::Name("query" | "mutation" | "subscription") => {
+                    self.next()?;
+                    if let Some(Token::Name(_)) = self.peek()? {
+                        self.next()?;
+                    }
+                    if self.peek()? == Some(Token::Punct('(')) {
+                        self.skip_group('(', ')')?;
+                    }
+                    self.directives()?;
+                    let set = self.selection_set(0)?;
+                    document.operations.push(set);
+                }
+                Token::Name("fragment") => {
+                    self.next()?;
+                    let name = self.name()?.to_string();
+                    if self.name()? != "on" {
+                        return Err(format!("fragment '{}' lacks a type condition", name));
+                    }
+                    self.name()?;
+                    self.directives()?;
+                    let set = self.selection_set(0)?;
+                    document.fragments.insert(name, set);
+                }
+                token => return Err(format!("unexpected {:?}", token)),
+            }
+        }
+        if document.operations.is_empty() {
+            return Err("no operation".into());
+        }
+        Ok(document)
+    }
+
+    fn selection_set(&mut self, nesting: usize) -> Result<SelectionSet, String> {
+        if nesting > MAX_NESTING {
+            return Err("document nests too deeply".into());
+        }
+        self.expect('{')?;
+        let mut set = SelectionSet::default();
+        loop {
+            match self.peek()? {
+                Some(Token::Punct('}')) => {
+                    self.next()?;
+                    return Ok(set);
+                }
+                Some(Token::Spread) => {
+                    self.next()?;
+                    match self.peek()? {
+                        Some(Token::Name(name)) if name != "on" => {
+                            self.next()?;
+                            set.spreads.push(name.to_string());
+                            self.directives()?;
+                        }
+                        _ => {
+                            if self.peek()? == Some(Token::Name("on")) {
+                                self.next()?;
+                                self.name()?;
+                            }
+                            self.directives()?;
+                            set.inline.push(self.selection_set(nesting + 1)?);
+                        }
+                    }
+                }
+                Some(Token::Name(_)) => {
+                    let mut name = self.name()?;
+                    let mut aliased = false;
+                    if self.peek()? == Some(Token::Punct(':')) {
+                        self.next()?;
+                        name = self.name()?;
+                        aliased = true;
+                    }
+                    if self.peek()? == Some(Token::Punct('(')) {
+                        self.skip_group('(', ')')?;
+                    }
+                    self.directives()?;
+                    let selections = match self.peek()? {
+                        Some(Token::Punct('{')) => Some(self.selection_set(nesting + 1)?),
+                        _ => None,
+                    };
+                    set.fields.push(Field {
+                        name: name.to_string(),
+                        aliased,
+                        selections,
+                    });
+                }
+                Some(token) => return Err(format!("unexpected {:?} in selection set", token)),
+                None => return Err("unterminated selection set".into()),
+            }
+        }
+    }
+
+    fn directives(&mut self) -> Result<(), String> {
+        while self.peek()? == Some(Token::Punct('@')) {
+            self.next()?;
+            self.name()?;
+            if self.peek()? == Some(Token::Punct('(')) {
+                self.skip_group('(', ')')?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Skips a bracketed group such as arguments or variable definitions. Values nest only
+    /// `[]` and `{}`, which can't unbalance the parentheses.
+    fn skip_group(&mut self, open: char, close: char) -> Result<(), String> {
+        self.expect(open)?;
+        let mut depth = 1usize;
+        while depth > 0 {
+            match self.next()? {
+                Some(Token::Punct(c)) if c == open => depth += 1,
+                Some(Token::Punct(c)) if c == close => depth -= 1,
+                Some(_) => {}
+                None => return Err(format!("unclosed '{}'", open)),
+            }
+        }
+        Ok(())
+    }
+
+    fn expect(&mut self, punct: char) -> Result<(), String> {
+        match self.next()? {
+            Some(Token::Punct(c)) if c == punct => Ok(()),
+            token => Err(format!("expected '{}', found {:?}", punct, token)),
+        }
+    }
+
+    fn name(&mut self) -> Result<&'a str, String> {
+        match self.next()? {
+            Some(Token::Name(name)) => Ok(name),
+            token => Err(format!("expected a name, found {:?}", token)),
+        }
+    }
+
+    fn peek(&mut self) -> Result<Option<Token<'a>>, String> {
+        if self.peeked.is_none() {
+            self.peeked = self.lex()?;
+        }
+        Ok(self.peeked.clone())
+    }
+
+    fn next(&mut self) -> Result<Option<Token<'a>>, String> {
+        match self.peeked.take() {
+            Some(token) => Ok(Some(token)),
+            None => self.lex(),
+        }
+    }
+
+    fn lex(&mut self) -> Result<Option<Token<'a>>, String> {
+        let bytes = self.input.as_bytes();
+        // Whitespace, commas, byte order marks and comments are insignificant
+        loop {
+            match bytes.get(self.pos) {
+                Some(b' ' | b'\t' | b'\n' | b'\r' | b',') => self.pos += 1,
+                Some(0xEF) if bytes[self.pos..].starts_with("\u{feff}".as_bytes()) => self.pos += 3,
+                Some(b'#') => {
+                    while bytes
+                        .get(self.pos)
+                        .is_some_and(|b| *b != b'\n' && *b != b'\r')
+                    {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+        let start = self.pos;
+        let Some(&byte) = bytes.get(start) else {
+            return Ok(None);
+        };
+        let token = match byte {
+            b'.' if bytes[start..].starts_with(b"...") => {
+                self.pos += 3;
+                Token::Spread
+            }
+            b'!' | b'$' | b'&' | b'(' | b')' | b':' | b'=' | b'@' | b'[' | b']' | b'{' | b'}'
+            | b'|' => {
+                self.pos += 1;
+                Token::Punct(byte as char)
+            }
+            b'_' | b'a'..=b'z' | b'A'..=b'Z' => {
+                while bytes
+                    .get(self.pos)
+                    .is_some_and(|b| b.is_ascii_alphanumeric() || *b == b'_')
+                {
+                    self.pos += 1;
+                }
+                Token::Name(&self.input[start..self.pos])
+            }
+            b'-' | b'0'..=b'9' => {
+                self.pos += 1;
+                while bytes
+                    .get(self.pos)
+                    .is_some_and(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'+' | b'-'))
+                {
+                    self.pos += 1;
+                }
+                Token::Value
+            }
+            b'"' if bytes[start..].starts_with(b"\"\"\"") => {
+                self.pos += 3;
+                loop {
+                    match bytes.get(self.pos) {
+                        None => return Err("unterminated block string".into()),
+                        Some(b'\\') if bytes[self.pos..].starts_with(b"\\\"\"\"") => self.pos += 4,
+                        Some(b'"') if bytes[self.pos..].starts_with(b"\"\"\"") => {
+                            self.pos += 3;
+                            break;
+                        }
+                        Some(_) => self.pos += 1,
+                    }
+                }
+                Token::Value
+            }
+            b'"' => {
+                self.pos += 1;
+                loop {
+                    match bytes.get(self.pos) {
+                        None | Some(b'\n' | b'\r') => return Err("unterminated string".into()),
+                        Some(b'\\') => self.pos += 2,
+                        Some(b'"') => {
+                            self.pos += 1;
+                            break;
+                        }
+                        Some(_) => self.pos += 1,
+                    }
+                }
+                Token::Value
+            }
+            _ => return Err(format!("unexpected character at offset {}", start)),
+        };
+        Ok(Some(token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(block_introspection: bool) -> GraphQlLimits {
+        GraphQlLimits::new(&GraphQlConfig {
+            max_depth: 5,
+            max_complexity: 100,
+            max_aliases: 3,
+            block_introspection,
+            max_body_kb: 64,
+        })
+    }
+
+    fn refused(limits: &GraphQlLimits, query: &str) -> Option<&'static str> {
+        limits.check_query(query).err().map(|r| r.reason)
+    }
+
+    fn get(query_string: &str) -> RequestHeader {
+        let uri = format!("/graphql?{}", query_string);
+        RequestHeader::build("GET", uri.as_bytes(), None).unwrap()
+    }
+
+    #[test]
+    fn fragment_cycles_are_refused() {
+        let limits = limits(false);
+        let query = "{ ...A } fragment A on T { a ...B } fragment B on T { b ...A }";
+        assert_eq!(refused(&limits, query), Some("graphql_malformed"));
+        let query = "{ ...A } fragment A on T { ...A }";
+        assert_eq!(refused(&limits, query), Some("graphql_malformed"));
+    }
+
+    #[test]
+    fn fragment_bombs_are_counted_at_full_size() {
+        let mut query = String::from("{ ...F20 } fragment F0 on T { a b }");
+        for level in 1..=20 {
+            let prev = level - 1;
+            query.push_str(&format!(
+                " fragment F{level} on T {{ ...F{prev} ...F{prev} }}"
+            ));
+        }
+        assert_eq!(refused(&limits(false), &query), Some("graphql_too_complex"));
+    }
+
+    #[test]
+    fn depth_counts_through_fragments() {
+        let limits = limits(false);
+        assert_eq!(refused(&limits, "{ a { b { c { d { e } } } } }"), None);
+        let query = "{ a { b { ...F } } } fragment F on T { c { d { e { f } } } }";
+        assert_eq!(refused(&limits, query), Some("graphql_too_deep"));
+    }
+
+    #[test]
+    fn block_strings_hide_their_punctuation() {
+        let limits = limits(false);
+        let query = r#"{ a(text: """ } { "quoted" \""" """) { b } }"#;
+        assert_eq!(refused(&limits, query), None);
+        assert_eq!(
+            refused(&limits, r#"{ a(text: """ never closed) }"#),
+            Some("graphql_malformed")
+        );
+    }
+
+    #[test]
+    fn aliases_over_the_limit_are_refused() {
+        let limits = limits(false);
+        assert_eq!(refused(&limits, "{ a: x b: x c: x }"), None);
+        assert_eq!(
+            refused(&limits, "{ a: x b: x c: x d: x }"),
+            Some("graphql_too_many_aliases")
+        );
+    }
+
+    #[test]
+    fn introspection_is_refused_only_when_blocked() {
+        let query = "query { __schema { types { name } } }";
+        assert_eq!(refused(&limits(false), query), None);
+        assert_eq!(refused(&limits(true), query), Some("graphql_introspection"));
+        let query = "{ ... on Query { __type(name: \"User\") { name } } }";
+        assert_eq!(refused(&limits(true), query), Some("graphql_introspection"));
+    }
+
+    #[test]
+    fn get_queries_are_decoded_and_must_be_unique() {
+        let limits = limits(true);
+        let introspect = "query=%7B__schema%7Btypes%7Bname%7D%7D%7D";
+        let reason = |req: &RequestHeader| limits.check(req).err().map(|r| r.reason);
+        assert_eq!(reason(&get(introspect)), Some("graphql_introspection"));
+        assert_eq!(reason(&get("query=%7Ba%7D")), None);
+        assert_eq!(
+            reason(&get("query=%7Ba%7D&%71uery=%7B__schema%7Bx%7D%7D")),
+            Some("graphql_malformed")
+        );
+    }
+}
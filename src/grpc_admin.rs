@@ -0,0 +1,242 @@
+use crate::admin_auth::{AdminAuth, Denial};
+use crate::controls::Controls;
+use crate::protobuf::{fields, put_bool, put_bytes};
+use crate::reload::Reloader;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use h2::server::SendResponse;
+use h2::RecvStream;
+use pingora::apps::ServerApp;
+use pingora::protocols::Stream;
+use pingora::server::ShutdownWatch;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Request messages are tiny; anything bigger is refused
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+const SERVICE_PREFIX: &str = "/flashproxy.admin.v1.Admin/";
+
+const INVALID_ARGUMENT: u32 = 3;
+const PERMISSION_DENIED: u32 = 7;
+const FAILED_PRECONDITION: u32 = 9;
+const UNIMPLEMENTED: u32 = 12;
+const INTERNAL: u32 = 13;
+const UNAUTHENTICATED: u32 = 16;
+
+type Status = (u32, String);
+
+/// The admin operations as a gRPC service (`proto/admin.proto`), for orchestration tooling.
+/// Served over plaintext HTTP/2 (h2c) unless `admin_auth` asks for client certificates; bind
+/// it to a loopback or otherwise trusted address.
+pub struct GrpcAdmin {
+    pub reloader: Arc<Reloader>,
+    pub controls: Arc<Controls>,
+    pub auth: Arc<AdminAuth>,
+}
+
+#[async_trait]
+impl ServerApp for GrpcAdmin {
+    async fn process_new(
+        self: &Arc<Self>,
+        io: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        // A dual-stack listener sees IPv4 clients as `::ffff:a.b.c.d`
+        let peer = io.get_socket_digest().and_then(|d| {
+            d.peer_addr()
+                .and_then(|a| a.as_inet())
+                .map(|a| a.ip().to_canonical())
+        });
+        let mut connection = match h2::server::handshake(io).await {
+            Ok(connection) => connection,
+            Err(e) => {
+                tracing::debug!(error = %e, "grpc admin handshake failed");
+                return None;
+            }
+        };
+        while let Some(stream) = connection.accept().await {
+            match stream {
+                Ok((request, respond)) => {
+                    let admin = self.clone();
+                    tokio::spawn(async move { admin.serve(peer, request, respond).await });
+                }
+                Err(e) => {
+                    tracing::debug!(error = %e, "grpc admin connection closed");
+                    break;
+                }
+            }
+        }
+        None
+    }
+}
+
+impl GrpcAdmin {
+    async fn serve(
+        &self,
+        peer: Option<IpAddr>,
+        request: http::Request<RecvStream>,
+        mut respond: SendResponse<Bytes>,
+    ) {
+        let method = request
+            .uri()
+            .path()
+            .strip_prefix(SERVICE_PREFIX)
+            .unwrap_or_default()
+            .to_string();
+        let authorization = request
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .map(|v| v.as_bytes());
+        let result = match self.auth.check(peer, authorization) {
+            Err(denial) => {
+                let code = match denial {
+                    Denial::Address => PERMISSION_DENIED,
+                    Denial::Token => UNAUTHENTICATED,
+                };
+                self.auth
+                    .audit("grpc", peer, "POST", &method, denial.reason(), code);
+                Err((code, denial.reason().to_string()))
+            }
+            Ok(()) => {
+                let result = match read_message(request.into_body()).await {
+                    Ok(message) => self.call(&method, &message),
+                    Err(status) => Err(status),
+                };
+                if method != "GetState" {
+                    let code = result.as_ref().err().map_or(0, |(code, _)| *code);
+                    self.auth
+                        .audit("grpc", peer, "POST", &method, "admin_action", code);
+                }
+                result
+            }
+        };
+        let (code, message, reply) = match result {
+            Ok(reply) => (0, String::new(), Some(reply)),
+            Err((code, message)) => {
+                tracing::warn!(method = %method, code, error = %message, "grpc admin call failed");
+                (code, message, None)
+            }
+        };
+        if let Err(e) = send_reply(&mut respond, code, &message, reply) {
+            tracing::debug!(error = %e, "grpc admin reply failed");
+        }
+    }
+
+    fn call(&self, method: &str, message: &[u8]) -> Result<Vec<u8>, Status> {
+        let fields = fields(message).map_err(|e| (INVALID_ARGUMENT, e.to_string()))?;
+        let field = |number: u64| fields.iter().find(|(n, _)| *n == number).map(|(_, v)| v);
+        let flag = |number: u64| field(number).and_then(|v| v.varint()).unwrap_or(0) != 0;
+        let ip = || -> Result<IpAddr, Status> {
+            let raw = field(1).and_then(|v| v.bytes()).unwrap_or_default();
+            String::from_utf8_lossy(raw)
+                .parse()
+                .map_err(|_| (INVALID_ARGUMENT, "ip is not an IP address".to_string()))
+        };
+        match method {
+            "Reload" => {
+                let changed = self
+                    .reloader
+                    .reload()
+                    .map_err(|e| (FAILED_PRECONDITION, e.to_string()))?;
+                tracing::info!(?changed, "configuration reloaded via grpc admin API");
+                let mut reply = Vec::new();
+                for name in changed {
+                    put_bytes(&mut reply, 1, name.as_bytes());
+                }
+                return Ok(reply);
+            }
+            "Drain" => {
+                let draining = flag(1);
+                tracing::info!(draining, "drain toggled via grpc admin API");
+                self.controls.set_draining(draining);
+            }
+            "BanIp" => {
+                let ip = ip()?;
+                tracing::info!(%ip, "client IP banned via grpc admin API");
+                self.controls.ban(ip);
+            }
+            "UnbanIp" => {
+                let ip = ip()?;
+                tracing::info!(%ip, "client IP unbanned via grpc admin API");
+                self.controls.unban(&ip);
+            }
+            "SetMaintenance" => {
+                let enabled = flag(1);
+                tracing::info!(enabled, "maintenance toggled via grpc admin API");
+                self.controls.set_maintenance(enabled);
+            }
+            "GetState" => {}
+            _ => return Err((UNIMPLEMENTED, format!("unknown method '{}'", method))),
+        }
+        Ok(self.control_state())
+    }
+
+    fn control_state(&self) -> Vec<u8> {
+        let mut state = Vec::new();
+        put_bool(&mut state, 1, self.controls.draining());
+        put_bool(&mut state, 2, self.controls.maintenance());
+        for ip in self.controls.banned() {
+            put_bytes(&mut state, 3, ip.to_string().as_bytes());
+        }
+        state
+    }
+}
+
+/// The single length-prefixed message of a unary call.
+async fn read_message(mut body: RecvStream) -> Result<Vec<u8>, Status> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| (INTERNAL, e.to_string()))?;
+        let _ = body.flow_control().release_capacity(chunk.len());
+        buf.extend_from_slice(&chunk);
+        if buf.len() > MAX_MESSAGE_LEN + 5 {
+            return Err((INVALID_ARGUMENT, "message too large".to_string()));
+        }
+    }
+    if buf.len() < 5 {
+        return Err((INVALID_ARGUMENT, "missing request message".to_string()));
+    }
+    if buf[0] != 0 {
+        return Err((
+            UNIMPLEMENTED,
+            "compressed messages aren't supported".to_string(),
+        ));
+    }
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    buf.get(5..5 + len)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| (INVALID_ARGUMENT, "truncated request message".to_string()))
+}
+
+fn send_reply(
+    respond: &mut SendResponse<Bytes>,
+    code: u32,
+    message: &str,
+    reply: Option<Vec<u8>>,
+) -> Result<(), h2::Error> {
+    let mut status = http::HeaderMap::new();
+    status.insert("grpc-status", code.into());
+    if let Ok(message) = http::HeaderValue::from_str(message) {
+        if !message.is_empty() {
+            status.insert("grpc-message", message);
+        }
+    }
+    let head = http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/grpc");
+    let Some(reply) = reply else {
+        // Trailers-only response
+        let mut response = head.body(()).expect("static response parts are valid");
+        response.headers_mut().extend(status);
+        respond.send_response(response, true)?;
+        return Ok(());
+    };
+    let response = head.body(()).expect("static response parts are valid");
+    let mut send = respond.send_response(response, false)?;
+    let mut frame = Vec::with_capacity(5 + reply.len());
+    frame.push(0);
+    frame.extend_from_slice(&(reply.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&reply);
+    send.send_data(Bytes::from(frame), false)?;
+    send.send_trailers(status)
+}
@@ -0,0 +1,186 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::{BufMut, Bytes, BytesMut};
+use pingora::http::{RequestHeader, ResponseHeader};
+
+const GRPC_WEB: &str = "application/grpc-web";
+const GRPC_WEB_TEXT: &str = "application/grpc-web-text";
+/// Flag byte marking a gRPC-Web frame that carries trailers instead of a message.
+const TRAILER_FRAME: u8 = 0x80;
+
+/// Translation state for one gRPC-Web call proxied to a native gRPC upstream.
+///
+/// Binary mode passes message frames through untouched and appends the upstream's HTTP/2
+/// trailers as a final trailer frame. Text mode additionally base64-decodes the request body
+/// and base64-encodes the response body, carrying partial groups across chunk boundaries.
+pub struct GrpcWebCall {
+    text: bool,
+    /// Undecoded base64 characters left over from the previous request chunk
+    request_pending: Vec<u8>,
+    /// Unencoded response bytes short of a full 3-byte group
+    response_pending: Vec<u8>,
+    /// Set once the upstream answered with a gRPC content type
+    translating_response: bool,
+}
+
+impl GrpcWebCall {
+    /// Returns a call if the request is gRPC-Web.
+    pub fn detect(req: &RequestHeader) -> Option<Self> {
+        let content_type = req
+            .headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())?
+            .to_ascii_lowercase();
+        let text = if content_type.starts_with(GRPC_WEB_TEXT) {
+            true
+        } else if content_type.starts_with(GRPC_WEB) {
+            false
+        } else {
+            return None;
+        };
+        Some(Self {
+            text,
+            request_pending: Vec::new(),
+            response_pending: Vec::new(),
+            translating_response: false,
+        })
+    }
+
+    /// Rewrite the upstream request into native gRPC.
+    pub fn upstream_request(&self, req: &mut RequestHeader) -> pingora::Result<()> {
+        let content_type = req
+            .headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or(GRPC_WEB)
+            .to_ascii_lowercase();
+        let suffix = content_type
+            .trim_start_matches(GRPC_WEB_TEXT)
+            .trim_start_matches(GRPC_WEB);
+        req.insert_header(
+            http::header::CONTENT_TYPE,
+            format!("application/grpc{}", suffix),
+        )?;
+        req.insert_header(http::header::TE, "trailers")?;
+        req.remove_header("x-grpc-web");
+        if self.text {
+            req.remove_header(&http::header::CONTENT_LENGTH);
+        }
+        Ok(())
+    }
+
+    pub fn request_body(
+        &mut self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+    ) -> pingora::Result<()> {
+        if !self.text {
+            return Ok(());
+        }
+        if let Some(chunk) = body.as_ref() {
+            self.request_pending
+                .extend(chunk.iter().filter(|b| !b.is_ascii_whitespace()));
+        }
+        // Decode whole 4-character groups only; the remainder waits for the next chunk.
+        let usable = if end_of_stream {
+            self.request_pending.len()
+        } else {
+            self.request_pending.len() / 4 * 4
+        };
+        let encoded: Vec<u8> = self.request_pending.drain(..usable).collect();
+        let decoded = decode_concatenated(&encoded).map_err(|e| {
+            pingora::Error::explain(
+                pingora::ErrorType::InvalidHTTPHeader,
+                format!("grpc-web-text body: {}", e),
+            )
+        })?;
+        *body = Some(Bytes::from(decoded));
+        Ok(())
+    }
+
+    /// Rewrite the upstream's native gRPC response header for the browser.
+    pub fn response_header(&mut self, resp: &mut ResponseHeader) -> pingora::Result<()> {
+        let content_type = resp
+            .headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        // Only native gRPC (`application/grpc`, `+proto`, `;charset=..`) needs translating
+        let Some(suffix) = grpc_suffix(&content_type) else {
+            return Ok(());
+        };
+        self.translating_response = true;
+        let base = if self.text { GRPC_WEB_TEXT } else { GRPC_WEB };
+        resp.insert_header(http::header::CONTENT_TYPE, format!("{}{}", base, suffix))?;
+        resp.remove_header(&http::header::CONTENT_LENGTH);
+        Ok(())
+    }
+
+    pub fn response_body(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) {
+        if !self.translating_response || !self.text {
+            return;
+        }
+        if let Some(chunk) = body.as_ref() {
+            self.response_pending.extend_from_slice(chunk);
+        }
+        let usable = if end_of_stream {
+            self.response_pending.len()
+        } else {
+            self.response_pending.len() / 3 * 3
+        };
+        let raw: Vec<u8> = self.response_pending.drain(..usable).collect();
+        *body = Some(Bytes::from(STANDARD.encode(raw)));
+    }
+
+    /// Encode the upstream trailers as the final gRPC-Web frame, written as response body.
+    pub fn response_trailers(&mut self, trailers: &http::HeaderMap) -> Option<Bytes> {
+        if !self.translating_response {
+            return None;
+        }
+        let mut block = Vec::new();
+        for (name, value) in trailers {
+            block.extend_from_slice(name.as_str().as_bytes());
+            block.extend_from_slice(b": ");
+            block.extend_from_slice(value.as_bytes());
+            block.extend_from_slice(b"\r\n");
+        }
+        let mut frame = BytesMut::with_capacity(5 + block.len());
+        frame.put_u8(TRAILER_FRAME);
+        frame.put_u32(block.len() as u32);
+        frame.put_slice(&block);
+
+        if self.text {
+            let mut raw = std::mem::take(&mut self.response_pending);
+            raw.extend_from_slice(&frame);
+            Some(Bytes::from(STANDARD.encode(raw)))
+        } else {
+            Some(frame.freeze())
+        }
+    }
+}
+
+/// Browsers may send several independently padded base64 segments back to back.
+fn decode_concatenated(encoded: &[u8]) -> Result<Vec<u8>, base64::DecodeError> {
+    let mut out = Vec::with_capacity(encoded.len() / 4 * 3);
+    let mut start = 0;
+    for (i, pair) in encoded.windows(2).enumerate() {
+        if pair[0] == b'=' && pair[1] != b'=' {
+            out.extend(STANDARD.decode(&encoded[start..=i])?);
+            start = i + 1;
+        }
+    }
+    out.extend(STANDARD.decode(&encoded[start..])?);
+    Ok(out)
+}
+
+/// Whether a response is native gRPC, by its (lowercase) content type.
+pub fn is_grpc(content_type: &str) -> bool {
+    grpc_suffix(content_type).is_some()
+}
+
+fn grpc_suffix(content_type: &str) -> Option<&str> {
+    content_type
+        .strip_prefix("application/grpc")
+        .filter(|s| s.is_empty() || s.starts_with(['+', ';']))
+}
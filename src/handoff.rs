@@ -0,0 +1,145 @@
+use crate::configuration::GatewayConfig;
+use async_trait::async_trait;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use pingora::server::{Fds, ListenFds, ShutdownWatch};
+use pingora::services::Service;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Set on the process started by a handoff so it takes over the listening sockets
+pub const UPGRADE_ENV: &str = "REVERSE_PROXY_UPGRADE";
+/// How long the new process has to start up and ask for the sockets
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Applies listener-level config changes (port, TLS, HTTP/2) that pingora can only set up at
+/// startup. A new process of the same binary is started on the reloaded file and receives
+/// the listening sockets over pingora's upgrade socket, minus those of addresses no longer
+/// configured; this process then shuts down gracefully, draining its in-flight requests.
+/// Restart-only fields take effect in the new process as well.
+#[derive(Default)]
+pub struct ListenerHandoff {
+    /// The server's listening sockets and upgrade socket path, once it has started
+    listeners: OnceLock<(ListenFds, String)>,
+    started: AtomicBool,
+}
+
+/// Service that only hands the server's listening sockets to [`ListenerHandoff`].
+pub struct ListenerCapture {
+    handoff: Arc<ListenerHandoff>,
+    upgrade_sock: String,
+}
+
+impl ListenerHandoff {
+    pub fn service(self: &Arc<Self>, upgrade_sock: &str) -> ListenerCapture {
+        ListenerCapture {
+            handoff: self.clone(),
+            upgrade_sock: upgrade_sock.to_string(),
+        }
+    }
+
+    /// Whether going from `old` to `new` changes anything only a new process can apply.
+    pub fn needed(old: &GatewayConfig, new: &GatewayConfig) -> bool {
+        old.listen_port != new.listen_port
+            || old.listen_host != new.listen_host
+            || old.tls_cert_path != new.tls_cert_path
+            || old.tls_key_path != new.tls_key_path
+            || old.tls_profile != new.tls_profile
+            || old.http2 != new.http2
+    }
+
+    /// Starts the handoff in the background; at most one runs at a time, and none after one
+    /// succeeded.
+    pub fn start(self: &Arc<Self>, old: &GatewayConfig, new: &GatewayConfig) {
+        let Some((fds, upgrade_sock)) = self.listeners.get().cloned() else {
+            tracing::warn!("listeners not started yet, listener changes need a restart");
+            return;
+        };
+        if self.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let keep = new.listen_addrs();
+        let retired: Vec<String> = old
+            .listen_addrs()
+            .into_iter()
+            .filter(|addr| !keep.contains(addr))
+            .collect();
+        tracing::info!(
+            ?retired,
+            "listener settings changed, handing listeners to a new process"
+        );
+        let handoff = self.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = hand_off(&fds, &upgrade_sock, &retired) {
+                tracing::error!(
+                    "listener handoff failed: {}. Keeping the current listeners.",
+                    e
+                );
+                handoff.started.store(false, Ordering::SeqCst);
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl Service for ListenerCapture {
+    async fn start_service(&mut self, fds: Option<ListenFds>, mut shutdown: ShutdownWatch) {
+        if let Some(fds) = fds {
+            let _ = self.handoff.listeners.set((fds, self.upgrade_sock.clone()));
+        }
+        let _ = shutdown.changed().await;
+    }
+
+    fn name(&self) -> &str {
+        "listener handoff"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+fn hand_off(fds: &ListenFds, upgrade_sock: &str, retired: &[String]) -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("locating binary: {}", e))?;
+    let mut child = Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .env(UPGRADE_ENV, "1")
+        // Lets the new process take over the systemd watchdog
+        .env_remove("WATCHDOG_PID")
+        .spawn()
+        .map_err(|e| format!("starting new process: {}", e))?;
+
+    let mut kept = Fds::new();
+    let (addrs, raw) = fds.blocking_lock().serialize();
+    for (addr, fd) in addrs.into_iter().zip(raw) {
+        if !retired.contains(&addr) {
+            kept.add(addr, fd);
+        }
+    }
+
+    // Each attempt already retries the connect for a few seconds
+    let deadline = Instant::now() + HANDOFF_TIMEOUT;
+    loop {
+        match kept.send_to_sock(upgrade_sock) {
+            Ok(_) => break,
+            Err(e) if Instant::now() >= deadline || exited(&mut child) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("sending sockets to {}: {}", upgrade_sock, e));
+            }
+            Err(_) => std::thread::sleep(Duration::from_secs(1)),
+        }
+    }
+    tracing::info!(
+        pid = child.id(),
+        "listeners handed off, draining this process"
+    );
+    // SIGTERM is pingora's graceful shutdown: stop accepting, finish in-flight requests
+    kill(Pid::this(), Signal::SIGTERM).map_err(|e| format!("signalling shutdown: {}", e))
+}
+
+fn exited(child: &mut Child) -> bool {
+    !matches!(child.try_wait(), Ok(None))
+}
@@ -0,0 +1,164 @@
+use crate::configuration::{HeaderCasing, HeaderNormalizationConfig};
+use http::{HeaderMap, HeaderName, HeaderValue};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::Result;
+use std::collections::HashMap;
+
+/// Re-spells and merges header names before a header goes out. pingora writes HTTP/1.1
+/// names with the case they were received or inserted with, and lowercase for ones that came
+/// over HTTP/2, so a header is rebuilt with the names chosen here rather than edited in place.
+pub struct HeaderNormalizer {
+    casing: HeaderCasing,
+    /// Lowercase name to its configured spelling
+    names: HashMap<HeaderName, String>,
+    merge_duplicates: bool,
+}
+
+/// The parts of pingora's request and response headers a rebuild needs.
+trait Rebuild: Sized {
+    fn headers(&self) -> &HeaderMap;
+    fn wire(&self) -> Vec<u8>;
+    fn empty_copy(&self) -> Result<Self>;
+    fn append(&mut self, name: String, value: HeaderValue) -> Result<bool>;
+}
+
+impl Rebuild for RequestHeader {
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn wire(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.header_to_h1_wire(&mut buf);
+        buf
+    }
+
+    fn empty_copy(&self) -> Result<Self> {
+        let mut req = RequestHeader::build(self.method.clone(), b"/", Some(self.headers.len()))?;
+        req.set_uri(self.uri.clone());
+        req.set_version(self.version);
+        Ok(req)
+    }
+
+    fn append(&mut self, name: String, value: HeaderValue) -> Result<bool> {
+        self.append_header(name, value)
+    }
+}
+
+impl Rebuild for ResponseHeader {
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn wire(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.header_to_h1_wire(&mut buf);
+        buf
+    }
+
+    fn empty_copy(&self) -> Result<Self> {
+        let mut resp = ResponseHeader::build(self.status, Some(self.headers.len()))?;
+        resp.set_version(self.version);
+        resp.set_reason_phrase(self.get_reason_phrase())?;
+        Ok(resp)
+    }
+
+    fn append(&mut self, name: String, value: HeaderValue) -> Result<bool> {
+        self.append_header(name, value)
+    }
+}
+
+impl HeaderNormalizer {
+    /// `None` when the config leaves headers as they are.
+    pub fn new(config: &HeaderNormalizationConfig) -> Option<Self> {
+        if config.casing == HeaderCasing::Preserve
+            && config.names.is_empty()
+            && !config.merge_duplicates
+        {
+            return None;
+        }
+        Some(Self {
+            casing: config.casing,
+            // Checked during config validation
+            names: config
+                .names
+                .iter()
+                .filter_map(|name| {
+                    Some((HeaderName::from_bytes(name.as_bytes()).ok()?, name.clone()))
+                })
+                .collect(),
+            merge_duplicates: config.merge_duplicates,
+        })
+    }
+
+    pub fn request(&self, req: &mut RequestHeader) -> Result<()> {
+        self.rebuild(req)
+    }
+
+    pub fn response(&self, resp: &mut ResponseHeader) -> Result<()> {
+        self.rebuild(resp)
+    }
+
+    fn rebuild<H: Rebuild>(&self, header: &mut H) -> Result<()> {
+        // The names pingora would write, in the same order as the map iterates
+        let wire = header.wire();
+        let sent: Vec<&[u8]> = wire
+            .split(|&b| b == b'\n')
+            .filter_map(|line| line.split(|&b| b == b':').next())
+            .filter(|name| !name.is_empty())
+            .collect();
+
+        let mut entries: Vec<(&HeaderName, String, Vec<HeaderValue>)> = Vec::new();
+        for (i, (name, value)) in header.headers().iter().enumerate() {
+            let spelling = match self.names.get(name) {
+                Some(spelling) => spelling.clone(),
+                None if self.casing == HeaderCasing::Title => title_case(name.as_str()),
+                None => match sent.get(i) {
+                    Some(sent) => String::from_utf8_lossy(sent).into_owned(),
+                    None => name.as_str().to_string(),
+                },
+            };
+            let merge = self.merge_duplicates && name != http::header::SET_COOKIE;
+            match entries.iter_mut().find(|(n, _, _)| merge && *n == name) {
+                Some((_, _, values)) => values.push(value.clone()),
+                None => entries.push((name, spelling, vec![value.clone()])),
+            }
+        }
+
+        let mut rebuilt = header.empty_copy()?;
+        for (name, spelling, values) in entries {
+            let value = match &values[..] {
+                [value] => value.clone(),
+                values => {
+                    let separator: &[u8] = if *name == http::header::COOKIE {
+                        b"; "
+                    } else {
+                        b", "
+                    };
+                    let joined = values
+                        .iter()
+                        .map(|v| v.as_bytes())
+                        .collect::<Vec<_>>()
+                        .join(separator);
+                    // Joining valid values with a separator keeps them valid
+                    HeaderValue::from_bytes(&joined).expect("joined header values are valid")
+                }
+            };
+            rebuilt.append(spelling, value)?;
+        }
+        *header = rebuilt;
+        Ok(())
+    }
+}
+
+/// `x-api-key` to `X-Api-Key`.
+fn title_case(name: &str) -> String {
+    let mut upper = true;
+    name.chars()
+        .map(|c| {
+            let c = if upper { c.to_ascii_uppercase() } else { c };
+            upper = c == '-';
+            c
+        })
+        .collect()
+}
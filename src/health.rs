@@ -0,0 +1,173 @@
+use crate::configuration::HealthCheckConfig;
+use crate::webhook::AlertWebhook;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use pingora::lb::health_check::HealthCheck;
+use pingora::lb::selection::RoundRobin;
+use pingora::lb::{Backend, LoadBalancer};
+use rand::Rng;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+/// Active health check scheduling for every upstream pool. Probes run in parallel, each
+/// after a random delay, and a semaphore shared by all pools caps how many are in flight, so
+/// hundreds of upstreams don't get probed in one synchronized burst each interval.
+pub struct HealthChecks {
+    interval: Duration,
+    jitter_ms: u64,
+    probes: Arc<Semaphore>,
+}
+
+impl HealthChecks {
+    pub fn new(config: &HealthCheckConfig) -> Self {
+        Self {
+            interval: Duration::from_secs(config.interval_secs),
+            jitter_ms: config.jitter_ms,
+            probes: Arc::new(Semaphore::new(config.max_concurrent_probes)),
+        }
+    }
+
+    /// Installs `check` on `lb`, scheduled per the config. Health flips seen in the probe
+    /// results are reported to `slow_start` and `alerts`.
+    pub fn apply(
+        &self,
+        lb: &mut LoadBalancer<RoundRobin>,
+        check: Box<dyn HealthCheck + Send + Sync>,
+        slow_start: Option<Arc<SlowStart>>,
+        alerts: Option<Arc<AlertWebhook>>,
+    ) {
+        let transitions = (slow_start.is_some() || alerts.is_some()).then(|| Transitions {
+            probes: DashMap::new(),
+            slow_start,
+            alerts,
+        });
+        lb.set_health_check(Box::new(Throttled {
+            inner: check,
+            jitter_ms: self.jitter_ms,
+            probes: self.probes.clone(),
+            transitions,
+        }));
+        lb.health_check_frequency = Some(self.interval);
+        lb.parallel_health_check = true;
+    }
+}
+
+struct Throttled {
+    inner: Box<dyn HealthCheck + Send + Sync>,
+    jitter_ms: u64,
+    probes: Arc<Semaphore>,
+    transitions: Option<Transitions>,
+}
+
+#[async_trait]
+impl HealthCheck for Throttled {
+    async fn check(&self, target: &Backend) -> pingora::Result<()> {
+        if self.jitter_ms > 0 {
+            let delay = rand::thread_rng().gen_range(0..self.jitter_ms);
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+        // The semaphore is never closed
+        let _permit = self.probes.acquire().await;
+        let result = self.inner.check(target).await;
+        if let Some(transitions) = &self.transitions {
+            let success = result.is_ok();
+            transitions.observe(target, success, self.inner.health_threshold(success));
+        }
+        result
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        self.inner.health_threshold(success)
+    }
+}
+
+/// Health of each backend tracked from the probe results with the same flip thresholds
+/// pingora applies, since the load balancer doesn't report transitions.
+struct Transitions {
+    probes: DashMap<Backend, ProbeState>,
+    slow_start: Option<Arc<SlowStart>>,
+    alerts: Option<Arc<AlertWebhook>>,
+}
+
+/// Backends start healthy, as in pingora
+struct ProbeState {
+    healthy: bool,
+    streak: usize,
+}
+
+impl Transitions {
+    fn observe(&self, backend: &Backend, success: bool, threshold: usize) {
+        let mut state = self.probes.entry(backend.clone()).or_insert(ProbeState {
+            healthy: true,
+            streak: 0,
+        });
+        if state.healthy == success {
+            state.streak = 0;
+            return;
+        }
+        state.streak += 1;
+        if state.streak < threshold {
+            return;
+        }
+        state.healthy = success;
+        state.streak = 0;
+        drop(state);
+        if !success {
+            tracing::warn!(upstream = ?backend.addr, "upstream became unhealthy");
+        }
+        if let Some(slow_start) = &self.slow_start {
+            slow_start.transition(backend, success);
+        }
+        if let Some(alerts) = &self.alerts {
+            let event = if success {
+                "upstream_healthy"
+            } else {
+                "upstream_unhealthy"
+            };
+            alerts.send(event, &backend.addr.to_string(), serde_json::json!({}));
+        }
+    }
+}
+
+/// Ramps a recovered backend's traffic share from nothing to a full round-robin share over
+/// `window`, so a cold backend isn't knocked over again by its first second of traffic.
+pub struct SlowStart {
+    window: Duration,
+    recovering: DashMap<Backend, Instant>,
+}
+
+impl SlowStart {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            recovering: DashMap::new(),
+        }
+    }
+
+    fn transition(&self, backend: &Backend, healthy: bool) {
+        if healthy {
+            tracing::info!(upstream = ?backend.addr, window = ?self.window, "upstream recovered, ramping up traffic");
+            self.recovering.insert(backend.clone(), Instant::now());
+        } else {
+            self.recovering.remove(backend);
+        }
+    }
+
+    /// Whether `backend` should take this request: always once it's past the window, and with
+    /// a probability growing linearly over the window while it ramps up.
+    pub fn accept(&self, backend: &Backend) -> bool {
+        if self.recovering.is_empty() {
+            return true;
+        }
+        let Some(since) = self.recovering.get(backend).map(|s| *s) else {
+            return true;
+        };
+        let share = since.elapsed().as_secs_f64() / self.window.as_secs_f64();
+        if share >= 1.0 {
+            self.recovering.remove(backend);
+            return true;
+        }
+        rand::thread_rng().gen_bool(share)
+    }
+}
@@ -0,0 +1,223 @@
+use crate::configuration::HmacAuthConfig;
+use pingora::http::RequestHeader;
+use ring::hmac;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const KEY_ID: &str = "x-signature-key-id";
+const TIMESTAMP: &str = "x-signature-timestamp";
+const NONCE: &str = "x-signature-nonce";
+const SIGNATURE: &str = "x-signature";
+
+/// Request authentication by shared-secret HMAC, for the `hmac` stage.
+///
+/// Clients send `X-Signature-Key-Id`, `X-Signature-Timestamp` (Unix seconds),
+/// `X-Signature-Nonce` and `X-Signature`: the hex HMAC-SHA256 of
+/// `METHOD\npath?query\ntimestamp\nnonce` under the key's secret. Timestamps outside
+/// `max_skew_secs` are refused, and each key's nonces are remembered for as long as their
+/// timestamp stays acceptable, so a captured request can't be replayed.
+pub struct HmacAuth {
+    keys: HashMap<String, hmac::Key>,
+    max_skew_secs: u64,
+    seen: Mutex<SeenNonces>,
+}
+
+/// Bounded record of accepted nonces. Expired entries go first; when full of live ones the
+/// entry closest to expiry is evicted, so a replay it lets through is refused again on
+/// timestamp soon after.
+struct SeenNonces {
+    expiry: HashMap<(String, String), u64>,
+    /// The same entries ordered by expiry, for expiry and eviction
+    by_expiry: BTreeSet<(u64, (String, String))>,
+    capacity: usize,
+}
+
+impl HmacAuth {
+    pub fn new(config: &HmacAuthConfig) -> Self {
+        Self {
+            keys: config
+                .keys
+                .iter()
+                .map(|(id, secret)| {
+                    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+                    (id.clone(), key)
+                })
+                .collect(),
+            max_skew_secs: config.max_skew_secs,
+            seen: Mutex::new(SeenNonces::new(config.max_nonces)),
+        }
+    }
+
+    /// The key id of a correctly signed, fresh request; `Err` carries the reason for the
+    /// audit trail.
+    pub fn verify(&self, req: &RequestHeader) -> Result<String, &'static str> {
+        let header = |name| {
+            req.headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .ok_or("hmac_missing")
+        };
+        let key_id = header(KEY_ID)?;
+        let nonce = header(NONCE)?;
+        let timestamp: u64 = header(TIMESTAMP)?.parse().map_err(|_| "hmac_invalid")?;
+        let signature = hex::decode(header(SIGNATURE)?).map_err(|_| "hmac_invalid")?;
+        let key = self.keys.get(key_id).ok_or("hmac_invalid")?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if now.abs_diff(timestamp) > self.max_skew_secs {
+            return Err("hmac_expired");
+        }
+        let path = req.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let message = format!("{}\n{}\n{}\n{}", req.method, path, timestamp, nonce);
+        hmac::verify(key, message.as_bytes(), &signature).map_err(|_| "hmac_invalid")?;
+
+        // Only signed requests reach the store, so it can't be flooded anonymously
+        let expires = timestamp + self.max_skew_secs;
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        if !seen.insert((key_id.to_string(), nonce.to_string()), expires, now) {
+            return Err("hmac_replayed");
+        }
+        Ok(key_id.to_string())
+    }
+}
+
+impl SeenNonces {
+    fn new(capacity: usize) -> Self {
+        Self {
+            expiry: HashMap::new(),
+            by_expiry: BTreeSet::new(),
+            capacity,
+        }
+    }
+
+    /// False if the nonce was already used within its window.
+    fn insert(&mut self, nonce: (String, String), expires: u64, now: u64) -> bool {
+        if self.expiry.get(&nonce).is_some_and(|e| *e >= now) {
+            return false;
+        }
+        while let Some((expiry, _)) = self.by_expiry.first() {
+            if *expiry >= now && self.by_expiry.len() < self.capacity {
+                break;
+            }
+            if let Some((_, old)) = self.by_expiry.pop_first() {
+                self.expiry.remove(&old);
+            }
+        }
+        // An expired entry for the same nonce may still be held
+        if let Some(previous) = self.expiry.insert(nonce.clone(), expires) {
+            self.by_expiry.remove(&(previous, nonce.clone()));
+        }
+        self.by_expiry.insert((expires, nonce));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    const SECRET: &str = "test-secret";
+
+    fn auth(max_skew_secs: u64) -> HmacAuth {
+        HmacAuth::new(&HmacAuthConfig {
+            keys: BTreeMap::from([("k1".to_string(), SECRET.to_string())]),
+            max_skew_secs,
+            max_nonces: 100,
+        })
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn signed(timestamp: u64, nonce: &str) -> RequestHeader {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes());
+        let message = format!("GET\n/orders?id=1\n{}\n{}", timestamp, nonce);
+        let signature = hex::encode(hmac::sign(&key, message.as_bytes()));
+        let mut req = RequestHeader::build("GET", b"/orders?id=1", None).unwrap();
+        for (name, value) in [
+            (KEY_ID, "k1".to_string()),
+            (TIMESTAMP, timestamp.to_string()),
+            (NONCE, nonce.to_string()),
+            (SIGNATURE, signature),
+        ] {
+            req.insert_header(name, value).unwrap();
+        }
+        req
+    }
+
+    fn nonce(value: &str) -> (String, String) {
+        ("k1".to_string(), value.to_string())
+    }
+
+    #[test]
+    fn replay_within_the_window_is_refused() {
+        let auth = auth(300);
+        let req = signed(now(), "n1");
+        assert_eq!(auth.verify(&req), Ok("k1".to_string()));
+        assert_eq!(auth.verify(&req), Err("hmac_replayed"));
+        assert_eq!(auth.verify(&signed(now(), "n2")), Ok("k1".to_string()));
+    }
+
+    #[test]
+    fn timestamp_outside_the_skew_is_refused() {
+        let auth = auth(300);
+        assert_eq!(
+            auth.verify(&signed(now() - 301, "old")),
+            Err("hmac_expired")
+        );
+        assert_eq!(
+            auth.verify(&signed(now() + 301, "new")),
+            Err("hmac_expired")
+        );
+    }
+
+    #[test]
+    fn tampered_request_is_refused() {
+        let auth = auth(300);
+        let mut req = signed(now(), "n1");
+        req.set_uri("/orders?id=2".parse().unwrap());
+        assert_eq!(auth.verify(&req), Err("hmac_invalid"));
+    }
+
+    #[test]
+    fn nonce_is_accepted_again_after_expiry() {
+        let mut seen = SeenNonces::new(10);
+        assert!(seen.insert(nonce("a"), 100, 50));
+        assert!(!seen.insert(nonce("a"), 150, 100));
+        assert!(seen.insert(nonce("a"), 200, 101));
+        assert!(!seen.insert(nonce("a"), 250, 150));
+        assert_eq!(seen.by_expiry.len(), 1);
+    }
+
+    #[test]
+    fn full_store_evicts_the_entry_closest_to_expiry() {
+        let mut seen = SeenNonces::new(2);
+        assert!(seen.insert(nonce("a"), 300, 10));
+        assert!(seen.insert(nonce("b"), 100, 10));
+        assert!(seen.insert(nonce("c"), 200, 10));
+        assert!(!seen.insert(nonce("a"), 300, 10));
+        assert!(!seen.insert(nonce("c"), 200, 10));
+        assert_eq!(seen.expiry.len(), 2);
+        assert_eq!(seen.by_expiry.len(), 2);
+    }
+
+    #[test]
+    fn expired_entries_go_before_live_ones() {
+        // Inserted out of expiry order: `a` is live when the store fills, `b` has expired
+        let mut seen = SeenNonces::new(2);
+        assert!(seen.insert(nonce("a"), 300, 10));
+        assert!(seen.insert(nonce("b"), 20, 10));
+        assert!(seen.insert(nonce("c"), 310, 30));
+        assert!(!seen.insert(nonce("a"), 300, 30));
+        assert!(!seen.insert(nonce("c"), 310, 30));
+    }
+}
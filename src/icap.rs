@@ -0,0 +1,233 @@
+use crate::configuration::IcapConfig;
+use pingora::http::{RequestHeader, ResponseHeader};
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Minimal ICAP (RFC 3507) client for handing bodies to an external AV/DLP scanner.
+///
+/// Bodies are buffered in full and scanned before any of them is forwarded. We advertise
+/// `Allow: 204`, so an unchanged message comes back as 204; anything else the scanner does
+/// (a 200 with a rewritten message, or an error page) counts as a block, since modified
+/// content isn't spliced back in. One connection per scan, which gets `timeout_ms` in all.
+pub struct IcapClient {
+    addr: String,
+    reqmod_service: Option<String>,
+    respmod_service: Option<String>,
+    timeout: Duration,
+    max_body_bytes: usize,
+    fail_open: bool,
+}
+
+pub enum Verdict {
+    Clean,
+    Blocked,
+}
+
+/// A body being buffered for scanning.
+pub struct IcapScan {
+    /// Upstream response header as received; `None` when scanning the request body
+    header: Option<ResponseHeader>,
+    body: Vec<u8>,
+    /// Outgrew `max_body_kb`; what was held back has been released and the rest streams through
+    overflowed: bool,
+}
+
+impl IcapClient {
+    pub fn new(config: &IcapConfig) -> Self {
+        Self {
+            addr: config.addr.clone(),
+            reqmod_service: config.reqmod_service.clone(),
+            respmod_service: config.respmod_service.clone(),
+            timeout: Duration::from_millis(config.timeout_ms),
+            max_body_bytes: config.max_body_kb * 1024,
+            fail_open: config.fail_open,
+        }
+    }
+
+    pub fn start_request(&self) -> Option<IcapScan> {
+        self.reqmod_service.as_ref()?;
+        Some(IcapScan {
+            header: None,
+            body: Vec::new(),
+            overflowed: false,
+        })
+    }
+
+    pub fn start_response(&self, resp: &ResponseHeader) -> Option<IcapScan> {
+        self.respmod_service.as_ref()?;
+        Some(IcapScan {
+            header: Some(resp.clone()),
+            body: Vec::new(),
+            overflowed: false,
+        })
+    }
+
+    /// Holds body chunks back until the end of the stream, then scans the whole body.
+    /// On `Ok` the body to forward is left in `body` (empty while still buffering); `Err`
+    /// means the message must not be delivered, with the reason for the audit trail.
+    pub async fn filter(
+        &self,
+        scan: &mut IcapScan,
+        req: &RequestHeader,
+        body: &mut Option<bytes::Bytes>,
+        end_of_stream: bool,
+    ) -> Result<(), &'static str> {
+        if scan.overflowed {
+            return Ok(());
+        }
+        if let Some(chunk) = body.take() {
+            scan.body.extend_from_slice(&chunk);
+        }
+        if scan.body.len() > self.max_body_bytes {
+            if !self.fail_open {
+                return Err("icap_body_too_large");
+            }
+            scan.overflowed = true;
+            *body = Some(std::mem::take(&mut scan.body).into());
+            return Ok(());
+        }
+        if !end_of_stream {
+            // An empty chunk rather than `None`, which pingora would read as end of body
+            *body = Some(bytes::Bytes::new());
+            return Ok(());
+        }
+        if scan.body.is_empty() {
+            return Ok(());
+        }
+
+        let result = match &scan.header {
+            None => self.reqmod(req, &scan.body).await,
+            Some(resp) => self.respmod(req, resp, &scan.body).await,
+        };
+        match result {
+            Ok(Verdict::Clean) => {}
+            Ok(Verdict::Blocked) => return Err("icap_blocked"),
+            Err(e) => {
+                tracing::error!(error = %e, addr = %self.addr, "icap scan failed");
+                if !self.fail_open {
+                    return Err("icap_unavailable");
+                }
+            }
+        }
+        *body = Some(std::mem::take(&mut scan.body).into());
+        Ok(())
+    }
+
+    async fn reqmod(&self, req: &RequestHeader, body: &[u8]) -> io::Result<Verdict> {
+        let service = self.reqmod_service.as_deref().unwrap_or_default();
+        let req_hdr = request_block(req);
+        let encapsulated = format!("req-hdr=0, req-body={}", req_hdr.len());
+        self.send("REQMOD", service, &encapsulated, &[&req_hdr], body)
+            .await
+    }
+
+    async fn respmod(
+        &self,
+        req: &RequestHeader,
+        resp: &ResponseHeader,
+        body: &[u8],
+    ) -> io::Result<Verdict> {
+        let service = self.respmod_service.as_deref().unwrap_or_default();
+        let req_hdr = request_block(req);
+        let res_hdr = response_block(resp);
+        let encapsulated = format!(
+            "req-hdr=0, res-hdr={}, res-body={}",
+            req_hdr.len(),
+            req_hdr.len() + res_hdr.len()
+        );
+        self.send(
+            "RESPMOD",
+            service,
+            &encapsulated,
+            &[&req_hdr, &res_hdr],
+            body,
+        )
+        .await
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        service: &str,
+        encapsulated: &str,
+        header_blocks: &[&[u8]],
+        body: &[u8],
+    ) -> io::Result<Verdict> {
+        let mut message = format!(
+            "{} icap://{}/{} ICAP/1.0\r\nHost: {}\r\nAllow: 204\r\nConnection: close\r\nEncapsulated: {}\r\n\r\n",
+            method,
+            self.addr,
+            service.trim_start_matches('/'),
+            self.addr,
+            encapsulated
+        )
+        .into_bytes();
+        for block in header_blocks {
+            message.extend_from_slice(block);
+        }
+        if !body.is_empty() {
+            message.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+            message.extend_from_slice(body);
+            message.extend_from_slice(b"\r\n");
+        }
+        message.extend_from_slice(b"0\r\n\r\n");
+
+        let exchange = async {
+            let mut stream = TcpStream::connect(self.addr.as_str()).await?;
+            stream.write_all(&message).await?;
+            let mut status_line = String::new();
+            BufReader::new(&mut stream)
+                .read_line(&mut status_line)
+                .await?;
+            Ok::<_, io::Error>(status_line)
+        };
+        let status_line = match tokio::time::timeout(self.timeout, exchange).await {
+            Ok(line) => line?,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "icap scan timed out",
+                ))
+            }
+        };
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad icap status line"))?;
+        match status {
+            204 => Ok(Verdict::Clean),
+            200 => Ok(Verdict::Blocked),
+            _ => Err(io::Error::other(format!("icap status {}", status))),
+        }
+    }
+}
+
+fn request_block(req: &RequestHeader) -> Vec<u8> {
+    let mut out = format!("{} {} HTTP/1.1\r\n", req.method, req.uri).into_bytes();
+    append_headers(&mut out, &req.headers);
+    out
+}
+
+fn response_block(resp: &ResponseHeader) -> Vec<u8> {
+    let mut out = format!(
+        "HTTP/1.1 {} {}\r\n",
+        resp.status.as_u16(),
+        resp.status.canonical_reason().unwrap_or("")
+    )
+    .into_bytes();
+    append_headers(&mut out, &resp.headers);
+    out
+}
+
+fn append_headers(out: &mut Vec<u8>, headers: &http::HeaderMap) {
+    for (name, value) in headers {
+        out.extend_from_slice(name.as_str().as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(value.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+}
@@ -0,0 +1,267 @@
+use crate::configuration::{ConfigError, IpFeedConfig, TorExitAction, TorExitConfig};
+use arc_swap::ArcSwap;
+use http::HeaderName;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Blocklists of addresses and CIDR ranges downloaded from `ip_feeds`, re-fetched in the
+/// background. A feed that can't be fetched keeps its last good list (or starts empty), so
+/// an outage at the feed's end never blocks startup or a reload; watch
+/// `ip_reputation_feed_age_seconds` instead. Refresh threads stop once the feeds are
+/// dropped, i.e. after the security layer holding them is replaced.
+pub struct IpReputation {
+    feeds: Vec<Arc<Feed>>,
+}
+
+struct Feed {
+    name: String,
+    url: String,
+    client: reqwest::blocking::Client,
+    ranges: ArcSwap<PrefixTree>,
+    /// Unix seconds of the last successful fetch, 0 before the first
+    updated: AtomicU64,
+}
+
+impl IpReputation {
+    pub fn load(feeds: &BTreeMap<String, IpFeedConfig>) -> Result<Self, ConfigError> {
+        let client = tokio::task::block_in_place(|| {
+            reqwest::blocking::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+        })
+        .map_err(|e| ConfigError::Secret(format!("ip feed client: {}", e)))?;
+        let feeds = feeds
+            .iter()
+            .map(|(name, config)| {
+                let feed = Arc::new(Feed {
+                    name: name.clone(),
+                    url: config.url.clone(),
+                    client: client.clone(),
+                    ranges: ArcSwap::from_pointee(PrefixTree::default()),
+                    updated: AtomicU64::new(0),
+                });
+                feed.refresh();
+                spawn_refresh(&feed, Duration::from_secs(config.refresh_secs));
+                feed
+            })
+            .collect();
+        Ok(Self { feeds })
+    }
+
+    /// Name of the first feed listing `ip`.
+    pub fn listed(&self, ip: IpAddr) -> Option<&str> {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        self.feeds
+            .iter()
+            .find(|feed| feed.ranges.load().contains(ip))
+            .map(|feed| feed.name.as_str())
+    }
+
+    /// Seconds since each feed last downloaded successfully; feeds that never have are left out.
+    pub fn ages(&self) -> Vec<(&str, u64)> {
+        let now = unix_now();
+        self.feeds
+            .iter()
+            .filter_map(|feed| match feed.updated.load(Ordering::Relaxed) {
+                0 => None,
+                updated => Some((feed.name.as_str(), now.saturating_sub(updated))),
+            })
+            .collect()
+    }
+}
+
+/// The Tor Project's exit node list, loaded as a single feed.
+pub struct TorExits {
+    list: IpReputation,
+    pub action: TorExitAction,
+    pub header: HeaderName,
+}
+
+impl TorExits {
+    /// Feed name used in logs and `ip_reputation_feed_age_seconds`
+    const FEED: &'static str = "tor_exit_nodes";
+
+    pub fn load(config: &TorExitConfig) -> Result<Self, ConfigError> {
+        let feed = IpFeedConfig {
+            url: config.url.clone(),
+            refresh_secs: config.refresh_secs,
+        };
+        Ok(Self {
+            list: IpReputation::load(&BTreeMap::from([(Self::FEED.to_string(), feed)]))?,
+            action: config.action,
+            // Checked during config validation
+            header: HeaderName::from_bytes(config.header.as_bytes())
+                .map_err(|e| ConfigError::Validation(format!("tor_exit_nodes.header: {}", e)))?,
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.list.listed(ip).is_some()
+    }
+
+    pub fn ages(&self) -> Vec<(&str, u64)> {
+        self.list.ages()
+    }
+}
+
+fn spawn_refresh(feed: &Arc<Feed>, every: Duration) {
+    let weak: Weak<Feed> = Arc::downgrade(feed);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(every);
+        let Some(feed) = weak.upgrade() else {
+            return;
+        };
+        feed.refresh();
+    });
+}
+
+impl Feed {
+    fn refresh(&self) {
+        match self.download() {
+            Ok(ranges) => {
+                tracing::info!(feed = %self.name, entries = ranges.entries, "IP reputation feed loaded");
+                self.ranges.store(Arc::new(ranges));
+                self.updated.store(unix_now(), Ordering::Relaxed);
+            }
+            Err(e) => {
+                tracing::warn!(feed = %self.name, url = %self.url, error = %e, "IP reputation feed refresh failed, keeping current list")
+            }
+        }
+    }
+
+    fn download(&self) -> Result<PrefixTree, String> {
+        let body = tokio::task::block_in_place(|| {
+            self.client
+                .get(&self.url)
+                .send()
+                .and_then(|r| r.error_for_status())
+                .and_then(|r| r.text())
+        })
+        .map_err(|e| e.to_string())?;
+        let mut ranges = PrefixTree::default();
+        for line in body.lines() {
+            // Spamhaus DROP puts the SBL reference after `;`, other lists comment with `#`
+            let entry = line.split([';', '#']).next().unwrap_or("");
+            let Some(entry) = entry.split_whitespace().next() else {
+                continue;
+            };
+            if let Some((ip, len)) = parse_range(entry) {
+                ranges.insert(ip, len);
+            }
+        }
+        // An error page served with 200 shouldn't wipe the list
+        if ranges.entries == 0 && body.lines().any(|l| !l.trim().is_empty()) {
+            return Err("no addresses or ranges in response".to_string());
+        }
+        Ok(ranges)
+    }
+}
+
+/// `addr` or `addr/len`.
+pub fn parse_range(entry: &str) -> Option<(IpAddr, u8)> {
+    let (addr, len) = match entry.split_once('/') {
+        Some((addr, len)) => (addr, Some(len.parse::<u8>().ok()?)),
+        None => (entry, None),
+    };
+    let ip: IpAddr = addr.parse().ok()?;
+    let width = if ip.is_ipv4() { 32 } else { 128 };
+    match len {
+        Some(len) if len > width => None,
+        len => Some((ip, len.unwrap_or(width))),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Binary radix tree over address bits, one root per family. A node marked `listed` covers
+/// everything below it, so lookups stop at the shortest matching prefix.
+#[derive(Default)]
+pub struct PrefixTree {
+    v4: Vec<Node>,
+    v6: Vec<Node>,
+    entries: usize,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Node {
+    /// Indexes into the family's nodes; 0 (the root) means no child
+    children: [u32; 2],
+    listed: bool,
+}
+
+impl PrefixTree {
+    pub fn insert(&mut self, ip: IpAddr, len: u8) {
+        let (nodes, bits, width) = self.family(ip);
+        if nodes.is_empty() {
+            nodes.push(Node::default());
+        }
+        let mut at = 0;
+        for i in 0..len {
+            if nodes[at].listed {
+                return;
+            }
+            let bit = ((bits >> (width - 1 - i)) & 1) as usize;
+            at = match nodes[at].children[bit] {
+                0 => {
+                    nodes.push(Node::default());
+                    let child = nodes.len() - 1;
+                    nodes[at].children[bit] = child as u32;
+                    child
+                }
+                child => child as usize,
+            };
+        }
+        if !nodes[at].listed {
+            nodes[at] = Node {
+                children: [0, 0],
+                listed: true,
+            };
+            self.entries += 1;
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (nodes, bits, width) = match ip {
+            IpAddr::V4(v4) => (&self.v4, u128::from(u32::from(v4)), 32),
+            IpAddr::V6(v6) => (&self.v6, u128::from(v6), 128),
+        };
+        let mut at = 0;
+        for i in 0..=width {
+            let Some(node) = nodes.get(at) else {
+                return false;
+            };
+            if node.listed {
+                return true;
+            }
+            if i == width {
+                break;
+            }
+            let bit = ((bits >> (width - 1 - i)) & 1) as usize;
+            at = match node.children[bit] {
+                0 => return false,
+                child => child as usize,
+            };
+        }
+        false
+    }
+
+    fn family(&mut self, ip: IpAddr) -> (&mut Vec<Node>, u128, u8) {
+        match ip {
+            IpAddr::V4(v4) => (&mut self.v4, u128::from(u32::from(v4)), 32),
+            IpAddr::V6(v6) => (&mut self.v6, u128::from(v6), 128),
+        }
+    }
+}
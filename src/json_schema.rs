@@ -0,0 +1,710 @@
+use crate::configuration::{ConfigError, JsonSchemaConfig};
+use crate::middleware::{media_type, Held, HeldBody, Rejection};
+use bytes::Bytes;
+use pingora::http::RequestHeader;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Validation stops collecting errors after this many
+const MAX_ERRORS: usize = 10;
+
+/// Compiled JSON Schemas sharing one document for `$ref` resolution, e.g. every schema of an
+/// OpenAPI spec. Covers the validation keywords of draft 2020-12 and OpenAPI 3.0 (`nullable`,
+/// boolean `exclusiveMinimum`); annotations such as `format` are ignored.
+pub struct Schemas {
+    nodes: Vec<Node>,
+}
+
+/// A schema within its `Schemas`
+#[derive(Debug, Clone, Copy)]
+pub struct SchemaId(usize);
+
+#[derive(Debug)]
+pub struct SchemaError {
+    /// JSON pointer to the offending value; empty for the document itself
+    pub pointer: String,
+    pub message: String,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pointer = if self.pointer.is_empty() {
+            "/"
+        } else {
+            &self.pointer
+        };
+        write!(f, "{}: {}", pointer, self.message)
+    }
+}
+
+enum Node {
+    Any,
+    Never,
+    Rules(Box<Rules>),
+}
+
+#[derive(Default)]
+struct Rules {
+    reference: Option<usize>,
+    /// Any type if empty
+    types: Vec<JsonType>,
+    nullable: bool,
+    enumeration: Option<Vec<Value>>,
+    constant: Option<Value>,
+    properties: Vec<(String, usize)>,
+    required: Vec<String>,
+    additional: Option<usize>,
+    min_properties: Option<usize>,
+    max_properties: Option<usize>,
+    items: Option<usize>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    pattern: Option<Regex>,
+    /// Bound and whether it's exclusive
+    minimum: Option<(f64, bool)>,
+    maximum: Option<(f64, bool)>,
+    multiple_of: Option<f64>,
+    all_of: Vec<usize>,
+    any_of: Vec<usize>,
+    one_of: Vec<usize>,
+    not: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum JsonType {
+    Null,
+    Boolean,
+    Integer,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+impl JsonType {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "null" => JsonType::Null,
+            "boolean" => JsonType::Boolean,
+            "integer" => JsonType::Integer,
+            "number" => JsonType::Number,
+            "string" => JsonType::String,
+            "array" => JsonType::Array,
+            "object" => JsonType::Object,
+            _ => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            JsonType::Null => "null",
+            JsonType::Boolean => "boolean",
+            JsonType::Integer => "integer",
+            JsonType::Number => "number",
+            JsonType::String => "string",
+            JsonType::Array => "array",
+            JsonType::Object => "object",
+        }
+    }
+
+    fn matches(self, value: &Value) -> bool {
+        match (self, value) {
+            (JsonType::Null, Value::Null)
+            | (JsonType::Boolean, Value::Bool(_))
+            | (JsonType::Number, Value::Number(_))
+            | (JsonType::String, Value::String(_))
+            | (JsonType::Array, Value::Array(_))
+            | (JsonType::Object, Value::Object(_)) => true,
+            (JsonType::Integer, Value::Number(n)) => {
+                n.is_i64() || n.is_u64() || n.as_f64().is_some_and(|f| f.fract() == 0.0)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Compiles schemas out of `document`, resolving `$ref`s (`#/...` pointers) against it.
+pub struct Compiler<'a> {
+    document: &'a Value,
+    nodes: Vec<Node>,
+    refs: HashMap<String, usize>,
+    /// Nodes known not to reach a cycle of schemas applied to the same value
+    acyclic: Vec<bool>,
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(document: &'a Value) -> Self {
+        Self {
+            document,
+            nodes: Vec::new(),
+            refs: HashMap::new(),
+            acyclic: Vec::new(),
+        }
+    }
+
+    pub fn compile(&mut self, schema: &Value) -> Result<SchemaId, String> {
+        let index = self.node(schema)?;
+        self.acyclic.resize(self.nodes.len(), false);
+        let mut on_path = vec![false; self.nodes.len()];
+        for node in 0..self.nodes.len() {
+            self.reject_cycles(node, &mut on_path)?;
+        }
+        Ok(SchemaId(index))
+    }
+
+    /// Refuses `$ref` cycles that keep applying schemas to the same value without descending
+    /// into it (`{"$ref": "#"}`, or A to B to A through `allOf`); validating against one would
+    /// never finish. Cycles through `properties` or `items` end with the value.
+    fn reject_cycles(&mut self, index: usize, on_path: &mut [bool]) -> Result<(), String> {
+        if self.acyclic[index] {
+            return Ok(());
+        }
+        if on_path[index] {
+            return Err("$ref cycle that never descends into the value".into());
+        }
+        let Node::Rules(rules) = &self.nodes[index] else {
+            self.acyclic[index] = true;
+            return Ok(());
+        };
+        let same_value: Vec<usize> = rules
+            .reference
+            .iter()
+            .chain(&rules.all_of)
+            .chain(&rules.any_of)
+            .chain(&rules.one_of)
+            .chain(&rules.not)
+            .copied()
+            .collect();
+        on_path[index] = true;
+        for next in same_value {
+            self.reject_cycles(next, on_path)?;
+        }
+        on_path[index] = false;
+        self.acyclic[index] = true;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Schemas {
+        Schemas { nodes: self.nodes }
+    }
+
+    fn node(&mut self, schema: &Value) -> Result<usize, String> {
+        let index = self.nodes.len();
+        self.nodes.push(Node::Any);
+        self.nodes[index] = match schema {
+            Value::Bool(true) => Node::Any,
+            Value::Bool(false) => Node::Never,
+            Value::Object(_) => Node::Rules(Box::new(self.rules(schema)?)),
+            _ => return Err("schema must be an object or a boolean".into()),
+        };
+        Ok(index)
+    }
+
+    fn reference(&mut self, reference: &str) -> Result<usize, String> {
+        if let Some(index) = self.refs.get(reference) {
+            return Ok(*index);
+        }
+        let target = reference
+            .strip_prefix('#')
+            .and_then(|pointer| self.document.pointer(pointer))
+            .ok_or_else(|| format!("unresolvable $ref '{}'", reference))?;
+        // Registered before compiling so recursive schemas refer back to it
+        let index = self.nodes.len();
+        self.refs.insert(reference.to_string(), index);
+        self.node(target)?;
+        Ok(index)
+    }
+
+    fn rules(&mut self, schema: &Value) -> Result<Rules, String> {
+        let mut rules = Rules::default();
+        let size = |key: &str| schema.get(key).and_then(Value::as_u64).map(|n| n as usize);
+        let number = |key: &str| schema.get(key).and_then(Value::as_f64);
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            rules.reference = Some(self.reference(reference)?);
+        }
+        rules.types = match schema.get("type") {
+            None => Vec::new(),
+            Some(Value::String(name)) => vec![parse_type(name)?],
+            Some(Value::Array(names)) => names
+                .iter()
+                .map(|n| parse_type(n.as_str().unwrap_or_default()))
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err("type must be a string or an array".into()),
+        };
+        rules.nullable = schema.get("nullable") == Some(&Value::Bool(true));
+        rules.enumeration = schema.get("enum").and_then(Value::as_array).cloned();
+        rules.constant = schema.get("const").cloned();
+        if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+            for (name, property) in properties {
+                let index = self.node(property)?;
+                rules.properties.push((name.clone(), index));
+            }
+        }
+        rules.required = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|n| n.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if let Some(additional) = schema.get("additionalProperties") {
+            rules.additional = Some(self.node(additional)?);
+        }
+        rules.min_properties = size("minProperties");
+        rules.max_properties = size("maxProperties");
+        if let Some(items) = schema.get("items") {
+            rules.items = Some(self.node(items)?);
+        }
+        rules.min_items = size("minItems");
+        rules.max_items = size("maxItems");
+        rules.min_length = size("minLength");
+        rules.max_length = size("maxLength");
+        if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+            rules.pattern = Some(
+                Regex::new(pattern).map_err(|e| format!("invalid pattern '{}': {}", pattern, e))?,
+            );
+        }
+        // OpenAPI 3.0 flags the bound exclusive; 2020-12 gives the exclusive bound itself
+        rules.minimum = match schema.get("exclusiveMinimum") {
+            Some(Value::Bool(exclusive)) => number("minimum").map(|m| (m, *exclusive)),
+            Some(Value::Number(bound)) => bound.as_f64().map(|b| (b, true)),
+            _ => number("minimum").map(|m| (m, false)),
+        };
+        rules.maximum = match schema.get("exclusiveMaximum") {
+            Some(Value::Bool(exclusive)) => number("maximum").map(|m| (m, *exclusive)),
+            Some(Value::Number(bound)) => bound.as_f64().map(|b| (b, true)),
+            _ => number("maximum").map(|m| (m, false)),
+        };
+        rules.multiple_of = number("multipleOf").filter(|m| *m > 0.0);
+        for (key, list) in [
+            ("allOf", &mut rules.all_of),
+            ("anyOf", &mut rules.any_of),
+            ("oneOf", &mut rules.one_of),
+        ] {
+            for schema in schema
+                .get(key)
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+            {
+                list.push(self.node(schema)?);
+            }
+        }
+        if let Some(not) = schema.get("not") {
+            rules.not = Some(self.node(not)?);
+        }
+        Ok(rules)
+    }
+}
+
+fn parse_type(name: &str) -> Result<JsonType, String> {
+    JsonType::parse(name).ok_or_else(|| format!("unknown type '{}'", name))
+}
+
+impl Schemas {
+    pub fn validate(&self, schema: SchemaId, value: &Value) -> Result<(), Vec<SchemaError>> {
+        let mut errors = Vec::new();
+        self.check(schema.0, value, &mut String::new(), &mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn is_valid(&self, index: usize, value: &Value) -> bool {
+        let mut errors = Vec::new();
+        self.check(index, value, &mut String::new(), &mut errors);
+        errors.is_empty()
+    }
+
+    fn check(
+        &self,
+        index: usize,
+        value: &Value,
+        pointer: &mut String,
+        errors: &mut Vec<SchemaError>,
+    ) {
+        if errors.len() >= MAX_ERRORS {
+            return;
+        }
+        let rules = match &self.nodes[index] {
+            Node::Any => return,
+            Node::Never => return fail(errors, pointer, "not allowed".into()),
+            Node::Rules(rules) => rules,
+        };
+        if value.is_null() && rules.nullable {
+            return;
+        }
+        if let Some(reference) = rules.reference {
+            self.check(reference, value, pointer, errors);
+        }
+        if !rules.types.is_empty() && !rules.types.iter().any(|t| t.matches(value)) {
+            let expected: Vec<&str> = rules.types.iter().map(|t| t.name()).collect();
+            return fail(
+                errors,
+                pointer,
+                format!("expected {}", expected.join(" or ")),
+            );
+        }
+        if let Some(allowed) = &rules.enumeration {
+            if !allowed.contains(value) {
+                fail(errors, pointer, "not one of the allowed values".into());
+            }
+        }
+        if rules.constant.as_ref().is_some_and(|c| c != value) {
+            fail(errors, pointer, "not the required constant".into());
+        }
+        match value {
+            Value::Object(object) => self.check_object(rules, object, pointer, errors),
+            Value::Array(items) => {
+                if rules.min_items.is_some_and(|min| items.len() < min) {
+                    fail(errors, pointer, "too few items".into());
+                }
+                if rules.max_items.is_some_and(|max| items.len() > max) {
+                    fail(errors, pointer, "too many items".into());
+                }
+                if let Some(schema) = rules.items {
+                    for (i, item) in items.iter().enumerate() {
+                        self.check_child(schema, item, pointer, &i.to_string(), errors);
+                    }
+                }
+            }
+            Value::String(s) => {
+                let len = s.chars().count();
+                if rules.min_length.is_some_and(|min| len < min) {
+                    fail(
+                        errors,
+                        pointer,
+                        format!(
+                            "shorter than {} characters",
+                            rules.min_length.unwrap_or_default()
+                        ),
+                    );
+                }
+                if rules.max_length.is_some_and(|max| len > max) {
+                    fail(
+                        errors,
+                        pointer,
+                        format!(
+                            "longer than {} characters",
+                            rules.max_length.unwrap_or_default()
+                        ),
+                    );
+                }
+                if rules.pattern.as_ref().is_some_and(|p| !p.is_match(s)) {
+                    fail(errors, pointer, "does not match the pattern".into());
+                }
+            }
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or_default();
+                if let Some((min, exclusive)) = rules.minimum {
+                    if n < min || (exclusive && n == min) {
+                        fail(errors, pointer, format!("below the minimum {}", min));
+                    }
+                }
+                if let Some((max, exclusive)) = rules.maximum {
+                    if n > max || (exclusive && n == max) {
+                        fail(errors, pointer, format!("above the maximum {}", max));
+                    }
+                }
+                if let Some(step) = rules.multiple_of {
+                    if ((n / step) - (n / step).round()).abs() > 1e-9 {
+                        fail(errors, pointer, format!("not a multiple of {}", step));
+                    }
+                }
+            }
+            _ => {}
+        }
+        for schema in &rules.all_of {
+            self.check(*schema, value, pointer, errors);
+        }
+        if !rules.any_of.is_empty() && !rules.any_of.iter().any(|s| self.is_valid(*s, value)) {
+            fail(errors, pointer, "matches none of anyOf".into());
+        }
+        if !rules.one_of.is_empty() {
+            let matched = rules
+                .one_of
+                .iter()
+                .filter(|s| self.is_valid(**s, value))
+                .count();
+            if matched != 1 {
+                fail(
+                    errors,
+                    pointer,
+                    format!("matches {} of oneOf, expected 1", matched),
+                );
+            }
+        }
+        if rules.not.is_some_and(|s| self.is_valid(s, value)) {
+            fail(errors, pointer, "matches a disallowed schema".into());
+        }
+    }
+
+    fn check_object(
+        &self,
+        rules: &Rules,
+        object: &serde_json::Map<String, Value>,
+        pointer: &mut String,
+        errors: &mut Vec<SchemaError>,
+    ) {
+        for name in &rules.required {
+            if !object.contains_key(name) {
+                fail(
+                    errors,
+                    pointer,
+                    format!("missing required property '{}'", name),
+                );
+            }
+        }
+        if rules.min_properties.is_some_and(|min| object.len() < min) {
+            fail(errors, pointer, "too few properties".into());
+        }
+        if rules.max_properties.is_some_and(|max| object.len() > max) {
+            fail(errors, pointer, "too many properties".into());
+        }
+        for (name, value) in object {
+            match rules.properties.iter().find(|(n, _)| n == name) {
+                Some((_, schema)) => self.check_child(*schema, value, pointer, name, errors),
+                None => {
+                    if let Some(schema) = rules.additional {
+                        if matches!(self.nodes[schema], Node::Never) {
+                            fail(errors, pointer, format!("unexpected property '{}'", name));
+                        } else {
+                            self.check_child(schema, value, pointer, name, errors);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_child(
+        &self,
+        schema: usize,
+        value: &Value,
+        pointer: &mut String,
+        token: &str,
+        errors: &mut Vec<SchemaError>,
+    ) {
+        let len = pointer.len();
+        pointer.push('/');
+        pointer.push_str(&token.replace('~', "~0").replace('/', "~1"));
+        self.check(schema, value, pointer, errors);
+        pointer.truncate(len);
+    }
+}
+
+fn fail(errors: &mut Vec<SchemaError>, pointer: &str, message: String) {
+    if errors.len() < MAX_ERRORS {
+        errors.push(SchemaError {
+            pointer: pointer.to_string(),
+            message,
+        });
+    }
+}
+
+/// A route's `json_schema`: request bodies declared as JSON are buffered (up to
+/// `max_body_kb`) and validated before any of them is forwarded. Invalid ones get a 422
+/// listing each violation as a JSON pointer and message.
+pub struct BodySchema {
+    schemas: Schemas,
+    root: SchemaId,
+    max_body_bytes: usize,
+}
+
+/// A request body held back until it can be validated whole.
+pub struct BodyCheck {
+    held: HeldBody,
+}
+
+impl BodySchema {
+    pub fn load(config: &JsonSchemaConfig) -> Result<Self, ConfigError> {
+        let invalid =
+            |e: String| ConfigError::Validation(format!("json_schema {}: {}", config.schema, e));
+        let text = std::fs::read_to_string(&config.schema)
+            .map_err(|e| ConfigError::Io(config.schema.clone(), e))?;
+        let document: Value = serde_yaml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+        let mut compiler = Compiler::new(&document);
+        let root = compiler.compile(&document).map_err(invalid)?;
+        Ok(Self {
+            schemas: compiler.finish(),
+            root,
+            max_body_bytes: config.max_body_kb * 1024,
+        })
+    }
+
+    /// Bodies must be JSON; returns a `BodyCheck` for requests that may carry one.
+    pub fn check(&self, req: &RequestHeader) -> Result<Option<BodyCheck>, Rejection> {
+        if req.method == http::Method::GET || req.method == http::Method::HEAD {
+            return Ok(None);
+        }
+        match media_type(req) {
+            Some(media) if media != "application/json" && !media.ends_with("+json") => Err(
+                Rejection::new(415, "json_schema_unsupported_media_type", media),
+            ),
+            _ => Ok(Some(BodyCheck {
+                held: HeldBody::default(),
+            })),
+        }
+    }
+
+    /// Validates the body once it's all in, answering violations with a JSON error body.
+    pub fn check_body(
+        &self,
+        check: &mut BodyCheck,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        monitor: bool,
+    ) -> Result<(), Rejection> {
+        let result = match check.held.hold(body, end_of_stream, self.max_body_bytes) {
+            Held::Pending => return Ok(()),
+            Held::TooLarge => {
+                let detail = format!("body exceeds {} bytes", self.max_body_bytes);
+                let response = json!({"error": "body_too_large", "message": detail});
+                Err(Rejection::new(413, "json_schema_body_too_large", detail)
+                    .with_response(response))
+            }
+            // Nothing to validate
+            Held::Complete([]) => Ok(()),
+            Held::Complete(bytes) => match serde_json::from_slice::<Value>(bytes) {
+                Ok(value) => self.schemas.validate(self.root, &value).map_err(|errors| {
+                    let details: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                    let response = json!({
+                        "error": "invalid_body",
+                        "message": "request body does not match the schema",
+                        "errors": errors
+                            .iter()
+                            .map(|e| json!({"pointer": e.pointer, "message": e.message}))
+                            .collect::<Vec<_>>(),
+                    });
+                    Rejection::new(422, "json_schema_invalid", details.join("; "))
+                        .with_response(response)
+                }),
+                Err(e) => {
+                    let response = json!({"error": "malformed_json", "message": e.to_string()});
+                    Err(Rejection::new(400, "json_schema_malformed", e.to_string())
+                        .with_response(response))
+                }
+            },
+        };
+        check.held.settle(body, result, monitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(schema: Value) -> Result<(Schemas, SchemaId), String> {
+        let mut compiler = Compiler::new(&schema);
+        let root = compiler.compile(&schema)?;
+        Ok((compiler.finish(), root))
+    }
+
+    fn errors(schema: Value, value: Value) -> Vec<String> {
+        let (schemas, root) = compile(schema).unwrap();
+        match schemas.validate(root, &value) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn nullable_admits_null_only_when_set() {
+        let schema = json!({"type": "string", "nullable": true});
+        assert!(errors(schema.clone(), json!(null)).is_empty());
+        assert!(errors(schema, json!("x")).is_empty());
+        assert_eq!(
+            errors(json!({"type": "string"}), json!(null)),
+            ["/: expected string"]
+        );
+    }
+
+    #[test]
+    fn exclusive_minimum_takes_both_forms() {
+        // OpenAPI 3.0: a flag on `minimum`
+        let flagged = json!({"type": "number", "minimum": 0, "exclusiveMinimum": true});
+        assert_eq!(
+            errors(flagged.clone(), json!(0)),
+            ["/: below the minimum 0"]
+        );
+        assert!(errors(flagged, json!(0.5)).is_empty());
+        let inclusive = json!({"minimum": 0, "exclusiveMinimum": false});
+        assert!(errors(inclusive, json!(0)).is_empty());
+        // 2020-12: the bound itself
+        let bound = json!({"exclusiveMinimum": 1, "exclusiveMaximum": 3});
+        assert!(errors(bound.clone(), json!(2)).is_empty());
+        assert_eq!(errors(bound.clone(), json!(1)), ["/: below the minimum 1"]);
+        assert_eq!(errors(bound, json!(3)), ["/: above the maximum 3"]);
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_value() {
+        let schema = json!({
+            "type": "object",
+            "required": ["items"],
+            "properties": {
+                "items": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {"a/b": {"type": "integer"}},
+                        "additionalProperties": false
+                    }
+                }
+            }
+        });
+        assert_eq!(
+            errors(
+                schema.clone(),
+                json!({"items": [{"a/b": 1}, {"a/b": "x", "c": 1}]})
+            ),
+            [
+                "/items/1/a~1b: expected integer",
+                "/items/1: unexpected property 'c'"
+            ]
+        );
+        assert_eq!(
+            errors(schema, json!({})),
+            ["/: missing required property 'items'"]
+        );
+    }
+
+    #[test]
+    fn cycles_that_never_descend_are_refused() {
+        assert!(compile(json!({"$ref": "#"})).is_err());
+        let mutual = json!({
+            "$ref": "#/$defs/a",
+            "$defs": {
+                "a": {"allOf": [{"$ref": "#/$defs/b"}]},
+                "b": {"anyOf": [{"$ref": "#/$defs/a"}]}
+            }
+        });
+        assert!(compile(mutual).is_err());
+    }
+
+    #[test]
+    fn recursion_through_properties_is_allowed() {
+        let tree = json!({
+            "type": "object",
+            "properties": {
+                "value": {"type": "integer"},
+                "children": {"type": "array", "items": {"$ref": "#"}}
+            }
+        });
+        let value = json!({"value": 1, "children": [{"value": 2, "children": [{"value": "x"}]}]});
+        assert_eq!(
+            errors(tree, value),
+            ["/children/0/children/0/value: expected integer"]
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use crate::configuration::ConfigError;
+use arc_swap::ArcSwap;
+use jsonwebtoken::jwk::{AlgorithmParameters, Jwk};
+use jsonwebtoken::{Algorithm, DecodingKey};
+use serde_json::Value;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Verification keys published at a JWKS URL, re-fetched in the background so issuer key
+/// rotation doesn't need a reload. The refresh thread stops once the set is dropped, i.e.
+/// after the security layer holding it is replaced.
+pub struct Jwks {
+    url: String,
+    client: reqwest::blocking::Client,
+    keys: ArcSwap<Vec<JwksKey>>,
+}
+
+struct JwksKey {
+    kid: Option<String>,
+    key: DecodingKey,
+    /// Algorithms a token may name for this key type; symmetric keys are never accepted
+    algorithms: &'static [Algorithm],
+}
+
+impl Jwks {
+    /// Fetches the key set, so a bad URL fails the load.
+    pub fn fetch(url: &str, refresh: Duration) -> Result<Arc<Self>, ConfigError> {
+        let client = tokio::task::block_in_place(|| {
+            reqwest::blocking::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+        })
+        .map_err(|e| ConfigError::Secret(format!("jwks client: {}", e)))?;
+        let jwks = Arc::new(Self {
+            url: url.to_string(),
+            client,
+            keys: ArcSwap::from_pointee(Vec::new()),
+        });
+        jwks.keys.store(Arc::new(jwks.download()?));
+
+        let weak: Weak<Self> = Arc::downgrade(&jwks);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(refresh);
+            let Some(jwks) = weak.upgrade() else {
+                return;
+            };
+            match jwks.download() {
+                Ok(keys) => jwks.keys.store(Arc::new(keys)),
+                Err(e) => {
+                    tracing::warn!(url = %jwks.url, error = %e, "JWKS refresh failed, keeping current keys")
+                }
+            }
+        });
+        Ok(jwks)
+    }
+
+    /// The key for a token's `kid` and `alg`; a token without `kid` matches a single-key set.
+    pub fn key(&self, kid: Option<&str>, alg: Algorithm) -> Option<DecodingKey> {
+        let keys = self.keys.load();
+        let candidates: Vec<&JwksKey> = keys
+            .iter()
+            .filter(|k| k.algorithms.contains(&alg))
+            .collect();
+        match kid {
+            Some(kid) => candidates
+                .into_iter()
+                .find(|k| k.kid.as_deref() == Some(kid)),
+            None if candidates.len() == 1 => candidates.into_iter().next(),
+            None => None,
+        }
+        .map(|k| k.key.clone())
+    }
+
+    fn download(&self) -> Result<Vec<JwksKey>, ConfigError> {
+        let fail = |e: String| ConfigError::Secret(format!("jwks {}: {}", self.url, e));
+        let body: Value = tokio::task::block_in_place(|| {
+            self.client
+                .get(&self.url)
+                .send()
+                .and_then(|r| r.error_for_status())
+                .and_then(|r| r.json())
+        })
+        .map_err(|e| fail(e.to_string()))?;
+        // Keys of types jsonwebtoken can't parse are skipped rather than failing the whole set
+        let keys: Vec<JwksKey> = body
+            .get("keys")
+            .and_then(Value::as_array)
+            .ok_or_else(|| fail("no keys array".to_string()))?
+            .iter()
+            .filter_map(|k| serde_json::from_value::<Jwk>(k.clone()).ok())
+            .filter_map(|jwk| {
+                let algorithms: &'static [Algorithm] = match &jwk.algorithm {
+                    AlgorithmParameters::RSA(_) => &[
+                        Algorithm::RS256,
+                        Algorithm::RS384,
+                        Algorithm::RS512,
+                        Algorithm::PS256,
+                        Algorithm::PS384,
+                        Algorithm::PS512,
+                    ],
+                    AlgorithmParameters::EllipticCurve(_) => &[Algorithm::ES256, Algorithm::ES384],
+                    AlgorithmParameters::OctetKeyPair(_) => &[Algorithm::EdDSA],
+                    AlgorithmParameters::OctetKey(_) => return None,
+                };
+                Some(JwksKey {
+                    kid: jwk.common.key_id.clone(),
+                    key: DecodingKey::from_jwk(&jwk).ok()?,
+                    algorithms,
+                })
+            })
+            .collect();
+        if keys.is_empty() {
+            return Err(fail("no usable keys".to_string()));
+        }
+        Ok(keys)
+    }
+}
@@ -0,0 +1,207 @@
+use async_trait::async_trait;
+use pingora::apps::ServerApp;
+use pingora::lb::selection::RoundRobin;
+use pingora::lb::LoadBalancer;
+use pingora::protocols::Stream;
+use pingora::server::ShutdownWatch;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a passthrough client gets to send its ClientHello
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest TLS record allowed by RFC 8446 (plaintext limit plus expansion)
+const MAX_RECORD_LEN: usize = 16384 + 2048;
+
+/// Raw TCP proxy for non-HTTP backends (databases, MQTT brokers). Each client connection is
+/// piped to one healthy backend picked by the service's own load balancer.
+///
+/// With SNI routes the service does TLS passthrough: it reads the ClientHello, picks the pool
+/// by server name and replays the hello to the backend, which terminates TLS itself.
+pub struct TcpProxy {
+    pub name: String,
+    /// Pool for connections no SNI route matches
+    pub lb: Option<Arc<LoadBalancer<RoundRobin>>>,
+    pub sni_routes: Vec<SniRoute>,
+}
+
+pub struct SniRoute {
+    pub server_names: Vec<String>,
+    pub lb: Arc<LoadBalancer<RoundRobin>>,
+}
+
+impl SniRoute {
+    fn matches(&self, server_name: &str) -> bool {
+        self.server_names
+            .iter()
+            .any(|pattern| match pattern.strip_prefix('*') {
+                Some(suffix) => {
+                    server_name.len() > suffix.len()
+                        && server_name[server_name.len() - suffix.len()..]
+                            .eq_ignore_ascii_case(suffix)
+                }
+                None => pattern.eq_ignore_ascii_case(server_name),
+            })
+    }
+}
+
+impl TcpProxy {
+    /// Reads the first TLS record and picks the pool for its SNI. Returns the bytes read so
+    /// they can be replayed to the backend.
+    async fn route_by_sni(
+        &self,
+        session: &mut Stream,
+    ) -> Option<(Vec<u8>, Option<&Arc<LoadBalancer<RoundRobin>>>)> {
+        let mut head = Vec::new();
+        let read = async {
+            while head.len() < 5 || head.len() < record_len(&head) {
+                if head.len() >= 5 && (head[0] != 0x16 || record_len(&head) > MAX_RECORD_LEN) {
+                    // Not a TLS handshake; no name to route on
+                    break;
+                }
+                if session.read_buf(&mut head).await.ok()? == 0 {
+                    return None;
+                }
+            }
+            Some(())
+        };
+        match tokio::time::timeout(CLIENT_HELLO_TIMEOUT, read).await {
+            Ok(Some(())) => {}
+            Ok(None) => return None,
+            Err(_) => {
+                tracing::debug!(service = %self.name, "timed out waiting for ClientHello");
+                return None;
+            }
+        }
+        let server_name = client_hello_sni(&head);
+        let lb = match &server_name {
+            Some(name) => self
+                .sni_routes
+                .iter()
+                .find(|r| r.matches(name))
+                .map(|r| &r.lb)
+                .or(self.lb.as_ref()),
+            None => self.lb.as_ref(),
+        };
+        if lb.is_none() {
+            tracing::warn!(service = %self.name, sni = ?server_name, "no route for server name");
+        }
+        Some((head, lb))
+    }
+}
+
+#[async_trait]
+impl ServerApp for TcpProxy {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut session: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let (preface, lb) = if self.sni_routes.is_empty() {
+            (Vec::new(), self.lb.as_ref())
+        } else {
+            self.route_by_sni(&mut session).await?
+        };
+        let Some(backend) = lb?.select(b"", 256) else {
+            tracing::error!(service = %self.name, "no healthy upstream");
+            return None;
+        };
+        let addr = backend.addr.as_inet()?;
+        let mut upstream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+            .await
+        {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                tracing::error!(service = %self.name, upstream = %addr, error = %e, "upstream connect failed");
+                return None;
+            }
+            Err(_) => {
+                tracing::error!(service = %self.name, upstream = %addr, "upstream connect timed out");
+                return None;
+            }
+        };
+        if !preface.is_empty() {
+            if let Err(e) = upstream.write_all(&preface).await {
+                tracing::error!(service = %self.name, upstream = %addr, error = %e, "upstream write failed");
+                return None;
+            }
+        }
+        if let Err(e) = tokio::io::copy_bidirectional(&mut session, &mut upstream).await {
+            tracing::debug!(service = %self.name, error = %e, "tcp session closed");
+        }
+        None
+    }
+}
+
+/// Total length of the TLS record starting at `buf`, header included.
+fn record_len(buf: &[u8]) -> usize {
+    5 + u16::from_be_bytes([buf[3], buf[4]]) as usize
+}
+
+/// Host name from the server_name extension of a ClientHello in the first TLS record.
+/// A hello fragmented over several records is treated as having no SNI.
+fn client_hello_sni(record: &[u8]) -> Option<String> {
+    if record.len() < 5 || record[0] != 0x16 {
+        return None;
+    }
+    let mut r = Reader(record.get(5..record_len(record))?);
+    if r.u8()? != 0x01 {
+        return None;
+    }
+    let hello_len = r.u24()?;
+    let mut hello = Reader(r.take(hello_len)?);
+    hello.take(2 + 32)?; // legacy_version, random
+    let session_id = hello.u8()? as usize;
+    hello.take(session_id)?;
+    let cipher_suites = hello.u16()? as usize;
+    hello.take(cipher_suites)?;
+    let compression = hello.u8()? as usize;
+    hello.take(compression)?;
+    let extensions_len = hello.u16()? as usize;
+    let mut extensions = Reader(hello.take(extensions_len)?);
+    while let (Some(kind), Some(len)) = (extensions.u16(), extensions.u16()) {
+        let mut data = Reader(extensions.take(len as usize)?);
+        if kind != 0x0000 {
+            continue;
+        }
+        let list_len = data.u16()? as usize;
+        let mut names = Reader(data.take(list_len)?);
+        while let Some(name_type) = names.u8() {
+            let name_len = names.u16()? as usize;
+            let name = names.take(name_len)?;
+            if name_type == 0x00 {
+                return std::str::from_utf8(name).ok().map(str::to_ascii_lowercase);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+}
@@ -0,0 +1,58 @@
+use std::sync::Mutex;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// The tracing filter of the running process, swapped by the admin API and SIGUSR2 for live
+/// debugging. Changes last until the next change or restart.
+pub struct LogLevel {
+    handle: reload::Handle<EnvFilter, Registry>,
+    /// `RUST_LOG`, or `info`
+    initial: String,
+    current: Mutex<String>,
+}
+
+impl LogLevel {
+    /// The filter layer to install, and its controller.
+    pub fn new() -> (reload::Layer<EnvFilter, Registry>, Self) {
+        let initial = std::env::var(EnvFilter::DEFAULT_ENV)
+            .ok()
+            .filter(|directives| EnvFilter::try_new(directives).is_ok())
+            .unwrap_or_else(|| "info".to_string());
+        let (layer, handle) = reload::Layer::new(EnvFilter::new(&initial));
+        let level = Self {
+            handle,
+            current: Mutex::new(initial.clone()),
+            initial,
+        };
+        (layer, level)
+    }
+
+    pub fn current(&self) -> String {
+        self.current
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Applies `EnvFilter` directives such as `info,reverse_proxy::security=debug`; the
+    /// startup filter if empty.
+    pub fn set(&self, directives: &str) -> Result<(), String> {
+        let directives = match directives.trim() {
+            "" => self.initial.as_str(),
+            directives => directives,
+        };
+        let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+        self.handle.reload(filter).map_err(|e| e.to_string())?;
+        *self.current.lock().unwrap_or_else(|e| e.into_inner()) = directives.to_string();
+        tracing::warn!(filter = directives, "log filter changed");
+        Ok(())
+    }
+
+    /// SIGUSR2: `debug` directives if the startup filter is active, else back to it.
+    pub fn toggle(&self, debug: &str) -> Result<(), String> {
+        if self.current() == self.initial {
+            self.set(debug)
+        } else {
+            self.set("")
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::configuration::LuaScriptConfig;
+use mlua::{Function, HookTriggers, Lua, Table, Value};
+use pingora::http::{RequestHeader, ResponseHeader};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// Instruction budget per hook call; scripts that exceed it are aborted.
+const INSTRUCTION_LIMIT: u32 = 1_000_000;
+
+/// Response returned by an `on_request` hook to short-circuit the request.
+pub struct ScriptResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+struct LuaScript {
+    name: String,
+    /// Held for a whole hook run, so a call never runs under another call's instruction hook
+    states: Vec<Mutex<Lua>>,
+    next: AtomicUsize,
+}
+
+/// Config-declared Lua scripts with optional global `on_request(req)` and `on_response(resp)`
+/// hooks. Hooks edit `req.headers` / `req.path` / `resp.headers` / `resp.status` in place;
+/// `on_request` may return `{ status = ..., body = ..., headers = {...} }` to answer directly.
+/// A failing script is logged and skipped.
+pub struct LuaScripts {
+    scripts: Vec<LuaScript>,
+}
+
+impl LuaScripts {
+    pub fn load(configs: &[LuaScriptConfig]) -> Result<Self, String> {
+        let mut scripts = Vec::with_capacity(configs.len());
+        for config in configs {
+            let source = std::fs::read_to_string(&config.path)
+                .map_err(|e| format!("script {}: read {}: {}", config.name, config.path, e))?;
+            let mut states = Vec::with_capacity(config.instances);
+            for _ in 0..config.instances.max(1) {
+                let lua = Lua::new();
+                lua.load(&source)
+                    .set_name(&config.name)
+                    .exec()
+                    .map_err(|e| format!("script {}: {}", config.name, e))?;
+                states.push(Mutex::new(lua));
+            }
+            scripts.push(LuaScript {
+                name: config.name.clone(),
+                states,
+                next: AtomicUsize::new(0),
+            });
+        }
+        Ok(Self { scripts })
+    }
+
+    pub fn on_request(&self, req: &mut RequestHeader, client_ip: &str) -> Option<ScriptResponse> {
+        for script in &self.scripts {
+            match script.run_request(req, client_ip) {
+                Ok(Some(response)) => return Some(response),
+                Ok(None) => {}
+                Err(e) => tracing::error!(script = %script.name, "lua on_request failed: {}", e),
+            }
+        }
+        None
+    }
+
+    pub fn on_response(&self, resp: &mut ResponseHeader) {
+        for script in &self.scripts {
+            if let Err(e) = script.run_response(resp) {
+                tracing::error!(script = %script.name, "lua on_response failed: {}", e);
+            }
+        }
+    }
+}
+
+impl LuaScript {
+    fn state(&self) -> MutexGuard<'_, Lua> {
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.states.len();
+        self.states[idx].lock().unwrap_or_else(|p| p.into_inner())
+    }
+
+    fn hook(lua: &Lua, name: &str) -> mlua::Result<Option<Function>> {
+        match lua.globals().get::<Value>(name)? {
+            Value::Function(f) => Ok(Some(f)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Call `f` with an instruction budget; the hook counter restarts on every `set_hook`.
+    fn call_limited<R: mlua::FromLuaMulti>(lua: &Lua, f: &Function, arg: Table) -> mlua::Result<R> {
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(INSTRUCTION_LIMIT),
+            |_, _| Err(mlua::Error::runtime("instruction limit exceeded")),
+        )?;
+        let result = f.call(arg);
+        lua.remove_hook();
+        result
+    }
+
+    fn run_request(
+        &self,
+        req: &mut RequestHeader,
+        client_ip: &str,
+    ) -> mlua::Result<Option<ScriptResponse>> {
+        let state = self.state();
+        let lua = &*state;
+        let Some(hook) = Self::hook(lua, "on_request")? else {
+            return Ok(None);
+        };
+
+        let original_headers = header_map(&req.headers);
+        let original_path = req
+            .uri
+            .path_and_query()
+            .map(|p| p.as_str().to_string())
+            .unwrap_or_default();
+
+        let table = lua.create_table()?;
+        table.set("method", req.method.as_str())?;
+        table.set("path", original_path.as_str())?;
+        table.set("client_ip", client_ip)?;
+        table.set("headers", lua.create_table_from(original_headers.clone())?)?;
+
+        let result: Value = Self::call_limited(lua, &hook, table.clone())?;
+
+        let new_headers: HashMap<String, String> = table
+            .get::<Table>("headers")?
+            .pairs()
+            .collect::<mlua::Result<_>>()?;
+        apply_header_changes(&original_headers, &new_headers, |edit| match edit {
+            Edit::Set(k, v) => req.insert_header(k, v).map(|_| ()),
+            Edit::Remove(k) => {
+                req.remove_header(&k);
+                Ok(())
+            }
+        });
+
+        let new_path: String = table.get("path")?;
+        if new_path != original_path {
+            match new_path.parse() {
+                Ok(uri) => req.set_uri(uri),
+                Err(e) => tracing::warn!(script = %self.name, "lua set invalid path: {}", e),
+            }
+        }
+
+        match result {
+            Value::Table(resp) => Ok(Some(ScriptResponse {
+                status: resp.get::<Option<u16>>("status")?.unwrap_or(200),
+                headers: match resp.get::<Option<Table>>("headers")? {
+                    Some(h) => h.pairs().collect::<mlua::Result<_>>()?,
+                    None => Vec::new(),
+                },
+                body: resp
+                    .get::<Option<mlua::String>>("body")?
+                    .map(|b| b.as_bytes().to_vec())
+                    .unwrap_or_default(),
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    fn run_response(&self, resp: &mut ResponseHeader) -> mlua::Result<()> {
+        let state = self.state();
+        let lua = &*state;
+        let Some(hook) = Self::hook(lua, "on_response")? else {
+            return Ok(());
+        };
+
+        let original_headers = header_map(&resp.headers);
+        let table = lua.create_table()?;
+        table.set("status", resp.status.as_u16())?;
+        table.set("headers", lua.create_table_from(original_headers.clone())?)?;
+
+        let _: Value = Self::call_limited(lua, &hook, table.clone())?;
+
+        let new_headers: HashMap<String, String> = table
+            .get::<Table>("headers")?
+            .pairs()
+            .collect::<mlua::Result<_>>()?;
+        apply_header_changes(&original_headers, &new_headers, |edit| match edit {
+            Edit::Set(k, v) => resp.insert_header(k, v).map(|_| ()),
+            Edit::Remove(k) => {
+                resp.remove_header(&k);
+                Ok(())
+            }
+        });
+
+        let status: u16 = table.get("status")?;
+        if status != resp.status.as_u16() {
+            if let Err(e) = resp.set_status(status) {
+                tracing::warn!(script = %self.name, "lua set invalid status: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Edit {
+    Set(String, String),
+    Remove(String),
+}
+
+/// Multi-valued headers are collapsed to their first value when exposed to scripts.
+fn header_map(headers: &http::HeaderMap) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    for (k, v) in headers {
+        map.entry(k.to_string())
+            .or_insert_with(|| String::from_utf8_lossy(v.as_bytes()).into_owned());
+    }
+    map
+}
+
+fn apply_header_changes(
+    old: &HashMap<String, String>,
+    new: &HashMap<String, String>,
+    mut apply: impl FnMut(Edit) -> pingora::Result<()>,
+) {
+    let removed = old
+        .keys()
+        .filter(|k| !new.contains_key(*k))
+        .map(|k| Edit::Remove(k.clone()));
+    let set = new
+        .iter()
+        .filter(|(k, v)| old.get(*k) != Some(*v))
+        .map(|(k, v)| Edit::Set(k.clone(), v.clone()));
+    for edit in removed.chain(set).collect::<Vec<_>>() {
+        if let Err(e) = apply(edit) {
+            tracing::warn!("lua header edit rejected: {}", e);
+        }
+    }
+}
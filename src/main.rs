@@ -1,28 +1,133 @@
+mod access_log;
+mod admin;
+mod admin_auth;
+mod anomaly;
+mod asn;
+mod aws_secrets;
+mod aws_signer;
+mod balancer;
+mod bandwidth;
+mod cache;
+mod capture;
+mod cert_expiry;
+mod challenge;
 mod configuration;
+mod controls;
+mod csrf;
+#[cfg(unix)]
+mod daemon;
+mod dns;
+mod downstream;
+mod egress;
+mod error_response;
+mod graphql;
+mod grpc_admin;
+mod grpc_web;
+#[cfg(unix)]
+mod handoff;
+mod header_normalization;
+mod health;
+mod hmac_auth;
+mod icap;
+mod ip_reputation;
+mod json_schema;
+mod jwks;
+mod l4;
+mod log_level;
+mod lua;
 mod metrics;
+mod metrics_push;
+mod middleware;
+mod openapi;
+mod protobuf;
 mod proxy;
+mod quota;
+mod reload;
 mod security;
+mod signals;
+mod sigv4;
+mod simulate;
+mod spiffe;
+mod synthetic;
+mod syslog;
+#[cfg(target_os = "linux")]
+mod systemd;
+mod tarpit;
+mod tls_info;
+mod token_exchange;
+mod upload_filter;
+mod upstream_connection;
+mod vault;
+mod wasm;
+mod webhook;
+mod websocket;
 
+use access_log::AccessLog;
+use admin::{AdminService, RecentBlocks};
+use admin_auth::AdminAuth;
+use anomaly::AnomalyDetector;
 use arc_swap::ArcSwap;
-use configuration::GatewayConfig;
+use aws_signer::AwsSigner;
+use balancer::{Balancer, LocalZone, Upstreams};
+use bandwidth::BandwidthLimits;
+use cache::ResponseCache;
+use capture::BodyCapture;
+use cert_expiry::CertExpiry;
+use configuration::{GatewayConfig, TlsProfile};
+use controls::Controls;
+#[cfg(unix)]
+use daemon::PrivilegeDrop;
+use dns::UpstreamDns;
+use downstream::DownstreamLimits;
+use egress::{Egress, EgressBridge, EgressHealthCheck};
+use error_response::ErrorResponses;
+use grpc_admin::GrpcAdmin;
+use header_normalization::HeaderNormalizer;
+use health::{HealthChecks, SlowStart};
+use hmac_auth::HmacAuth;
+use icap::IcapClient;
+use l4::{SniRoute, TcpProxy};
+use log_level::LogLevel;
+use lua::LuaScripts;
 use metrics::Metrics;
+use middleware::{Middlewares, Router};
 use proxy::SecureProxy;
+use quota::Quotas;
+use reload::Reloader;
 use security::SecurityLayer;
+use signals::{Trigger, Triggers};
+use spiffe::Spiffe;
 use std::sync::Arc;
-use tokio::signal::unix::{signal, SignalKind};
+use synthetic::SyntheticResponses;
+use syslog::SyslogSink;
+#[cfg(target_os = "linux")]
+use systemd::SystemdNotify;
+use tarpit::Tarpit;
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::prelude::*;
-use tracing_subscriber::EnvFilter;
+use upload_filter::UploadFilter;
+use vault::{Vault, VaultCertificate};
+use wasm::WasmPlugins;
+use webhook::AlertWebhook;
+use websocket::WebSocketLimits;
 
+#[cfg(unix)]
+use handoff::{ListenerHandoff, UPGRADE_ENV};
+use pingora::lb::health_check::HealthCheck;
 use pingora::listeners::TlsSettings;
 use pingora::prelude::*;
+use pingora::services::listening::Service;
+use pingora::tls::ssl::SslVersion;
 
 fn main() {
+    if std::env::args().nth(1).as_deref() == Some("test-request") {
+        std::process::exit(simulate::run(std::env::args().skip(1)));
+    }
     let config_path = std::env::args()
         .nth(1)
         .unwrap_or_else(|| "config.yaml".to_string());
 
-    let config = match GatewayConfig::from_file(&config_path) {
+    let mut config = match GatewayConfig::load(&config_path) {
         Ok(c) => c,
         Err(e) => {
             eprintln!("Failed to load config from {}: {}", config_path, e);
@@ -30,8 +135,33 @@ fn main() {
         }
     };
 
+    // Set when an earlier process is handing its listeners over to this one
+    #[cfg(unix)]
+    let upgrade = std::env::var_os(UPGRADE_ENV).is_some();
+    #[cfg(not(unix))]
+    let upgrade = false;
+    #[cfg(unix)]
+    std::env::remove_var(UPGRADE_ENV);
+    // That earlier process already runs in the background, and this one inherits it
+    #[cfg(unix)]
+    if config.server.daemon && !upgrade {
+        if let Err(e) = daemon::daemonize(&config.server) {
+            eprintln!("Failed to daemonize: {}", e);
+            std::process::exit(1);
+        }
+    }
+    #[cfg(unix)]
+    if let Some(path) = &config.server.pid_file {
+        if let Err(e) = daemon::write_pid_file(path) {
+            eprintln!("Failed to write pid file: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let (log_filter, log_level) = LogLevel::new();
+    let log_level = Arc::new(log_level);
     tracing_subscriber::registry()
-        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with(log_filter)
         .with(
             tracing_subscriber::fmt::layer()
                 .json()
@@ -43,84 +173,491 @@ fn main() {
 
     tracing::info!("Starting FlashProxy with Hot Reload...");
 
+    let vault = match config.vault.clone() {
+        Some(vault_config) => {
+            match Vault::new(&vault_config).and_then(|v| v.resolve(&mut config).map(|_| v)) {
+                Ok(vault) => Some(Arc::new(vault)),
+                Err(e) => {
+                    eprintln!("Failed to load secrets from Vault: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
     // --- HOT RELOAD SETUP ---
-    let initial_security = SecurityLayer::new(config.rate_limit_per_second, &config.jwt_secret);
+    let initial_security = match SecurityLayer::new(&config) {
+        Ok(security) => security,
+        Err(e) => {
+            eprintln!("Failed to set up security layer: {}", e);
+            std::process::exit(1);
+        }
+    };
     let security_config = Arc::new(ArcSwap::from_pointee(initial_security));
+    let active_config = Arc::new(ArcSwap::from_pointee(config.clone()));
 
-    let security_reloader = security_config.clone();
-    let config_path_reloader = config_path.clone();
+    let access_log = config
+        .access_log_path
+        .as_ref()
+        .map(|path| match AccessLog::open(path) {
+            Ok(log) => Arc::new(log),
+            Err(e) => {
+                eprintln!("Failed to open access log {}: {}", path, e);
+                std::process::exit(1);
+            }
+        });
+    let body_capture = config
+        .body_capture
+        .as_ref()
+        .map(|c| match BodyCapture::new(c) {
+            Ok(capture) => Arc::new(capture),
+            Err(e) => {
+                eprintln!("Failed to open body capture file {}: {}", c.path, e);
+                std::process::exit(1);
+            }
+        });
+    let wasm_plugins = if config.wasm_plugins.is_empty() {
+        None
+    } else {
+        match WasmPlugins::load(&config.wasm_plugins) {
+            Ok(plugins) => Some(Arc::new(plugins)),
+            Err(e) => {
+                eprintln!("Failed to load wasm plugins: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+    let lua_scripts = if config.lua_scripts.is_empty() {
+        None
+    } else {
+        match LuaScripts::load(&config.lua_scripts) {
+            Ok(scripts) => Some(Arc::new(scripts)),
+            Err(e) => {
+                eprintln!("Failed to load lua scripts: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+    // FIX IS HERE: We DO NOT wrap this in Arc::new().
+    // Your Metrics::new() already returns Arc<Metrics>, so we assign it directly.
+    let metrics = Metrics::new(&config.metrics_labels);
+    if let Some(push) = &config.metrics_push {
+        metrics_push::start(push, &metrics);
+    }
+
+    let synthetic = match SyntheticResponses::load(&config.synthetic_responses) {
+        Ok(responses) => Arc::new(ArcSwap::from_pointee(responses)),
+        Err(e) => {
+            eprintln!("Failed to load synthetic responses: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let quotas = config.quota.as_ref().map(|q| match Quotas::open(q) {
+        Ok(quotas) => quotas,
+        Err(e) => {
+            eprintln!("Failed to open quota store {}: {}", q.store_path, e);
+            std::process::exit(1);
+        }
+    });
+
+    let middlewares = Arc::new(Middlewares::new(
+        security_config.clone(),
+        metrics.clone(),
+        synthetic.clone(),
+        quotas,
+        config
+            .hmac_auth
+            .as_ref()
+            .map(|h| Arc::new(HmacAuth::new(h))),
+        wasm_plugins.clone(),
+        lua_scripts.clone(),
+    ));
+    let router = match Router::build(&config, &middlewares) {
+        Ok(router) => Arc::new(ArcSwap::from_pointee(router)),
+        Err(e) => {
+            eprintln!("Failed to build routes: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    #[cfg(unix)]
+    let handoff = Arc::new(ListenerHandoff::default());
+    let upstream_pool = Arc::new(Upstreams::default());
+    let upstream_dns = Arc::new(UpstreamDns::new(&config.upstream_dns, metrics.clone()));
+    let reloader = Arc::new(Reloader {
+        config_path: config_path.clone(),
+        config: active_config.clone(),
+        security: security_config.clone(),
+        router: router.clone(),
+        middlewares,
+        synthetic,
+        vault: vault.clone(),
+        metrics: metrics.clone(),
+        #[cfg(unix)]
+        handoff: handoff.clone(),
+        upstreams: upstream_pool.clone(),
+        dns: upstream_dns.clone(),
+    });
+    if let Some(vault) = &vault {
+        vault
+            .clone()
+            .spawn_refresh(active_config.clone(), reloader.clone());
+    }
+    CertExpiry::new(&config, vault.clone(), metrics.clone()).spawn();
+    let signal_reloader = reloader.clone();
+    let signal_access_log = access_log.clone();
+    let signal_body_capture = body_capture.clone();
+    let signal_config = active_config.clone();
+    let signal_log_level = log_level.clone();
 
     std::thread::spawn(move || {
         let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async {
-            let mut sig_hup = signal(SignalKind::hangup()).unwrap();
-            tracing::info!("Hot Reload Service active. Run 'kill -HUP <PID>' to reload.");
+            let mut triggers = Triggers::new().unwrap();
+            tracing::info!("Hot Reload Service active. {}", Triggers::RELOAD_HINT);
 
             loop {
-                sig_hup.recv().await;
-                tracing::info!("Received SIGHUP! Reloading configuration...");
-
-                match GatewayConfig::from_file(&config_path_reloader) {
-                    Ok(new_conf) => {
-                        let new_layer = SecurityLayer::new(
-                            new_conf.rate_limit_per_second,
-                            &new_conf.jwt_secret,
-                        );
-                        security_reloader.store(Arc::new(new_layer));
-                        tracing::info!("✅ Configuration successfully reloaded!");
+                match triggers.next().await {
+                    Trigger::Reload(source) => {
+                        tracing::info!("Received {}! Reloading configuration...", source);
+
+                        match signal_reloader.reload() {
+                            Ok(changed) => {
+                                tracing::info!(?changed, "✅ Configuration successfully reloaded!");
+                            }
+                            Err(e) => {
+                                tracing::error!(
+                                    "❌ Failed to reload config: {}. Keeping old config.",
+                                    e
+                                );
+                            }
+                        }
                     }
-                    Err(e) => {
-                        tracing::error!("❌ Failed to reload config: {}. Keeping old config.", e);
+                    Trigger::ToggleDebugLog => {
+                        let debug = signal_config.load().debug_log_filter.clone();
+                        if let Err(e) = signal_log_level.toggle(&debug) {
+                            tracing::error!("Failed to change the log filter: {}", e);
+                        }
+                    }
+                    Trigger::ReopenLogs => {
+                        let sinks = signal_access_log
+                            .as_deref()
+                            .into_iter()
+                            .chain(signal_body_capture.as_deref().map(BodyCapture::sink));
+                        for sink in sinks {
+                            match sink.reopen() {
+                                Ok(()) => tracing::info!("Log file reopened"),
+                                Err(e) => tracing::error!("Failed to reopen log file: {}", e),
+                            }
+                        }
                     }
                 }
             }
         });
     });
 
-    let upstream_list: Vec<&str> = config.upstream_ips.iter().map(String::as_str).collect();
-    let mut lb = LoadBalancer::try_from_iter(upstream_list).expect("Invalid upstream list");
+    let upstream_ips = balancer::configured(&config);
+    if config.upstream_subset.is_some() {
+        tracing::info!(upstreams = ?upstream_ips, "Using a subset of the upstreams");
+    }
+    upstream_pool.set(
+        upstream_dns
+            .resolve(&upstream_ips)
+            .expect("Invalid upstream list"),
+        std::time::Duration::ZERO,
+    );
+    upstream_dns.spawn_refresh(active_config.clone(), upstream_pool.clone());
+    let mut lb = upstream_pool.load_balancer();
 
-    let hc = TcpHealthCheck::new();
-    lb.set_health_check(hc);
-    lb.health_check_frequency = Some(std::time::Duration::from_secs(1));
+    let egress = config
+        .egress_proxy
+        .as_ref()
+        .map(|c| Arc::new(Egress::new(c)));
+    let health_checks = HealthChecks::new(&config.health_check);
+    let slow_start = config
+        .health_check
+        .slow_start_secs
+        .map(|secs| Arc::new(SlowStart::new(std::time::Duration::from_secs(secs))));
+    let check: Box<dyn HealthCheck + Send + Sync> = match &egress {
+        Some(egress) => Box::new(EgressHealthCheck::new(egress.clone())),
+        None => TcpHealthCheck::new(),
+    };
+    let alerts = config
+        .alert_webhook
+        .as_ref()
+        .map(|c| Arc::new(AlertWebhook::new(c)));
+    health_checks.apply(&mut lb, check, slow_start.clone(), alerts.clone());
 
-    let mut server = Server::new(None).unwrap();
-    server.bootstrap();
+    let spiffe = config.spiffe.as_ref().map(|c| match Spiffe::start(c) {
+        Ok(spiffe) => spiffe,
+        Err(e) => {
+            eprintln!("Failed to get an SVID from {}: {}", c.socket_path, e);
+            std::process::exit(1);
+        }
+    });
+
+    let local_zone = config.zone.as_ref().map(|zone| {
+        LocalZone::resolve(&config.upstream_zones[zone]).unwrap_or_else(|e| {
+            eprintln!("Failed to resolve upstreams of zone {}: {}", zone, e);
+            std::process::exit(1);
+        })
+    });
+
+    let mut server = Server::new(Opt {
+        upgrade,
+        ..Default::default()
+    })
+    .unwrap();
+    if let Some(conf) = Arc::get_mut(&mut server.configuration) {
+        if let Some(pool_size) = config.upstream_keepalive.pool_size {
+            conf.upstream_keepalive_pool_size = pool_size;
+        }
+        if let Some(threads) = config.server.threads {
+            conf.threads = threads;
+        }
+        conf.upgrade_sock = config.server.upgrade_sock.clone();
+    }
 
     let background = background_service("health check", lb);
     let upstreams = background.task();
+    upstream_pool.attach(&upstreams);
 
-    // FIX IS HERE: We DO NOT wrap this in Arc::new().
-    // Your Metrics::new() already returns Arc<Metrics>, so we assign it directly.
-    let metrics = Metrics::new();
-
+    // The host without port; IPv6 literals keep their brackets, as Host needs them
     let upstream_sni = config
         .upstream_ips
         .first()
-        .and_then(|s| s.split(':').next())
+        .map(|s| match s.rsplit_once(':') {
+            Some((host, port))
+                if !port.contains(']') && (!host.contains(':') || host.ends_with(']')) =>
+            {
+                host
+            }
+            _ => s.as_str(),
+        })
         .unwrap_or("localhost")
         .to_string();
 
+    let recent_blocks = Arc::new(RecentBlocks::default());
+    let controls = Arc::new(Controls::default());
+    let syslog = config.syslog.as_ref().map(|c| Arc::new(SyslogSink::new(c)));
+    let anomalies = config
+        .anomaly_detection
+        .as_ref()
+        .map(|c| AnomalyDetector::start(c, metrics.clone(), syslog.clone()));
+    let downstream = Arc::new(DownstreamLimits::new(&config.downstream));
+    downstream.clone().spawn_sweep(metrics.clone());
     let proxy = SecureProxy {
-        lb: upstreams,
+        balancer: Balancer::new(
+            upstreams.clone(),
+            upstream_pool,
+            config.load_balancing,
+            slow_start,
+            local_zone,
+        ),
+        retry_after: config.upstream_retry_after.clone(),
+        upstream_idle_timeout: config
+            .upstream_keepalive
+            .idle_timeout_secs
+            .map(std::time::Duration::from_secs),
+        aws_signer: AwsSigner::new(),
         security: security_config,
         // We pass the single-wrapped Arc here.
-        metrics: metrics,
+        metrics: metrics.clone(),
         upstream_sni,
+        access_log,
+        syslog: syslog.clone(),
+        body_capture,
+        wasm_plugins,
+        lua_scripts,
+        router,
+        grpc_web: config.grpc_web,
+        downstream,
+        forward_trailers: config.forward_trailers,
+        cache: config
+            .cache
+            .as_ref()
+            .map(|c| match ResponseCache::new(c, metrics.cache()) {
+                Ok(cache) => Arc::new(cache),
+                Err(e) => {
+                    eprintln!("Failed to open the response cache: {}", e);
+                    std::process::exit(1);
+                }
+            }),
+        upload_filter: config
+            .upload_filter
+            .as_ref()
+            .map(|c| Arc::new(UploadFilter::new(c))),
+        websocket: config
+            .websocket
+            .as_ref()
+            .map(|c| Arc::new(WebSocketLimits::new(c))),
+        icap: config.icap.as_ref().map(|c| Arc::new(IcapClient::new(c))),
+        egress,
+        spiffe,
+        recent_blocks: recent_blocks.clone(),
+        controls: controls.clone(),
+        anomalies,
+        alerts: alerts.clone(),
+        error_responses: ErrorResponses::new(&config.error_responses),
+        header_normalizer: HeaderNormalizer::new(&config.header_normalization),
+        tarpit: config.tarpit.as_ref().map(Tarpit::new),
+        bandwidth: BandwidthLimits::new(&config.bandwidth_limits),
+        absolute_form: config.absolute_form,
+        connect_requests: config.connect_requests,
     };
 
     let mut proxy_service = http_proxy_service(&server.configuration, proxy);
 
-    let mut tls_settings =
-        TlsSettings::intermediate(&config.tls_cert_path, &config.tls_key_path).unwrap();
-    tls_settings.enable_h2();
+    let mut tls_settings = match vault.filter(|v| v.serves_tls()) {
+        Some(vault) => TlsSettings::with_callbacks(Box::new(VaultCertificate(vault))).unwrap(),
+        None => TlsSettings::intermediate(&config.tls_cert_path, &config.tls_key_path).unwrap(),
+    };
+    if config.tls_profile == TlsProfile::Modern {
+        tls_settings
+            .set_min_proto_version(Some(SslVersion::TLS1_3))
+            .unwrap();
+    }
+    if config.http2 {
+        tls_settings.enable_h2();
+    }
 
-    let listen_addr = format!("0.0.0.0:{}", config.listen_port);
+    let listen_addr = config.listen_addr();
     tracing::info!(addr = %listen_addr, "Listening for HTTPS");
     proxy_service.add_tls_with_settings(&listen_addr, None, tls_settings);
 
     server.add_service(proxy_service);
+
+    if let Some(egress_config) = &config.egress_proxy {
+        let mut bridge = Service::new(
+            "egress bridge".to_string(),
+            EgressBridge::new(egress_config),
+        );
+        bridge.add_uds(&egress_config.socket_path, None);
+        tracing::info!(proxy = %egress_config.addr, "Upstream connections go through egress proxy");
+        server.add_service(bridge);
+    }
     server.add_service(background);
+
+    for tcp in &config.tcp_services {
+        let pool = |label: &str, upstreams: &[String]| {
+            let mut lb = LoadBalancer::try_from_iter(upstreams.iter().map(String::as_str))
+                .unwrap_or_else(|e| {
+                    eprintln!("Invalid upstream list for tcp service {}: {}", tcp.name, e);
+                    std::process::exit(1);
+                });
+            health_checks.apply(&mut lb, TcpHealthCheck::new(), None, alerts.clone());
+            background_service(&format!("{} health check", label), lb)
+        };
+        let mut backgrounds = Vec::new();
+        let lb = (!tcp.upstreams.is_empty()).then(|| {
+            let background = pool(&tcp.name, &tcp.upstreams);
+            let task = background.task();
+            backgrounds.push(background);
+            task
+        });
+        let sni_routes = tcp
+            .sni_routes
+            .iter()
+            .map(|route| {
+                let background = pool(
+                    &format!("{} {}", tcp.name, route.server_names.join(",")),
+                    &route.upstreams,
+                );
+                let lb = background.task();
+                backgrounds.push(background);
+                SniRoute {
+                    server_names: route.server_names.clone(),
+                    lb,
+                }
+            })
+            .collect();
+        let mut service = Service::new(
+            format!("tcp {}", tcp.name),
+            TcpProxy {
+                name: tcp.name.clone(),
+                lb,
+                sni_routes,
+            },
+        );
+        service.add_tcp(&tcp.listen);
+        tracing::info!(service = %tcp.name, addr = %tcp.listen, "TCP proxy listening");
+        server.add_service(service);
+        for background in backgrounds {
+            server.add_service(background);
+        }
+    }
+
+    let admin_auth = Arc::new(AdminAuth::new(config.admin_auth.as_ref(), syslog.clone()));
+    let admin_tls = |h2: bool| {
+        let auth = config.admin_auth.as_ref()?;
+        let client_ca = auth.client_ca.as_ref()?;
+        match admin_auth::tls_settings(&config.tls_cert_path, &config.tls_key_path, client_ca, h2) {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                eprintln!("Failed to set up admin TLS: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+    if let Some(grpc_addr) = &config.admin_grpc_listen_addr {
+        let mut grpc_service = Service::new(
+            "grpc admin".to_string(),
+            GrpcAdmin {
+                reloader: reloader.clone(),
+                controls: controls.clone(),
+                auth: admin_auth.clone(),
+            },
+        );
+        match admin_tls(true) {
+            Some(tls) => grpc_service.add_tls_with_settings(grpc_addr, None, tls),
+            None => grpc_service.add_tcp(grpc_addr),
+        }
+        tracing::info!(addr = %grpc_addr, "gRPC admin API listening");
+        server.add_service(grpc_service);
+    }
+
+    if let Some(admin_addr) = &config.admin_listen_addr {
+        let mut admin_service = Service::new(
+            "admin".to_string(),
+            AdminService {
+                config: active_config,
+                reloader,
+                metrics,
+                upstreams: upstreams.clone(),
+                recent_blocks,
+                log_level,
+                auth: admin_auth,
+                controls,
+            },
+        );
+        match admin_tls(false) {
+            Some(tls) => admin_service.add_tls_with_settings(admin_addr, None, tls),
+            None => admin_service.add_tcp(admin_addr),
+        }
+        tracing::info!(addr = %admin_addr, "Admin API listening");
+        server.add_service(admin_service);
+    }
+
+    #[cfg(unix)]
+    server.add_service(handoff.service(&server.configuration.upgrade_sock));
+    #[cfg(unix)]
+    match PrivilegeDrop::new(&config.server, config.listen_addrs()) {
+        Ok(Some(drop)) => server.add_service(drop),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("Failed to set up privilege drop: {}", e);
+            std::process::exit(1);
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(notify) = SystemdNotify::from_env(upstreams, config.listen_port) {
+        server.add_service(notify);
+    }
+
+    // Last, so a process taking over listeners only does so once the rest of startup succeeded
+    server.bootstrap();
     server.run_forever();
 }
 // upstream selection aint working idk why, need to fix it
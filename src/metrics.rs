@@ -1,15 +1,176 @@
-use prometheus::{Encoder, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder};
+use dashmap::DashMap;
+use prometheus::core::Collector;
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Label names the metrics already use, which constant labels can't take
+pub const VARIABLE_LABELS: &[&str] = &[
+    "status",
+    "method",
+    "path",
+    "reason",
+    "tenant",
+    "upstream",
+    "reused",
+    "result",
+    "tier",
+    "direction",
+    "grpc_code",
+    "grpc_method",
+    "feed",
+    "kind",
+];
+
+/// Request counts summed over every label, since startup.
+pub struct RequestTotals {
+    pub requests: u64,
+    pub client_errors: u64,
+    pub server_errors: u64,
+}
 
 pub struct Metrics {
     registry: Registry,
     http_requests_total: IntCounterVec,
     http_request_duration_seconds: HistogramVec,
+    security_rule_monitored_total: IntCounterVec,
+    tenant_requests_total: IntCounterVec,
+    rate_limit_checks_total: IntCounterVec,
+    /// Clients the `rate_limit` stage refused lately; kept out of Prometheus to bound cardinality
+    rate_limit_offenders: DashMap<IpAddr, Offender>,
+    upstream_connections_total: IntCounterVec,
+    upstream_connections_active: IntGaugeVec,
+    active_connections: IntGauge,
+    route_active_connections: IntGaugeVec,
+    in_flight_requests: IntGauge,
+    route_in_flight_requests: IntGaugeVec,
+    websocket_messages_total: IntCounterVec,
+    websocket_terminations_total: IntCounterVec,
+    grpc_responses_total: IntCounterVec,
+    ip_feed_age_seconds: IntGaugeVec,
+    cert_expiry_timestamp: IntGaugeVec,
+    anomalies_total: IntCounterVec,
+    anomaly_penalized_ips: IntGauge,
+    proxy_errors_total: IntCounterVec,
+    smuggling_rejections_total: IntCounterVec,
+    upstream_dns_lookups_total: IntCounterVec,
+    config_generation: IntGauge,
+    cache: CacheMetrics,
+}
+
+/// Offenders idle this long are forgotten, and their count starts over
+const OFFENDER_IDLE: Duration = Duration::from_secs(600);
+/// Bound on the offenders tracked; new ones are skipped while it is reached
+const MAX_OFFENDERS: usize = 10_000;
+
+struct Offender {
+    rejections: u64,
+    last: Instant,
+}
+
+/// A client refused by the `rate_limit` stage, as reported by the admin API.
+pub struct RateLimitOffender {
+    pub ip: IpAddr,
+    pub rejections: u64,
+    /// Since the latest rejection
+    pub idle: Duration,
+}
+
+/// Names of the gRPC status codes, indexed by code
+const GRPC_CODES: &[&str] = &[
+    "OK",
+    "CANCELLED",
+    "UNKNOWN",
+    "INVALID_ARGUMENT",
+    "DEADLINE_EXCEEDED",
+    "NOT_FOUND",
+    "ALREADY_EXISTS",
+    "PERMISSION_DENIED",
+    "RESOURCE_EXHAUSTED",
+    "FAILED_PRECONDITION",
+    "ABORTED",
+    "OUT_OF_RANGE",
+    "UNIMPLEMENTED",
+    "INTERNAL",
+    "UNAVAILABLE",
+    "DATA_LOSS",
+    "UNAUTHENTICATED",
+];
+
+/// Response cache metrics, handed to the cache so it can record them itself.
+#[derive(Clone)]
+pub struct CacheMetrics {
+    lookups_total: IntCounterVec,
+    evictions_total: IntCounterVec,
+    entries: IntGaugeVec,
+    bytes: IntGaugeVec,
+}
+
+impl CacheMetrics {
+    pub fn record_lookup(&self, result: &str) {
+        self.lookups_total.with_label_values(&[result]).inc();
+    }
+
+    pub fn record_eviction(&self, tier: &str) {
+        self.evictions_total.with_label_values(&[tier]).inc();
+    }
+
+    /// Adjusts the stored entry and byte gauges of `tier`.
+    pub fn add_stored(&self, tier: &str, entries: i64, bytes: i64) {
+        self.entries.with_label_values(&[tier]).add(entries);
+        self.bytes.with_label_values(&[tier]).add(bytes);
+    }
+}
+
+/// An upstream connection checked out of the pool by a request; counted in
+/// `upstream_connections_active` until dropped.
+pub struct ActiveConnection(IntGauge);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// A client request being handled; counted in `in_flight_requests`, and in
+/// `route_in_flight_requests` once routed, until dropped.
+pub struct InFlightRequest {
+    total: IntGauge,
+    routes: IntGaugeVec,
+    route: Option<IntGauge>,
+}
+
+impl InFlightRequest {
+    pub fn set_route(&mut self, route: &str) {
+        if let Some(previous) = self.route.take() {
+            previous.dec();
+        }
+        let gauge = self.routes.with_label_values(&[route]);
+        gauge.inc();
+        self.route = Some(gauge);
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.total.dec();
+        if let Some(route) = &self.route {
+            route.dec();
+        }
+    }
 }
 
 impl Metrics {
-    pub fn new() -> Arc<Self> {
-        let registry = Registry::new();
+    /// `const_labels` (e.g. environment, region) are added to every metric. Label names are checked during config validation.
+    pub fn new(const_labels: &BTreeMap<String, String>) -> Arc<Self> {
+        let labels: HashMap<String, String> = const_labels.clone().into_iter().collect();
+        let registry = Registry::new_custom(None, (!labels.is_empty()).then_some(labels))
+            .expect("registry can be created");
 
         let http_requests_total = IntCounterVec::new(
             Opts::new("http_requests_total", "Total number of HTTP requests"),
@@ -27,20 +188,313 @@ impl Metrics {
         )
         .expect("metric can be created");
 
+        let security_rule_monitored_total = IntCounterVec::new(
+            Opts::new(
+                "security_rule_monitored_total",
+                "Requests a rule in monitor mode would have blocked",
+            ),
+            &["reason"],
+        )
+        .expect("metric can be created");
+
+        let tenant_requests_total = IntCounterVec::new(
+            Opts::new(
+                "tenant_requests_total",
+                "Requests to hosts with tenant limits, rejections included",
+            ),
+            &["tenant", "status"],
+        )
+        .expect("metric can be created");
+
+        let rate_limit_checks_total = IntCounterVec::new(
+            Opts::new(
+                "rate_limit_checks_total",
+                "Requests checked by the rate_limit stage, by route (`default` for unrouted requests) and result: allowed or limited",
+            ),
+            &["route", "result"],
+        )
+        .expect("metric can be created");
+
+        let upstream_connections_total = IntCounterVec::new(
+            Opts::new(
+                "upstream_connections_total",
+                "Upstream connections used by requests, by whether they came from the keepalive pool",
+            ),
+            &["upstream", "reused"],
+        )
+        .expect("metric can be created");
+
+        let upstream_connections_active = IntGaugeVec::new(
+            Opts::new(
+                "upstream_connections_active",
+                "Upstream connections currently held by requests",
+            ),
+            &["upstream"],
+        )
+        .expect("metric can be created");
+
+        let active_connections = IntGauge::new(
+            "active_connections",
+            "Open client connections that have sent a request",
+        )
+        .expect("metric can be created");
+        let route_active_connections = IntGaugeVec::new(
+            Opts::new(
+                "route_active_connections",
+                "Open client connections, by the route of their latest request",
+            ),
+            &["route"],
+        )
+        .expect("metric can be created");
+        let in_flight_requests = IntGauge::new(
+            "in_flight_requests",
+            "Client requests currently being handled",
+        )
+        .expect("metric can be created");
+        let route_in_flight_requests = IntGaugeVec::new(
+            Opts::new(
+                "route_in_flight_requests",
+                "Client requests currently being handled, by route",
+            ),
+            &["route"],
+        )
+        .expect("metric can be created");
+
+        let websocket_messages_total = IntCounterVec::new(
+            Opts::new(
+                "websocket_messages_total",
+                "WebSocket data messages relayed, by direction: client or upstream",
+            ),
+            &["direction"],
+        )
+        .expect("metric can be created");
+
+        let websocket_terminations_total = IntCounterVec::new(
+            Opts::new(
+                "websocket_terminations_total",
+                "WebSocket connections closed for breaking a limit",
+            ),
+            &["reason"],
+        )
+        .expect("metric can be created");
+
+        let grpc_responses_total = IntCounterVec::new(
+            Opts::new(
+                "grpc_responses_total",
+                "gRPC responses by grpc-status code and method",
+            ),
+            &["grpc_code", "grpc_method"],
+        )
+        .expect("metric can be created");
+
+        let ip_feed_age_seconds = IntGaugeVec::new(
+            Opts::new(
+                "ip_reputation_feed_age_seconds",
+                "Seconds since each IP reputation feed was last fetched successfully",
+            ),
+            &["feed"],
+        )
+        .expect("metric can be created");
+
+        let cert_expiry_timestamp = IntGaugeVec::new(
+            Opts::new(
+                "tls_certificate_expiry_timestamp",
+                "Unix time at which each certificate the proxy loaded stops being valid",
+            ),
+            &["source", "subject"],
+        )
+        .expect("metric can be created");
+
+        let anomalies_total = IntCounterVec::new(
+            Opts::new(
+                "anomalies_total",
+                "Traffic anomalies found by the analyzer, by kind",
+            ),
+            &["kind"],
+        )
+        .expect("metric can be created");
+
+        let proxy_errors_total = IntCounterVec::new(
+            Opts::new(
+                "proxy_errors_total",
+                "Requests that failed in the proxy, by class (connect_timeout, connect_failed, tls, upstream_reset, upstream_timeout, upstream_protocol, no_healthy_upstream, upstream_drained, client or proxy) and pingora error type",
+            ),
+            &["class", "type"],
+        )
+        .expect("metric can be created");
+
+        let smuggling_rejections_total = IntCounterVec::new(
+            Opts::new(
+                "smuggling_rejections_total",
+                "Requests refused for ambiguous body framing, by reason",
+            ),
+            &["reason"],
+        )
+        .expect("metric can be created");
+
+        let upstream_dns_lookups_total = IntCounterVec::new(
+            Opts::new(
+                "upstream_dns_lookups_total",
+                "Upstream hostname lookups, by host and result (ok, system or failed)",
+            ),
+            &["host", "result"],
+        )
+        .expect("metric can be created");
+
+        let anomaly_penalized_ips = IntGauge::new(
+            "anomaly_penalized_ips",
+            "IPs currently held to the anomaly penalty rate limit",
+        )
+        .expect("metric can be created");
+
+        // The config loaded at startup is generation 1
+        let config_generation = IntGauge::new(
+            "config_generation",
+            "Successful config loads, startup included",
+        )
+        .expect("metric can be created");
+        config_generation.set(1);
+
+        let cache = CacheMetrics {
+            lookups_total: IntCounterVec::new(
+                Opts::new(
+                    "cache_lookups_total",
+                    "Response cache lookups by result: hit, miss, not_modified or coalesced",
+                ),
+                &["result"],
+            )
+            .expect("metric can be created"),
+            evictions_total: IntCounterVec::new(
+                Opts::new(
+                    "cache_evictions_total",
+                    "Cached responses evicted to make room, by tier",
+                ),
+                &["tier"],
+            )
+            .expect("metric can be created"),
+            entries: IntGaugeVec::new(
+                Opts::new("cache_entries", "Responses currently cached, by tier"),
+                &["tier"],
+            )
+            .expect("metric can be created"),
+            bytes: IntGaugeVec::new(
+                Opts::new("cache_size_bytes", "Size of the cached responses, by tier"),
+                &["tier"],
+            )
+            .expect("metric can be created"),
+        };
+
         registry
             .register(Box::new(http_requests_total.clone()))
             .expect("collector can be registered");
         registry
             .register(Box::new(http_request_duration_seconds.clone()))
             .expect("collector can be registered");
+        registry
+            .register(Box::new(security_rule_monitored_total.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(tenant_requests_total.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(rate_limit_checks_total.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(upstream_connections_total.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(upstream_connections_active.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(active_connections.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(route_active_connections.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(in_flight_requests.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(route_in_flight_requests.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(websocket_messages_total.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(websocket_terminations_total.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(grpc_responses_total.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(ip_feed_age_seconds.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(cert_expiry_timestamp.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(anomalies_total.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(proxy_errors_total.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(smuggling_rejections_total.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(upstream_dns_lookups_total.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(anomaly_penalized_ips.clone()))
+            .expect("collector can be registered");
+        registry
+            .register(Box::new(config_generation.clone()))
+            .expect("collector can be registered");
+        for collector in [
+            Box::new(cache.lookups_total.clone()) as Box<dyn Collector>,
+            Box::new(cache.evictions_total.clone()),
+            Box::new(cache.entries.clone()),
+            Box::new(cache.bytes.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("collector can be registered");
+        }
 
         Arc::new(Self {
             registry,
             http_requests_total,
             http_request_duration_seconds,
+            security_rule_monitored_total,
+            tenant_requests_total,
+            rate_limit_checks_total,
+            rate_limit_offenders: DashMap::new(),
+            upstream_connections_total,
+            upstream_connections_active,
+            active_connections,
+            route_active_connections,
+            in_flight_requests,
+            route_in_flight_requests,
+            websocket_messages_total,
+            websocket_terminations_total,
+            grpc_responses_total,
+            ip_feed_age_seconds,
+            cert_expiry_timestamp,
+            anomalies_total,
+            proxy_errors_total,
+            smuggling_rejections_total,
+            upstream_dns_lookups_total,
+            anomaly_penalized_ips,
+            config_generation,
+            cache,
         })
     }
 
+    /// Current values of every collector, constant labels included.
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        self.registry.gather()
+    }
+
     /// Encode metrics in Prometheus text format. Fails only on encoder or UTF-8 error (should not happen in practice).
     pub fn encode(&self) -> Result<String, prometheus::Error> {
         let mut buffer = vec![];
@@ -51,6 +505,32 @@ impl Metrics {
             .map_err(|e| prometheus::Error::Msg(format!("metrics UTF-8: {}", e)))
     }
 
+    pub fn request_totals(&self) -> RequestTotals {
+        let mut totals = RequestTotals {
+            requests: 0,
+            client_errors: 0,
+            server_errors: 0,
+        };
+        for family in self.http_requests_total.collect() {
+            for metric in family.get_metric() {
+                let count = metric.get_counter().get_value() as u64;
+                let status = metric
+                    .get_label()
+                    .iter()
+                    .find(|l| l.get_name() == "status")
+                    .map(|l| l.get_value())
+                    .unwrap_or_default();
+                totals.requests += count;
+                match status.as_bytes().first() {
+                    Some(b'4') => totals.client_errors += count,
+                    Some(b'5') => totals.server_errors += count,
+                    _ => {}
+                }
+            }
+        }
+        totals
+    }
+
     pub fn record_request(&self, status: u16, method: &str, path: &str, duration_secs: f64) {
         let status_str = status.to_string();
         self.http_requests_total
@@ -60,4 +540,180 @@ impl Metrics {
             .with_label_values(&[&status_str, method, path])
             .observe(duration_secs);
     }
+
+    /// Replaces the feed ages, dropping feeds no longer configured.
+    pub fn set_ip_feed_ages(&self, ages: &[(&str, u64)]) {
+        self.ip_feed_age_seconds.reset();
+        for (feed, age) in ages {
+            self.ip_feed_age_seconds
+                .with_label_values(&[feed])
+                .set(i64::try_from(*age).unwrap_or(i64::MAX));
+        }
+    }
+
+    /// Replaces every series, so certificates no longer loaded drop out.
+    pub fn set_cert_expiries(&self, expiries: &[(&str, String, i64)]) {
+        self.cert_expiry_timestamp.reset();
+        for (source, subject, at) in expiries {
+            self.cert_expiry_timestamp
+                .with_label_values(&[source, subject])
+                .set(*at);
+        }
+    }
+
+    pub fn record_anomaly(&self, kind: &str) {
+        self.anomalies_total.with_label_values(&[kind]).inc();
+    }
+
+    pub fn set_anomaly_penalized_ips(&self, count: i64) {
+        self.anomaly_penalized_ips.set(count);
+    }
+
+    /// `by_route` covers the connections that have been routed.
+    pub fn set_active_connections(&self, total: i64, by_route: &HashMap<String, i64>) {
+        self.active_connections.set(total);
+        self.route_active_connections.reset();
+        for (route, count) in by_route {
+            self.route_active_connections
+                .with_label_values(&[route])
+                .set(*count);
+        }
+    }
+
+    pub fn start_request(&self) -> InFlightRequest {
+        self.in_flight_requests.inc();
+        InFlightRequest {
+            total: self.in_flight_requests.clone(),
+            routes: self.route_in_flight_requests.clone(),
+            route: None,
+        }
+    }
+
+    pub fn bump_config_generation(&self) -> i64 {
+        self.config_generation.inc();
+        self.config_generation.get()
+    }
+
+    pub fn record_monitored_block(&self, reason: &str) {
+        self.security_rule_monitored_total
+            .with_label_values(&[reason])
+            .inc();
+    }
+
+    pub fn record_upstream_dns_lookup(&self, host: &str, result: &str) {
+        self.upstream_dns_lookups_total
+            .with_label_values(&[host, result])
+            .inc();
+    }
+
+    pub fn record_proxy_error(&self, class: &str, etype: &str) {
+        self.proxy_errors_total
+            .with_label_values(&[class, etype])
+            .inc();
+    }
+
+    pub fn record_smuggling_rejection(&self, reason: &str) {
+        self.smuggling_rejections_total
+            .with_label_values(&[reason])
+            .inc();
+    }
+
+    pub fn record_tenant_request(&self, tenant: &str, status: u16) {
+        self.tenant_requests_total
+            .with_label_values(&[tenant, &status.to_string()])
+            .inc();
+    }
+
+    /// Counts a `rate_limit` check on `route` and tracks the client if it was refused.
+    pub fn record_rate_limit(&self, route: &str, ip: Option<IpAddr>, limited: bool) {
+        let result = if limited { "limited" } else { "allowed" };
+        self.rate_limit_checks_total
+            .with_label_values(&[route, result])
+            .inc();
+        let Some(ip) = ip.filter(|_| limited) else {
+            return;
+        };
+        let now = Instant::now();
+        if self.rate_limit_offenders.len() >= MAX_OFFENDERS {
+            self.rate_limit_offenders
+                .retain(|_, o| now.duration_since(o.last) < OFFENDER_IDLE);
+            if self.rate_limit_offenders.len() >= MAX_OFFENDERS
+                && !self.rate_limit_offenders.contains_key(&ip)
+            {
+                return;
+            }
+        }
+        let mut offender = self.rate_limit_offenders.entry(ip).or_insert(Offender {
+            rejections: 0,
+            last: now,
+        });
+        if now.duration_since(offender.last) >= OFFENDER_IDLE {
+            offender.rejections = 0;
+        }
+        offender.rejections += 1;
+        offender.last = now;
+    }
+
+    /// The `top` clients with the most recent rate limit rejections, most first.
+    pub fn rate_limit_offenders(&self, top: usize) -> Vec<RateLimitOffender> {
+        let now = Instant::now();
+        let mut offenders: Vec<RateLimitOffender> = self
+            .rate_limit_offenders
+            .iter()
+            .map(|entry| RateLimitOffender {
+                ip: *entry.key(),
+                rejections: entry.rejections,
+                idle: now.duration_since(entry.last),
+            })
+            .filter(|o| o.idle < OFFENDER_IDLE)
+            .collect();
+        offenders.sort_by_key(|o| std::cmp::Reverse(o.rejections));
+        offenders.truncate(top);
+        offenders
+    }
+
+    pub fn record_websocket_messages(&self, direction: &str, count: u32) {
+        self.websocket_messages_total
+            .with_label_values(&[direction])
+            .inc_by(u64::from(count));
+    }
+
+    pub fn record_websocket_termination(&self, reason: &str) {
+        self.websocket_terminations_total
+            .with_label_values(&[reason])
+            .inc();
+    }
+
+    /// `status` is the response's `grpc-status`; a stream that ended without one counts as
+    /// UNKNOWN, as clients see it. UNIMPLEMENTED calls are counted under method `other`, so
+    /// requests for made-up methods can't grow the label set.
+    pub fn record_grpc_response(&self, method: &str, status: Option<&str>) {
+        let code = status
+            .and_then(|s| s.trim().parse::<usize>().ok())
+            .and_then(|c| GRPC_CODES.get(c).copied())
+            .unwrap_or("UNKNOWN");
+        let method = if code == "UNIMPLEMENTED" {
+            "other"
+        } else {
+            method
+        };
+        self.grpc_responses_total
+            .with_label_values(&[code, method])
+            .inc();
+    }
+
+    pub fn cache(&self) -> CacheMetrics {
+        self.cache.clone()
+    }
+
+    pub fn record_upstream_connection(&self, upstream: &str, reused: bool) -> ActiveConnection {
+        self.upstream_connections_total
+            .with_label_values(&[upstream, if reused { "true" } else { "false" }])
+            .inc();
+        let active = self
+            .upstream_connections_active
+            .with_label_values(&[upstream]);
+        active.inc();
+        ActiveConnection(active)
+    }
 }
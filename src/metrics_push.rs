@@ -0,0 +1,198 @@
+use crate::configuration::{MetricsPushConfig, MetricsPushMode};
+use crate::metrics::Metrics;
+use crate::protobuf::{put_bytes, put_double, put_int64, put_varint};
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Largest literal a single snappy element can carry with a two-byte length
+const SNAPPY_LITERAL_MAX: usize = 1 << 16;
+
+/// Starts a thread pushing `metrics` every `interval_secs` until they are dropped. A failing
+/// endpoint is logged once when pushes start failing and once when they recover.
+pub fn start(config: &MetricsPushConfig, metrics: &Arc<Metrics>) {
+    let client = match reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("metrics push client: {}", e);
+            return;
+        }
+    };
+    let config = config.clone();
+    let instance = config.instance.clone().unwrap_or_else(|| {
+        std::fs::read_to_string("/etc/hostname")
+            .map(|h| h.trim().to_string())
+            .ok()
+            .filter(|h| !h.is_empty())
+            .unwrap_or_else(|| "-".to_string())
+    });
+    let weak: Weak<Metrics> = Arc::downgrade(metrics);
+    std::thread::spawn(move || {
+        let mut failing = false;
+        loop {
+            std::thread::sleep(Duration::from_secs(config.interval_secs));
+            let Some(metrics) = weak.upgrade() else {
+                return;
+            };
+            match push(&client, &config, &instance, &metrics) {
+                Ok(()) if failing => {
+                    tracing::info!(url = %config.url, "metrics push recovered");
+                    failing = false;
+                }
+                Ok(()) => {}
+                Err(e) if !failing => {
+                    tracing::warn!(url = %config.url, error = %e, "metrics push failed");
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+    });
+}
+
+fn push(
+    client: &reqwest::blocking::Client,
+    config: &MetricsPushConfig,
+    instance: &str,
+    metrics: &Metrics,
+) -> Result<(), String> {
+    let mut request = match config.mode {
+        MetricsPushMode::Pushgateway => {
+            let body = metrics.encode().map_err(|e| e.to_string())?;
+            let url = format!(
+                "{}/metrics/job/{}/instance/{}",
+                config.url.trim_end_matches('/'),
+                config.job,
+                instance
+            );
+            client
+                .put(url)
+                .header(reqwest::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(body)
+        }
+        MetricsPushMode::RemoteWrite => {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as i64;
+            let body = write_request(&metrics.gather(), &config.job, instance, timestamp);
+            client
+                .post(&config.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+                .header(reqwest::header::CONTENT_ENCODING, "snappy")
+                .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+                .body(snappy(&body))
+        }
+    };
+    for (name, value) in &config.headers {
+        request = request.header(name, value);
+    }
+    request
+        .send()
+        .and_then(|r| r.error_for_status())
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Remote-write `WriteRequest` with one sample per series. Histograms and summaries are
+/// flattened into their `_bucket`/`_sum`/`_count` series, as a scrape would see them.
+fn write_request(families: &[MetricFamily], job: &str, instance: &str, timestamp: i64) -> Vec<u8> {
+    let mut out = Vec::new();
+    for family in families {
+        let name = family.get_name();
+        for metric in family.get_metric() {
+            let mut series = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                let mut labels = BTreeMap::new();
+                labels.insert("job".to_string(), job.to_string());
+                labels.insert("instance".to_string(), instance.to_string());
+                for label in metric.get_label() {
+                    labels.insert(label.get_name().to_string(), label.get_value().to_string());
+                }
+                if let Some((label, value)) = extra {
+                    labels.insert(label.to_string(), value);
+                }
+                labels.insert("__name__".to_string(), format!("{}{}", name, suffix));
+                put_bytes(&mut out, 1, &time_series(&labels, value, timestamp));
+            };
+            flatten(family.get_field_type(), metric, &mut series);
+        }
+    }
+    out
+}
+
+fn flatten(
+    kind: MetricType,
+    metric: &Metric,
+    series: &mut impl FnMut(&str, Option<(&str, String)>, f64),
+) {
+    match kind {
+        MetricType::COUNTER => series("", None, metric.get_counter().get_value()),
+        MetricType::GAUGE => series("", None, metric.get_gauge().get_value()),
+        MetricType::UNTYPED => series("", None, metric.get_untyped().get_value()),
+        MetricType::HISTOGRAM => {
+            let histogram = metric.get_histogram();
+            for bucket in histogram.get_bucket() {
+                let le = bucket.get_upper_bound().to_string();
+                series(
+                    "_bucket",
+                    Some(("le", le)),
+                    bucket.get_cumulative_count() as f64,
+                );
+            }
+            let count = histogram.get_sample_count() as f64;
+            series("_bucket", Some(("le", "+Inf".to_string())), count);
+            series("_sum", None, histogram.get_sample_sum());
+            series("_count", None, count);
+        }
+        MetricType::SUMMARY => {
+            let summary = metric.get_summary();
+            for quantile in summary.get_quantile() {
+                let q = quantile.get_quantile().to_string();
+                series("", Some(("quantile", q)), quantile.get_value());
+            }
+            series("_sum", None, summary.get_sample_sum());
+            series("_count", None, summary.get_sample_count() as f64);
+        }
+    }
+}
+
+/// `TimeSeries`: labels in name order, as remote-write receivers require, then one sample.
+fn time_series(labels: &BTreeMap<String, String>, value: f64, timestamp: i64) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (name, label_value) in labels {
+        let mut label = Vec::new();
+        put_bytes(&mut label, 1, name.as_bytes());
+        put_bytes(&mut label, 2, label_value.as_bytes());
+        put_bytes(&mut out, 1, &label);
+    }
+    let mut sample = Vec::new();
+    put_double(&mut sample, 1, value);
+    put_int64(&mut sample, 2, timestamp);
+    put_bytes(&mut out, 2, &sample);
+    out
+}
+
+/// Snappy block format made only of literals: valid for any decoder, just not compressed.
+/// Remote-write mandates the encoding, and a metrics payload is small enough not to matter.
+fn snappy(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / SNAPPY_LITERAL_MAX * 3 + 8);
+    put_varint(&mut out, data.len() as u64);
+    for chunk in data.chunks(SNAPPY_LITERAL_MAX) {
+        let n = chunk.len() - 1;
+        if n < 60 {
+            out.push((n as u8) << 2);
+        } else if n < 256 {
+            out.push(60 << 2);
+            out.push(n as u8);
+        } else {
+            out.push(61 << 2);
+            out.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        out.extend_from_slice(chunk);
+    }
+    out
+}
@@ -0,0 +1,1053 @@
+use crate::configuration::{
+    AwsSigV4Config, ConfigError, GatewayConfig, MaintenanceResponseConfig, ResponseBufferingMode,
+    RouteState, RuleMode,
+};
+use crate::csrf::Csrf;
+use crate::graphql::GraphQlLimits;
+use crate::hmac_auth::HmacAuth;
+use crate::json_schema::BodySchema;
+use crate::lua::LuaScripts;
+use crate::metrics::Metrics;
+use crate::openapi::OpenApi;
+use crate::proxy::RequestCtx;
+use crate::quota::{Quotas, Refused};
+use crate::security::SecurityLayer;
+use crate::synthetic::SyntheticResponses;
+use crate::upstream_connection::UpstreamConnection;
+use crate::wasm::WasmPlugins;
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use pingora::http::RequestHeader;
+use pingora::Result;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Every stage that can appear in a chain, in the order used when a route doesn't list its own.
+pub const STAGE_NAMES: &[&str] = &[
+    "metrics",
+    "synthetic",
+    "tls",
+    "rate_limit",
+    "path_filter",
+    "schedule",
+    "waf",
+    "user_agent",
+    "challenge",
+    "jwt",
+    "hmac",
+    "csrf",
+    "openapi",
+    "graphql",
+    "json_schema",
+    "quota",
+    "wasm",
+    "lua",
+];
+
+/// Blocking rules that `security_rules` can switch to monitor mode.
+pub const RULE_STAGES: &[&str] = &[
+    "tls",
+    "rate_limit",
+    "path_filter",
+    "schedule",
+    "waf",
+    "user_agent",
+    "csrf",
+    "openapi",
+    "graphql",
+    "json_schema",
+];
+
+/// Stages left out of the default chain; routes opt in with `enable`.
+const OPT_IN_STAGES: &[&str] = &["waf", "hmac"];
+
+/// Outcome of a single middleware stage.
+pub enum Decision {
+    Continue,
+    /// Answer with an error status; `reason` goes to the audit trail.
+    Reject {
+        status: u16,
+        reason: &'static str,
+    },
+    /// Answer directly from the proxy.
+    Respond {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    },
+}
+
+/// Why a stage refused a request once the chain has run, e.g. over its body; `reason` goes to
+/// the audit trail, `detail` to the log.
+pub struct Rejection {
+    pub status: u16,
+    pub reason: &'static str,
+    pub detail: String,
+    /// JSON error body sent to the client; an empty error page if unset
+    pub response: Option<serde_json::Value>,
+}
+
+impl Rejection {
+    pub fn new(status: u16, reason: &'static str, detail: String) -> Self {
+        Self {
+            status,
+            reason,
+            detail,
+            response: None,
+        }
+    }
+
+    pub fn with_response(mut self, response: serde_json::Value) -> Self {
+        self.response = Some(response);
+        self
+    }
+}
+
+/// A request body held back until a stage can check it whole, for stages that finish in the
+/// body filter.
+#[derive(Default)]
+pub struct HeldBody {
+    buffer: Vec<u8>,
+    /// Checked or released; later chunks pass through
+    released: bool,
+}
+
+pub enum Held<'a> {
+    /// Still buffering, or already released
+    Pending,
+    Complete(&'a [u8]),
+    /// Grew past the limit before the end of the stream
+    TooLarge,
+}
+
+impl HeldBody {
+    /// Takes the chunk out of `body` until the stream ends, then hands back the whole body.
+    pub fn hold(
+        &mut self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        limit: usize,
+    ) -> Held<'_> {
+        if self.released {
+            return Held::Pending;
+        }
+        if let Some(chunk) = body.take() {
+            self.buffer.extend_from_slice(&chunk);
+        }
+        if self.buffer.len() > limit {
+            return Held::TooLarge;
+        }
+        if !end_of_stream {
+            // An empty chunk rather than `None`, which pingora would read as end of body
+            *body = Some(Bytes::new());
+            return Held::Pending;
+        }
+        Held::Complete(&self.buffer)
+    }
+
+    /// Forwards what was held back; the rest of the stream passes straight through.
+    pub fn release(&mut self, body: &mut Option<Bytes>) {
+        self.released = true;
+        *body = Some(std::mem::take(&mut self.buffer).into());
+    }
+
+    /// Ends the hold with the stage's verdict on the body: it's forwarded if it passed, or
+    /// failed in `monitor` mode so the request can proceed, and held back otherwise.
+    pub fn settle(
+        &mut self,
+        body: &mut Option<Bytes>,
+        result: std::result::Result<(), Rejection>,
+        monitor: bool,
+    ) -> std::result::Result<(), Rejection> {
+        if result.is_ok() || monitor {
+            self.release(body);
+        }
+        result
+    }
+}
+
+/// The request's `Content-Type` without parameters, lowercased.
+pub fn media_type(req: &RequestHeader) -> Option<String> {
+    let value = req.headers.get(http::header::CONTENT_TYPE)?.to_str().ok()?;
+    let media = value.split(';').next().unwrap_or_default();
+    Some(media.trim().to_ascii_lowercase())
+}
+
+/// One stage of the request pipeline. Stages only see the request header and context, so a
+/// chain can be run without a live session.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision>;
+}
+
+/// Ordered list of stages; the first non-`Continue` decision wins.
+#[derive(Clone)]
+pub struct Chain {
+    stages: Vec<Stage>,
+}
+
+#[derive(Clone)]
+struct Stage {
+    name: &'static str,
+    middleware: Arc<dyn Middleware>,
+    /// Rejections are recorded in `ctx.monitored` instead of enforced
+    monitor: bool,
+}
+
+impl Chain {
+    pub fn run(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        self.run_traced(req, ctx, |_, _, _| {})
+    }
+
+    /// Like `run`, reporting each stage's name, decision and monitor flag as it runs.
+    pub fn run_traced(
+        &self,
+        req: &mut RequestHeader,
+        ctx: &mut RequestCtx,
+        mut observe: impl FnMut(&'static str, &Decision, bool),
+    ) -> Result<Decision> {
+        for stage in &self.stages {
+            let decision = stage.middleware.handle(req, ctx)?;
+            observe(stage.name, &decision, stage.monitor);
+            match decision {
+                Decision::Continue => {}
+                Decision::Reject { reason, .. } if stage.monitor => ctx.monitored.push(reason),
+                decision => return Ok(decision),
+            }
+        }
+        Ok(Decision::Continue)
+    }
+
+    /// Whether the named stage runs in monitor mode, for checks that finish outside the chain.
+    pub fn monitors(&self, name: &str) -> bool {
+        self.stages.iter().any(|s| s.name == name && s.monitor)
+    }
+
+    /// Switches the named stages to monitor mode.
+    fn monitor(mut self, names: &[&str]) -> Self {
+        for stage in &mut self.stages {
+            stage.monitor = names.contains(&stage.name);
+        }
+        self
+    }
+}
+
+/// The stage instances chains are assembled from. Built once at startup; chains share them.
+pub struct Middlewares {
+    metrics: Arc<dyn Middleware>,
+    synthetic: Arc<dyn Middleware>,
+    tls: Arc<dyn Middleware>,
+    rate_limit: Arc<dyn Middleware>,
+    path_filter: Arc<dyn Middleware>,
+    schedule: Arc<dyn Middleware>,
+    waf: Arc<dyn Middleware>,
+    user_agent: Arc<dyn Middleware>,
+    challenge: Arc<dyn Middleware>,
+    jwt: Arc<dyn Middleware>,
+    hmac: Arc<dyn Middleware>,
+    csrf: Arc<dyn Middleware>,
+    openapi: Arc<dyn Middleware>,
+    graphql: Arc<dyn Middleware>,
+    json_schema: Arc<dyn Middleware>,
+    quota: Arc<dyn Middleware>,
+    wasm: Arc<dyn Middleware>,
+    lua: Arc<dyn Middleware>,
+}
+
+impl Middlewares {
+    pub fn new(
+        security: Arc<ArcSwap<SecurityLayer>>,
+        metrics: Arc<Metrics>,
+        synthetic: Arc<ArcSwap<SyntheticResponses>>,
+        quotas: Option<Arc<Quotas>>,
+        hmac: Option<Arc<HmacAuth>>,
+        wasm: Option<Arc<WasmPlugins>>,
+        lua: Option<Arc<LuaScripts>>,
+    ) -> Self {
+        Self {
+            metrics: Arc::new(MetricsEndpoint(metrics.clone(), security.clone())),
+            synthetic: Arc::new(Synthetic(synthetic)),
+            tls: Arc::new(TlsCheck(security.clone())),
+            rate_limit: Arc::new(RateLimit(security.clone(), metrics)),
+            path_filter: Arc::new(PathFilter(security.clone())),
+            schedule: Arc::new(Schedule(security.clone())),
+            waf: Arc::new(Waf(security.clone())),
+            user_agent: Arc::new(UserAgentFilter(security.clone())),
+            challenge: Arc::new(ChallengeCheck(security.clone())),
+            jwt: Arc::new(JwtAuth(security)),
+            hmac: Arc::new(HmacCheck(hmac)),
+            csrf: Arc::new(CsrfCheck),
+            openapi: Arc::new(OpenApiCheck),
+            graphql: Arc::new(GraphQlCheck),
+            json_schema: Arc::new(JsonSchemaCheck),
+            quota: Arc::new(Quota(quotas)),
+            wasm: Arc::new(WasmFilter(wasm)),
+            lua: Arc::new(LuaFilter(lua)),
+        }
+    }
+
+    fn stage(&self, name: &'static str) -> Option<Stage> {
+        let middleware = match name {
+            "metrics" => &self.metrics,
+            "synthetic" => &self.synthetic,
+            "tls" => &self.tls,
+            "rate_limit" => &self.rate_limit,
+            "path_filter" => &self.path_filter,
+            "schedule" => &self.schedule,
+            "waf" => &self.waf,
+            "user_agent" => &self.user_agent,
+            "challenge" => &self.challenge,
+            "jwt" => &self.jwt,
+            "hmac" => &self.hmac,
+            "csrf" => &self.csrf,
+            "openapi" => &self.openapi,
+            "graphql" => &self.graphql,
+            "json_schema" => &self.json_schema,
+            "quota" => &self.quota,
+            "wasm" => &self.wasm,
+            "lua" => &self.lua,
+            _ => return None,
+        };
+        Some(Stage {
+            name,
+            middleware: middleware.clone(),
+            monitor: false,
+        })
+    }
+
+    /// Names are checked against `STAGE_NAMES` during config validation; unknown ones are skipped.
+    pub fn chain<S: AsRef<str>>(&self, names: &[S]) -> Chain {
+        Chain {
+            stages: names
+                .iter()
+                .filter_map(|n| STAGE_NAMES.iter().find(|s| **s == n.as_ref()))
+                .filter_map(|n| self.stage(n))
+                .collect(),
+        }
+    }
+}
+
+/// A matched route: its chain plus the per-route policy later phases consult.
+pub struct Route {
+    /// `None` for the fallback route
+    pub name: Option<String>,
+    host: Option<String>,
+    path_prefix: String,
+    pub chain: Chain,
+    /// Responses may be stored in and served from the response cache
+    pub cache: bool,
+    /// Add an ETag to responses the upstream sent without one
+    pub generate_etag: bool,
+    pub streaming: bool,
+    pub idle_timeout: Option<Duration>,
+    /// Accepted methods; any if `None`
+    pub methods: Option<Vec<http::Method>>,
+    pub aws_sigv4: Option<AwsSigV4Config>,
+    pub csrf: Option<Arc<Csrf>>,
+    /// Spec the `openapi` stage checks requests against
+    pub openapi: Option<Arc<OpenApi>>,
+    /// Limits the `graphql` stage checks operations against
+    pub graphql: Option<Arc<GraphQlLimits>>,
+    /// Schema the `json_schema` stage checks request bodies against
+    pub json_schema: Option<Arc<BodySchema>>,
+    pub upstream_connection: Arc<UpstreamConnection>,
+    /// As configured; the admin API's override, if any, is kept in `Controls`
+    pub state: RouteState,
+    pub maintenance_response: Option<MaintenanceResponseConfig>,
+    /// Response bodies up to this many bytes are held until complete
+    pub buffer_responses: Option<usize>,
+    /// Response bodies skip everything that accumulates them
+    pub stream_responses: bool,
+}
+
+impl Route {
+    pub fn allows(&self, method: &http::Method) -> bool {
+        self.methods.as_ref().is_none_or(|methods| {
+            methods.contains(method)
+                || (method == http::Method::HEAD && methods.contains(&http::Method::GET))
+        })
+    }
+
+    /// Value for the `Allow` header of a 405
+    pub fn allow_header(&self) -> String {
+        let mut names: Vec<&str> = self.methods.iter().flatten().map(|m| m.as_str()).collect();
+        if names.contains(&"GET") && !names.contains(&"HEAD") {
+            names.push("HEAD");
+        }
+        names.join(", ")
+    }
+}
+
+/// Picks the route for a request: the first whose host and path prefix match, else a fallback
+/// running the default chain. Rebuilt on reload.
+pub struct Router {
+    routes: Vec<Arc<Route>>,
+    default: Arc<Route>,
+    strip_query_params: Vec<String>,
+    metrics_internal_paths: Vec<String>,
+    metrics_internal_bucket: bool,
+    pub debug_headers: bool,
+}
+
+impl Router {
+    /// Fails if a route's OpenAPI spec, JSON Schema or upstream CA bundle can't be loaded.
+    pub fn build(config: &GatewayConfig, middlewares: &Middlewares) -> Result<Self, ConfigError> {
+        let monitored: Vec<&str> = config
+            .security_rules
+            .iter()
+            .filter(|(_, rule)| rule.mode == RuleMode::Monitor)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        let default_chain = middlewares
+            .chain(&default_stages(&[], &[]))
+            .monitor(&monitored);
+        let parse_methods = |names: &Vec<String>| {
+            // Names are checked during config validation
+            names
+                .iter()
+                .filter_map(|m| http::Method::from_bytes(m.as_bytes()).ok())
+                .collect::<Vec<_>>()
+        };
+        let default_methods = config.allowed_methods.as_ref().map(parse_methods);
+        let default_connection = Arc::new(UpstreamConnection::load(&config.upstream_connection)?);
+        let mut routes = Vec::with_capacity(config.routes.len());
+        for r in &config.routes {
+            let openapi = match &r.openapi {
+                Some(openapi) => Some(Arc::new(OpenApi::load(openapi)?)),
+                None => None,
+            };
+            let json_schema = match &r.json_schema {
+                Some(schema) => Some(Arc::new(BodySchema::load(schema)?)),
+                None => None,
+            };
+            let upstream_connection = match &r.upstream_connection {
+                Some(connection) => Arc::new(UpstreamConnection::load(connection)?),
+                None => default_connection.clone(),
+            };
+            routes.push(Arc::new(Route {
+                name: Some(r.name.clone()),
+                host: r.host.as_ref().map(|h| h.to_ascii_lowercase()),
+                path_prefix: r.path_prefix.clone(),
+                chain: match &r.middleware {
+                    Some(names) => middlewares.chain(names).monitor(&monitored),
+                    None if r.enable.is_empty() && r.disable.is_empty() => default_chain.clone(),
+                    None => middlewares
+                        .chain(&default_stages(&r.enable, &r.disable))
+                        .monitor(&monitored),
+                },
+                cache: r.cache,
+                generate_etag: r.generate_etag,
+                streaming: r.streaming,
+                idle_timeout: r.idle_timeout_secs.map(Duration::from_secs),
+                methods: r
+                    .methods
+                    .as_ref()
+                    .map(parse_methods)
+                    .or_else(|| default_methods.clone()),
+                aws_sigv4: r.aws_sigv4.clone(),
+                openapi,
+                graphql: r.graphql.as_ref().map(|g| Arc::new(GraphQlLimits::new(g))),
+                json_schema,
+                csrf: r.csrf.as_ref().map(|c| Arc::new(Csrf::new(c))),
+                upstream_connection,
+                state: r.state,
+                maintenance_response: r.maintenance_response.clone(),
+                buffer_responses: r
+                    .response_buffering
+                    .as_ref()
+                    .filter(|b| b.mode == ResponseBufferingMode::Buffered)
+                    .map(|b| b.max_buffer_kb as usize * 1024),
+                stream_responses: r
+                    .response_buffering
+                    .as_ref()
+                    .is_some_and(|b| b.mode == ResponseBufferingMode::Streaming),
+            }));
+        }
+        let default = Arc::new(Route {
+            name: None,
+            host: None,
+            path_prefix: "/".to_string(),
+            chain: default_chain,
+            cache: false,
+            generate_etag: false,
+            streaming: false,
+            idle_timeout: None,
+            methods: default_methods,
+            aws_sigv4: None,
+            openapi: None,
+            graphql: None,
+            json_schema: None,
+            csrf: None,
+            upstream_connection: default_connection,
+            state: RouteState::Active,
+            maintenance_response: None,
+            buffer_responses: None,
+            stream_responses: false,
+        });
+        Ok(Self {
+            routes,
+            default,
+            strip_query_params: config.strip_query_params.clone(),
+            metrics_internal_paths: config.metrics_internal_paths.clone(),
+            metrics_internal_bucket: config.metrics_internal_bucket,
+            debug_headers: config.debug_headers,
+        })
+    }
+
+    /// Drops the `strip_query_params` parameters from the request's query string.
+    pub fn strip_query(&self, req: &mut RequestHeader) {
+        if self.strip_query_params.is_empty() {
+            return;
+        }
+        let Some(query) = req.uri.query() else {
+            return;
+        };
+        let kept: Vec<&str> = query
+            .split('&')
+            .filter(|param| {
+                let name = param.split('=').next().unwrap_or_default();
+                !self
+                    .strip_query_params
+                    .iter()
+                    .any(|pattern| matches_pattern(pattern, name))
+            })
+            .collect();
+        if kept.len() == query.split('&').count() {
+            return;
+        }
+        let path_and_query = match kept.join("&") {
+            q if q.is_empty() => req.uri.path().to_string(),
+            q => format!("{}?{}", req.uri.path(), q),
+        };
+        let mut parts = req.uri.clone().into_parts();
+        parts.path_and_query = path_and_query.parse().ok();
+        if let Ok(uri) = http::Uri::from_parts(parts) {
+            req.set_uri(uri);
+        }
+    }
+
+    /// Path label for the request metrics: the path itself, `internal` for the
+    /// `metrics_internal_paths` when bucketed, or `None` to leave the request uncounted.
+    pub fn metrics_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let bare = path.split('?').next().unwrap_or_default();
+        if !self
+            .metrics_internal_paths
+            .iter()
+            .any(|pattern| matches_pattern(pattern, bare))
+        {
+            return Some(path);
+        }
+        self.metrics_internal_bucket.then_some("internal")
+    }
+
+    pub fn route(&self, req: &RequestHeader) -> Arc<Route> {
+        let host = request_host(req);
+        let path = req.uri.path();
+        self.routes
+            .iter()
+            .find(|r| {
+                r.host.as_deref().is_none_or(|h| host.as_deref() == Some(h))
+                    && path.starts_with(r.path_prefix.as_str())
+            })
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+/// Exact match, or prefix match for patterns ending in `*`.
+pub fn matches_pattern(pattern: &str, value: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => value.starts_with(prefix),
+        None => value == pattern,
+    }
+}
+
+/// Default chain order with opt-in stages added and disabled stages removed.
+fn default_stages<'a>(enable: &[String], disable: &[String]) -> Vec<&'a str> {
+    STAGE_NAMES
+        .iter()
+        .copied()
+        .filter(|name| !OPT_IN_STAGES.contains(name) || enable.iter().any(|e| e == name))
+        .filter(|name| !disable.iter().any(|d| d == name))
+        .collect()
+}
+
+/// Host without port, from the Host header or (HTTP/2) the URI authority.
+pub fn request_host(req: &RequestHeader) -> Option<String> {
+    let host = req
+        .headers
+        .get(http::header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri.host())?;
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+/// Feed ages are refreshed from the security layer on each scrape.
+struct MetricsEndpoint(Arc<Metrics>, Arc<ArcSwap<SecurityLayer>>);
+
+impl Middleware for MetricsEndpoint {
+    fn handle(&self, req: &mut RequestHeader, _ctx: &mut RequestCtx) -> Result<Decision> {
+        if req.uri.path() != "/metrics" || req.method != http::Method::GET {
+            return Ok(Decision::Continue);
+        }
+        self.0.set_ip_feed_ages(&self.1.load().ip_feed_ages());
+        let body = self.0.encode().map_err(|e| {
+            pingora::Error::explain(
+                pingora::ErrorType::InternalError,
+                format!("metrics encode: {}", e),
+            )
+        })?;
+        Ok(Decision::Respond {
+            status: 200,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: body.into_bytes(),
+        })
+    }
+}
+
+struct Synthetic(Arc<ArcSwap<SyntheticResponses>>);
+
+impl Middleware for Synthetic {
+    fn handle(&self, req: &mut RequestHeader, _ctx: &mut RequestCtx) -> Result<Decision> {
+        let responses = self.0.load();
+        let host = request_host(req);
+        Ok(match responses.find(req, host.as_deref()) {
+            Some(response) => Decision::Respond {
+                status: response.status,
+                headers: response.headers.clone(),
+                body: response.body.clone(),
+            },
+            None => Decision::Continue,
+        })
+    }
+}
+
+struct RateLimit(Arc<ArcSwap<SecurityLayer>>, Arc<Metrics>);
+
+impl Middleware for RateLimit {
+    fn handle(&self, _req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        // Per address, so one client can't dodge the limit by opening more connections
+        let security = self.0.load();
+        let key = match ctx.client_addr {
+            Some(ip) => security.rate_limit_key(ip),
+            None => ctx.client_ip.clone(),
+        };
+        let result = security.check_rate_limit(&key);
+        let route = ctx.route.as_ref().and_then(|r| r.name.as_deref());
+        self.1
+            .record_rate_limit(route.unwrap_or("default"), ctx.client_addr, result.is_err());
+        Ok(match result {
+            Ok(()) => Decision::Continue,
+            Err(status) => {
+                tracing::warn!(client_ip = %ctx.client_ip, "rate limit exceeded");
+                Decision::Reject {
+                    status,
+                    reason: "rate_limited",
+                }
+            }
+        })
+    }
+}
+
+struct PathFilter(Arc<ArcSwap<SecurityLayer>>);
+
+impl Middleware for PathFilter {
+    fn handle(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        Ok(match self.0.load().check_path(req.raw_path()) {
+            Ok(()) => Decision::Continue,
+            Err(status) => {
+                tracing::warn!(path = %ctx.path, "blocked path");
+                Decision::Reject {
+                    status,
+                    reason: "blocked_path",
+                }
+            }
+        })
+    }
+}
+
+struct Schedule(Arc<ArcSwap<SecurityLayer>>);
+
+impl Middleware for Schedule {
+    fn handle(&self, _req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        Ok(match self.0.load().check_schedule(&ctx.path) {
+            Ok(()) => Decision::Continue,
+            Err(reason) => {
+                tracing::warn!(path = %ctx.path, reason, "path closed by schedule");
+                Decision::Reject {
+                    status: 403,
+                    reason,
+                }
+            }
+        })
+    }
+}
+
+struct Waf(Arc<ArcSwap<SecurityLayer>>);
+
+impl Middleware for Waf {
+    fn handle(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        Ok(
+            match self.0.load().check_attack_signatures(req.raw_path()) {
+                Ok(()) => Decision::Continue,
+                Err(status) => {
+                    tracing::warn!(client_ip = %ctx.client_ip, path = %ctx.path, "waf signature match");
+                    Decision::Reject {
+                        status,
+                        reason: "waf_signature",
+                    }
+                }
+            },
+        )
+    }
+}
+
+struct TlsCheck(Arc<ArcSwap<SecurityLayer>>);
+
+impl Middleware for TlsCheck {
+    fn handle(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        let security = self.0.load();
+        let (Some(policy), Some(tls)) = (security.tls_policy(), &ctx.tls) else {
+            return Ok(Decision::Continue);
+        };
+        let host = request_host(req);
+        Ok(match policy.check(tls, host.as_deref()) {
+            Ok(()) => Decision::Continue,
+            Err((status, reason)) => {
+                tracing::warn!(
+                    client_ip = %ctx.client_ip,
+                    tls_version = tls.version,
+                    tls_cipher = tls.cipher,
+                    sni = tls.sni.as_deref(),
+                    host = host.as_deref(),
+                    reason,
+                    "request refused by tls_policy"
+                );
+                Decision::Reject { status, reason }
+            }
+        })
+    }
+}
+
+struct UserAgentFilter(Arc<ArcSwap<SecurityLayer>>);
+
+impl Middleware for UserAgentFilter {
+    fn handle(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        let user_agent = req.headers.get("User-Agent").map(|v| v.as_bytes());
+        Ok(match self.0.load().check_user_agent(user_agent) {
+            Ok(()) => Decision::Continue,
+            Err(status) => {
+                tracing::warn!(client_ip = %ctx.client_ip, "blocked user agent");
+                Decision::Reject {
+                    status,
+                    reason: "blocked_user_agent",
+                }
+            }
+        })
+    }
+}
+
+struct ChallengeCheck(Arc<ArcSwap<SecurityLayer>>);
+
+impl Middleware for ChallengeCheck {
+    fn handle(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        let security = self.0.load();
+        let Some(challenge) = security.challenge() else {
+            return Ok(Decision::Continue);
+        };
+        let ip = ctx.client_addr;
+        let tor_exit = ip.is_some_and(|ip| security.tor_exits().is_some_and(|t| t.contains(ip)));
+        let client = ip.map(|ip| ip.to_string()).unwrap_or_default();
+        match challenge.verify(req, &client) {
+            Some(Ok(clearance)) => {
+                return Ok(Decision::Respond {
+                    status: 303,
+                    headers: vec![
+                        ("Location".to_string(), clearance.location),
+                        ("Set-Cookie".to_string(), clearance.set_cookie),
+                        ("Cache-Control".to_string(), "no-store".to_string()),
+                    ],
+                    body: Vec::new(),
+                });
+            }
+            // A failed solution gets the widget again
+            Some(Err(reason)) => {
+                tracing::warn!(client_ip = %ctx.client_ip, reason, "CAPTCHA not passed");
+            }
+            None if !challenge.suspicious(req, tor_exit) || challenge.passed(req, &client) => {
+                return Ok(Decision::Continue);
+            }
+            None => {
+                tracing::info!(client_ip = %ctx.client_ip, path = %ctx.path, "challenging client")
+            }
+        }
+        Ok(Decision::Respond {
+            status: 403,
+            headers: vec![
+                (
+                    "Content-Type".to_string(),
+                    "text/html; charset=utf-8".to_string(),
+                ),
+                ("Cache-Control".to_string(), "no-store".to_string()),
+            ],
+            body: challenge.page(req, &client).into_bytes(),
+        })
+    }
+}
+
+struct JwtAuth(Arc<ArcSwap<SecurityLayer>>);
+
+impl Middleware for JwtAuth {
+    fn handle(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        let security = self.0.load();
+        let host = request_host(req);
+        let auth_header = req.headers.get("Authorization").map(|v| v.as_bytes());
+        Ok(match security.check_jwt(host.as_deref(), auth_header) {
+            Ok(claims) => {
+                ctx.subject = claims
+                    .get("sub")
+                    .and_then(|sub| sub.as_str())
+                    .map(str::to_string);
+                security.set_claim_headers(req, &claims);
+                if let Some(minter) = security.token_minter() {
+                    let token = minter.mint(&claims).map_err(|e| {
+                        pingora::Error::explain(
+                            pingora::ErrorType::InternalError,
+                            format!("upstream token: {}", e),
+                        )
+                    })?;
+                    ctx.upstream_token = Some(token);
+                }
+                Decision::Continue
+            }
+            Err(kind) => {
+                // Requests without a token are routine; a token that fails to verify isn't
+                if matches!(kind, "missing_token" | "not_bearer") {
+                    tracing::info!(client_ip = %ctx.client_ip, host = host.as_deref(), kind, "jwt auth failed");
+                } else {
+                    tracing::warn!(client_ip = %ctx.client_ip, host = host.as_deref(), kind, "jwt auth failed");
+                }
+                Decision::Reject {
+                    status: 401,
+                    reason: "jwt_invalid",
+                }
+            }
+        })
+    }
+}
+
+struct HmacCheck(Option<Arc<HmacAuth>>);
+
+impl Middleware for HmacCheck {
+    fn handle(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        let Some(hmac) = &self.0 else {
+            return Ok(Decision::Continue);
+        };
+        Ok(match hmac.verify(req) {
+            Ok(key_id) => {
+                ctx.subject = Some(key_id);
+                Decision::Continue
+            }
+            Err(reason) => {
+                tracing::warn!(client_ip = %ctx.client_ip, reason, "hmac auth failed");
+                Decision::Reject {
+                    status: 401,
+                    reason,
+                }
+            }
+        })
+    }
+}
+
+struct CsrfCheck;
+
+impl Middleware for CsrfCheck {
+    fn handle(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        let Some(csrf) = ctx.route.as_ref().and_then(|r| r.csrf.clone()) else {
+            return Ok(Decision::Continue);
+        };
+        ctx.csrf_cookie = csrf.issue(req);
+        Ok(match csrf.check(req) {
+            Ok(()) => Decision::Continue,
+            Err(reason) => {
+                tracing::warn!(client_ip = %ctx.client_ip, path = %ctx.path, reason, "csrf check failed");
+                Decision::Reject {
+                    status: 403,
+                    reason,
+                }
+            }
+        })
+    }
+}
+
+struct OpenApiCheck;
+
+impl Middleware for OpenApiCheck {
+    fn handle(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        let Some(spec) = ctx.route.as_ref().and_then(|r| r.openapi.clone()) else {
+            return Ok(Decision::Continue);
+        };
+        Ok(match spec.check(req) {
+            Ok(body) => {
+                ctx.openapi_body = body;
+                Decision::Continue
+            }
+            Err(rejection) => {
+                tracing::warn!(
+                    client_ip = %ctx.client_ip,
+                    path = %ctx.path,
+                    reason = rejection.reason,
+                    detail = %rejection.detail,
+                    "request does not match openapi spec"
+                );
+                Decision::Reject {
+                    status: rejection.status,
+                    reason: rejection.reason,
+                }
+            }
+        })
+    }
+}
+
+struct GraphQlCheck;
+
+impl Middleware for GraphQlCheck {
+    fn handle(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        let Some(limits) = ctx.route.as_ref().and_then(|r| r.graphql.clone()) else {
+            return Ok(Decision::Continue);
+        };
+        Ok(match limits.check(req) {
+            Ok(body) => {
+                ctx.graphql_body = body;
+                Decision::Continue
+            }
+            Err(rejection) => {
+                tracing::warn!(
+                    client_ip = %ctx.client_ip,
+                    path = %ctx.path,
+                    reason = rejection.reason,
+                    detail = %rejection.detail,
+                    "graphql query refused"
+                );
+                Decision::Reject {
+                    status: rejection.status,
+                    reason: rejection.reason,
+                }
+            }
+        })
+    }
+}
+
+struct JsonSchemaCheck;
+
+impl Middleware for JsonSchemaCheck {
+    fn handle(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        let Some(schema) = ctx.route.as_ref().and_then(|r| r.json_schema.clone()) else {
+            return Ok(Decision::Continue);
+        };
+        Ok(match schema.check(req) {
+            Ok(body) => {
+                ctx.json_body = body;
+                Decision::Continue
+            }
+            Err(rejection) => {
+                tracing::warn!(
+                    client_ip = %ctx.client_ip,
+                    path = %ctx.path,
+                    reason = rejection.reason,
+                    detail = %rejection.detail,
+                    "request body refused"
+                );
+                Decision::Reject {
+                    status: rejection.status,
+                    reason: rejection.reason,
+                }
+            }
+        })
+    }
+}
+
+struct Quota(Option<Arc<Quotas>>);
+
+impl Middleware for Quota {
+    fn handle(&self, _req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        let Some(quotas) = &self.0 else {
+            return Ok(Decision::Continue);
+        };
+        // Only identities an auth stage vouched for are counted, so clients can't make up
+        // new ones to dodge their quota
+        let Some(subject) = ctx.subject.as_deref() else {
+            tracing::warn!(client_ip = %ctx.client_ip, "quota stage reached without an authenticated subject");
+            return Ok(Decision::Reject {
+                status: 401,
+                reason: "quota_unauthenticated",
+            });
+        };
+        // Prefixed as the store has always keyed subjects
+        let identity = format!("sub:{}", subject);
+        Ok(match quotas.consume(&identity) {
+            Ok(()) => Decision::Continue,
+            Err(Refused::Full) => {
+                tracing::error!(%identity, "quota store is full; raise quota.max_identities");
+                Decision::Reject {
+                    status: 503,
+                    reason: "quota_store_full",
+                }
+            }
+            Err(Refused::Exhausted { limit, reset }) => {
+                tracing::warn!(client_ip = %ctx.client_ip, %identity, "quota exhausted");
+                let retry_after = (reset - chrono::Utc::now()).num_seconds().max(1);
+                Decision::Respond {
+                    status: 429,
+                    headers: vec![
+                        ("Retry-After".to_string(), retry_after.to_string()),
+                        ("X-Quota-Limit".to_string(), limit.to_string()),
+                        ("X-Quota-Remaining".to_string(), "0".to_string()),
+                        ("X-Quota-Reset".to_string(), reset.timestamp().to_string()),
+                    ],
+                    body: Vec::new(),
+                }
+            }
+        })
+    }
+}
+
+struct WasmFilter(Option<Arc<WasmPlugins>>);
+
+impl Middleware for WasmFilter {
+    fn handle(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        let Some(plugins) = &self.0 else {
+            return Ok(Decision::Continue);
+        };
+        Ok(match plugins.on_request_headers(req, &mut ctx.wasm) {
+            Some(local) => Decision::Respond {
+                status: local.status,
+                headers: local.headers,
+                body: local.body,
+            },
+            None => Decision::Continue,
+        })
+    }
+}
+
+struct LuaFilter(Option<Arc<LuaScripts>>);
+
+impl Middleware for LuaFilter {
+    fn handle(&self, req: &mut RequestHeader, ctx: &mut RequestCtx) -> Result<Decision> {
+        let Some(scripts) = &self.0 else {
+            return Ok(Decision::Continue);
+        };
+        ctx.lua = true;
+        Ok(match scripts.on_request(req, &ctx.client_ip) {
+            Some(local) => Decision::Respond {
+                status: local.status,
+                headers: local.headers,
+                body: local.body,
+            },
+            None => Decision::Continue,
+        })
+    }
+}
@@ -0,0 +1,703 @@
+use crate::configuration::{ConfigError, OpenApiConfig};
+use crate::json_schema::{Compiler, SchemaError, SchemaId, Schemas};
+use crate::middleware::{media_type, Held, HeldBody, Rejection};
+use bytes::Bytes;
+use pingora::http::RequestHeader;
+use serde_json::Value;
+
+const METHODS: &[&str] = &[
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+/// Headers OpenAPI says parameter definitions must not describe.
+const RESERVED_HEADERS: &[&str] = &["accept", "content-type", "authorization"];
+
+/// An OpenAPI 3 spec compiled for checking requests against: path, method, path/query/header
+/// parameters (form style only) and JSON request bodies. Loaded with the router, so a reload
+/// re-reads the spec file.
+pub struct OpenApi {
+    base_path: String,
+    /// Templated segments after literal ones, so `/users/me` matches before `/users/{id}`
+    paths: Vec<PathItem>,
+    schemas: Schemas,
+    allow_unknown_operations: bool,
+    allow_unknown_query_params: bool,
+    validate_body: bool,
+    max_body_bytes: usize,
+}
+
+/// A JSON request body held back until it can be validated whole.
+pub struct BodyCheck {
+    schema: SchemaId,
+    required: bool,
+    held: HeldBody,
+}
+
+struct PathItem {
+    template: String,
+    segments: Vec<Segment>,
+    operations: Vec<(http::Method, Operation)>,
+}
+
+enum Segment {
+    Literal(String),
+    Param {
+        prefix: String,
+        name: String,
+        suffix: String,
+    },
+}
+
+struct Operation {
+    parameters: Vec<Parameter>,
+    body: Option<RequestBody>,
+}
+
+struct Parameter {
+    name: String,
+    location: Location,
+    required: bool,
+    schema: Option<SchemaId>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Location {
+    Path,
+    Query,
+    Header,
+}
+
+impl Location {
+    fn as_str(self) -> &'static str {
+        match self {
+            Location::Path => "path",
+            Location::Query => "query",
+            Location::Header => "header",
+        }
+    }
+}
+
+struct RequestBody {
+    required: bool,
+    /// Media type (possibly `type/*` or `*/*`) and, for JSON ones, the schema to validate with
+    content: Vec<(String, Option<SchemaId>)>,
+}
+
+impl OpenApi {
+    pub fn load(config: &OpenApiConfig) -> Result<Self, ConfigError> {
+        let invalid =
+            |e: String| ConfigError::Validation(format!("openapi {}: {}", config.spec, e));
+        let text = std::fs::read_to_string(&config.spec)
+            .map_err(|e| ConfigError::Io(config.spec.clone(), e))?;
+        // JSON is YAML, so one parser reads both
+        let document: Value = serde_yaml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+        if !document
+            .get("openapi")
+            .and_then(Value::as_str)
+            .is_some_and(|v| v.starts_with("3."))
+        {
+            return Err(invalid("only OpenAPI 3.x specs are supported".into()));
+        }
+
+        let mut compiler = Compiler::new(&document);
+        let mut paths = Vec::new();
+        let items = document.get("paths").and_then(Value::as_object);
+        for (template, item) in items.into_iter().flatten() {
+            let item = resolve(&document, item);
+            let segments = parse_template(template).map_err(&invalid)?;
+            let shared = item.get("parameters");
+            let mut operations = Vec::new();
+            for name in METHODS {
+                let Some(operation) = item.get(*name) else {
+                    continue;
+                };
+                let operation = compile_operation(&mut compiler, &document, shared, operation)
+                    .map_err(|e| invalid(format!("{} {}: {}", name, template, e)))?;
+                let method = http::Method::from_bytes(name.to_ascii_uppercase().as_bytes())
+                    .map_err(|e| invalid(e.to_string()))?;
+                operations.push((method, operation));
+            }
+            paths.push(PathItem {
+                template: template.clone(),
+                segments,
+                operations,
+            });
+        }
+        paths.sort_by_cached_key(|p| {
+            p.segments
+                .iter()
+                .map(|s| matches!(s, Segment::Param { .. }))
+                .collect::<Vec<_>>()
+        });
+
+        let base_path = config
+            .base_path
+            .clone()
+            .or_else(|| server_path(&document))
+            .unwrap_or_default();
+        Ok(Self {
+            base_path: base_path.trim_end_matches('/').to_string(),
+            paths,
+            schemas: compiler.finish(),
+            allow_unknown_operations: config.allow_unknown_operations,
+            allow_unknown_query_params: config.allow_unknown_query_params,
+            validate_body: config.validate_body,
+            max_body_bytes: config.max_body_kb * 1024,
+        })
+    }
+
+    /// Checks everything but the body, returning a `BodyCheck` when the body must be
+    /// validated as it arrives.
+    pub fn check(&self, req: &RequestHeader) -> Result<Option<BodyCheck>, Rejection> {
+        let path = req.uri.path();
+        let Some((item, captures)) = self.find(path) else {
+            return match self.allow_unknown_operations {
+                true => Ok(None),
+                false => Err(Rejection::new(
+                    404,
+                    "openapi_unknown_path",
+                    path.to_string(),
+                )),
+            };
+        };
+        let operation = item
+            .operations
+            .iter()
+            .find(|(m, _)| *m == req.method)
+            .or_else(|| {
+                let head = req.method == http::Method::HEAD;
+                item.operations
+                    .iter()
+                    .find(|(m, _)| head && *m == http::Method::GET)
+            });
+        let Some((_, operation)) = operation else {
+            return match self.allow_unknown_operations {
+                true => Ok(None),
+                false => Err(Rejection::new(
+                    405,
+                    "openapi_unknown_method",
+                    format!("{} {}", req.method, item.template),
+                )),
+            };
+        };
+
+        let query = parse_query(req.uri.query().unwrap_or_default());
+        for parameter in &operation.parameters {
+            let values: Vec<String> = match parameter.location {
+                Location::Path => captures
+                    .iter()
+                    .filter(|(name, _)| *name == parameter.name)
+                    .map(|(_, value)| value.clone())
+                    .collect(),
+                Location::Query => query
+                    .iter()
+                    .filter(|(name, _)| *name == parameter.name)
+                    .map(|(_, value)| value.clone())
+                    .collect(),
+                Location::Header => req
+                    .headers
+                    .get_all(parameter.name.as_str())
+                    .iter()
+                    .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+                    .collect(),
+            };
+            let detail = |message: String| {
+                format!(
+                    "{} parameter '{}': {}",
+                    parameter.location.as_str(),
+                    parameter.name,
+                    message
+                )
+            };
+            if values.is_empty() {
+                if parameter.required {
+                    let detail = detail("missing".into());
+                    return Err(Rejection::new(400, "openapi_invalid_parameter", detail));
+                }
+                continue;
+            }
+            if let Some(schema) = parameter.schema {
+                if let Err(errors) = self.validate_parameter(schema, &values) {
+                    let detail = detail(describe(&errors));
+                    return Err(Rejection::new(400, "openapi_invalid_parameter", detail));
+                }
+            }
+        }
+        if !self.allow_unknown_query_params {
+            let unknown = query.iter().find(|(name, _)| {
+                !operation
+                    .parameters
+                    .iter()
+                    .any(|p| p.location == Location::Query && p.name == *name)
+            });
+            if let Some((name, _)) = unknown {
+                let detail = format!("query parameter '{}' is not declared", name);
+                return Err(Rejection::new(400, "openapi_unknown_parameter", detail));
+            }
+        }
+
+        let Some(body) = &operation.body else {
+            return Ok(None);
+        };
+        if !has_body(req) {
+            return match body.required {
+                true => Err(Rejection::new(400, "openapi_missing_body", String::new())),
+                false => Ok(None),
+            };
+        }
+        let content_type = media_type(req).unwrap_or_default();
+        let Some(schema) = media_schema(&body.content, &content_type) else {
+            return Err(Rejection::new(
+                415,
+                "openapi_unsupported_media_type",
+                content_type,
+            ));
+        };
+        Ok(match (self.validate_body, schema) {
+            (true, Some(schema)) => Some(BodyCheck {
+                schema,
+                required: body.required,
+                held: HeldBody::default(),
+            }),
+            _ => None,
+        })
+    }
+
+    /// Validates the body against the operation's schema once it's all in.
+    pub fn check_body(
+        &self,
+        check: &mut BodyCheck,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        monitor: bool,
+    ) -> Result<(), Rejection> {
+        let result = match check.held.hold(body, end_of_stream, self.max_body_bytes) {
+            Held::Pending => return Ok(()),
+            Held::TooLarge => Err(Rejection::new(
+                413,
+                "openapi_body_too_large",
+                format!("body exceeds {} bytes", self.max_body_bytes),
+            )),
+            Held::Complete([]) => match check.required {
+                true => Err(Rejection::new(400, "openapi_missing_body", String::new())),
+                false => Ok(()),
+            },
+            Held::Complete(bytes) => match serde_json::from_slice::<Value>(bytes) {
+                Ok(value) => self
+                    .schemas
+                    .validate(check.schema, &value)
+                    .map_err(|errors| {
+                        Rejection::new(422, "openapi_invalid_body", describe(&errors))
+                    }),
+                Err(e) => Err(Rejection::new(400, "openapi_malformed_body", e.to_string())),
+            },
+        };
+        check.held.settle(body, result, monitor)
+    }
+
+    fn find<'a>(&'a self, path: &str) -> Option<(&'a PathItem, Vec<(&'a str, String)>)> {
+        let path = path.strip_prefix(self.base_path.as_str())?;
+        if !(path.is_empty() || path.starts_with('/')) {
+            return None;
+        }
+        let parts: Vec<&str> = path.split('/').skip(1).collect();
+        self.paths.iter().find_map(|item| {
+            if item.segments.len() != parts.len() {
+                return None;
+            }
+            let mut captures = Vec::new();
+            for (segment, part) in item.segments.iter().zip(&parts) {
+                match segment {
+                    Segment::Literal(literal) => {
+                        if literal != part {
+                            return None;
+                        }
+                    }
+                    Segment::Param {
+                        prefix,
+                        name,
+                        suffix,
+                    } => {
+                        let value = part.strip_prefix(prefix.as_str())?;
+                        let value = value.strip_suffix(suffix.as_str())?;
+                        if value.is_empty() {
+                            return None;
+                        }
+                        captures.push((name.as_str(), percent_decode(value, false)));
+                    }
+                }
+            }
+            Some((item, captures))
+        })
+    }
+
+    /// Parameters arrive as text; they pass if the raw string, the value it reads as, or (for
+    /// repeated or comma-separated parameters) an array of either conforms.
+    fn validate_parameter(
+        &self,
+        schema: SchemaId,
+        values: &[String],
+    ) -> Result<(), Vec<SchemaError>> {
+        let items: Vec<&str> = match values {
+            [single] => single.split(',').collect(),
+            _ => values.iter().map(String::as_str).collect(),
+        };
+        let mut candidates = Vec::new();
+        if let [single] = values {
+            candidates.push(Value::String(single.clone()));
+            if let Some(value) = scalar(single) {
+                candidates.push(value);
+            }
+        }
+        candidates.push(Value::Array(
+            items.iter().map(|v| Value::String(v.to_string())).collect(),
+        ));
+        candidates.push(Value::Array(
+            items
+                .iter()
+                .map(|v| scalar(v).unwrap_or_else(|| Value::String(v.to_string())))
+                .collect(),
+        ));
+        let mut first = None;
+        for candidate in &candidates {
+            match self.schemas.validate(schema, candidate) {
+                Ok(()) => return Ok(()),
+                Err(errors) => {
+                    first.get_or_insert(errors);
+                }
+            }
+        }
+        Err(first.unwrap_or_default())
+    }
+}
+
+fn describe(errors: &[SchemaError]) -> String {
+    errors
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Follows `$ref`s to components within the document.
+fn resolve<'a>(document: &'a Value, mut value: &'a Value) -> &'a Value {
+    // Bounded so a reference cycle can't hang loading
+    for _ in 0..16 {
+        let target = value
+            .get("$ref")
+            .and_then(Value::as_str)
+            .and_then(|r| r.strip_prefix('#'))
+            .and_then(|pointer| document.pointer(pointer));
+        match target {
+            Some(target) => value = target,
+            None => break,
+        }
+    }
+    value
+}
+
+fn parse_template(template: &str) -> Result<Vec<Segment>, String> {
+    template
+        .split('/')
+        .skip(1)
+        .map(|segment| {
+            let Some(open) = segment.find('{') else {
+                return Ok(Segment::Literal(segment.to_string()));
+            };
+            let close = segment[open..]
+                .find('}')
+                .map(|i| open + i)
+                .ok_or_else(|| format!("path {}: unclosed parameter", template))?;
+            if segment[close + 1..].contains('{') {
+                return Err(format!(
+                    "path {}: more than one parameter in a segment is not supported",
+                    template
+                ));
+            }
+            Ok(Segment::Param {
+                prefix: segment[..open].to_string(),
+                name: segment[open + 1..close].to_string(),
+                suffix: segment[close + 1..].to_string(),
+            })
+        })
+        .collect()
+}
+
+fn compile_operation(
+    compiler: &mut Compiler,
+    document: &Value,
+    shared: Option<&Value>,
+    operation: &Value,
+) -> Result<Operation, String> {
+    let mut parameters: Vec<Parameter> = Vec::new();
+    // Operation-level definitions override path-level ones with the same name and location
+    let definitions = shared
+        .into_iter()
+        .chain(operation.get("parameters"))
+        .filter_map(Value::as_array)
+        .flatten();
+    for definition in definitions {
+        let definition = resolve(document, definition);
+        let name = definition
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or("parameter without a name")?;
+        let location = match definition.get("in").and_then(Value::as_str) {
+            Some("path") => Location::Path,
+            Some("query") => Location::Query,
+            Some("header") if RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) => {
+                continue
+            }
+            Some("header") => Location::Header,
+            // Cookies aren't checked
+            _ => continue,
+        };
+        let schema = match definition.get("schema") {
+            Some(schema) => Some(compiler.compile(schema)?),
+            None => None,
+        };
+        let parameter = Parameter {
+            name: match location {
+                Location::Header => name.to_ascii_lowercase(),
+                _ => name.to_string(),
+            },
+            location,
+            required: location == Location::Path
+                || definition.get("required") == Some(&Value::Bool(true)),
+            schema,
+        };
+        parameters.retain(|p| !(p.name == parameter.name && p.location == location));
+        parameters.push(parameter);
+    }
+
+    let body = match operation.get("requestBody") {
+        Some(body) => {
+            let body = resolve(document, body);
+            let mut content = Vec::new();
+            for (media, definition) in body
+                .get("content")
+                .and_then(Value::as_object)
+                .into_iter()
+                .flatten()
+            {
+                let media = media.to_ascii_lowercase();
+                let schema = match definition.get("schema") {
+                    Some(schema) if is_json(&media) => Some(compiler.compile(schema)?),
+                    _ => None,
+                };
+                content.push((media, schema));
+            }
+            Some(RequestBody {
+                required: body.get("required") == Some(&Value::Bool(true)),
+                content,
+            })
+        }
+        None => None,
+    };
+    Ok(Operation { parameters, body })
+}
+
+/// Path of the first server URL, e.g. `/v1` for `https://api.example.com/v1`. URLs with
+/// variables are skipped.
+fn server_path(document: &Value) -> Option<String> {
+    let url = document.pointer("/servers/0/url")?.as_str()?;
+    if url.contains('{') {
+        return None;
+    }
+    let path = match url.split_once("://") {
+        Some((_, rest)) => rest.find('/').map(|i| &rest[i..]).unwrap_or_default(),
+        None => url,
+    };
+    Some(path.to_string())
+}
+
+fn is_json(media: &str) -> bool {
+    media == "application/json" || media.ends_with("+json") || media == "*/*"
+}
+
+/// The declared media type a `Content-Type` falls under: exact, then `type/*`, then `*/*`.
+/// `Some(None)` when it matches but has no schema to validate against.
+fn media_schema(
+    content: &[(String, Option<SchemaId>)],
+    content_type: &str,
+) -> Option<Option<SchemaId>> {
+    let wildcard = content_type
+        .split_once('/')
+        .map(|(kind, _)| format!("{}/*", kind))
+        .unwrap_or_default();
+    [content_type, wildcard.as_str(), "*/*"]
+        .iter()
+        .find_map(|media| content.iter().find(|(m, _)| m == media))
+        .map(|(_, schema)| *schema)
+}
+
+fn has_body(req: &RequestHeader) -> bool {
+    let length = req
+        .headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    match length {
+        Some(length) => length > 0,
+        // HTTP/2 bodies need not announce their length
+        None => {
+            req.headers.contains_key(http::header::TRANSFER_ENCODING)
+                || (req.version == http::Version::HTTP_2
+                    && matches!(
+                        req.method,
+                        http::Method::POST | http::Method::PUT | http::Method::PATCH
+                    ))
+        }
+    }
+}
+
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name, true), percent_decode(value, true))
+        })
+        .collect()
+}
+
+/// Number, boolean or null a parameter's text reads as.
+fn scalar(text: &str) -> Option<Value> {
+    match serde_json::from_str(text) {
+        Ok(value @ (Value::Number(_) | Value::Bool(_) | Value::Null)) => Some(value),
+        _ => None,
+    }
+}
+
+pub(crate) fn percent_decode(input: &str, plus_as_space: bool) -> String {
+    let input = input.as_bytes();
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'%' if i + 3 <= input.len() => {
+                let hex = std::str::from_utf8(&input[i + 1..i + 3]).ok();
+                if let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    out.push(byte);
+                    i += 3;
+                    continue;
+                }
+                out.push(b'%');
+            }
+            b'+' if plus_as_space => out.push(b' '),
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn load(name: &str, spec: Value) -> OpenApi {
+        let path =
+            std::env::temp_dir().join(format!("openapi-test-{}-{}.json", std::process::id(), name));
+        std::fs::write(&path, spec.to_string()).unwrap();
+        let api = OpenApi::load(&OpenApiConfig {
+            spec: path.to_string_lossy().into_owned(),
+            base_path: None,
+            allow_unknown_operations: false,
+            allow_unknown_query_params: false,
+            validate_body: true,
+            max_body_kb: 64,
+        });
+        std::fs::remove_file(&path).unwrap();
+        api.unwrap()
+    }
+
+    fn users() -> OpenApi {
+        load(
+            "users",
+            json!({
+                "openapi": "3.0.3",
+                "servers": [{"url": "https://api.example.com/v1"}],
+                "paths": {
+                    "/users/{id}": {
+                        "parameters": [
+                            {"name": "id", "in": "path", "schema": {"type": "integer"}}
+                        ],
+                        "get": {}
+                    },
+                    // Sorts after `/users/{id}` in the spec's map
+                    "/users/~me": {"get": {}},
+                    "/users/{id}/posts": {
+                        "get": {
+                            "parameters": [
+                                {
+                                    "name": "limit",
+                                    "in": "query",
+                                    "schema": {"type": "integer", "maximum": 100}
+                                },
+                                {
+                                    "name": "ids",
+                                    "in": "query",
+                                    "schema": {"type": "array", "items": {"type": "integer"}}
+                                },
+                                {"name": "draft", "in": "query", "schema": {"type": "boolean"}},
+                                {"name": "tag", "in": "query", "schema": {"type": "string"}}
+                            ]
+                        }
+                    }
+                }
+            }),
+        )
+    }
+
+    fn refused(api: &OpenApi, method: &str, uri: &str) -> Option<&'static str> {
+        let req = RequestHeader::build(method, uri.as_bytes(), None).unwrap();
+        api.check(&req).err().map(|r| r.reason)
+    }
+
+    #[test]
+    fn literal_segments_match_before_templates() {
+        let api = users();
+        // `~me` isn't an integer, so only the literal path can take it
+        assert_eq!(refused(&api, "GET", "/v1/users/~me"), None);
+        assert_eq!(refused(&api, "GET", "/v1/users/42"), None);
+        assert_eq!(
+            refused(&api, "GET", "/v1/users/bob"),
+            Some("openapi_invalid_parameter")
+        );
+    }
+
+    #[test]
+    fn unknown_paths_and_methods_are_refused() {
+        let api = users();
+        assert_eq!(
+            refused(&api, "GET", "/users/42"),
+            Some("openapi_unknown_path")
+        );
+        assert_eq!(
+            refused(&api, "GET", "/v1/users/42/comments"),
+            Some("openapi_unknown_path")
+        );
+        assert_eq!(
+            refused(&api, "DELETE", "/v1/users/42"),
+            Some("openapi_unknown_method")
+        );
+        assert_eq!(refused(&api, "HEAD", "/v1/users/42"), None);
+    }
+
+    #[test]
+    fn query_parameters_are_coerced_from_text() {
+        let api = users();
+        let posts = |query: &str| refused(&api, "GET", &format!("/v1/users/1/posts?{}", query));
+        assert_eq!(posts("limit=10&draft=true&tag=123"), None);
+        assert_eq!(posts("limit=ten"), Some("openapi_invalid_parameter"));
+        assert_eq!(posts("limit=500"), Some("openapi_invalid_parameter"));
+        assert_eq!(posts("draft=maybe"), Some("openapi_invalid_parameter"));
+        assert_eq!(posts("ids=1,2,3"), None);
+        assert_eq!(posts("ids=1&ids=2"), None);
+        assert_eq!(posts("ids=1,x"), Some("openapi_invalid_parameter"));
+        assert_eq!(posts("page=2"), Some("openapi_unknown_parameter"));
+    }
+}
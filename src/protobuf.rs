@@ -0,0 +1,95 @@
+use std::io;
+
+/// A decoded protobuf field value. Fixed-width fields are skipped by [`fields`].
+pub enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Field<'a> {
+    pub fn bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            Field::Bytes(b) => Some(b),
+            Field::Varint(_) => None,
+        }
+    }
+
+    pub fn varint(&self) -> Option<u64> {
+        match self {
+            Field::Varint(v) => Some(*v),
+            Field::Bytes(_) => None,
+        }
+    }
+}
+
+/// Fields of a protobuf message as (field number, value), in wire order.
+pub fn fields(mut buf: &[u8]) -> io::Result<Vec<(u64, Field<'_>)>> {
+    let mut out = Vec::new();
+    while !buf.is_empty() {
+        let key = varint(&mut buf)?;
+        match key & 7 {
+            0 => out.push((key >> 3, Field::Varint(varint(&mut buf)?))),
+            1 => buf = buf.get(8..).ok_or_else(|| invalid("truncated field"))?,
+            2 => {
+                let len = varint(&mut buf)? as usize;
+                let value = buf.get(..len).ok_or_else(|| invalid("truncated field"))?;
+                out.push((key >> 3, Field::Bytes(value)));
+                buf = &buf[len..];
+            }
+            5 => buf = buf.get(4..).ok_or_else(|| invalid("truncated field"))?,
+            _ => return Err(invalid("unsupported wire type")),
+        }
+    }
+    Ok(out)
+}
+
+fn varint(buf: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf
+            .split_first()
+            .ok_or_else(|| invalid("truncated varint"))?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+pub fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+pub fn put_bytes(out: &mut Vec<u8>, field: u64, value: &[u8]) {
+    put_varint(out, field << 3 | 2);
+    put_varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+pub fn put_int64(out: &mut Vec<u8>, field: u64, value: i64) {
+    put_varint(out, field << 3);
+    put_varint(out, value as u64);
+}
+
+pub fn put_double(out: &mut Vec<u8>, field: u64, value: f64) {
+    put_varint(out, field << 3 | 1);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Proto3 omits default values, so `false` writes nothing.
+pub fn put_bool(out: &mut Vec<u8>, field: u64, value: bool) {
+    if value {
+        put_varint(out, field << 3);
+        out.push(1);
+    }
+}
+
+pub fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
@@ -1,26 +1,472 @@
-use crate::metrics::Metrics;
-use crate::security::SecurityLayer;
+use crate::access_log::AccessLog;
+use crate::admin::RecentBlocks;
+use crate::anomaly::AnomalyDetector;
+use crate::aws_signer::AwsSigner;
+use crate::balancer::{Balancer, Lease};
+use crate::bandwidth::{BandwidthLimits, Throttle};
+use crate::cache::{self, CacheFill, Flight, Lookup, ResponseCache};
+use crate::capture::{BodyCapture, CaptureRecord};
+use crate::configuration::{
+    AbsoluteFormPolicy, ConnectPolicy, RetryAfterConfig, RouteState, TorExitAction,
+};
+use crate::controls::Controls;
+use crate::downstream::DownstreamLimits;
+use crate::egress::Egress;
+use crate::error_response::ErrorResponses;
+use crate::graphql;
+use crate::grpc_web::{self, GrpcWebCall};
+use crate::header_normalization::HeaderNormalizer;
+use crate::icap::{IcapClient, IcapScan};
+use crate::json_schema;
+use crate::lua::LuaScripts;
+use crate::metrics::{ActiveConnection, InFlightRequest, Metrics};
+use crate::middleware::{self, Decision, Rejection, Route, Router};
+use crate::openapi;
+use crate::security::{self, SecurityLayer, TenantPermit};
+use crate::spiffe::Spiffe;
+use crate::syslog::{EventKind, SyslogSink};
+use crate::tarpit::Tarpit;
+use crate::tls_info::TlsInfo;
+use crate::upload_filter::{MultipartScan, UploadFilter};
+use crate::wasm::{PluginContext, WasmPlugins};
+use crate::webhook::AlertWebhook;
+use crate::websocket::{Violation, WebSocketConn, WebSocketLimits};
 use arc_swap::ArcSwap; // NEW: Required for Hot Reload
 use async_trait::async_trait;
 use bytes::Bytes;
 use pingora::http::ResponseHeader;
+use pingora::lb::Backend;
 use pingora::prelude::*;
+use pingora::protocols::ALPN;
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Instant;
-use tracing;
 
 pub struct RequestCtx {
     pub start: Instant,
+    /// Sent upstream in the `request_id` header and put in logs and error bodies; random
+    /// unless the client's is kept
+    pub request_id: String,
+    /// `request_id` is the client's own
+    pub request_id_kept: bool,
     pub method: String,
     pub path: String,
+    /// `ip:port` of the peer, or the address a trusted proxy reported for the client
+    pub client_ip: String,
+    /// The client's address, for per-client checks; `None` on non-IP connections
+    pub client_addr: Option<IpAddr>,
+    /// Route picked by the router; `None` until routing ran
+    pub route: Option<Arc<Route>>,
+    /// Set when the response may be stored in the cache
+    pub cache_key: Option<String>,
+    pub cache_fill: Option<CacheFill>,
+    /// Held while this request fills its cache key for coalesced misses
+    pub cache_flight: Option<Flight>,
+    /// The response skips everything that accumulates its body: an event stream on a
+    /// `streaming` route, or any response on a route with streaming `response_buffering`
+    pub streamed: bool,
+    /// Response body held back on a route with buffered `response_buffering`
+    pub response_buffer: Option<Vec<u8>>,
+    /// `bandwidth_limits` caps for the request and response bodies
+    pub upload_throttle: Option<Throttle>,
+    pub download_throttle: Option<Throttle>,
+    pub capture: Option<CaptureRecord>,
+    pub grpc_web: Option<GrpcWebCall>,
+    pub upload_scan: Option<MultipartScan>,
+    pub icap_request: Option<IcapScan>,
+    pub icap_response: Option<IcapScan>,
+    /// Counts this request in the in-flight gauges until the context is dropped
+    pub in_flight: Option<InFlightRequest>,
+    /// Whether `DownstreamLimits::on_done` is owed for this request
+    pub downstream_counted: bool,
+    /// Request body bytes read from the client so far
+    pub request_body_bytes: u64,
+    pub wasm: Vec<PluginContext>,
+    /// The route's chain ran the lua stage, so the response hooks run too
+    pub lua: bool,
+    /// Internal token minted by the jwt stage to replace the client's
+    pub upstream_token: Option<String>,
+    /// Set by the openapi stage when the request body must be validated
+    pub openapi_body: Option<openapi::BodyCheck>,
+    /// Set by the graphql stage when the query is in the request body
+    pub graphql_body: Option<graphql::BodyCheck>,
+    /// Set by the json_schema stage when the request has a body to validate
+    pub json_body: Option<json_schema::BodyCheck>,
+    /// JSON body for the error response when a body check fails the request
+    pub error_response: Option<serde_json::Value>,
+    /// `Set-Cookie` value for a CSRF token the csrf stage issued
+    pub csrf_cookie: Option<String>,
+    /// Rejection reasons of rules in monitor mode that matched this request
+    pub monitored: Vec<&'static str>,
+    /// `sub` of the validated JWT, for quota accounting
+    pub subject: Option<String>,
+    /// Set for hosts with `tenant_limits`; releases the tenant's concurrency slot on drop
+    pub tenant: Option<TenantPermit>,
+    /// A connection to a same-zone upstream failed; pick from any zone
+    pub zone_spill: bool,
+    /// Load accounting for the picked upstream (P2C mode)
+    pub upstream_lease: Option<Lease>,
+    /// The upstream picked for the current attempt
+    pub upstream: Option<Backend>,
+    pub upstream_connection: Option<ActiveConnection>,
+    /// Set once the upstream accepted a WebSocket upgrade, when limits are configured
+    pub websocket: Option<WebSocketConn>,
+    /// The upstream answered with native gRPC
+    pub grpc: bool,
+    /// `grpc-status` from the response trailers, or the header of a trailers-only response
+    pub grpc_status: Option<String>,
+    /// Handshake details of the client connection; `None` for plaintext
+    pub tls: Option<TlsInfo>,
+    /// Every upstream tried for this request, in order
+    pub attempts: Vec<UpstreamAttempt>,
+}
+
+/// One try at an upstream, listed in the access record.
+pub struct UpstreamAttempt {
+    upstream: String,
+    started: Instant,
+    /// Until a connection was made or taken from the pool
+    connect: Option<std::time::Duration>,
+    reused: bool,
+    /// Error type and message of a failed attempt; `None` if it got a response
+    error: Option<(String, String)>,
+}
+
+impl UpstreamAttempt {
+    fn to_json(&self, retry: usize) -> serde_json::Value {
+        serde_json::json!({
+            "upstream": self.upstream,
+            "retry": retry,
+            "connect_ms": self.connect.map(|d| d.as_secs_f64() * 1000.0),
+            "reused": self.reused,
+            "error": self.error.as_ref().map(|(kind, _)| kind),
+            "error_detail": self.error.as_ref().map(|(_, detail)| detail),
+        })
+    }
+
+    fn fail(&mut self, e: &pingora::Error) {
+        self.error = Some((e.etype().as_str().to_string(), e.to_string()));
+    }
+}
+
+impl Default for RequestCtx {
+    fn default() -> Self {
+        RequestCtx {
+            start: Instant::now(),
+            request_id: format!("{:032x}", rand::random::<u128>()),
+            request_id_kept: false,
+            method: String::new(),
+            path: String::new(),
+            client_ip: String::new(),
+            client_addr: None,
+            route: None,
+            cache_key: None,
+            cache_fill: None,
+            cache_flight: None,
+            streamed: false,
+            response_buffer: None,
+            upload_throttle: None,
+            download_throttle: None,
+            capture: None,
+            grpc_web: None,
+            upload_scan: None,
+            icap_request: None,
+            icap_response: None,
+            in_flight: None,
+            downstream_counted: false,
+            request_body_bytes: 0,
+            wasm: Vec::new(),
+            lua: false,
+            upstream_token: None,
+            openapi_body: None,
+            graphql_body: None,
+            json_body: None,
+            error_response: None,
+            csrf_cookie: None,
+            monitored: Vec::new(),
+            subject: None,
+            tenant: None,
+            zone_spill: false,
+            upstream_lease: None,
+            upstream: None,
+            upstream_connection: None,
+            websocket: None,
+            grpc: false,
+            grpc_status: None,
+            tls: None,
+            attempts: Vec::new(),
+        }
+    }
 }
 
 pub struct SecureProxy {
-    pub lb: Arc<LoadBalancer<RoundRobin>>,
+    pub balancer: Balancer,
+    pub retry_after: Option<RetryAfterConfig>,
+    pub upstream_idle_timeout: Option<std::time::Duration>,
+    pub aws_signer: AwsSigner,
     // CHANGED: Wrapped in ArcSwap to allow swapping config while running
     pub security: Arc<ArcSwap<SecurityLayer>>,
     pub metrics: Arc<Metrics>,
     pub upstream_sni: String,
+    pub access_log: Option<Arc<AccessLog>>,
+    pub syslog: Option<Arc<SyslogSink>>,
+    pub body_capture: Option<Arc<BodyCapture>>,
+    pub wasm_plugins: Option<Arc<WasmPlugins>>,
+    pub lua_scripts: Option<Arc<LuaScripts>>,
+    pub router: Arc<ArcSwap<Router>>,
+    pub grpc_web: bool,
+    pub downstream: Arc<DownstreamLimits>,
+    pub forward_trailers: bool,
+    pub cache: Option<Arc<ResponseCache>>,
+    pub upload_filter: Option<Arc<UploadFilter>>,
+    pub websocket: Option<Arc<WebSocketLimits>>,
+    pub icap: Option<Arc<IcapClient>>,
+    pub egress: Option<Arc<Egress>>,
+    pub spiffe: Option<Arc<Spiffe>>,
+    pub recent_blocks: Arc<RecentBlocks>,
+    pub controls: Arc<Controls>,
+    pub anomalies: Option<Arc<AnomalyDetector>>,
+    pub alerts: Option<Arc<AlertWebhook>>,
+    pub error_responses: ErrorResponses,
+    pub header_normalizer: Option<HeaderNormalizer>,
+    pub tarpit: Option<Tarpit>,
+    pub bandwidth: Option<BandwidthLimits>,
+    pub absolute_form: AbsoluteFormPolicy,
+    pub connect_requests: ConnectPolicy,
+}
+
+impl SecureProxy {
+    async fn reject_malformed_path(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestCtx,
+    ) -> Result<bool> {
+        ctx.path = String::from_utf8_lossy(session.req_header().raw_path()).into_owned();
+        tracing::warn!(client_ip = %ctx.client_ip, path = %ctx.path, "malformed request path");
+        self.audit("malformed_path", ctx);
+        self.respond_rejected(session, 400, "malformed_path", ctx)
+            .await?;
+        Ok(true)
+    }
+
+    /// Applies `connect_requests` and `absolute_form`, the request targets meant for forward
+    /// proxies. `Some` if the request was answered or dropped.
+    async fn check_request_target(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestCtx,
+    ) -> Result<Option<bool>> {
+        let req = session.req_header();
+        // pingora keeps an HTTP/1 absolute-form target whole as the path
+        let absolute = match req.raw_path() {
+            raw if raw.starts_with(b"/") || raw == b"*" => None,
+            raw => http::Uri::try_from(raw)
+                .ok()
+                .filter(|uri| uri.scheme().is_some() && uri.authority().is_some()),
+        };
+        let (status, reason, drop) = if req.method == http::Method::CONNECT {
+            (
+                405,
+                "connect_method",
+                self.connect_requests == ConnectPolicy::Drop,
+            )
+        } else if let Some(uri) = absolute {
+            if self.absolute_form == AbsoluteFormPolicy::OriginForm {
+                // The host check then compares the URL's authority with Host
+                session.req_header_mut().set_uri(uri);
+                return Ok(None);
+            }
+            (
+                400,
+                "absolute_form_target",
+                self.absolute_form == AbsoluteFormPolicy::Drop,
+            )
+        } else {
+            return Ok(None);
+        };
+        ctx.path = String::from_utf8_lossy(req.raw_path()).into_owned();
+        tracing::warn!(client_ip = %ctx.client_ip, method = %ctx.method, target = %ctx.path, reason, "forward proxy request refused");
+        self.audit(reason, ctx);
+        if drop {
+            session.shutdown().await;
+        } else {
+            self.respond_rejected(session, status, reason, ctx).await?;
+        }
+        Ok(Some(true))
+    }
+
+    /// Backs the upstream off for as long as its `Retry-After` asks, within the configured cap.
+    fn handle_retry_after(
+        &self,
+        config: &RetryAfterConfig,
+        upstream_response: &mut ResponseHeader,
+        ctx: &RequestCtx,
+    ) {
+        let delay = upstream_response
+            .headers
+            .get(http::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_retry_after);
+        if let (true, Some(delay), Some(upstream)) = (config.back_off, delay, &ctx.upstream) {
+            let delay = delay.min(std::time::Duration::from_secs(config.max_backoff_secs));
+            tracing::warn!(
+                upstream = ?upstream.addr,
+                status = upstream_response.status.as_u16(),
+                backoff = ?delay,
+                "upstream asked to back off"
+            );
+            self.balancer.back_off(upstream, delay);
+            if let Some(alerts) = &self.alerts {
+                let fields = serde_json::json!({
+                    "status": upstream_response.status.as_u16(),
+                    "backoff_secs": delay.as_secs(),
+                });
+                alerts.send("upstream_backed_off", &upstream.addr.to_string(), fields);
+            }
+        }
+        if !config.propagate {
+            upstream_response.remove_header(&http::header::RETRY_AFTER);
+        }
+    }
+
+    /// Ends a WebSocket connection that broke a limit; failing the body filter drops both sides.
+    fn websocket_violation<T>(
+        &self,
+        violation: Violation,
+        direction: &str,
+        ctx: &RequestCtx,
+    ) -> Result<T> {
+        tracing::warn!(
+            client_ip = %ctx.client_ip,
+            path = %ctx.path,
+            direction,
+            reason = violation.as_str(),
+            "websocket connection closed"
+        );
+        self.metrics
+            .record_websocket_termination(violation.as_str());
+        pingora::Error::e_explain(
+            pingora::ErrorType::Custom("WebSocketLimit"),
+            violation.as_str(),
+        )
+    }
+
+    /// Fails the request for a stage that refused its body, or only reports it in monitor mode.
+    fn reject_body(
+        &self,
+        rejection: Rejection,
+        monitor: bool,
+        message: &'static str,
+        ctx: &mut RequestCtx,
+    ) -> Result<()> {
+        tracing::warn!(
+            client_ip = %ctx.client_ip,
+            path = %ctx.path,
+            reason = rejection.reason,
+            detail = %rejection.detail,
+            "{}",
+            message
+        );
+        if monitor {
+            self.metrics.record_monitored_block(rejection.reason);
+            self.audit_event(rejection.reason, "monitor", ctx);
+            return Ok(());
+        }
+        self.audit(rejection.reason, ctx);
+        ctx.error_response = match rejection.response {
+            Some(response) => Some(self.error_responses.annotate(response, &ctx.request_id)),
+            None => self
+                .error_responses
+                .body(rejection.status, rejection.reason, &ctx.request_id),
+        };
+        pingora::Error::e_explain(pingora::ErrorType::HTTPStatus(rejection.status), message)
+    }
+
+    /// Answers a refused request with the `error_responses` body, or an empty error page,
+    /// through the tarpit if it takes the reason.
+    async fn respond_rejected(
+        &self,
+        session: &mut Session,
+        status: u16,
+        reason: &str,
+        ctx: &RequestCtx,
+    ) -> Result<()> {
+        let body = self.error_responses.body(status, reason, &ctx.request_id);
+        if let Some(tarpit) = &self.tarpit {
+            if let Some(slot) = tarpit.admit(reason) {
+                tracing::info!(client_ip = %ctx.client_ip, reason, "holding refused client in the tarpit");
+                return tarpit.hold(session, status, body, slot).await;
+            }
+        }
+        match body {
+            Some(body) => write_json_response(session, status, Vec::new(), &body).await,
+            None => session.respond_error(status).await,
+        }
+    }
+
+    /// Answers for a route in maintenance (503, with its `maintenance_response` if set) or
+    /// disabled (404).
+    async fn respond_route_state(
+        &self,
+        session: &mut Session,
+        route: &Route,
+        state: RouteState,
+        ctx: &RequestCtx,
+    ) -> Result<()> {
+        tracing::debug!(route = ?route.name, path = %ctx.path, state = state.as_str(), "route unavailable");
+        match (state, &route.maintenance_response) {
+            (RouteState::Maintenance, Some(response)) => {
+                let mut headers = vec![("Content-Type".to_string(), response.content_type.clone())];
+                if let Some(secs) = response.retry_after_secs {
+                    headers.push(("Retry-After".to_string(), secs.to_string()));
+                }
+                write_local_response(session, 503, headers, response.body.clone().into_bytes())
+                    .await
+            }
+            (RouteState::Maintenance, None) => {
+                self.respond_rejected(session, 503, "route_maintenance", ctx)
+                    .await
+            }
+            _ => {
+                self.respond_rejected(session, 404, "route_disabled", ctx)
+                    .await
+            }
+        }
+    }
+
+    /// Record a security rejection for the SIEM audit trail.
+    fn audit(&self, reason: &str, ctx: &RequestCtx) {
+        self.audit_event(reason, "enforce", ctx);
+    }
+
+    /// Log and count the would-be blocks of rules in monitor mode.
+    fn report_monitored(&self, ctx: &RequestCtx) {
+        for reason in &ctx.monitored {
+            tracing::warn!(
+                client_ip = %ctx.client_ip,
+                path = %ctx.path,
+                reason,
+                "monitor-mode rule would have blocked request"
+            );
+            self.metrics.record_monitored_block(reason);
+            self.audit_event(reason, "monitor", ctx);
+        }
+    }
+
+    fn audit_event(&self, reason: &str, mode: &str, ctx: &RequestCtx) {
+        let event = serde_json::json!({
+            "reason": reason,
+            "mode": mode,
+            "client_ip": ctx.client_ip,
+            "method": ctx.method,
+            "path": ctx.path,
+            "route": ctx.route.as_ref().and_then(|r| r.name.as_deref()),
+        });
+        if let Some(syslog) = &self.syslog {
+            syslog.send(EventKind::Audit, &event);
+        }
+        self.recent_blocks.push(event);
+    }
 }
 
 #[async_trait]
@@ -28,144 +474,858 @@ impl ProxyHttp for SecureProxy {
     type CTX = RequestCtx;
 
     fn new_ctx(&self) -> Self::CTX {
-        RequestCtx {
-            start: Instant::now(),
-            method: String::new(),
-            path: String::new(),
-        }
+        RequestCtx::default()
     }
 
     async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        ctx.in_flight = Some(self.metrics.start_request());
+        match self.downstream.on_request(session) {
+            Ok(counted) => ctx.downstream_counted = counted,
+            Err(code) => {
+                tracing::warn!("too many concurrent streams on connection");
+                self.respond_rejected(session, code, "too_many_streams", ctx)
+                    .await?;
+                return Ok(true);
+            }
+        }
+        if let Err(code) = self.downstream.check_body_size(session, 0) {
+            tracing::warn!("request body exceeds max_request_body_kb");
+            self.respond_rejected(session, code, "body_too_large", ctx)
+                .await?;
+            return Ok(true);
+        }
+
+        // A dual-stack listener sees IPv4 clients as `::ffff:a.b.c.d`
+        let peer = session
+            .client_addr()
+            .and_then(|a| a.as_inet())
+            .map(|a| a.ip().to_canonical());
+        let forwarded = peer.and_then(|peer| {
+            self.security
+                .load()
+                .forwarded_client_ip(peer, session.req_header())
+        });
+        ctx.client_addr = forwarded.or(peer);
+        ctx.client_ip = match forwarded {
+            Some(ip) => ip.to_string(),
+            None => match (session.client_addr(), peer) {
+                (Some(addr), Some(ip)) => addr
+                    .as_inet()
+                    .map(|inet| std::net::SocketAddr::new(ip, inet.port()).to_string())
+                    .unwrap_or_else(|| addr.to_string()),
+                (Some(addr), None) => addr.to_string(),
+                (None, _) => "unknown".to_string(),
+            },
+        };
+        if let Some(id) = self
+            .security
+            .load()
+            .client_request_id(ctx.client_addr, session.req_header())
+        {
+            ctx.request_id = id;
+            ctx.request_id_kept = true;
+        }
+        ctx.method = session.req_header().method.as_str().to_string();
+        ctx.tls = TlsInfo::from_session(session);
+        if let Err(reason) = security::check_framing(session.req_header()) {
+            ctx.path = String::from_utf8_lossy(session.req_header().raw_path()).into_owned();
+            tracing::warn!(client_ip = %ctx.client_ip, reason, "ambiguous request framing");
+            self.metrics.record_smuggling_rejection(reason);
+            self.audit(reason, ctx);
+            // Where this request's body ends is unclear, so nothing after it can be trusted
+            session.set_keepalive(None);
+            self.respond_rejected(session, 400, reason, ctx).await?;
+            return Ok(true);
+        }
+        if let Some(done) = self.check_request_target(session, ctx).await? {
+            return Ok(done);
+        }
+        // Everything after this point, routing included, sees the canonical path
+        match security::normalize_path(session.req_header().raw_path()) {
+            Ok(None) => {}
+            Ok(Some(path)) => {
+                // Keep the scheme and authority HTTP/2 requests carry in the URI
+                let mut parts = session.req_header().uri.clone().into_parts();
+                parts.path_and_query = path.parse().ok();
+                match http::Uri::from_parts(parts) {
+                    Ok(uri) if uri.path_and_query().is_some() => {
+                        session.req_header_mut().set_uri(uri)
+                    }
+                    _ => return self.reject_malformed_path(session, ctx).await,
+                }
+            }
+            Err(()) => return self.reject_malformed_path(session, ctx).await,
+        }
+        self.router.load().strip_query(session.req_header_mut());
         let req = session.req_header();
-        let path_bytes = req.raw_path();
-        let path = std::str::from_utf8(path_bytes).unwrap_or("").to_string();
-        let method = req.method.as_str().to_string();
-
-        ctx.path = path.clone();
-        ctx.method = method.clone();
-
-        // --- 1. Internal Metrics Endpoint Interception ---
-        // We handle /metrics requests directly here; they never go to the upstream.
-        if path == "/metrics" && method == "GET" {
-            let body = self
-                .metrics
-                .encode()
-                .map_err(|e| {
-                    pingora::Error::explain(
-                        pingora::ErrorType::InternalError,
-                        format!("metrics encode: {}", e),
-                    )
-                })?
-                .into_bytes();
-
-            let mut header = ResponseHeader::build(200, Some(4)).map_err(|e| {
-                pingora::Error::explain(
-                    pingora::ErrorType::InternalError,
-                    format!("response header build: {}", e),
-                )
-            })?;
+        ctx.path = std::str::from_utf8(req.raw_path())
+            .unwrap_or("")
+            .to_string();
+        // Routing, tenant limits and the cache key all go by the canonical host
+        match security::normalize_host(session.req_header()) {
+            Ok(host) => session
+                .req_header_mut()
+                .insert_header(http::header::HOST, host)?,
+            Err(()) => {
+                let host = session.req_header().headers.get(http::header::HOST);
+                tracing::warn!(client_ip = %ctx.client_ip, host = ?host, "invalid host");
+                self.audit("invalid_host", ctx);
+                self.respond_rejected(session, 400, "invalid_host", ctx)
+                    .await?;
+                return Ok(true);
+            }
+        }
 
-            header
-                .insert_header("Content-Type", "text/plain")
-                .map_err(|e| {
-                    pingora::Error::explain(
-                        pingora::ErrorType::InternalError,
-                        format!("insert header: {}", e),
-                    )
-                })?;
-
-            session
-                .write_response_header(Box::new(header), false)
+        let client_addr = ctx.client_addr;
+        if client_addr.is_some_and(|ip| self.controls.is_banned(&ip)) {
+            tracing::warn!(client_ip = %ctx.client_ip, "request from banned IP");
+            self.audit("ip_banned", ctx);
+            self.respond_rejected(session, 403, "ip_banned", ctx)
                 .await?;
-            session
-                .write_response_body(Some(Bytes::from(body)), true)
+            return Ok(true);
+        }
+        let listed = client_addr.and_then(|ip| {
+            let security = self.security.load();
+            let feed = security.check_ip_reputation(ip)?;
+            Some(feed.to_string())
+        });
+        if let Some(feed) = listed {
+            tracing::warn!(client_ip = %ctx.client_ip, %feed, "request from IP on reputation feed");
+            self.audit("ip_reputation", ctx);
+            self.respond_rejected(session, 403, "ip_reputation", ctx)
                 .await?;
-
-            return Ok(true); // Stop processing, request handled internally
+            return Ok(true);
         }
+        let penalized = client_addr
+            .zip(self.anomalies.as_ref())
+            .is_some_and(|(ip, anomalies)| !anomalies.admit(ip));
+        if penalized {
+            tracing::warn!(client_ip = %ctx.client_ip, "anomaly penalty rate limit exceeded");
+            self.audit("anomaly_rate_limit", ctx);
+            self.respond_rejected(session, 429, "anomaly_rate_limit", ctx)
+                .await?;
+            return Ok(true);
+        }
+        let asn = client_addr.map(|ip| self.security.load().check_asn(ip));
+        if let Some(Err(asn)) = asn {
+            tracing::warn!(client_ip = %ctx.client_ip, asn, "request from denied ASN");
+            self.audit("asn_denied", ctx);
+            self.respond_rejected(session, 403, "asn_denied", ctx)
+                .await?;
+            return Ok(true);
+        }
+        // Resolved before responding, so the security layer isn't held across an await
+        let tor = self.security.load().tor_exits().map(|tor| {
+            let exit = client_addr.is_some_and(|ip| tor.contains(ip));
+            (exit, tor.action, tor.header.clone())
+        });
+        if let Some((exit, action, header)) = tor {
+            match action {
+                TorExitAction::Block if exit => {
+                    tracing::warn!(client_ip = %ctx.client_ip, "request from Tor exit node");
+                    self.audit("tor_exit_node", ctx);
+                    self.respond_rejected(session, 403, "tor_exit_node", ctx)
+                        .await?;
+                    return Ok(true);
+                }
+                TorExitAction::Block => {}
+                TorExitAction::Tag => {
+                    let req = session.req_header_mut();
+                    req.remove_header(&header);
+                    if exit {
+                        tracing::info!(client_ip = %ctx.client_ip, path = %ctx.path, "request from Tor exit node tagged");
+                        req.insert_header(header, "true")?;
+                    }
+                }
+            }
+        }
+        if self.controls.maintenance() {
+            self.respond_rejected(session, 503, "maintenance", ctx)
+                .await?;
+            return Ok(true);
+        }
+        if self.controls.draining() {
+            session.set_keepalive(None);
+        }
+        let req = session.req_header();
+        if self.grpc_web {
+            ctx.grpc_web = GrpcWebCall::detect(req);
+        }
+        ctx.capture = self.body_capture.as_ref().and_then(|c| c.start(req));
+        ctx.upload_scan = self.upload_filter.as_ref().and_then(|f| f.start(req));
+        ctx.icap_request = self.icap.as_ref().and_then(|c| c.start_request());
 
-        // --- 2. Security Checks (Hot Reloadable) ---
-        // Load the current security configuration snapshot.
-        // If config changed, this instantly gets the new rules.
-        let security_snapshot = self.security.load();
-
-        let user_agent = session.get_header("User-Agent").map(|v| v.as_bytes());
-        let auth_header = session.get_header("Authorization").map(|v| v.as_bytes());
-        let client_ip = session
-            .client_addr()
-            .map(|a| a.to_string())
-            .unwrap_or_else(|| "unknown".to_string());
+        let host = middleware::request_host(session.req_header());
+        match self.security.load().admit_tenant(host.as_deref()) {
+            Ok(permit) => ctx.tenant = permit,
+            Err((status, reason)) => {
+                tracing::warn!(tenant = ?host, reason, "tenant limit exceeded");
+                self.audit(reason, ctx);
+                self.metrics
+                    .record_tenant_request(host.as_deref().unwrap_or_default(), status);
+                self.respond_rejected(session, status, reason, ctx).await?;
+                return Ok(true);
+            }
+        }
 
-        // Check Rate Limit
-        if let Err(code) = security_snapshot.check_rate_limit(&client_ip) {
-            tracing::warn!(client_ip = %client_ip, "rate limit exceeded");
-            session.respond_error(code).await?;
+        // Built-in checks and plugins run as an ordered chain, chosen per route.
+        // The router is swapped on reload, so this always sees the latest rules.
+        let route = self.router.load().route(session.req_header());
+        ctx.route = Some(route.clone());
+        let route_name = route.name.as_deref().unwrap_or("default");
+        if let Some(in_flight) = &mut ctx.in_flight {
+            in_flight.set_route(route_name);
+        }
+        self.downstream.set_route(session, route_name);
+        let state = route
+            .name
+            .as_deref()
+            .and_then(|name| self.controls.route_state(name))
+            .unwrap_or(route.state);
+        if state != RouteState::Active {
+            self.respond_route_state(session, &route, state, ctx)
+                .await?;
             return Ok(true);
         }
-
-        // Check Blocked Paths
-        if let Err(code) = security_snapshot.check_path(path_bytes) {
-            tracing::warn!(path = %path, "blocked path");
-            session.respond_error(code).await?;
+        if !route.allows(&session.req_header().method) {
+            tracing::warn!(method = %ctx.method, path = %ctx.path, "method not allowed");
+            self.audit("method_not_allowed", ctx);
+            let allow = vec![("Allow".to_string(), route.allow_header())];
+            match self
+                .error_responses
+                .body(405, "method_not_allowed", &ctx.request_id)
+            {
+                Some(body) => write_json_response(session, 405, allow, &body).await?,
+                None => write_local_response(session, 405, allow, Vec::new()).await?,
+            }
             return Ok(true);
         }
+        // Before the chain, so routes without the jwt stage can't be sent forged identities
+        self.security
+            .load()
+            .strip_claim_headers(session.req_header_mut());
+
+        let decision = route.chain.run(session.req_header_mut(), ctx)?;
+        self.report_monitored(ctx);
+        match decision {
+            Decision::Continue => {}
+            Decision::Reject { status, reason } => {
+                self.audit(reason, ctx);
+                self.respond_rejected(session, status, reason, ctx).await?;
+                return Ok(true);
+            }
+            Decision::Respond {
+                status,
+                headers,
+                body,
+            } => {
+                write_local_response(session, status, headers, body).await?;
+                return Ok(true);
+            }
+        }
 
-        // Check Bot / User Agent
-        if let Err(code) = security_snapshot.check_user_agent(user_agent) {
-            tracing::warn!(client_ip = %client_ip, "blocked user agent");
-            session.respond_error(code).await?;
-            return Ok(true);
+        // After the chain, so client classes can come from claim headers it set
+        if let Some(bandwidth) = &self.bandwidth {
+            (ctx.upload_throttle, ctx.download_throttle) =
+                bandwidth.select(route.name.as_deref(), session.req_header());
         }
 
-        // Check JWT Authentication
-        if let Err(code) = security_snapshot.check_jwt(auth_header) {
-            tracing::warn!(client_ip = %client_ip, "jwt auth failed");
-            session.respond_error(code).await?;
-            return Ok(true);
+        if let (Some(cache), true) = (&self.cache, route.cache) {
+            if let Some(key) = ResponseCache::key(session.req_header()) {
+                match cache.lookup(&key, session.req_header()).await {
+                    Lookup::Miss(flight) => {
+                        ctx.cache_key = Some(key);
+                        ctx.cache_flight = flight;
+                    }
+                    Lookup::Hit(entry, body) => {
+                        let mut header = entry.hit_header(&body);
+                        self.response_filter(session, &mut header, ctx).await?;
+                        let head_only = session.req_header().method == http::Method::HEAD;
+                        session
+                            .write_response_header(Box::new(header), head_only)
+                            .await?;
+                        if !head_only {
+                            body.write(session).await?;
+                        }
+                        return Ok(true);
+                    }
+                    Lookup::NotModified(entry, encoding) => {
+                        let mut header = entry.not_modified_header(encoding)?;
+                        self.response_filter(session, &mut header, ctx).await?;
+                        session
+                            .write_response_header(Box::new(header), true)
+                            .await?;
+                        return Ok(true);
+                    }
+                }
+            }
         }
 
         Ok(false) // Passed all checks, forward to upstream
     }
 
+    async fn request_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if let (Some(limits), Some(conn), Some(chunk)) =
+            (&self.websocket, ctx.websocket.as_mut(), body.as_ref())
+        {
+            match limits.client_bytes(conn, chunk) {
+                Ok(0) => {}
+                Ok(messages) => self.metrics.record_websocket_messages("client", messages),
+                Err(violation) => return self.websocket_violation(violation, "client", ctx),
+            }
+        }
+        if let Some(chunk) = body.as_ref() {
+            ctx.request_body_bytes += chunk.len() as u64;
+            if let Err(code) = self
+                .downstream
+                .check_body_size(session, ctx.request_body_bytes)
+            {
+                return pingora::Error::e_explain(
+                    pingora::ErrorType::HTTPStatus(code),
+                    "request body exceeds max_request_body_kb",
+                );
+            }
+            // Holding the chunk here stops pingora reading further from the client
+            if let Some(delay) = self.downstream.upload_delay(session, chunk.len()) {
+                tokio::time::sleep(delay).await;
+            }
+            if let Some(delay) = ctx
+                .upload_throttle
+                .as_mut()
+                .and_then(|t| t.take(chunk.len()))
+            {
+                tokio::time::sleep(delay).await;
+            }
+        }
+        let route = ctx.route.clone();
+        if let (Some(check), Some(spec)) = (
+            ctx.openapi_body.as_mut(),
+            route.as_ref().and_then(|r| r.openapi.as_ref()),
+        ) {
+            let monitor = route.as_ref().is_some_and(|r| r.chain.monitors("openapi"));
+            if let Err(rejection) = spec.check_body(check, body, end_of_stream, monitor) {
+                let message = "request body does not match openapi spec";
+                self.reject_body(rejection, monitor, message, ctx)?;
+            }
+        }
+        if let (Some(check), Some(limits)) = (
+            ctx.graphql_body.as_mut(),
+            route.as_ref().and_then(|r| r.graphql.as_ref()),
+        ) {
+            let monitor = route.as_ref().is_some_and(|r| r.chain.monitors("graphql"));
+            if let Err(rejection) = limits.check_body(check, body, end_of_stream, monitor) {
+                self.reject_body(rejection, monitor, "graphql query refused", ctx)?;
+            }
+        }
+        if let (Some(check), Some(schema)) = (
+            ctx.json_body.as_mut(),
+            route.as_ref().and_then(|r| r.json_schema.as_ref()),
+        ) {
+            let monitor = route
+                .as_ref()
+                .is_some_and(|r| r.chain.monitors("json_schema"));
+            if let Err(rejection) = schema.check_body(check, body, end_of_stream, monitor) {
+                self.reject_body(rejection, monitor, "request body refused", ctx)?;
+            }
+        }
+        if let (Some(filter), Some(scan)) = (&self.upload_filter, ctx.upload_scan.as_mut()) {
+            let chunk = body.as_deref().unwrap_or_default();
+            if let Err(reason) = filter.scan(scan, chunk, end_of_stream) {
+                tracing::warn!(client_ip = %ctx.client_ip, path = %ctx.path, reason, "upload blocked");
+                self.audit(reason, ctx);
+                // Failing here aborts the upstream request before the body is complete
+                return pingora::Error::e_explain(
+                    pingora::ErrorType::HTTPStatus(403),
+                    "blocked upload",
+                );
+            }
+        }
+        if let (Some(icap), Some(scan)) = (&self.icap, ctx.icap_request.as_mut()) {
+            let scanned = icap
+                .filter(scan, session.req_header(), body, end_of_stream)
+                .await;
+            if let Err(reason) = scanned {
+                tracing::warn!(client_ip = %ctx.client_ip, path = %ctx.path, reason, "request body rejected by scanner");
+                self.audit(reason, ctx);
+                return pingora::Error::e_explain(
+                    pingora::ErrorType::HTTPStatus(403),
+                    "request body rejected by scanner",
+                );
+            }
+        }
+        if let (Some(capture), Some(record), Some(chunk)) =
+            (&self.body_capture, ctx.capture.as_mut(), body.as_ref())
+        {
+            capture.request_body(record, chunk);
+        }
+        if let Some(call) = ctx.grpc_web.as_mut() {
+            call.request_body(body, end_of_stream)?;
+        }
+        Ok(())
+    }
+
     async fn upstream_peer(
         &self,
         _session: &mut Session,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
-        let upstream = self.lb.select(b"", 256).ok_or_else(|| {
-            pingora::Error::explain(pingora::ErrorType::InternalError, "no healthy upstream")
+        let upstream = self.balancer.select(ctx.zone_spill).ok_or_else(|| {
+            pingora::Error::explain(
+                pingora::ErrorType::Custom("NoHealthyUpstream"),
+                "no healthy upstream",
+            )
         })?;
+        // Replacing the lease of a failed attempt charges it to that upstream
+        ctx.upstream_lease = self.balancer.lease(&upstream);
+        ctx.upstream = Some(upstream.clone());
+        ctx.attempts.push(UpstreamAttempt {
+            upstream: upstream.addr.to_string(),
+            started: Instant::now(),
+            connect: None,
+            reused: false,
+            error: None,
+        });
 
-        // TLS is set to 'true'. Change to 'false' if testing with local HTTP servers.
-        let peer = Box::new(HttpPeer::new(upstream, true, self.upstream_sni.clone()));
+        // TLS unless the route's `upstream_connection` turns it off
+        let mut peer = Box::new(HttpPeer::new(
+            upstream.clone(),
+            true,
+            self.upstream_sni.clone(),
+        ));
+        if let Some(egress) = &self.egress {
+            peer.proxy = egress.proxy(&upstream.addr);
+        }
+        if let Some(spiffe) = &self.spiffe {
+            spiffe.apply(&mut peer);
+        }
+        if self.forward_trailers {
+            // the HTTP/1.1 upstream client drops trailers, so prefer h2 where the upstream has it
+            peer.options.alpn = ALPN::H2H1;
+        }
+        if let Some(route) = &ctx.route {
+            route.upstream_connection.apply(&mut peer);
+        }
+        // gRPC needs HTTP/2 end to end on the upstream side
+        if ctx.grpc_web.is_some() {
+            peer.options.alpn = ALPN::H2;
+        }
+        peer.options.read_timeout = ctx.route.as_ref().and_then(|r| r.idle_timeout);
+        peer.options.idle_timeout = self.upstream_idle_timeout;
         Ok(peer)
     }
 
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        if self.balancer.can_spill() && !ctx.zone_spill {
+            tracing::warn!(upstream = %peer._address, error = %e, "upstream connect failed, retrying in any zone");
+            ctx.zone_spill = true;
+            e.set_retry(true);
+        }
+        if let Some(attempt) = ctx.attempts.last_mut() {
+            attempt.fail(&e);
+        }
+        e
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<pingora::Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<pingora::Error> {
+        // pingora's default handling, plus the attempt trace
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        e.retry
+            .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+        if let Some(attempt) = ctx.attempts.last_mut() {
+            attempt.fail(&e);
+        }
+        e
+    }
+
     async fn upstream_request_filter(
         &self,
         _session: &mut Session,
         upstream_request: &mut RequestHeader,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<()> {
         // FIX: Force Host header to match SNI.
         // This solves the 502 error when using strict cloud providers (e.g., Cloudflare).
         upstream_request.insert_header("Host", &self.upstream_sni)?;
+        let request_id_header = self.security.load().request_id_header().clone();
+        if request_id_header == "traceparent" {
+            // A kept traceparent goes upstream unchanged; otherwise a new trace starts here
+            if !ctx.request_id_kept {
+                let traceparent = format!(
+                    "00-{}-{:016x}-00",
+                    ctx.request_id,
+                    rand::random::<u64>() | 1
+                );
+                upstream_request.insert_header(request_id_header, traceparent)?;
+            }
+        } else {
+            upstream_request.insert_header(request_id_header, &ctx.request_id)?;
+        }
+        // With token exchange on, the client's credentials never go upstream; requests that
+        // skipped the jwt stage are forwarded without any
+        if self.security.load().token_minter().is_some() {
+            upstream_request.remove_header(&http::header::AUTHORIZATION);
+            if let Some(token) = ctx.upstream_token.take() {
+                upstream_request
+                    .insert_header(http::header::AUTHORIZATION, format!("Bearer {}", token))?;
+            }
+        }
+        if self.forward_trailers {
+            // HTTP/2 only allows `TE: trailers`; keep the client's opt-in and drop anything else
+            let wants_trailers = upstream_request
+                .headers
+                .get_all(http::header::TE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .any(|v| {
+                    v.split(',')
+                        .any(|t| t.trim().eq_ignore_ascii_case("trailers"))
+                });
+            upstream_request.remove_header(&http::header::TE);
+            if wants_trailers {
+                upstream_request.insert_header(http::header::TE, "trailers")?;
+            }
+        }
+        if let Some(call) = &ctx.grpc_web {
+            call.upstream_request(upstream_request)?;
+        }
+        if let Some(normalizer) = &self.header_normalizer {
+            normalizer.request(upstream_request)?;
+        }
+        // Last, so the signature covers the headers as sent
+        if let Some(aws) = ctx.route.as_ref().and_then(|r| r.aws_sigv4.as_ref()) {
+            self.aws_signer.sign(aws, upstream_request).map_err(|e| {
+                tracing::error!(error = %e, path = %ctx.path, "aws sigv4 signing failed");
+                pingora::Error::explain(pingora::ErrorType::InternalError, e)
+            })?;
+        }
         Ok(())
     }
 
-    async fn response_filter(
+    async fn connected_to_upstream(
         &self,
         _session: &mut Session,
+        reused: bool,
+        peer: &HttpPeer,
+        _fd: std::os::unix::io::RawFd,
+        digest: Option<&pingora::protocols::Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if let Some(attempt) = ctx.attempts.last_mut() {
+            attempt.connect = Some(attempt.started.elapsed());
+            attempt.reused = reused;
+        }
+        if let Some(route) = &ctx.route {
+            if !route.upstream_connection.pinned(digest) {
+                tracing::warn!(upstream = %peer._address, "upstream certificate matches no pinned fingerprint");
+                return Err(pingora::Error::explain(
+                    pingora::ErrorType::InvalidCert,
+                    "upstream certificate matches no pinned fingerprint",
+                )
+                .into_up());
+            }
+        }
+        ctx.upstream_connection = Some(
+            self.metrics
+                .record_upstream_connection(&peer._address.to_string(), reused),
+        );
+        Ok(())
+    }
+
+    fn upstream_response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) {
+        if let Some(lease) = &mut ctx.upstream_lease {
+            lease.observe();
+        }
+        ctx.grpc = upstream_response
+            .headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| grpc_web::is_grpc(&v.to_ascii_lowercase()));
+        ctx.grpc_status = grpc_status(&upstream_response.headers);
+        if let Some(limits) = &self.websocket {
+            if upstream_response.status == http::StatusCode::SWITCHING_PROTOCOLS
+                && is_websocket_upgrade(session.req_header())
+            {
+                ctx.websocket = Some(limits.connection());
+            }
+        }
+        if let Some(config) = &self.retry_after {
+            if matches!(upstream_response.status.as_u16(), 429 | 503) {
+                self.handle_retry_after(config, upstream_response, ctx);
+            }
+        }
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
         upstream_response: &mut pingora::http::ResponseHeader,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<()> {
+        // Interim responses (103 Early Hints, 100 Continue) are relayed as the upstream sent them;
+        // everything below is for the final response. pingora 0.3 only relays them to HTTP/1.1
+        // clients: its HTTP/2 server can't send interim HEADERS yet (hyperium/h2#167).
+        if is_interim(upstream_response) {
+            if session.is_http2() {
+                tracing::warn!(
+                    status = upstream_response.status.as_u16(),
+                    path = %ctx.path,
+                    "interim response cannot be relayed to an HTTP/2 client"
+                );
+            }
+            return Ok(());
+        }
+
+        if ctx.route.as_ref().is_some_and(|r| r.generate_etag) {
+            cache::ensure_etag(upstream_response);
+        }
+        let route = ctx.route.clone();
+        if route.as_ref().is_some_and(|r| {
+            r.stream_responses || (r.streaming && is_event_stream(upstream_response))
+        }) {
+            // pingora flushes headers and each chunk as they arrive when there's no
+            // Content-Length; keep the stream out of everything that accumulates it
+            ctx.streamed = true;
+            ctx.cache_key = None;
+            upstream_response.insert_header("X-Accel-Buffering", "no")?;
+        } else {
+            ctx.icap_response = self
+                .icap
+                .as_ref()
+                .and_then(|c| c.start_response(upstream_response));
+            // Upgraded connections have no body to hold
+            if route.is_some_and(|r| r.buffer_responses.is_some()) && ctx.websocket.is_none() {
+                ctx.response_buffer = Some(Vec::new());
+            }
+        }
+        // The cache keeps the upstream header as received; hits run through this filter again
+        if let (Some(cache), Some(key)) = (&self.cache, ctx.cache_key.take()) {
+            ctx.cache_fill = cache.start_fill(key, session.req_header(), upstream_response);
+        }
+        if ctx.cache_fill.is_none() {
+            // Nothing will be stored; let coalesced requests go upstream now
+            ctx.cache_flight = None;
+        }
+
+        if let Some(call) = ctx.grpc_web.as_mut() {
+            call.response_header(upstream_response)?;
+        }
+
+        // After the cache took its copy, so tokens are never stored
+        if let Some(cookie) = ctx.csrf_cookie.take() {
+            upstream_response.append_header(http::header::SET_COOKIE, cookie)?;
+        }
+
         // We load the snapshot again to ensure we use the latest header config
         self.security
             .load()
             .inject_security_headers(upstream_response);
+
+        if let Some(plugins) = &self.wasm_plugins {
+            plugins.on_response_headers(upstream_response, &ctx.wasm);
+        }
+
+        if let Some(scripts) = self.lua_scripts.as_ref().filter(|_| ctx.lua) {
+            scripts.on_response(upstream_response);
+        }
+
+        if self.router.load().debug_headers {
+            let route = ctx.route.as_ref().and_then(|r| r.name.as_deref());
+            upstream_response.insert_header("X-FlashProxy-Route", route.unwrap_or("default"))?;
+            // Cache hits never reach a backend
+            if let Some(upstream) = &ctx.upstream {
+                upstream_response
+                    .insert_header("X-FlashProxy-Upstream", upstream.addr.to_string())?;
+            }
+        }
+
+        if let Some(normalizer) = &self.header_normalizer {
+            normalizer.response(upstream_response)?;
+        }
+
+        if let (Some(capture), Some(record)) = (&self.body_capture, ctx.capture.as_mut()) {
+            capture.response_header(record, upstream_response);
+        }
         Ok(())
     }
 
+    fn response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<std::time::Duration>> {
+        if let Some(upstream) = ctx.upstream.as_ref() {
+            if !end_of_stream && self.balancer.drain_expired(upstream) {
+                tracing::info!(upstream = %upstream.addr, path = %ctx.path, "cutting a request to a drained upstream");
+                return pingora::Error::e_explain(
+                    pingora::ErrorType::Custom("UpstreamDrained"),
+                    "upstream removed and its drain period is over",
+                );
+            }
+        }
+        if let (Some(limits), Some(conn), Some(chunk)) =
+            (&self.websocket, ctx.websocket.as_mut(), body.as_ref())
+        {
+            match limits.upstream_bytes(conn, chunk) {
+                Ok(0) => {}
+                Ok(messages) => self.metrics.record_websocket_messages("upstream", messages),
+                Err(violation) => return self.websocket_violation(violation, "upstream", ctx),
+            }
+        }
+        // Scanned first, so capture and the cache only ever see a body the scanner passed
+        if let (Some(icap), Some(scan)) = (&self.icap, ctx.icap_response.as_mut()) {
+            // pingora 0.3 runs response body filters synchronously, so the scan is waited on
+            // here, with the worker's other tasks handed to another thread meanwhile
+            let scanned = tokio::task::block_in_place(|| {
+                tokio::runtime::Handle::current().block_on(icap.filter(
+                    scan,
+                    session.req_header(),
+                    body,
+                    end_of_stream,
+                ))
+            });
+            if let Err(reason) = scanned {
+                // Answered with a 403 if the header is still buffered, else the connection drops
+                tracing::warn!(client_ip = %ctx.client_ip, path = %ctx.path, reason, "response body rejected by scanner");
+                self.audit(reason, ctx);
+                return pingora::Error::e_explain(
+                    pingora::ErrorType::HTTPStatus(403),
+                    "response body rejected by scanner",
+                );
+            }
+        }
+        if let (Some(capture), Some(record), Some(chunk), false) = (
+            &self.body_capture,
+            ctx.capture.as_mut(),
+            body.as_ref(),
+            ctx.streamed,
+        ) {
+            capture.response_body(record, chunk);
+        }
+        if let (Some(cache), Some(fill)) = (&self.cache, ctx.cache_fill.as_mut()) {
+            let fits = body
+                .as_ref()
+                .is_none_or(|chunk| cache.fill_body(fill, chunk));
+            if !fits {
+                ctx.cache_fill = None;
+                ctx.cache_flight = None;
+            } else if end_of_stream {
+                let generate_etag = ctx.route.as_ref().is_some_and(|r| r.generate_etag);
+                if let Some(fill) = ctx.cache_fill.take() {
+                    cache.finish_fill(fill, generate_etag);
+                }
+                ctx.cache_flight = None;
+            }
+        }
+        if let Some(call) = ctx.grpc_web.as_mut() {
+            call.response_body(body, end_of_stream);
+        }
+        if let (Some(max), Some(buffer)) = (
+            ctx.route.as_ref().and_then(|r| r.buffer_responses),
+            ctx.response_buffer.as_mut(),
+        ) {
+            if let Some(chunk) = body.take() {
+                buffer.extend_from_slice(&chunk);
+            }
+            if end_of_stream || buffer.len() > max {
+                if !end_of_stream {
+                    tracing::debug!(path = %ctx.path, "response outgrew max_buffer_kb, streaming the rest");
+                }
+                *body = Some(std::mem::take(buffer).into());
+                ctx.response_buffer = None;
+            } else {
+                // An empty chunk rather than `None`, which pingora would read as end of body
+                *body = Some(Bytes::new());
+            }
+        }
+        // pingora waits this long after sending the chunk
+        let delay = match (ctx.download_throttle.as_mut(), body.as_ref()) {
+            (Some(throttle), Some(chunk)) if !chunk.is_empty() => throttle.take(chunk.len()),
+            _ => None,
+        };
+        Ok(delay)
+    }
+
+    async fn response_trailer_filter(
+        &self,
+        _session: &mut Session,
+        upstream_trailers: &mut http::HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Bytes>> {
+        if let Some(status) = grpc_status(upstream_trailers) {
+            ctx.grpc_status = Some(status);
+        }
+        // gRPC-Web clients can't read HTTP trailers, so they travel in the body instead
+        Ok(ctx
+            .grpc_web
+            .as_mut()
+            .and_then(|call| call.response_trailers(upstream_trailers)))
+    }
+
+    /// Pingora's default error page, unless a body check left a JSON error to answer with.
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &pingora::Error,
+        ctx: &mut Self::CTX,
+    ) -> u16 {
+        if let Some(class) = error_class(e) {
+            self.metrics.record_proxy_error(class, e.etype().as_str());
+        }
+        let code = match e.etype() {
+            pingora::ErrorType::HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                pingora::ErrorSource::Upstream => 502,
+                pingora::ErrorSource::Downstream => match e.etype() {
+                    // The connection is already dead
+                    WriteError | ReadError | ConnectionClosed => 0,
+                    _ => 400,
+                },
+                pingora::ErrorSource::Internal | pingora::ErrorSource::Unset => 500,
+            },
+        };
+        if code == 0 {
+            return code;
+        }
+        match ctx.error_response.take() {
+            Some(response) => {
+                if write_json_response(session, code, Vec::new(), &response)
+                    .await
+                    .is_err()
+                {
+                    return 0;
+                }
+            }
+            None => session.as_mut().respond_error(code).await,
+        }
+        code
+    }
+
     async fn logging(
         &self,
         session: &mut Session,
@@ -173,10 +1333,14 @@ impl ProxyHttp for SecureProxy {
         ctx: &mut Self::CTX,
     ) {
         let duration = ctx.start.elapsed().as_secs_f64();
-        let client_ip = session
-            .client_addr()
-            .map(|a| a.to_string())
-            .unwrap_or_else(|| "unknown".to_string());
+        // Empty if the request failed before request_filter ran
+        let client_ip = match ctx.client_ip.as_str() {
+            "" => session
+                .client_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+            client_ip => client_ip.to_string(),
+        };
 
         let status_code = session
             .response_written()
@@ -184,17 +1348,179 @@ impl ProxyHttp for SecureProxy {
             .unwrap_or(0);
 
         // Record the metrics for Prometheus
-        self.metrics
-            .record_request(status_code, &ctx.method, &ctx.path, duration);
+        if let Some(path) = self.router.load().metrics_path(&ctx.path) {
+            self.metrics
+                .record_request(status_code, &ctx.method, path, duration);
+        }
+        if let Some(tenant) = &ctx.tenant {
+            self.metrics
+                .record_tenant_request(&tenant.tenant, status_code);
+        }
+        if ctx.grpc {
+            self.metrics
+                .record_grpc_response(&ctx.path, ctx.grpc_status.as_deref());
+        }
+        if let Some(anomalies) = &self.anomalies {
+            if let Some(ip) = ctx.client_addr {
+                anomalies.record(ip, &ctx.path, status_code);
+            }
+        }
 
         // Structured logging
+        let tls = ctx.tls.as_ref();
         tracing::info!(
+            request_id = %ctx.request_id,
             client_ip = %client_ip,
             method = %ctx.method,
             path = %ctx.path,
             latency_sec = %duration,
             status_code = %status_code,
+            tls_version = tls.map(|t| t.version),
+            tls_cipher = tls.map(|t| t.cipher),
+            alpn = tls.and_then(|t| t.alpn.as_deref()),
+            sni = tls.and_then(|t| t.sni.as_deref()),
+            upstream_attempts = ctx.attempts.len(),
             "request"
         );
+
+        if self.access_log.is_some() || self.syslog.is_some() {
+            let mut record = serde_json::json!({
+                "request_id": ctx.request_id,
+                "client_ip": client_ip,
+                "method": ctx.method,
+                "path": ctx.path,
+                "latency_sec": duration,
+                "status_code": status_code,
+            });
+            if let Some(tls) = tls {
+                record["tls_version"] = tls.version.into();
+                record["tls_cipher"] = tls.cipher.into();
+                record["alpn"] = tls.alpn.clone().into();
+                record["sni"] = tls.sni.clone().into();
+            }
+            if !ctx.attempts.is_empty() {
+                record["upstream_attempts"] = ctx
+                    .attempts
+                    .iter()
+                    .enumerate()
+                    .map(|(retry, attempt)| attempt.to_json(retry))
+                    .collect();
+            }
+            if let Some(access_log) = &self.access_log {
+                access_log.write(&record);
+            }
+            if let Some(syslog) = &self.syslog {
+                syslog.send(EventKind::Access, &record);
+            }
+        }
+
+        if let Some(plugins) = &self.wasm_plugins {
+            plugins.on_done(&ctx.wasm);
+        }
+
+        if ctx.downstream_counted {
+            self.downstream.on_done(session);
+        }
+
+        if let (Some(capture), Some(record)) = (&self.body_capture, ctx.capture.take()) {
+            capture.finish(record, &ctx.method, &ctx.path, status_code);
+        }
+    }
+}
+
+/// Answer a request from the proxy itself with a plugin- or script-provided response.
+async fn write_local_response(
+    session: &mut Session,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+) -> Result<()> {
+    let mut header = ResponseHeader::build(status, Some(headers.len()))?;
+    for (name, value) in headers {
+        header.insert_header(name, value)?;
+    }
+    header.insert_header("Content-Length", body.len().to_string())?;
+    session
+        .write_response_header(Box::new(header), body.is_empty())
+        .await?;
+    if !body.is_empty() {
+        session
+            .write_response_body(Some(Bytes::from(body)), true)
+            .await?;
     }
+    Ok(())
+}
+
+async fn write_json_response(
+    session: &mut Session,
+    status: u16,
+    mut headers: Vec<(String, String)>,
+    body: &serde_json::Value,
+) -> Result<()> {
+    headers.push(("Content-Type".to_string(), "application/json".to_string()));
+    write_local_response(session, status, headers, body.to_string().into_bytes()).await
+}
+
+/// `Retry-After` as delta-seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<std::time::Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(std::time::Duration::from_secs(secs));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (at.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+/// The `proxy_errors_total` class of a failed request: whether the backend, the client or the
+/// proxy itself is to blame. `None` for deliberate refusals, which carry their status.
+fn error_class(e: &pingora::Error) -> Option<&'static str> {
+    let class = match e.etype() {
+        HTTPStatus(_) => return None,
+        Custom("NoHealthyUpstream") => "no_healthy_upstream",
+        Custom("UpstreamDrained") => "upstream_drained",
+        _ if e.esource() == &pingora::ErrorSource::Downstream => "client",
+        ConnectTimedout => "connect_timeout",
+        ConnectRefused | ConnectNoRoute | ConnectError | SocketError | ConnectProxyFailure => {
+            "connect_failed"
+        }
+        TLSHandshakeFailure | TLSHandshakeTimedout | InvalidCert | HandshakeError => "tls",
+        _ if e.esource() != &pingora::ErrorSource::Upstream => "proxy",
+        ReadError | WriteError | ConnectionClosed => "upstream_reset",
+        ReadTimedout | WriteTimedout => "upstream_timeout",
+        InvalidHTTPHeader | H1Error | H2Error | H2Downgrade | InvalidH2 => "upstream_protocol",
+        _ => "proxy",
+    };
+    Some(class)
+}
+
+fn grpc_status(headers: &http::HeaderMap) -> Option<String> {
+    headers
+        .get("grpc-status")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+fn is_websocket_upgrade(req: &RequestHeader) -> bool {
+    req.headers
+        .get(http::header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+/// 1xx other than 101, which is the final response of an upgrade.
+fn is_interim(resp: &ResponseHeader) -> bool {
+    resp.status.is_informational() && resp.status != http::StatusCode::SWITCHING_PROTOCOLS
+}
+
+fn is_event_stream(resp: &ResponseHeader) -> bool {
+    resp.headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.trim_start()
+                .to_ascii_lowercase()
+                .starts_with("text/event-stream")
+        })
 }
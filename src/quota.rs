@@ -0,0 +1,139 @@
+use crate::configuration::QuotaConfig;
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, NaiveDate, TimeZone, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Daily and monthly request quotas per authenticated subject, counted in UTC calendar days
+/// and months. Counters live in memory and are flushed to `store_path` in the background, so
+/// a restart loses at most one flush interval of counts. Counters of past months are dropped
+/// on flush.
+pub struct Quotas {
+    daily: Option<u64>,
+    monthly: Option<u64>,
+    max_identities: usize,
+    store_path: String,
+    usage: DashMap<String, Usage>,
+    dirty: AtomicBool,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Usage {
+    day: String,
+    day_count: u64,
+    month: String,
+    month_count: u64,
+}
+
+/// Why a request was refused.
+pub enum Refused {
+    /// A quota is used up until `reset`
+    Exhausted { limit: u64, reset: DateTime<Utc> },
+    /// `max_identities` are already counted this month
+    Full,
+}
+
+impl Quotas {
+    /// Loads the counters saved by a previous run and starts the flush thread.
+    pub fn open(config: &QuotaConfig) -> io::Result<Arc<Self>> {
+        let saved: HashMap<String, Usage> = match std::fs::read(&config.store_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(io::Error::other)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        let quotas = Arc::new(Self {
+            daily: config.daily,
+            monthly: config.monthly,
+            max_identities: config.max_identities,
+            store_path: config.store_path.clone(),
+            usage: saved.into_iter().collect(),
+            dirty: AtomicBool::new(false),
+        });
+        let flusher = quotas.clone();
+        let interval = Duration::from_secs(config.flush_interval_secs);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            if let Err(e) = flusher.flush() {
+                tracing::error!(path = %flusher.store_path, error = %e, "quota store flush failed");
+            }
+        });
+        Ok(quotas)
+    }
+
+    /// Counts one request against `identity`, the subject the `jwt` or `hmac` stage
+    /// authenticated, unless a quota is already used up.
+    pub fn consume(&self, identity: &str) -> Result<(), Refused> {
+        let now = Utc::now();
+        let day = now.format("%Y-%m-%d").to_string();
+        let month = now.format("%Y-%m").to_string();
+        if !self.usage.contains_key(identity) && self.usage.len() >= self.max_identities {
+            self.usage.retain(|_, usage| usage.month == month);
+            if self.usage.len() >= self.max_identities {
+                return Err(Refused::Full);
+            }
+        }
+        let mut usage = self.usage.entry(identity.to_string()).or_default();
+        if usage.day != day {
+            usage.day = day;
+            usage.day_count = 0;
+        }
+        if usage.month != month {
+            usage.month = month;
+            usage.month_count = 0;
+        }
+        if let Some(limit) = self.monthly.filter(|l| usage.month_count >= *l) {
+            return Err(Refused::Exhausted {
+                limit,
+                reset: next_month(now),
+            });
+        }
+        if let Some(limit) = self.daily.filter(|l| usage.day_count >= *l) {
+            return Err(Refused::Exhausted {
+                limit,
+                reset: next_day(now),
+            });
+        }
+        usage.day_count += 1;
+        usage.month_count += 1;
+        self.dirty.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Writes the counters if they changed, replacing the file atomically.
+    fn flush(&self) -> io::Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let month = Utc::now().format("%Y-%m").to_string();
+        self.usage.retain(|_, usage| usage.month == month);
+        let snapshot: HashMap<String, Usage> = self
+            .usage
+            .iter()
+            .map(|e| (e.key().clone(), e.value().clone()))
+            .collect();
+        let tmp = format!("{}.tmp", self.store_path);
+        std::fs::write(
+            &tmp,
+            serde_json::to_vec(&snapshot).map_err(io::Error::other)?,
+        )?;
+        std::fs::rename(&tmp, &self.store_path)
+    }
+}
+
+fn next_day(now: DateTime<Utc>) -> DateTime<Utc> {
+    let tomorrow = now.date_naive() + ChronoDuration::days(1);
+    Utc.from_utc_datetime(&tomorrow.and_hms_opt(0, 0, 0).unwrap_or_default())
+}
+
+fn next_month(now: DateTime<Utc>) -> DateTime<Utc> {
+    let (year, month) = match now.month() {
+        12 => (now.year() + 1, 1),
+        m => (now.year(), m + 1),
+    };
+    let first = NaiveDate::from_ymd_opt(year, month, 1).unwrap_or_default();
+    Utc.from_utc_datetime(&first.and_hms_opt(0, 0, 0).unwrap_or_default())
+}
@@ -0,0 +1,83 @@
+use crate::balancer::{self, Upstreams};
+use crate::configuration::{ConfigError, GatewayConfig};
+use crate::dns::UpstreamDns;
+#[cfg(unix)]
+use crate::handoff::ListenerHandoff;
+use crate::metrics::Metrics;
+use crate::middleware::{Middlewares, Router};
+use crate::security::SecurityLayer;
+use crate::synthetic::SyntheticResponses;
+use crate::vault::Vault;
+use arc_swap::ArcSwap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Re-reads the config file and swaps every hot-reloadable component.
+/// Shared by the SIGHUP handler and the admin API so both behave identically.
+pub struct Reloader {
+    pub config_path: String,
+    pub config: Arc<ArcSwap<GatewayConfig>>,
+    pub security: Arc<ArcSwap<SecurityLayer>>,
+    pub router: Arc<ArcSwap<Router>>,
+    pub middlewares: Arc<Middlewares>,
+    pub synthetic: Arc<ArcSwap<SyntheticResponses>>,
+    pub vault: Option<Arc<Vault>>,
+    pub metrics: Arc<Metrics>,
+    #[cfg(unix)]
+    pub handoff: Arc<ListenerHandoff>,
+    pub upstreams: Arc<Upstreams>,
+    pub dns: Arc<UpstreamDns>,
+}
+
+impl Reloader {
+    /// Returns the names of the fields that changed, each also logged with its redacted old
+    /// and new value. On error the running config is untouched.
+    pub fn reload(&self) -> Result<Vec<String>, ConfigError> {
+        let mut new_conf = GatewayConfig::load(&self.config_path)?;
+        if let Some(vault) = &self.vault {
+            vault.resolve(&mut new_conf)?;
+        }
+        let old_conf = self.config.load_full();
+        let changes = old_conf.diff(&new_conf);
+        #[cfg(unix)]
+        let handoff = ListenerHandoff::needed(&old_conf, &new_conf);
+
+        let new_layer = SecurityLayer::new(&new_conf)?;
+        let synthetic = SyntheticResponses::load(&new_conf.synthetic_responses)?;
+        let router = Router::build(&new_conf, &self.middlewares)?;
+        let backends = self
+            .dns
+            .resolve(&balancer::configured(&new_conf))
+            .map_err(|e| ConfigError::Validation(format!("upstream_ips: {}", e)))?;
+        for backend in self
+            .upstreams
+            .set(backends, Duration::from_secs(new_conf.upstream_drain_secs))
+        {
+            tracing::info!(upstream = %backend.addr, drain_secs = new_conf.upstream_drain_secs, "upstream removed, draining");
+        }
+        self.security.store(Arc::new(new_layer));
+        self.router.store(Arc::new(router));
+        self.synthetic.store(Arc::new(synthetic));
+        let new_conf = Arc::new(new_conf);
+        self.config.store(new_conf.clone());
+        #[cfg(unix)]
+        if handoff && new_conf.server.listener_handoff {
+            self.handoff.start(&old_conf, &new_conf);
+        } else if handoff {
+            tracing::warn!("listener settings changed; they take effect after a restart");
+        }
+
+        let generation = self.metrics.bump_config_generation();
+        for change in &changes {
+            tracing::info!(
+                generation,
+                field = %change.field,
+                change = change.kind.as_str(),
+                old = %change.old,
+                new = %change.new,
+                "config field changed"
+            );
+        }
+        Ok(changes.into_iter().map(|c| c.field).collect())
+    }
+}
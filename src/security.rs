@@ -1,43 +1,444 @@
+use crate::asn::AsnRules;
+use crate::challenge::Challenge;
+use crate::configuration::{
+    ClientRequestIds, ConfigError, GatewayConfig, JwtTenantConfig, ScheduleAction,
+    ScheduleRuleConfig,
+};
+use crate::ip_reputation::{parse_range, IpReputation, PrefixTree, TorExits};
+use crate::jwks::Jwks;
+use crate::middleware::matches_pattern;
+use crate::openapi::percent_decode;
+use crate::tls_info::TlsPolicy;
+use crate::token_exchange::TokenMinter;
+use chrono::{DateTime, Datelike, FixedOffset, NaiveTime, Timelike, Utc, Weekday};
 use dashmap::DashMap;
-use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
-use pingora::http::ResponseHeader;
+use http::{HeaderName, HeaderValue};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use pingora::http::{RequestHeader, ResponseHeader};
 use serde::{Deserialize, Serialize};
-use std::sync::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 const BLOCKED_USER_AGENTS: &[&str] = &["curl", "python-requests", "wget", "python-urllib"];
 const BLOCKED_PATHS: &[&str] = &["/.env", "/.git", "/admin", "/.aws", "/.ssh"];
 const PATH_TRAVERSAL: &str = "..";
+/// Common injection payloads, matched against the lowercased, percent-decoded path and query.
+const ATTACK_SIGNATURES: &[&str] = &[
+    "<script",
+    "javascript:",
+    "onerror=",
+    "union select",
+    "' or '1'='1",
+    "\" or \"1\"=\"1",
+    "; drop table",
+    "/etc/passwd",
+    "\0",
+];
 
 pub struct SecurityLayer {
+    trusted_proxies: PrefixTree,
+    client_ip_header: HeaderName,
+    request_id_header: HeaderName,
+    client_request_ids: ClientRequestIds,
+    /// Clients whose request IDs are kept under `client_ids: internal`
+    request_id_internal: PrefixTree,
     rate_limit_store: DashMap<String, Mutex<SlidingWindow>>,
     rate_limit_per_second: u32,
+    rate_limit_ipv6_prefix: u8,
     jwt_decoding_key: DecodingKey,
+    /// Lowercased host to that tenant's token validation
+    jwt_tenants: HashMap<String, JwtTenant>,
+    /// Lowercased host to that tenant's shared caps
+    tenant_limits: HashMap<String, TenantLimit>,
+    /// Claim name to upstream header name
+    claim_headers: Vec<(String, HeaderName)>,
+    token_minter: Option<TokenMinter>,
+    schedule_rules: Vec<ScheduleRule>,
+    ip_reputation: Option<IpReputation>,
+    tor_exits: Option<TorExits>,
+    asn_rules: Option<AsnRules>,
+    challenge: Option<Challenge>,
+    tls_policy: Option<TlsPolicy>,
+}
+
+/// A weekly window in minutes since local midnight, over a fixed UTC offset.
+struct ScheduleRule {
+    path: String,
+    action: ScheduleAction,
+    /// Bit per weekday, Monday first
+    days: u8,
+    from: u32,
+    to: u32,
+    offset: FixedOffset,
+}
+
+impl ScheduleRule {
+    fn new(config: &ScheduleRuleConfig) -> Result<Self, ConfigError> {
+        let invalid = |what: &str| {
+            ConfigError::Validation(format!("schedule_rules.{}: invalid {}", config.path, what))
+        };
+        let minutes = |hhmm: &str| {
+            NaiveTime::parse_from_str(hhmm, "%H:%M")
+                .map(|t| t.hour() * 60 + t.minute())
+                .map_err(|_| invalid("time (expected HH:MM)"))
+        };
+        let mut days = 0u8;
+        for day in &config.days {
+            let day: Weekday = day.parse().map_err(|_| invalid("day"))?;
+            days |= 1 << day.num_days_from_monday();
+        }
+        let offset = if config.timezone.eq_ignore_ascii_case("utc") {
+            FixedOffset::east_opt(0).ok_or_else(|| invalid("timezone"))?
+        } else {
+            config
+                .timezone
+                .parse()
+                .map_err(|_| invalid("timezone (expected UTC or an offset like +02:00)"))?
+        };
+        Ok(Self {
+            path: config.path.clone(),
+            action: config.action,
+            days: if days == 0 { 0x7f } else { days },
+            from: minutes(&config.from)?,
+            to: minutes(&config.to)?,
+            offset,
+        })
+    }
+
+    fn is_open(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.offset);
+        let minute = local.hour() * 60 + local.minute();
+        let today = local.weekday();
+        let open_on = |day: Weekday| self.days & (1 << day.num_days_from_monday()) != 0;
+        if self.from == self.to {
+            open_on(today)
+        } else if self.from < self.to {
+            open_on(today) && (self.from..self.to).contains(&minute)
+        } else {
+            // Past midnight: the evening part today, or the morning part of yesterday's window
+            (open_on(today) && minute >= self.from) || (open_on(today.pred()) && minute < self.to)
+        }
+    }
+}
+
+struct JwtTenant {
+    key: TenantKey,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+enum TenantKey {
+    Secret(DecodingKey),
+    Jwks(Arc<Jwks>),
+}
+
+impl JwtTenant {
+    fn new(config: &JwtTenantConfig) -> Result<Self, ConfigError> {
+        let key = match (&config.secret, &config.jwks_url) {
+            (Some(secret), _) => TenantKey::Secret(DecodingKey::from_secret(secret.as_bytes())),
+            (None, Some(url)) => TenantKey::Jwks(Jwks::fetch(
+                url,
+                Duration::from_secs(config.jwks_refresh_secs),
+            )?),
+            (None, None) => {
+                return Err(ConfigError::Validation(
+                    "jwt_tenants: no secret or jwks_url".into(),
+                ))
+            }
+        };
+        Ok(Self {
+            key,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+        })
+    }
+
+    fn verify(&self, token: &str) -> jsonwebtoken::errors::Result<Claims> {
+        let (key, algorithm) = match &self.key {
+            TenantKey::Secret(key) => (key.clone(), Algorithm::HS256),
+            TenantKey::Jwks(jwks) => {
+                let header = decode_header(token)?;
+                let key = jwks
+                    .key(header.kid.as_deref(), header.alg)
+                    .ok_or(ErrorKind::InvalidAlgorithm)?;
+                (key, header.alg)
+            }
+        };
+        let mut validation = Validation::new(algorithm);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        decode::<Claims>(token, &key, &validation).map(|data| data.claims)
+    }
 }
 
 struct SlidingWindow {
     timestamps: Vec<Instant>,
 }
 
+impl SlidingWindow {
+    /// Records a request unless `limit` were already seen in the last second.
+    fn admit(&mut self, limit: usize) -> bool {
+        let now = Instant::now();
+        self.timestamps
+            .retain(|t| now.saturating_duration_since(*t) < Duration::from_secs(1));
+        if self.timestamps.len() >= limit {
+            return false;
+        }
+        self.timestamps.push(now);
+        true
+    }
+}
+
+struct TenantLimit {
+    rate_limit_per_second: Option<u32>,
+    window: Mutex<SlidingWindow>,
+    max_concurrent: Option<usize>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// A tenant request counted against its concurrency cap until dropped with the request
+/// context. Holds the counter itself, so it stays correct across reloads.
+pub struct TenantPermit {
+    pub tenant: String,
+    in_flight: Option<Arc<AtomicUsize>>,
+}
+
+impl Drop for TenantPermit {
+    fn drop(&mut self) {
+        if let Some(in_flight) = &self.in_flight {
+            in_flight.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
-struct Claims {
+pub struct Claims {
     exp: usize,
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Claims {
+    pub fn get(&self, name: &str) -> Option<&serde_json::Value> {
+        self.other.get(name)
+    }
+
+    /// `exp`, in seconds since the epoch
+    pub fn expires_at(&self) -> u64 {
+        self.exp as u64
+    }
 }
 
 impl SecurityLayer {
-    pub fn new(rate_limit_per_second: u32, jwt_secret: &str) -> Self {
-        Self {
+    pub fn new(config: &GatewayConfig) -> Result<Self, ConfigError> {
+        let mut trusted_proxies = PrefixTree::default();
+        // Checked during config validation
+        for (ip, len) in config.trusted_proxies.iter().filter_map(|e| parse_range(e)) {
+            trusted_proxies.insert(ip, len);
+        }
+        let mut request_id_internal = PrefixTree::default();
+        for (ip, len) in config
+            .request_id
+            .internal_cidrs
+            .iter()
+            .filter_map(|e| parse_range(e))
+        {
+            request_id_internal.insert(ip, len);
+        }
+        Ok(Self {
+            trusted_proxies,
+            client_ip_header: HeaderName::from_bytes(config.client_ip_header.as_bytes()).map_err(
+                |_| ConfigError::Validation("client_ip_header is not a valid header name".into()),
+            )?,
+            request_id_header: HeaderName::from_bytes(config.request_id.header.as_bytes())
+                .map_err(|_| {
+                    ConfigError::Validation("request_id.header is not a valid header name".into())
+                })?,
+            client_request_ids: config.request_id.client_ids,
+            request_id_internal,
             rate_limit_store: DashMap::new(),
-            rate_limit_per_second,
-            jwt_decoding_key: DecodingKey::from_secret(jwt_secret.as_bytes()),
+            rate_limit_per_second: config.rate_limit_per_second,
+            rate_limit_ipv6_prefix: config.rate_limit_ipv6_prefix,
+            jwt_decoding_key: DecodingKey::from_secret(config.jwt_secret.as_bytes()),
+            tenant_limits: config
+                .tenant_limits
+                .iter()
+                .map(|(host, limits)| {
+                    (
+                        host.to_ascii_lowercase(),
+                        TenantLimit {
+                            rate_limit_per_second: limits.rate_limit_per_second,
+                            window: Mutex::new(SlidingWindow {
+                                timestamps: Vec::new(),
+                            }),
+                            max_concurrent: limits.max_concurrent,
+                            in_flight: Arc::new(AtomicUsize::new(0)),
+                        },
+                    )
+                })
+                .collect(),
+            jwt_tenants: config
+                .jwt_tenants
+                .iter()
+                .map(|(host, tenant)| Ok((host.to_ascii_lowercase(), JwtTenant::new(tenant)?)))
+                .collect::<Result<_, ConfigError>>()?,
+            // Header names are checked during config validation
+            claim_headers: config
+                .jwt_claim_headers
+                .iter()
+                .filter_map(|(claim, header)| {
+                    Some((
+                        claim.clone(),
+                        HeaderName::from_bytes(header.as_bytes()).ok()?,
+                    ))
+                })
+                .collect(),
+            token_minter: config
+                .upstream_token
+                .as_ref()
+                .map(TokenMinter::new)
+                .transpose()?,
+            schedule_rules: config
+                .schedule_rules
+                .iter()
+                .map(ScheduleRule::new)
+                .collect::<Result<_, ConfigError>>()?,
+            ip_reputation: (!config.ip_feeds.is_empty())
+                .then(|| IpReputation::load(&config.ip_feeds))
+                .transpose()?,
+            tor_exits: config
+                .tor_exit_nodes
+                .as_ref()
+                .map(TorExits::load)
+                .transpose()?,
+            asn_rules: config.asn_rules.as_ref().map(AsnRules::load).transpose()?,
+            challenge: config.challenge.as_ref().map(Challenge::new).transpose()?,
+            tls_policy: config.tls_policy.as_ref().map(TlsPolicy::new),
+        })
+    }
+
+    /// Name of the `ip_feeds` blocklist naming the client, if any.
+    pub fn check_ip_reputation(&self, ip: IpAddr) -> Option<&str> {
+        self.ip_reputation.as_ref()?.listed(ip)
+    }
+
+    /// `Err` carries the ASN the client is refused for.
+    pub fn check_asn(&self, ip: IpAddr) -> Result<(), u32> {
+        match &self.asn_rules {
+            Some(rules) => rules.check(ip),
+            None => Ok(()),
         }
     }
 
-    pub fn check_rate_limit(&self, client_ip: &str) -> Result<(), u16> {
-        let now = Instant::now();
-        let window_duration = Duration::from_secs(1);
-        let limit = self.rate_limit_per_second as usize;
+    pub fn challenge(&self) -> Option<&Challenge> {
+        self.challenge.as_ref()
+    }
+
+    pub fn tls_policy(&self) -> Option<&TlsPolicy> {
+        self.tls_policy.as_ref()
+    }
+
+    pub fn tor_exits(&self) -> Option<&TorExits> {
+        self.tor_exits.as_ref()
+    }
+
+    /// Seconds since each blocklist feed, Tor's included, last downloaded successfully.
+    pub fn ip_feed_ages(&self) -> Vec<(&str, u64)> {
+        let feeds = self.ip_reputation.iter().flat_map(|r| r.ages());
+        feeds
+            .chain(self.tor_exits.iter().flat_map(|t| t.ages()))
+            .collect()
+    }
+
+    /// Set when validated tokens are exchanged for internal ones before forwarding
+    pub fn token_minter(&self) -> Option<&TokenMinter> {
+        self.token_minter.as_ref()
+    }
+
+    pub fn request_id_header(&self) -> &HeaderName {
+        &self.request_id_header
+    }
+
+    /// The ID the client sent, if the `request_id` settings keep it for `client` and it's
+    /// well-formed. For `traceparent` this is the trace ID.
+    pub fn client_request_id(&self, client: Option<IpAddr>, req: &RequestHeader) -> Option<String> {
+        let keep = match self.client_request_ids {
+            ClientRequestIds::Overwrite => false,
+            ClientRequestIds::Trust => true,
+            ClientRequestIds::Internal => {
+                client.is_some_and(|ip| self.request_id_internal.contains(ip))
+            }
+        };
+        if !keep {
+            return None;
+        }
+        let mut values = req.headers.get_all(&self.request_id_header).iter();
+        let value = values.next()?.to_str().ok()?;
+        if values.next().is_some() {
+            return None;
+        }
+        if self.request_id_header == "traceparent" {
+            return trace_id(value).map(str::to_string);
+        }
+        (!value.is_empty() && value.len() <= 128 && value.bytes().all(|b| b.is_ascii_graphic()))
+            .then(|| value.to_string())
+    }
+
+    /// The client's address as reported by `peer`, if it is a trusted proxy that sent one.
+    pub fn forwarded_client_ip(&self, peer: IpAddr, req: &RequestHeader) -> Option<IpAddr> {
+        if !self.trusted_proxies.contains(peer.to_canonical()) {
+            return None;
+        }
+        let values = req.headers.get_all(&self.client_ip_header);
+        let entries: Vec<&str> = values
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect();
+        if self.client_ip_header.as_str() != "x-forwarded-for" {
+            // A single address; several mean a client-sent copy got past the proxy
+            return match entries[..] {
+                [value] => parse_forwarded(value),
+                _ => None,
+            };
+        }
+        // Walk back through the proxies appending to the header until one isn't trusted
+        let mut client = None;
+        for entry in entries.iter().rev() {
+            let Some(ip) = parse_forwarded(entry) else {
+                break;
+            };
+            client = Some(ip);
+            if !self.trusted_proxies.contains(ip) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// The rate limit bucket of a client: its address, or for IPv6 its
+    /// `rate_limit_ipv6_prefix` network.
+    pub fn rate_limit_key(&self, ip: IpAddr) -> String {
+        match ip {
+            IpAddr::V4(v4) => v4.to_string(),
+            IpAddr::V6(v6) => {
+                let mask = u128::MAX << (128 - u32::from(self.rate_limit_ipv6_prefix));
+                let network = std::net::Ipv6Addr::from(u128::from(v6) & mask);
+                format!("{}/{}", network, self.rate_limit_ipv6_prefix)
+            }
+        }
+    }
 
+    pub fn check_rate_limit(&self, client_ip: &str) -> Result<(), u16> {
         let entry = self
             .rate_limit_store
             .entry(client_ip.to_string())
@@ -47,16 +448,53 @@ impl SecurityLayer {
                 })
             });
         let mut guard = entry.lock().expect("lock");
-        guard
-            .timestamps
-            .retain(|t| now.saturating_duration_since(*t) < window_duration);
-        if guard.timestamps.len() >= limit {
+        if !guard.admit(self.rate_limit_per_second as usize) {
             return Err(429);
         }
-        guard.timestamps.push(now);
         Ok(())
     }
 
+    /// Applies the `tenant_limits` of the request's host. `Ok(None)` for hosts without
+    /// limits; otherwise the permit to keep for the rest of the request, or the status and
+    /// audit reason to reject with.
+    pub fn admit_tenant(
+        &self,
+        host: Option<&str>,
+    ) -> Result<Option<TenantPermit>, (u16, &'static str)> {
+        let Some((tenant, limit)) = host.and_then(|h| self.tenant_limits.get_key_value(h)) else {
+            return Ok(None);
+        };
+        if let Some(rate) = limit.rate_limit_per_second {
+            if !limit
+                .window
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .admit(rate as usize)
+            {
+                return Err((429, "tenant_rate_limited"));
+            }
+        }
+        let in_flight = match limit.max_concurrent {
+            Some(max) => {
+                let admitted = limit
+                    .in_flight
+                    .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                        (n < max).then_some(n + 1)
+                    })
+                    .is_ok();
+                if !admitted {
+                    return Err((503, "tenant_concurrency_limited"));
+                }
+                Some(limit.in_flight.clone())
+            }
+            None => None,
+        };
+        Ok(Some(TenantPermit {
+            tenant: tenant.clone(),
+            in_flight,
+        }))
+    }
+
     pub fn check_user_agent(&self, user_agent: Option<&[u8]>) -> Result<(), u16> {
         let ua = match user_agent {
             Some(b) if !b.is_empty() => std::str::from_utf8(b).unwrap_or("").to_lowercase(),
@@ -84,31 +522,84 @@ impl SecurityLayer {
         Ok(())
     }
 
-    /// Check for valid JWT in Authorization header
-    pub fn check_jwt(&self, auth_header: Option<&[u8]>) -> Result<(), u16> {
-        let auth_val = match auth_header {
-            Some(v) => std::str::from_utf8(v).unwrap_or(""),
-            None => {
-                println!("DEBUG JWT: Missing Authorization header");
-                return Err(401);
-            }
+    /// Applies the first `schedule_rules` entry matching `path`; the error is the audit reason.
+    pub fn check_schedule(&self, path: &str) -> Result<(), &'static str> {
+        let Some(rule) = self
+            .schedule_rules
+            .iter()
+            .find(|r| matches_pattern(&r.path, path))
+        else {
+            return Ok(());
         };
+        match (rule.action, rule.is_open(Utc::now())) {
+            (ScheduleAction::Allow, false) => Err("outside_schedule"),
+            (ScheduleAction::Deny, true) => Err("scheduled_block"),
+            _ => Ok(()),
+        }
+    }
 
-        if !auth_val.starts_with("Bearer ") {
-            println!("DEBUG JWT: Invalid format (missing 'Bearer ')");
-            return Err(401);
+    /// Stricter inspection for routes that opt into the `waf` stage.
+    pub fn check_attack_signatures(&self, path_and_query: &[u8]) -> Result<(), u16> {
+        // Decoded twice over, so double-encoded payloads are caught as well
+        let once = percent_decode(&String::from_utf8_lossy(path_and_query), false);
+        let normalized = percent_decode(&once, false)
+            .replace('+', " ")
+            .to_lowercase();
+        if ATTACK_SIGNATURES.iter().any(|sig| normalized.contains(sig)) {
+            return Err(403);
         }
+        Ok(())
+    }
+
+    /// Check for valid JWT in Authorization header, against the tenant serving `host` if any.
+    /// `Err` carries the kind of failure, e.g. `expired`, for the log.
+    pub fn check_jwt(
+        &self,
+        host: Option<&str>,
+        auth_header: Option<&[u8]>,
+    ) -> Result<Claims, &'static str> {
+        let Some(auth_val) = auth_header else {
+            return Err("missing_token");
+        };
+        let Some(token) = std::str::from_utf8(auth_val)
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+        else {
+            return Err("not_bearer");
+        };
+        let result = match host.and_then(|h| self.jwt_tenants.get(h)) {
+            Some(tenant) => tenant.verify(token),
+            // Force HS256 validation
+            None => decode::<Claims>(
+                token,
+                &self.jwt_decoding_key,
+                &Validation::new(Algorithm::HS256),
+            )
+            .map(|data| data.claims),
+        };
+
+        result.map_err(|e| jwt_failure_kind(e.kind()))
+    }
 
-        let token = &auth_val[7..];
-        // Force HS256 validation
-        let validation = Validation::new(Algorithm::HS256);
+    /// Removes client-supplied copies of the claim headers, so only values set from a
+    /// validated token reach the upstream.
+    pub fn strip_claim_headers(&self, req: &mut RequestHeader) {
+        for (_, header) in &self.claim_headers {
+            req.remove_header(header);
+        }
+    }
 
-        match decode::<Claims>(token, &self.jwt_decoding_key, &validation) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                // THIS IS THE KEY: It will print why it failed
-                println!("DEBUG JWT: Verification Failed! Reason: {:?}", e.kind());
-                Err(401)
+    /// Sets the configured claim headers from a validated token. Strings are copied as they
+    /// are; other claim types are sent as JSON.
+    pub fn set_claim_headers(&self, req: &mut RequestHeader, claims: &Claims) {
+        for (claim, header) in &self.claim_headers {
+            let value = match claims.other.get(claim) {
+                None | Some(serde_json::Value::Null) => continue,
+                Some(serde_json::Value::String(s)) => s.clone(),
+                Some(other) => other.to_string(),
+            };
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                let _ = req.insert_header(header.clone(), value);
             }
         }
     }
@@ -121,3 +612,346 @@ impl SecurityLayer {
         let _ = resp.insert_header("Content-Security-Policy", "default-src 'self'");
     }
 }
+
+/// An address in a forwarding header, with or without port, IPv6 optionally in brackets
+/// (`[2001:db8::1]`) and the whole optionally quoted, as RFC 7239 writes them.
+fn parse_forwarded(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    let value = value
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .unwrap_or(value);
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<std::net::SocketAddr>().ok().map(|a| a.ip()))
+        .map(|ip| ip.to_canonical())
+}
+
+/// The trace ID of a W3C `traceparent` (`00-<trace id>-<parent id>-<flags>`).
+fn trace_id(traceparent: &str) -> Option<&str> {
+    let hex = |s: &str, len: usize| {
+        s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    let nonzero = |s: &str| s.bytes().any(|b| b != b'0');
+    let mut parts = traceparent.trim().split('-');
+    let (version, trace, parent, flags) =
+        (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    // Later versions may append fields; version 00 has exactly four
+    if !hex(version, 2) || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    (hex(trace, 32) && nonzero(trace) && hex(parent, 16) && nonzero(parent) && hex(flags, 2))
+        .then_some(trace)
+}
+
+fn jwt_failure_kind(kind: &ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::ExpiredSignature => "expired",
+        ErrorKind::ImmatureSignature => "not_yet_valid",
+        ErrorKind::InvalidSignature => "bad_signature",
+        ErrorKind::InvalidIssuer => "bad_issuer",
+        ErrorKind::InvalidAudience => "bad_audience",
+        ErrorKind::InvalidSubject => "bad_subject",
+        ErrorKind::MissingRequiredClaim(_) => "missing_claim",
+        // Also a `kid` the tenant's JWKS doesn't have
+        ErrorKind::InvalidAlgorithm | ErrorKind::MissingAlgorithm => "bad_algorithm",
+        ErrorKind::InvalidToken
+        | ErrorKind::Base64(_)
+        | ErrorKind::Json(_)
+        | ErrorKind::Utf8(_) => "malformed",
+        _ => "bad_key",
+    }
+}
+
+/// Canonical form of a request target's path, so security checks and routing see the same
+/// path the upstream will act on: escapes of unreserved and sub-delim characters are decoded
+/// (`%2e` is `.`), duplicate slashes are collapsed and dot segments resolved. Escapes that
+/// would change the meaning of the path (`%2F`, `%25`, non-ASCII) stay encoded. The query is
+/// left untouched. Returns `None` if the path is already canonical, `Err` if it is malformed
+/// (raw control or non-ASCII bytes, bad escapes, `%00`, not starting with `/`).
+pub fn normalize_path(raw: &[u8]) -> Result<Option<String>, ()> {
+    if raw == b"*" {
+        return Ok(None);
+    }
+    let (path, query) = match raw.iter().position(|&b| b == b'?') {
+        Some(i) => raw.split_at(i),
+        None => (raw, &b""[..]),
+    };
+    if path.first() != Some(&b'/') || !raw.iter().all(|b| b.is_ascii_graphic()) {
+        return Err(());
+    }
+
+    let mut decoded = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        if path[i] != b'%' {
+            decoded.push(path[i] as char);
+            i += 1;
+            continue;
+        }
+        let hex = path.get(i + 1..i + 3).ok_or(())?;
+        let byte = std::str::from_utf8(hex)
+            .ok()
+            .and_then(|h| u8::from_str_radix(h, 16).ok())
+            .ok_or(())?;
+        if byte == 0 {
+            return Err(());
+        }
+        if byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&byte) {
+            decoded.push(byte as char);
+        } else {
+            decoded.push_str(&format!("%{:02X}", byte));
+        }
+        i += 3;
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/').skip(1) {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    // Empty segments from `//` are dropped, but a trailing slash (or a final dot segment)
+    // still names a directory
+    let ends_in_dir = decoded.ends_with('/') || decoded.ends_with("/.") || decoded.ends_with("/..");
+    segments.retain(|s| !s.is_empty());
+    let mut normalized = String::with_capacity(raw.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if ends_in_dir || segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized.push_str(std::str::from_utf8(query).map_err(|_| ())?);
+
+    Ok((normalized.as_bytes() != raw).then_some(normalized))
+}
+
+/// Refuses HTTP/1 requests whose body framing could be read differently by the proxy and
+/// the upstream: Content-Length together with Transfer-Encoding, repeated or non-numeric
+/// Content-Length, or a Transfer-Encoding other than plain `chunked`. `Err` holds the
+/// reason. CR, LF and NUL inside header values never get this far: pingora's parser refuses
+/// them and `HeaderValue` can't hold them.
+pub fn check_framing(req: &RequestHeader) -> Result<(), &'static str> {
+    if req.version >= http::Version::HTTP_2 {
+        return Ok(());
+    }
+    let lengths: Vec<&HeaderValue> = req
+        .headers
+        .get_all(http::header::CONTENT_LENGTH)
+        .iter()
+        .collect();
+    let encodings: Vec<&HeaderValue> = req
+        .headers
+        .get_all(http::header::TRANSFER_ENCODING)
+        .iter()
+        .collect();
+    if !lengths.is_empty() && !encodings.is_empty() {
+        return Err("smuggling_cl_te_conflict");
+    }
+    match lengths.as_slice() {
+        [] => {}
+        [length] if !length.is_empty() && length.as_bytes().iter().all(u8::is_ascii_digit) => {}
+        _ => return Err("smuggling_content_length"),
+    }
+    match encodings.as_slice() {
+        [] => Ok(()),
+        // No tabs, padding or other codings that parsers disagree on; HTTP/1.0 has no chunking
+        [encoding]
+            if req.version == http::Version::HTTP_11
+                && encoding.as_bytes().eq_ignore_ascii_case(b"chunked") =>
+        {
+            Ok(())
+        }
+        _ => Err("smuggling_transfer_encoding"),
+    }
+}
+
+/// The host a request is for, as the canonical `Host` value: lowercase, internationalized
+/// names in punycode, no port or trailing dot, IPv6 in brackets. Taken from the Host header,
+/// or the URI authority when an HTTP/2 request has none. `Err` if both are missing, Host is
+/// repeated or malformed, or Host and the authority name different hosts.
+pub fn normalize_host(req: &RequestHeader) -> Result<String, ()> {
+    let mut values = req.headers.get_all(http::header::HOST).iter();
+    let header = values.next();
+    if values.next().is_some() {
+        return Err(());
+    }
+    let authority = req
+        .uri
+        .authority()
+        .map(|a| canonical_host(a.as_str().as_bytes()));
+    match (header, authority) {
+        (Some(header), Some(authority)) => {
+            let host = canonical_host(header.as_bytes())?;
+            if authority? != host {
+                return Err(());
+            }
+            Ok(host)
+        }
+        (Some(header), None) => canonical_host(header.as_bytes()),
+        (None, Some(authority)) => authority,
+        (None, None) => Err(()),
+    }
+}
+
+fn canonical_host(raw: &[u8]) -> Result<String, ()> {
+    let raw = std::str::from_utf8(raw).map_err(|_| ())?;
+    let (host, port) = match raw.strip_prefix('[') {
+        Some(rest) => {
+            let (v6, after) = rest.split_once(']').ok_or(())?;
+            let ip: std::net::Ipv6Addr = v6.parse().map_err(|_| ())?;
+            (format!("[{}]", ip), after.strip_prefix(':'))
+        }
+        None => {
+            let (name, port) = match raw.split_once(':') {
+                Some((name, port)) => (name, Some(port)),
+                None => (raw, None),
+            };
+            let name = name.strip_suffix('.').unwrap_or(name);
+            // Maps to lowercase and punycode, and refuses labels IDNA doesn't allow
+            let ascii = idna::domain_to_ascii(name).map_err(|_| ())?;
+            let valid_label = |label: &str| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && label
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+            };
+            if ascii.len() > 253 || !ascii.split('.').all(valid_label) {
+                return Err(());
+            }
+            (ascii, port)
+        }
+    };
+    match port {
+        // An empty port is allowed and means the default
+        Some(port) if !port.is_empty() && port.parse::<u16>().is_err() => Err(()),
+        _ => Ok(host),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(raw: &str) -> Result<Option<String>, ()> {
+        normalize_path(raw.as_bytes())
+    }
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        for (name, value) in headers {
+            req.append_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn encoded_dot_segments_are_resolved() {
+        assert_eq!(normalized("/a/%2e%2e/admin"), Ok(Some("/admin".into())));
+        assert_eq!(normalized("/a/%2E%2e/admin"), Ok(Some("/admin".into())));
+        assert_eq!(normalized("/a/.%2E/b/%2e/c"), Ok(Some("/b/c".into())));
+    }
+
+    #[test]
+    fn double_encoding_is_decoded_once() {
+        // `%25` stays escaped, so `%252e` can't turn into a dot segment upstream
+        assert_eq!(normalized("/a/%252e%252e/admin"), Ok(None));
+        assert_eq!(normalized("/a/%252E"), Ok(None));
+    }
+
+    #[test]
+    fn duplicate_slashes_are_collapsed() {
+        assert_eq!(normalized("//admin"), Ok(Some("/admin".into())));
+        assert_eq!(normalized("/a//b///c/"), Ok(Some("/a/b/c/".into())));
+    }
+
+    #[test]
+    fn dot_dot_stops_at_the_root() {
+        assert_eq!(
+            normalized("/../../etc/passwd"),
+            Ok(Some("/etc/passwd".into()))
+        );
+        assert_eq!(normalized("/a/../.."), Ok(Some("/".into())));
+    }
+
+    #[test]
+    fn path_escapes_and_query() {
+        assert_eq!(normalized("/a%2fb"), Ok(Some("/a%2Fb".into())));
+        assert_eq!(normalized("/a/../b?x=../y"), Ok(Some("/b?x=../y".into())));
+        assert_eq!(normalized("/a/b?x=1"), Ok(None));
+        assert_eq!(normalized("/a%00b"), Err(()));
+        assert_eq!(normalized("/a%zz"), Err(()));
+        assert_eq!(normalized("a/b"), Err(()));
+    }
+
+    #[test]
+    fn content_length_with_transfer_encoding_is_refused() {
+        let req = request(&[("Content-Length", "5"), ("Transfer-Encoding", "chunked")]);
+        assert_eq!(check_framing(&req), Err("smuggling_cl_te_conflict"));
+    }
+
+    #[test]
+    fn obfuscated_transfer_encoding_is_refused() {
+        for te in ["chunked, identity", "xchunked", "chunked\t", "identity"] {
+            let req = request(&[("Transfer-Encoding", te)]);
+            assert_eq!(
+                check_framing(&req),
+                Err("smuggling_transfer_encoding"),
+                "{:?}",
+                te
+            );
+        }
+        let req = request(&[
+            ("Transfer-Encoding", "chunked"),
+            ("Transfer-Encoding", "chunked"),
+        ]);
+        assert_eq!(check_framing(&req), Err("smuggling_transfer_encoding"));
+        let mut req = request(&[("Transfer-Encoding", "chunked")]);
+        req.set_version(http::Version::HTTP_10);
+        assert_eq!(check_framing(&req), Err("smuggling_transfer_encoding"));
+    }
+
+    #[test]
+    fn plain_framing_is_allowed() {
+        assert_eq!(check_framing(&request(&[])), Ok(()));
+        assert_eq!(check_framing(&request(&[("Content-Length", "42")])), Ok(()));
+        assert_eq!(
+            check_framing(&request(&[("Transfer-Encoding", "Chunked")])),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn bad_content_length_is_refused() {
+        for headers in [
+            &[("Content-Length", "5"), ("Content-Length", "5")][..],
+            &[("Content-Length", "+5")][..],
+            &[("Content-Length", "5, 5")][..],
+        ] {
+            assert_eq!(
+                check_framing(&request(headers)),
+                Err("smuggling_content_length")
+            );
+        }
+    }
+
+    #[test]
+    fn hosts_are_canonicalized() {
+        let host = |value: &str| normalize_host(&request(&[("Host", value)]));
+        assert_eq!(host("Example.COM.:8443"), Ok("example.com".into()));
+        assert_eq!(host("bücher.example"), Ok("xn--bcher-kva.example".into()));
+        assert_eq!(host("[::1]:443"), Ok("[::1]".into()));
+        assert_eq!(host("example.com:99999"), Err(()));
+        assert_eq!(host("exa mple.com"), Err(()));
+        let repeated = request(&[("Host", "a.example"), ("Host", "b.example")]);
+        assert_eq!(normalize_host(&repeated), Err(()));
+    }
+}
@@ -0,0 +1,67 @@
+use std::io;
+
+/// An operator's request from outside the process, other than shutdown (which pingora
+/// handles itself).
+pub enum Trigger {
+    /// Re-read the config; carries what asked for it, for the log
+    Reload(&'static str),
+    /// Reopen log files after rotation
+    ReopenLogs,
+    /// Switch between the startup log filter and `debug_log_filter`
+    ToggleDebugLog,
+}
+
+/// Platform source of [`Trigger`]s: SIGHUP, SIGUSR1 and SIGUSR2 on Unix. The admin API's
+/// `/-/reload` works as well. Must be created inside a Tokio runtime.
+///
+/// The Ctrl-Break variant for Windows consoles is untested: pingora 0.3 only builds on Unix,
+/// so this crate doesn't build on Windows at all yet.
+pub struct Triggers {
+    #[cfg(unix)]
+    hangup: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    user1: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    user2: tokio::signal::unix::Signal,
+    #[cfg(windows)]
+    ctrl_break: tokio::signal::windows::CtrlBreak,
+}
+
+#[cfg(unix)]
+impl Triggers {
+    pub const RELOAD_HINT: &'static str = "Run 'kill -HUP <PID>' to reload.";
+
+    pub fn new() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self {
+            hangup: signal(SignalKind::hangup())?,
+            user1: signal(SignalKind::user_defined1())?,
+            user2: signal(SignalKind::user_defined2())?,
+        })
+    }
+
+    pub async fn next(&mut self) -> Trigger {
+        tokio::select! {
+            _ = self.hangup.recv() => Trigger::Reload("SIGHUP"),
+            _ = self.user1.recv() => Trigger::ReopenLogs,
+            _ = self.user2.recv() => Trigger::ToggleDebugLog,
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Triggers {
+    pub const RELOAD_HINT: &'static str = "Press Ctrl-Break or POST /-/reload to reload.";
+
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            ctrl_break: tokio::signal::windows::ctrl_break()?,
+        })
+    }
+
+    /// Log files can't be renamed while open on Windows, so there is nothing to reopen.
+    pub async fn next(&mut self) -> Trigger {
+        self.ctrl_break.recv().await;
+        Trigger::Reload("Ctrl-Break")
+    }
+}
@@ -0,0 +1,172 @@
+use crate::configuration::GatewayConfig;
+use crate::hmac_auth::HmacAuth;
+use crate::lua::LuaScripts;
+use crate::metrics::Metrics;
+use crate::middleware::{Decision, Middlewares, Router};
+use crate::proxy::RequestCtx;
+use crate::security::{self, SecurityLayer};
+use crate::synthetic::SyntheticResponses;
+use crate::wasm::WasmPlugins;
+use arc_swap::ArcSwap;
+use clap::Parser;
+use pingora::http::RequestHeader;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Runs a described request through the config's path normalization, routing and middleware
+/// chain without serving traffic, and prints what each step decided. Rate limits start from
+/// an empty window; quotas, body inspection, caching and the upstream aren't simulated.
+#[derive(Parser)]
+#[command(name = "test-request", bin_name = "flashproxy test-request")]
+pub struct TestRequest {
+    /// Config file to evaluate against
+    #[arg(short, long, default_value = "config.yaml")]
+    config: String,
+    #[arg(short = 'X', long, default_value = "GET")]
+    method: String,
+    /// Request target as the client sends it, query included
+    path: String,
+    /// Request header as `Name: value`; repeatable
+    #[arg(short = 'H', long = "header")]
+    headers: Vec<String>,
+    /// Client address
+    #[arg(long, default_value = "127.0.0.1")]
+    ip: String,
+}
+
+/// Entry point for `flashproxy test-request`; returns the process exit code.
+pub fn run<I: IntoIterator<Item = String>>(args: I) -> i32 {
+    let args = TestRequest::parse_from(args);
+    match simulate(&args) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn simulate(args: &TestRequest) -> Result<(), String> {
+    let config = GatewayConfig::load(&args.config)
+        .map_err(|e| format!("Failed to load config from {}: {}", args.config, e))?;
+    if config
+        .vault
+        .as_ref()
+        .is_some_and(|v| v.jwt_secret.is_some())
+    {
+        println!("note: the JWT secret comes from Vault and isn't fetched; jwt will reject");
+    }
+    if config.quota.is_some() {
+        println!("note: quotas aren't simulated; the quota stage always passes");
+    }
+    let security = SecurityLayer::new(&config).map_err(|e| e.to_string())?;
+    let synthetic =
+        SyntheticResponses::load(&config.synthetic_responses).map_err(|e| e.to_string())?;
+    let wasm = match config.wasm_plugins.is_empty() {
+        true => None,
+        false => Some(Arc::new(WasmPlugins::load(&config.wasm_plugins)?)),
+    };
+    let lua = match config.lua_scripts.is_empty() {
+        true => None,
+        false => Some(Arc::new(LuaScripts::load(&config.lua_scripts)?)),
+    };
+    let security = Arc::new(ArcSwap::from_pointee(security));
+    let middlewares = Middlewares::new(
+        security.clone(),
+        Metrics::new(&config.metrics_labels),
+        Arc::new(ArcSwap::from_pointee(synthetic)),
+        None,
+        config
+            .hmac_auth
+            .as_ref()
+            .map(|h| Arc::new(HmacAuth::new(h))),
+        wasm,
+        lua,
+    );
+    let router = Router::build(&config, &middlewares).map_err(|e| e.to_string())?;
+
+    let mut req = RequestHeader::build(args.method.as_str(), args.path.as_bytes(), None)
+        .map_err(|e| format!("invalid request: {}", e))?;
+    for header in &args.headers {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| format!("invalid header '{}', expected 'Name: value'", header))?;
+        req.insert_header(name.trim().to_string(), value.trim())
+            .map_err(|e| format!("invalid header '{}': {}", header, e))?;
+    }
+    let peer = args.ip.parse().ok().map(|ip: IpAddr| ip.to_canonical());
+    let forwarded = peer.and_then(|peer| security.load().forwarded_client_ip(peer, &req));
+    let mut ctx = RequestCtx {
+        client_ip: forwarded.map_or_else(|| args.ip.clone(), |ip| ip.to_string()),
+        client_addr: forwarded.or(peer),
+        method: args.method.clone(),
+        ..RequestCtx::default()
+    };
+    println!("request: {} {} from {}", args.method, args.path, args.ip);
+    if let Some(ip) = forwarded {
+        println!("client: {} (reported by trusted proxy)", ip);
+    }
+
+    match security::normalize_path(req.raw_path()) {
+        Ok(None) => {}
+        Ok(Some(path)) => {
+            println!("path: normalized to {}", path);
+            let uri = path.parse().map_err(|_| "path: malformed".to_string())?;
+            req.set_uri(uri);
+        }
+        Err(()) => return verdict("400 (malformed_path)"),
+    }
+    let normalized = String::from_utf8_lossy(req.raw_path()).into_owned();
+    router.strip_query(&mut req);
+    ctx.path = String::from_utf8_lossy(req.raw_path()).into_owned();
+    if ctx.path != normalized {
+        println!("query: stripped to {}", ctx.path);
+    }
+
+    let route = router.route(&req);
+    println!(
+        "route: {}",
+        route.name.as_deref().unwrap_or("<default chain>")
+    );
+    ctx.route = Some(route.clone());
+    if !route.allows(&req.method) {
+        println!("method: not allowed (Allow: {})", route.allow_header());
+        return verdict("405 (method_not_allowed)");
+    }
+    security.load().strip_claim_headers(&mut req);
+
+    let decision = route
+        .chain
+        .run_traced(&mut req, &mut ctx, |stage, decision, monitor| {
+            let mode = if monitor {
+                " [monitor: not enforced]"
+            } else {
+                ""
+            };
+            println!("stage {}: {}{}", stage, describe(decision), mode);
+        })
+        .map_err(|e| format!("stage failed: {}", e))?;
+    verdict(&match decision {
+        Decision::Continue => "forwarded to upstream".to_string(),
+        decision => describe(&decision),
+    })
+}
+
+fn describe(decision: &Decision) -> String {
+    match decision {
+        Decision::Continue => "pass".to_string(),
+        Decision::Reject { status, reason } => format!("{} ({})", status, reason),
+        Decision::Respond { status, body, .. } => {
+            format!(
+                "{} answered by the proxy ({} byte body)",
+                status,
+                body.len()
+            )
+        }
+    }
+}
+
+fn verdict(result: &str) -> Result<(), String> {
+    println!("result: {}", result);
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use crate::configuration::SpiffeConfig;
+use crate::protobuf::{fields, invalid};
+use arc_swap::ArcSwapOption;
+use bytes::{Buf, Bytes, BytesMut};
+use pingora::tls::pkey::PKey;
+use pingora::tls::x509::X509;
+use pingora::upstreams::peer::HttpPeer;
+use pingora::utils::CertKey;
+use std::io;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+use tokio::net::UnixStream;
+
+/// How long startup waits for the first SVID
+const FIRST_SVID_TIMEOUT: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Workload identity from a SPIRE agent, presented on upstream mTLS connections.
+///
+/// Keeps a `FetchX509SVID` stream open on the SPIFFE Workload API; the agent pushes a new
+/// message whenever the SVID or trust bundle rotates. Upstream certificates are checked
+/// against the trust bundle instead of by hostname, since SVIDs carry only a URI SAN. pingora
+/// doesn't expose the peer certificate, so the upstream's SPIFFE ID itself isn't checked:
+/// any workload in the trust domain is accepted.
+pub struct Spiffe {
+    socket_path: String,
+    verify_upstream: bool,
+    current: ArcSwapOption<Svid>,
+}
+
+struct Svid {
+    spiffe_id: String,
+    cert_key: Arc<CertKey>,
+    bundle: Arc<Box<[X509]>>,
+}
+
+impl Spiffe {
+    /// Starts watching the Workload API and waits for the first SVID.
+    pub fn start(config: &SpiffeConfig) -> io::Result<Arc<Self>> {
+        let spiffe = Arc::new(Self {
+            socket_path: config.socket_path.clone(),
+            verify_upstream: config.verify_upstream,
+            current: ArcSwapOption::empty(),
+        });
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let watcher = spiffe.clone();
+        std::thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            rt.block_on(watcher.watch(ready_tx));
+        });
+        ready_rx
+            .recv_timeout(FIRST_SVID_TIMEOUT)
+            .map_err(|_| io::Error::other("no SVID from the SPIFFE Workload API"))?;
+        Ok(spiffe)
+    }
+
+    /// Sets the SVID as client certificate and, if enabled, the trust bundle as CA.
+    pub fn apply(&self, peer: &mut HttpPeer) {
+        let Some(svid) = self.current.load_full() else {
+            return;
+        };
+        peer.client_cert_key = Some(svid.cert_key.clone());
+        if self.verify_upstream {
+            peer.options.ca = Some(svid.bundle.clone());
+            peer.options.verify_hostname = false;
+        }
+    }
+
+    async fn watch(self: Arc<Self>, ready: mpsc::Sender<()>) {
+        let mut ready = Some(ready);
+        loop {
+            if let Err(e) = self.fetch_svids(&mut ready).await {
+                tracing::warn!(socket = %self.socket_path, error = %e, "SPIFFE Workload API stream failed");
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn fetch_svids(&self, ready: &mut Option<mpsc::Sender<()>>) -> io::Result<()> {
+        let io = UnixStream::connect(&self.socket_path).await?;
+        let (client, connection) = h2::client::handshake(io).await.map_err(io::Error::other)?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!(error = %e, "workload api connection closed");
+            }
+        });
+        let mut client = client.ready().await.map_err(io::Error::other)?;
+        let request = http::Request::builder()
+            .method(http::Method::POST)
+            .uri("http://localhost/SpiffeWorkloadAPI/FetchX509SVID")
+            .header(http::header::CONTENT_TYPE, "application/grpc")
+            .header(http::header::TE, "trailers")
+            .header("workload.spiffe.io", "true")
+            .body(())
+            .map_err(io::Error::other)?;
+        let (response, mut send) = client
+            .send_request(request, false)
+            .map_err(io::Error::other)?;
+        // Empty X509SVIDRequest
+        send.send_data(Bytes::from_static(&[0, 0, 0, 0, 0]), true)
+            .map_err(io::Error::other)?;
+        let response = response.await.map_err(io::Error::other)?;
+        check_grpc_status(response.headers())?;
+
+        let mut body = response.into_body();
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(io::Error::other)?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+            buf.extend_from_slice(&chunk);
+            while buf.len() >= 5 {
+                let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+                if buf.len() < 5 + len {
+                    break;
+                }
+                buf.advance(5);
+                let message = buf.split_to(len);
+                let svid = parse_response(&message)?;
+                tracing::info!(spiffe_id = %svid.spiffe_id, "received X.509 SVID");
+                self.current.store(Some(Arc::new(svid)));
+                if let Some(ready) = ready.take() {
+                    let _ = ready.send(());
+                }
+            }
+        }
+        if let Some(trailers) = body.trailers().await.map_err(io::Error::other)? {
+            check_grpc_status(&trailers)?;
+        }
+        Err(io::Error::other("stream ended"))
+    }
+}
+
+fn check_grpc_status(headers: &http::HeaderMap) -> io::Result<()> {
+    match headers.get("grpc-status").and_then(|v| v.to_str().ok()) {
+        None | Some("0") => Ok(()),
+        Some(status) => {
+            let message = headers
+                .get("grpc-message")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("");
+            Err(io::Error::other(format!(
+                "grpc-status {} {}",
+                status, message
+            )))
+        }
+    }
+}
+
+/// Default (first) SVID of an `X509SVIDResponse`.
+fn parse_response(message: &[u8]) -> io::Result<Svid> {
+    let svid = fields(message)?
+        .into_iter()
+        .find(|(tag, _)| *tag == 1)
+        .and_then(|(_, v)| v.bytes())
+        .ok_or_else(|| invalid("response without an SVID"))?;
+    let (mut spiffe_id, mut chain, mut key, mut bundle) =
+        (String::new(), &[][..], &[][..], &[][..]);
+    for (tag, value) in fields(svid)? {
+        let Some(value) = value.bytes() else {
+            continue;
+        };
+        match tag {
+            1 => spiffe_id = String::from_utf8_lossy(value).into_owned(),
+            2 => chain = value,
+            3 => key = value,
+            4 => bundle = value,
+            _ => {}
+        }
+    }
+    let chain = split_der(chain)?;
+    if chain.is_empty() {
+        return Err(invalid("SVID without certificates"));
+    }
+    let key = PKey::private_key_from_pkcs8(key).map_err(io::Error::other)?;
+    Ok(Svid {
+        spiffe_id,
+        cert_key: Arc::new(CertKey::new(chain, key)),
+        bundle: Arc::new(split_der(bundle)?.into_boxed_slice()),
+    })
+}
+
+/// Certificates from concatenated ASN.1 DER, as the Workload API sends chains and bundles.
+fn split_der(mut buf: &[u8]) -> io::Result<Vec<X509>> {
+    let mut certs = Vec::new();
+    while buf.len() >= 2 {
+        let (header, len) = match buf[1] {
+            n if n < 0x80 => (2, n as usize),
+            n => {
+                let octets = (n & 0x7f) as usize;
+                let bytes = buf
+                    .get(2..2 + octets)
+                    .ok_or_else(|| invalid("truncated DER"))?;
+                (
+                    2 + octets,
+                    bytes.iter().fold(0, |acc, b| acc << 8 | *b as usize),
+                )
+            }
+        };
+        let der = buf
+            .get(..header + len)
+            .ok_or_else(|| invalid("truncated DER"))?;
+        certs.push(X509::from_der(der).map_err(io::Error::other)?);
+        buf = &buf[header + len..];
+    }
+    Ok(certs)
+}
@@ -0,0 +1,51 @@
+use crate::configuration::{ConfigError, SyntheticResponseConfig};
+use pingora::http::RequestHeader;
+
+/// Fixed responses from `synthetic_responses`, answered by the proxy without an upstream.
+/// Body files are read when the config is loaded, so a reload picks up edits.
+pub struct SyntheticResponses {
+    responses: Vec<SyntheticResponse>,
+}
+
+pub struct SyntheticResponse {
+    path: String,
+    host: Option<String>,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl SyntheticResponses {
+    pub fn load(configs: &[SyntheticResponseConfig]) -> Result<Self, ConfigError> {
+        let mut responses = Vec::with_capacity(configs.len());
+        for config in configs {
+            let body = match (&config.body, &config.body_file) {
+                (_, Some(path)) => {
+                    std::fs::read(path).map_err(|e| ConfigError::Io(path.clone(), e))?
+                }
+                (Some(body), None) => body.clone().into_bytes(),
+                (None, None) => Vec::new(),
+            };
+            responses.push(SyntheticResponse {
+                path: config.path.clone(),
+                host: config.host.as_ref().map(|h| h.to_ascii_lowercase()),
+                status: config.status,
+                headers: config
+                    .headers
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect(),
+                body,
+            });
+        }
+        Ok(Self { responses })
+    }
+
+    /// The first response whose path and (if set) host match the request.
+    pub fn find(&self, req: &RequestHeader, host: Option<&str>) -> Option<&SyntheticResponse> {
+        let path = req.uri.path();
+        self.responses
+            .iter()
+            .find(|r| r.path == path && r.host.as_deref().is_none_or(|h| Some(h) == host))
+    }
+}
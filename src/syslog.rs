@@ -0,0 +1,178 @@
+use crate::configuration::{SyslogConfig, SyslogFormat, SyslogProtocol};
+use serde_json::Value;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::time::Duration;
+
+const QUEUE_DEPTH: usize = 4096;
+const SEVERITY_INFO: u8 = 6;
+const SEVERITY_WARNING: u8 = 4;
+
+/// Ships access and audit events to a SIEM collector over syslog.
+/// Messages are handed to a background thread; if the collector falls behind, events are
+/// dropped rather than slowing down request handling.
+pub struct SyslogSink {
+    tx: SyncSender<String>,
+    format: SyslogFormat,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+}
+
+#[derive(Clone, Copy)]
+pub enum EventKind {
+    Access,
+    Audit,
+}
+
+impl EventKind {
+    fn msg_id(self) -> &'static str {
+        match self {
+            EventKind::Access => "access",
+            EventKind::Audit => "audit",
+        }
+    }
+
+    fn severity(self) -> u8 {
+        match self {
+            EventKind::Access => SEVERITY_INFO,
+            EventKind::Audit => SEVERITY_WARNING,
+        }
+    }
+}
+
+impl SyslogSink {
+    pub fn new(config: &SyslogConfig) -> Self {
+        let (tx, rx) = sync_channel(QUEUE_DEPTH);
+        let addr = config.addr.clone();
+        let protocol = config.protocol;
+        std::thread::spawn(move || run_sender(addr, protocol, rx));
+
+        let hostname = std::fs::read_to_string("/etc/hostname")
+            .map(|h| h.trim().to_string())
+            .ok()
+            .filter(|h| !h.is_empty())
+            .unwrap_or_else(|| "-".to_string());
+
+        Self {
+            tx,
+            format: config.format,
+            facility: config.facility,
+            hostname,
+            app_name: config.app_name.clone(),
+        }
+    }
+
+    pub fn send(&self, kind: EventKind, event: &Value) {
+        let msg = match self.format {
+            SyslogFormat::Rfc5424 => event.to_string(),
+            SyslogFormat::Cef => cef_message(kind, event),
+        };
+        let line = format!(
+            "<{}>1 {} {} {} {} {} - {}",
+            self.facility as u16 * 8 + kind.severity() as u16,
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            self.hostname,
+            self.app_name,
+            std::process::id(),
+            kind.msg_id(),
+            msg
+        );
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(line) {
+            tracing::warn!("syslog queue full, dropping event");
+        }
+    }
+}
+
+fn run_sender(addr: String, protocol: SyslogProtocol, rx: Receiver<String>) {
+    match protocol {
+        SyslogProtocol::Udp => {
+            // Bound to the family of the collector's address
+            let ipv6 = addr
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .is_some_and(|a| a.is_ipv6());
+            let socket = match UdpSocket::bind(if ipv6 { "[::]:0" } else { "0.0.0.0:0" }) {
+                Ok(s) => s,
+                Err(e) => {
+                    tracing::error!("syslog udp bind failed: {}", e);
+                    return;
+                }
+            };
+            for line in rx {
+                if let Err(e) = socket.send_to(line.as_bytes(), &addr) {
+                    tracing::warn!(addr = %addr, "syslog send failed: {}", e);
+                }
+            }
+        }
+        SyslogProtocol::Tcp => {
+            let mut stream: Option<TcpStream> = None;
+            for line in rx {
+                if stream.is_none() {
+                    stream = TcpStream::connect(&addr)
+                        .and_then(|s| s.set_write_timeout(Some(Duration::from_secs(5))).map(|_| s))
+                        .map_err(|e| tracing::warn!(addr = %addr, "syslog connect failed: {}", e))
+                        .ok();
+                }
+                // RFC 6587 octet-counting framing
+                let framed = format!("{} {}", line.len(), line);
+                if let Some(s) = stream.as_mut() {
+                    if let Err(e) = s.write_all(framed.as_bytes()) {
+                        tracing::warn!(addr = %addr, "syslog send failed: {}", e);
+                        stream = None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Map our JSON event fields onto CEF extension keys.
+fn cef_message(kind: EventKind, event: &Value) -> String {
+    let name = event
+        .get("reason")
+        .and_then(Value::as_str)
+        .unwrap_or(kind.msg_id());
+    let mut ext = Vec::new();
+    if let Value::Object(fields) = event {
+        for (key, value) in fields {
+            let cef_key = match key.as_str() {
+                "client_ip" => "src",
+                "method" => "requestMethod",
+                "path" => "request",
+                "status_code" => "outcome",
+                "reason" => "reason",
+                other => other,
+            };
+            let value = match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            ext.push(format!("{}={}", cef_key, cef_escape_ext(&value)));
+        }
+    }
+    format!(
+        "CEF:0|FlashProxy|FlashProxy|{}|{}|{}|{}|{}",
+        env!("CARGO_PKG_VERSION"),
+        kind.msg_id(),
+        cef_escape_header(name),
+        match kind {
+            EventKind::Access => 3,
+            EventKind::Audit => 7,
+        },
+        ext.join(" ")
+    )
+}
+
+fn cef_escape_header(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn cef_escape_ext(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
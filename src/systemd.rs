@@ -0,0 +1,147 @@
+use async_trait::async_trait;
+use pingora::lb::selection::RoundRobin;
+use pingora::lb::LoadBalancer;
+use pingora::server::{ListenFds, ShutdownWatch};
+use pingora::services::Service;
+use pingora::tls::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use std::net::{SocketAddr, TcpStream};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{self, UnixDatagram};
+use std::sync::Arc;
+use std::time::Duration;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const READY_POLL: Duration = Duration::from_millis(250);
+
+/// sd_notify integration for `Type=notify` units. `READY=1` is sent once the HTTPS listener
+/// completes a TLS handshake and at least one upstream is healthy; with `WatchdogSec=` set,
+/// the watchdog is pinged only while that handshake keeps succeeding, so a wedged proxy
+/// stops pinging and gets restarted. Also sends `MAINPID`, so after a listener handoff
+/// systemd follows the new process; that needs `NotifyAccess=all` in the unit.
+pub struct SystemdNotify {
+    socket: UnixDatagram,
+    addr: net::SocketAddr,
+    watchdog: Option<Duration>,
+    upstreams: Arc<LoadBalancer<RoundRobin>>,
+    listen_port: u16,
+}
+
+impl SystemdNotify {
+    /// `None` unless started by systemd with a notification socket.
+    pub fn from_env(upstreams: Arc<LoadBalancer<RoundRobin>>, listen_port: u16) -> Option<Self> {
+        let path = std::env::var("NOTIFY_SOCKET").ok()?;
+        let addr = match path.strip_prefix('@') {
+            Some(name) => net::SocketAddr::from_abstract_name(name),
+            None => net::SocketAddr::from_pathname(&path),
+        };
+        let (addr, socket) = match addr.and_then(|a| UnixDatagram::unbound().map(|s| (a, s))) {
+            Ok(pair) => pair,
+            Err(e) => {
+                tracing::warn!(socket = %path, error = %e, "systemd notification socket unusable");
+                return None;
+            }
+        };
+        // A watchdog armed for another process (e.g. a parent shell) isn't ours to ping
+        let for_us = std::env::var("WATCHDOG_PID")
+            .map(|pid| pid == std::process::id().to_string())
+            .unwrap_or(true);
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|usec| *usec > 0 && for_us)
+            .map(Duration::from_micros);
+        Some(Self {
+            socket,
+            addr,
+            watchdog,
+            upstreams,
+            listen_port,
+        })
+    }
+
+    fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            tracing::warn!(error = %e, "systemd notification failed");
+        }
+    }
+
+    fn upstream_healthy(&self) -> bool {
+        let backends = self.upstreams.backends();
+        backends.get_backend().iter().any(|b| backends.ready(b))
+    }
+
+    /// TLS handshake with our own HTTPS listener, which needs its accept loop and runtime alive.
+    fn probe(port: u16) -> Result<(), String> {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let stream = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(PROBE_TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(PROBE_TIMEOUT)))
+            .map_err(|e| e.to_string())?;
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string())?;
+        builder.set_verify(SslVerifyMode::NONE);
+        builder
+            .build()
+            .configure()
+            .map_err(|e| e.to_string())?
+            .verify_hostname(false)
+            .connect("localhost", stream)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn probe_listener(&self) -> Result<(), String> {
+        let port = self.listen_port;
+        tokio::task::spawn_blocking(move || Self::probe(port))
+            .await
+            .map_err(|e| e.to_string())?
+    }
+}
+
+#[async_trait]
+impl Service for SystemdNotify {
+    async fn start_service(&mut self, _fds: Option<ListenFds>, mut shutdown: ShutdownWatch) {
+        self.notify("STATUS=Waiting for the listener and a healthy upstream");
+        loop {
+            if self.upstream_healthy() && self.probe_listener().await.is_ok() {
+                break;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(READY_POLL) => {}
+                _ = shutdown.changed() => return,
+            }
+        }
+        self.notify(&format!(
+            "READY=1\nMAINPID={}\nSTATUS=Serving",
+            std::process::id()
+        ));
+        tracing::info!(watchdog = ?self.watchdog, "notified systemd of readiness");
+
+        let Some(watchdog) = self.watchdog else {
+            let _ = shutdown.changed().await;
+            return;
+        };
+        // Half the timeout, as systemd recommends, leaves room for one slow probe
+        let mut ticks = tokio::time::interval(watchdog / 2);
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = shutdown.changed() => return,
+            }
+            match self.probe_listener().await {
+                Ok(()) => self.notify("WATCHDOG=1"),
+                Err(e) => {
+                    tracing::warn!(error = %e, "listener probe failed, skipping watchdog ping")
+                }
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "systemd notify"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
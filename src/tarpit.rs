@@ -0,0 +1,70 @@
+use crate::configuration::TarpitConfig;
+use crate::middleware::matches_pattern;
+use bytes::Bytes;
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Answers refusals slowly: the status goes out at once, then a byte of padding per interval
+/// until the hold time is up, then the error body. A held client costs a sleeping task, not a
+/// worker thread.
+pub struct Tarpit {
+    reasons: Vec<String>,
+    duration: Duration,
+    interval: Duration,
+    slots: Semaphore,
+}
+
+impl Tarpit {
+    pub fn new(config: &TarpitConfig) -> Self {
+        Self {
+            reasons: config.reasons.clone(),
+            duration: Duration::from_secs(config.duration_secs),
+            interval: Duration::from_millis(config.interval_ms),
+            slots: Semaphore::new(config.max_clients),
+        }
+    }
+
+    /// A slot to hold a client refused for `reason`, if that reason is tarpitted and the
+    /// tarpit isn't full.
+    pub fn admit(&self, reason: &str) -> Option<SemaphorePermit<'_>> {
+        if !self.reasons.iter().any(|p| matches_pattern(p, reason)) {
+            return None;
+        }
+        self.slots.try_acquire().ok()
+    }
+
+    /// Holds the client for the configured time; `Ok` also when it gives up and disconnects.
+    pub async fn hold(
+        &self,
+        session: &mut Session,
+        status: u16,
+        body: Option<serde_json::Value>,
+        _slot: SemaphorePermit<'_>,
+    ) -> Result<()> {
+        let mut header = ResponseHeader::build(status, Some(2))?;
+        if body.is_some() {
+            header.insert_header("Content-Type", "application/json")?;
+        }
+        // No Content-Length: HTTP/1.1 clients read until the connection closes
+        session.set_keepalive(None);
+        session
+            .write_response_header(Box::new(header), false)
+            .await?;
+        let until = Instant::now() + self.duration;
+        while Instant::now() < until {
+            tokio::time::sleep(self.interval.min(until - Instant::now())).await;
+            if session
+                .write_response_body(Some(Bytes::from_static(b" ")), false)
+                .await
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+        let body = body.map(|b| Bytes::from(b.to_string()));
+        let _ = session.write_response_body(body, true).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use crate::configuration::{TlsPolicyConfig, TlsVersion};
+use crate::middleware::matches_pattern;
+use pingora::proxy::Session;
+use pingora::tls::ssl::NameType;
+
+/// What the client negotiated on the connection a request came in on, for the access log and
+/// the `tls` stage.
+#[derive(Debug, Clone)]
+pub struct TlsInfo {
+    /// OpenSSL's name, e.g. `TLSv1.3`
+    pub version: &'static str,
+    pub cipher: &'static str,
+    /// e.g. `h2` or `http/1.1`; `None` if the client offered no ALPN
+    pub alpn: Option<String>,
+    /// Server name the client asked for. Pingora 0.3 only exposes the TLS session of
+    /// HTTP/1.1 connections, so this is `None` on HTTP/2.
+    pub sni: Option<String>,
+}
+
+impl TlsInfo {
+    /// `None` for plaintext connections.
+    pub fn from_session(session: &Session) -> Option<Self> {
+        let digest = session.digest()?.ssl_digest.clone()?;
+        let ssl = session.stream().and_then(|stream| stream.get_ssl());
+        let alpn = match ssl {
+            Some(ssl) => ssl
+                .selected_alpn_protocol()
+                .map(|p| String::from_utf8_lossy(p).into_owned()),
+            // HTTP/2 over TLS is only ever negotiated through ALPN
+            None => session.is_http2().then(|| "h2".to_string()),
+        };
+        let sni = ssl
+            .and_then(|ssl| ssl.servername(NameType::HOST_NAME))
+            .map(str::to_ascii_lowercase);
+        Some(Self {
+            version: digest.version,
+            cipher: digest.cipher,
+            alpn,
+            sni,
+        })
+    }
+
+    fn version(&self) -> Option<TlsVersion> {
+        Some(match self.version {
+            "TLSv1" => TlsVersion::Tls10,
+            "TLSv1.1" => TlsVersion::Tls11,
+            "TLSv1.2" => TlsVersion::Tls12,
+            "TLSv1.3" => TlsVersion::Tls13,
+            _ => return None,
+        })
+    }
+}
+
+/// The `tls_policy` checks, run by the `tls` stage.
+pub struct TlsPolicy {
+    min_version: Option<TlsVersion>,
+    deny_ciphers: Vec<String>,
+    sni_must_match_host: bool,
+}
+
+impl TlsPolicy {
+    pub fn new(config: &TlsPolicyConfig) -> Self {
+        Self {
+            min_version: config.min_version,
+            deny_ciphers: config.deny_ciphers.clone(),
+            sni_must_match_host: config.sni_must_match_host,
+        }
+    }
+
+    /// `Err` carries the status and audit reason. `host` is the lowercased request host.
+    pub fn check(&self, tls: &TlsInfo, host: Option<&str>) -> Result<(), (u16, &'static str)> {
+        if let Some(min) = self.min_version {
+            // Anything OpenSSL doesn't name as TLS (SSLv3) is older than every minimum
+            if tls.version().is_none_or(|v| v < min) {
+                return Err((403, "tls_version"));
+            }
+        }
+        if self
+            .deny_ciphers
+            .iter()
+            .any(|pattern| matches_pattern(pattern, tls.cipher))
+        {
+            return Err((403, "tls_cipher"));
+        }
+        if self.sni_must_match_host {
+            if let (Some(sni), Some(host)) = (&tls.sni, host) {
+                if sni != host {
+                    return Err((421, "tls_sni_mismatch"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use crate::configuration::{ConfigError, UpstreamTokenConfig};
+use crate::security::Claims;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde_json::{json, Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Mints the internal token that replaces a validated edge JWT on the upstream request.
+pub struct TokenMinter {
+    header: Header,
+    key: EncodingKey,
+    issuer: Option<String>,
+    audience: String,
+    ttl_secs: u64,
+    claims: Vec<String>,
+}
+
+impl TokenMinter {
+    pub fn new(config: &UpstreamTokenConfig) -> Result<Self, ConfigError> {
+        let (algorithm, key) = match (&config.secret, &config.private_key_path) {
+            (Some(secret), _) => (
+                Algorithm::HS256,
+                EncodingKey::from_secret(secret.as_bytes()),
+            ),
+            (None, Some(path)) => {
+                let pem = std::fs::read(path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+                let key = EncodingKey::from_rsa_pem(&pem).map_err(|e| {
+                    ConfigError::Validation(format!("upstream_token.private_key_path: {}", e))
+                })?;
+                (Algorithm::RS256, key)
+            }
+            (None, None) => {
+                return Err(ConfigError::Validation(
+                    "upstream_token: no signing key".into(),
+                ))
+            }
+        };
+        Ok(Self {
+            header: Header::new(algorithm),
+            key,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            ttl_secs: config.ttl_secs,
+            claims: config.claims.clone(),
+        })
+    }
+
+    pub fn mint(&self, edge: &Claims) -> Result<String, jsonwebtoken::errors::Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut claims = Map::new();
+        for name in &self.claims {
+            if let Some(value) = edge.get(name) {
+                claims.insert(name.clone(), value.clone());
+            }
+        }
+        claims.insert("aud".to_string(), json!(self.audience));
+        if let Some(issuer) = &self.issuer {
+            claims.insert("iss".to_string(), json!(issuer));
+        }
+        claims.insert("iat".to_string(), json!(now));
+        let exp = (now + self.ttl_secs).min(edge.expires_at());
+        claims.insert("exp".to_string(), json!(exp));
+        encode(&self.header, &Value::Object(claims), &self.key)
+    }
+}
@@ -0,0 +1,195 @@
+use crate::configuration::UploadFilterConfig;
+use crate::openapi::percent_decode;
+use pingora::http::RequestHeader;
+
+/// File types recognised by their leading bytes, by the name used in `blocked_types`.
+pub const FILE_TYPES: &[(&str, &[&[u8]])] = &[
+    ("exe", &[b"MZ"]),
+    ("elf", &[b"\x7fELF"]),
+    (
+        "macho",
+        &[
+            b"\xfe\xed\xfa\xce",
+            b"\xfe\xed\xfa\xcf",
+            b"\xce\xfa\xed\xfe",
+            b"\xcf\xfa\xed\xfe",
+            b"\xca\xfe\xba\xbe",
+        ],
+    ),
+    ("script", &[b"#!"]),
+    ("msi", &[b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1"]),
+    ("zip", &[b"PK\x03\x04"]),
+    ("pdf", &[b"%PDF-"]),
+];
+
+/// Longest magic number above
+const SNIFF_LEN: usize = 8;
+/// Part headers larger than this are treated as malformed rather than buffered
+const MAX_PART_HEADERS: usize = 16 * 1024;
+
+/// Blocks `multipart/form-data` uploads by file extension or by the magic bytes at the start
+/// of each file part.
+pub struct UploadFilter {
+    blocked_extensions: Vec<String>,
+    blocked_magic: Vec<&'static [u8]>,
+}
+
+/// Streaming multipart parser state for one request, carried in the request context.
+pub struct MultipartScan {
+    /// `--boundary`
+    delimiter: Vec<u8>,
+    /// Bytes not yet consumed by the parser
+    pending: Vec<u8>,
+    state: State,
+}
+
+enum State {
+    /// Looking for the next delimiter line
+    Boundary,
+    /// Inside a part's header block
+    Headers,
+    /// At the start of a part's content, waiting for enough bytes to sniff
+    Content,
+    /// Closing delimiter seen; the epilogue is ignored
+    Done,
+}
+
+impl UploadFilter {
+    pub fn new(config: &UploadFilterConfig) -> Self {
+        Self {
+            blocked_extensions: config
+                .blocked_extensions
+                .iter()
+                .map(|e| e.trim_start_matches('.').to_ascii_lowercase())
+                .collect(),
+            blocked_magic: FILE_TYPES
+                .iter()
+                .filter(|(name, _)| config.blocked_types.iter().any(|t| t == name))
+                .flat_map(|(_, magic)| magic.iter().copied())
+                .collect(),
+        }
+    }
+
+    /// Starts a scan if the request carries a multipart form body.
+    pub fn start(&self, req: &RequestHeader) -> Option<MultipartScan> {
+        let content_type = req
+            .headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())?;
+        let mut params = content_type.split(';').map(str::trim);
+        if !params.next()?.eq_ignore_ascii_case("multipart/form-data") {
+            return None;
+        }
+        let boundary = params
+            .filter_map(|p| p.split_once('='))
+            .find(|(k, _)| k.trim().eq_ignore_ascii_case("boundary"))
+            .map(|(_, v)| v.trim().trim_matches('"'))?;
+        Some(MultipartScan {
+            delimiter: [b"--", boundary.as_bytes()].concat(),
+            pending: Vec::new(),
+            state: State::Boundary,
+        })
+    }
+
+    /// Feeds the next body chunk. Returns `Err(reason)` once a blocked file is seen.
+    pub fn scan(
+        &self,
+        scan: &mut MultipartScan,
+        chunk: &[u8],
+        end_of_stream: bool,
+    ) -> Result<(), &'static str> {
+        scan.pending.extend_from_slice(chunk);
+        loop {
+            match scan.state {
+                State::Boundary => {
+                    let Some(pos) = find(&scan.pending, &scan.delimiter) else {
+                        // Keep enough of the tail to match a delimiter split across chunks
+                        let keep = scan.delimiter.len().min(scan.pending.len());
+                        scan.pending.drain(..scan.pending.len() - keep);
+                        return Ok(());
+                    };
+                    let after = pos + scan.delimiter.len();
+                    if scan.pending.len() < after + 2 {
+                        return Ok(());
+                    }
+                    scan.state = if &scan.pending[after..after + 2] == b"--" {
+                        State::Done
+                    } else {
+                        State::Headers
+                    };
+                    scan.pending.drain(..after);
+                }
+                State::Headers => {
+                    let Some(end) = find(&scan.pending, b"\r\n\r\n") else {
+                        if scan.pending.len() > MAX_PART_HEADERS {
+                            return Err("malformed_multipart");
+                        }
+                        return Ok(());
+                    };
+                    let headers = String::from_utf8_lossy(&scan.pending[..end]).into_owned();
+                    if part_filenames(&headers).any(|n| self.extension_blocked(&n)) {
+                        return Err("blocked_upload_extension");
+                    }
+                    scan.pending.drain(..end + 4);
+                    scan.state = State::Content;
+                }
+                State::Content => {
+                    let delimiter_at = find(&scan.pending, &scan.delimiter);
+                    let available = delimiter_at.unwrap_or(scan.pending.len()).min(SNIFF_LEN);
+                    if available < SNIFF_LEN && delimiter_at.is_none() && !end_of_stream {
+                        return Ok(());
+                    }
+                    let head = &scan.pending[..available];
+                    if self.blocked_magic.iter().any(|m| head.starts_with(m)) {
+                        return Err("blocked_upload_type");
+                    }
+                    scan.state = State::Boundary;
+                }
+                State::Done => {
+                    scan.pending.clear();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn extension_blocked(&self, filename: &str) -> bool {
+        // Windows ignores trailing dots and spaces, so `evil.exe.` is still an .exe
+        let name = filename.trim_end_matches(['.', ' ']);
+        let base = name.rsplit(['/', '\\']).next().unwrap_or(name);
+        base.rsplit_once('.').is_some_and(|(_, ext)| {
+            let ext = ext.to_ascii_lowercase();
+            self.blocked_extensions.contains(&ext)
+        })
+    }
+}
+
+/// Every `filename` and RFC 5987 `filename*` in a part's Content-Disposition header. Both are
+/// checked, since servers differ in which one they prefer.
+fn part_filenames(headers: &str) -> impl Iterator<Item = String> + '_ {
+    let disposition = headers.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("content-disposition")
+            .then_some(value)
+    });
+    disposition
+        .into_iter()
+        .flat_map(|d| d.split(';'))
+        .filter_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            match key.trim().to_ascii_lowercase().as_str() {
+                "filename*" => {
+                    let value = value.trim();
+                    let encoded = value.split_once("''").map_or(value, |(_, v)| v);
+                    Some(percent_decode(encoded, false))
+                }
+                "filename" => Some(value.trim().trim_matches('"').to_string()),
+                _ => None,
+            }
+        })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
@@ -0,0 +1,120 @@
+use crate::configuration::{
+    ConfigError, UpstreamConnectionConfig, UpstreamProtocol, UpstreamVerify,
+};
+use pingora::protocols::{Digest, ALPN};
+use pingora::tls::x509::X509;
+use pingora::upstreams::peer::{HttpPeer, Scheme};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+/// A route's `upstream_connection` settings, applied to each peer the route connects to.
+pub struct UpstreamConnection {
+    /// `None` leaves the proxy-wide choice in place
+    alpn: Option<ALPN>,
+    tls: bool,
+    verify: UpstreamVerify,
+    ca: Option<Arc<Box<[X509]>>>,
+    /// Accepted leaf certificate digests; any if empty
+    pins: Vec<Vec<u8>>,
+    /// Keeps pooled connections apart: pingora reuses a connection for any peer with the same
+    /// address and verification flags, whatever protocol or CA it was set up with
+    group_key: u64,
+}
+
+impl UpstreamConnection {
+    /// Fails if the CA bundle can't be read or holds no certificate, a pin isn't a SHA-256
+    /// digest, or certificate checks are set without TLS.
+    pub fn load(config: &UpstreamConnectionConfig) -> Result<Self, ConfigError> {
+        if !config.tls
+            && (config.verify != UpstreamVerify::Full
+                || config.ca_file.is_some()
+                || !config.pin_sha256.is_empty())
+        {
+            return Err(ConfigError::Validation(
+                "upstream_connection: verify, ca_file and pin_sha256 need tls".into(),
+            ));
+        }
+        let ca = match &config.ca_file {
+            Some(path) => {
+                let pem = std::fs::read(path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+                let certs = X509::stack_from_pem(&pem).map_err(|e| {
+                    ConfigError::Validation(format!("upstream_connection.ca_file {}: {}", path, e))
+                })?;
+                if certs.is_empty() {
+                    return Err(ConfigError::Validation(format!(
+                        "upstream_connection.ca_file {}: no certificates",
+                        path
+                    )));
+                }
+                Some(Arc::new(certs.into_boxed_slice()))
+            }
+            None => None,
+        };
+        let pins = config
+            .pin_sha256
+            .iter()
+            .map(|pin| match hex::decode(pin.replace(':', "")) {
+                Ok(digest) if digest.len() == 32 => Ok(digest),
+                _ => Err(ConfigError::Validation(format!(
+                    "upstream_connection.pin_sha256: '{}' is not a SHA-256 fingerprint",
+                    pin
+                ))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut hasher = DefaultHasher::new();
+        (
+            config.protocol,
+            config.tls,
+            config.verify,
+            &config.ca_file,
+            &pins,
+        )
+            .hash(&mut hasher);
+        Ok(Self {
+            alpn: config.protocol.map(|protocol| match protocol {
+                UpstreamProtocol::H1 => ALPN::H1,
+                UpstreamProtocol::H2 => ALPN::H2,
+                UpstreamProtocol::Auto => ALPN::H2H1,
+            }),
+            tls: config.tls,
+            verify: config.verify,
+            ca,
+            pins,
+            group_key: hasher.finish(),
+        })
+    }
+
+    /// Runs after the SPIFFE settings, so an explicit CA bundle wins over the trust bundle.
+    pub fn apply(&self, peer: &mut HttpPeer) {
+        if let Some(alpn) = &self.alpn {
+            peer.options.alpn = alpn.clone();
+        }
+        if !self.tls {
+            peer.scheme = Scheme::HTTP;
+        }
+        match self.verify {
+            UpstreamVerify::Full => {}
+            UpstreamVerify::SkipHostname => peer.options.verify_hostname = false,
+            UpstreamVerify::None => {
+                peer.options.verify_cert = false;
+                peer.options.verify_hostname = false;
+            }
+        }
+        if let Some(ca) = &self.ca {
+            peer.options.ca = Some(ca.clone());
+        }
+        peer.group_key = self.group_key;
+    }
+
+    /// Whether the upstream's certificate matches a pin, checked once connected since pingora
+    /// has no hook into the handshake itself.
+    pub fn pinned(&self, digest: Option<&Digest>) -> bool {
+        if self.pins.is_empty() {
+            return true;
+        }
+        digest
+            .and_then(|d| d.ssl_digest.as_ref())
+            .is_some_and(|ssl| self.pins.contains(&ssl.cert_digest))
+    }
+}
@@ -0,0 +1,303 @@
+use crate::configuration::{
+    ConfigError, GatewayConfig, VaultConfig, VaultSecretRef, VaultTlsConfig,
+};
+use crate::reload::Reloader;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use async_trait::async_trait;
+use pingora::listeners::TlsAccept;
+use pingora::tls::ext;
+use pingora::tls::pkey::{PKey, Private};
+use pingora::tls::ssl::SslRef;
+use pingora::tls::x509::X509;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Secrets and the listener certificate sourced from HashiCorp Vault.
+///
+/// The JWT secret is read from a KV secret whenever the config is loaded, and re-read on every
+/// refresh; a change triggers a normal reload. The certificate is either issued by a PKI
+/// mount and re-issued after two thirds of its lifetime, or read from KV and swapped whenever
+/// it changes. Handshakes pick up the current certificate through [`VaultCertificate`].
+pub struct Vault {
+    addr: String,
+    token_path: Option<String>,
+    env_token: Option<String>,
+    jwt_secret: Option<VaultSecretRef>,
+    tls: Option<VaultTlsConfig>,
+    refresh: Duration,
+    client: reqwest::blocking::Client,
+    cert: ArcSwapOption<ServerCert>,
+}
+
+struct ServerCert {
+    pem: String,
+    leaf: X509,
+    chain: Vec<X509>,
+    key: PKey<Private>,
+    /// When a PKI certificate is due for re-issue; `None` for KV certificates
+    renew_at: Option<SystemTime>,
+}
+
+impl Vault {
+    /// Connects and fetches the initial certificate, so startup fails if Vault can't serve it.
+    pub fn new(config: &VaultConfig) -> Result<Self, ConfigError> {
+        let mut builder = reqwest::blocking::Client::builder().timeout(REQUEST_TIMEOUT);
+        if let Some(path) = &config.ca_cert_path {
+            let pem = std::fs::read(path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+            let ca = reqwest::Certificate::from_pem(&pem)
+                .map_err(|e| secret_error(format!("vault ca_cert_path: {}", e)))?;
+            builder = builder.add_root_certificate(ca);
+        }
+        let client = tokio::task::block_in_place(|| builder.build())
+            .map_err(|e| secret_error(format!("vault client: {}", e)))?;
+        let vault = Self {
+            addr: config.addr.trim_end_matches('/').to_string(),
+            token_path: config.token_path.clone(),
+            env_token: std::env::var("VAULT_TOKEN").ok(),
+            jwt_secret: config.jwt_secret.clone(),
+            tls: config.tls.clone(),
+            refresh: Duration::from_secs(config.refresh_interval_secs),
+            client,
+            cert: ArcSwapOption::empty(),
+        };
+        if let Some(tls) = &vault.tls {
+            vault.cert.store(Some(Arc::new(vault.fetch_cert(tls)?)));
+        }
+        Ok(vault)
+    }
+
+    pub fn serves_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// The listener certificate currently served, leaf first.
+    pub fn certificates(&self) -> Vec<X509> {
+        self.cert
+            .load()
+            .as_ref()
+            .map(|cert| {
+                std::iter::once(cert.leaf.clone())
+                    .chain(cert.chain.iter().cloned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Fills in the secrets a freshly loaded config takes from Vault.
+    pub fn resolve(&self, config: &mut GatewayConfig) -> Result<(), ConfigError> {
+        if let Some(secret) = &self.jwt_secret {
+            config.jwt_secret = self.read_field(secret)?;
+        }
+        Ok(())
+    }
+
+    /// Renews the token and rotates secrets every `refresh_interval_secs`, for the life of the
+    /// process.
+    pub fn spawn_refresh(
+        self: Arc<Self>,
+        config: Arc<ArcSwap<GatewayConfig>>,
+        reloader: Arc<Reloader>,
+    ) {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(self.refresh);
+            if let Err(e) = self.renew_token() {
+                tracing::warn!(error = %e, "vault token renewal failed");
+            }
+            if let Some(secret) = &self.jwt_secret {
+                match self.read_field(secret) {
+                    Ok(value) if value != config.load().jwt_secret => {
+                        tracing::info!("JWT secret changed in Vault, reloading");
+                        if let Err(e) = reloader.reload() {
+                            tracing::error!(error = %e, "reload after JWT secret rotation failed");
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        tracing::error!(error = %e, "vault read failed, keeping current JWT secret")
+                    }
+                }
+            }
+            if let Some(tls) = &self.tls {
+                self.rotate_cert(tls);
+            }
+        });
+    }
+
+    fn rotate_cert(&self, tls: &VaultTlsConfig) {
+        let current = self.cert.load_full();
+        let due = current
+            .as_ref()
+            .and_then(|c| c.renew_at)
+            .is_none_or(|at| SystemTime::now() >= at);
+        if !due {
+            return;
+        }
+        match self.fetch_cert(tls) {
+            Ok(cert) if current.as_ref().is_some_and(|c| c.pem == cert.pem) => {}
+            Ok(cert) => {
+                tracing::info!(path = %tls.path, "TLS certificate rotated from Vault");
+                self.cert.store(Some(Arc::new(cert)));
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "vault certificate refresh failed, keeping current one")
+            }
+        }
+    }
+
+    fn fetch_cert(&self, tls: &VaultTlsConfig) -> Result<ServerCert, ConfigError> {
+        let (data, issued) = match &tls.common_name {
+            Some(common_name) => {
+                let mut body = json!({ "common_name": common_name });
+                if let Some(ttl) = &tls.ttl {
+                    body["ttl"] = json!(ttl);
+                }
+                (
+                    self.request(reqwest::Method::POST, &tls.path, Some(body))?,
+                    true,
+                )
+            }
+            None => (
+                kv_data(self.request(reqwest::Method::GET, &tls.path, None)?),
+                false,
+            ),
+        };
+        let field = |name: &str| {
+            data.get(name)
+                .and_then(Value::as_str)
+                .ok_or_else(|| secret_error(format!("vault {}: no {} field", tls.path, name)))
+        };
+        let mut pem = field("certificate")?.to_string();
+        let key_pem = field("private_key")?;
+        // PKI returns the issuer chain separately; KV secrets carry it in `certificate`
+        if let Some(chain) = data.get("ca_chain").and_then(Value::as_array) {
+            for ca in chain.iter().filter_map(Value::as_str) {
+                pem.push('\n');
+                pem.push_str(ca);
+            }
+        }
+        let invalid = |e| secret_error(format!("vault {}: {}", tls.path, e));
+        let mut certs = X509::stack_from_pem(pem.as_bytes())
+            .map_err(invalid)?
+            .into_iter();
+        let leaf = certs
+            .next()
+            .ok_or_else(|| secret_error(format!("vault {}: empty certificate", tls.path)))?;
+        let key = PKey::private_key_from_pem(key_pem.as_bytes()).map_err(invalid)?;
+        let renew_at = issued.then(|| {
+            let now = SystemTime::now();
+            let expires = data
+                .get("expiration")
+                .and_then(Value::as_u64)
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap_or(now);
+            now + expires.duration_since(now).unwrap_or_default() * 2 / 3
+        });
+        Ok(ServerCert {
+            pem,
+            leaf,
+            chain: certs.collect(),
+            key,
+            renew_at,
+        })
+    }
+
+    fn read_field(&self, secret: &VaultSecretRef) -> Result<String, ConfigError> {
+        let data = kv_data(self.request(reqwest::Method::GET, &secret.path, None)?);
+        data.get(&secret.field)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| {
+                secret_error(format!("vault {}: no {} field", secret.path, secret.field))
+            })
+    }
+
+    fn renew_token(&self) -> Result<(), ConfigError> {
+        self.request(
+            reqwest::Method::POST,
+            "auth/token/renew-self",
+            Some(json!({})),
+        )
+        .map(|_| ())
+    }
+
+    /// Re-read on every call, since Vault Agent rewrites the sink when it renews.
+    fn token(&self) -> Result<String, ConfigError> {
+        match &self.token_path {
+            Some(path) => std::fs::read_to_string(path)
+                .map(|t| t.trim().to_string())
+                .map_err(|e| ConfigError::Io(path.clone(), e)),
+            None => self
+                .env_token
+                .clone()
+                .ok_or_else(|| secret_error("vault: set token_path or VAULT_TOKEN".to_string())),
+        }
+    }
+
+    /// `data` of a Vault API response. Reload runs on runtime workers, so the blocking
+    /// request is moved off the async context.
+    fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Value, ConfigError> {
+        let url = format!("{}/v1/{}", self.addr, path.trim_start_matches('/'));
+        let token = self.token()?;
+        let fail = |e: String| secret_error(format!("vault {}: {}", path, e));
+        tokio::task::block_in_place(|| {
+            let mut request = self
+                .client
+                .request(method, &url)
+                .header("X-Vault-Token", token);
+            if let Some(body) = body {
+                request = request.json(&body);
+            }
+            let response = request.send().map_err(|e| fail(e.to_string()))?;
+            let status = response.status();
+            if !status.is_success() {
+                return Err(fail(format!("status {}", status.as_u16())));
+            }
+            let mut body: Value = response.json().map_err(|e| fail(e.to_string()))?;
+            Ok(body.get_mut("data").map(Value::take).unwrap_or(Value::Null))
+        })
+    }
+}
+
+/// Unwraps the extra `data` level of KV v2 responses; KV v1 secrets are returned as they are.
+fn kv_data(data: Value) -> Value {
+    match data {
+        Value::Object(mut map) if map.get("metadata").is_some_and(Value::is_object) => {
+            map.remove("data").unwrap_or(Value::Null)
+        }
+        other => other,
+    }
+}
+
+fn secret_error(msg: String) -> ConfigError {
+    ConfigError::Secret(msg)
+}
+
+/// Serves the Vault-provided certificate on every TLS handshake.
+pub struct VaultCertificate(pub Arc<Vault>);
+
+#[async_trait]
+impl TlsAccept for VaultCertificate {
+    async fn certificate_callback(&self, ssl: &mut SslRef) {
+        let Some(cert) = self.0.cert.load_full() else {
+            return;
+        };
+        let result = ext::ssl_use_certificate(ssl, &cert.leaf)
+            .and_then(|_| ext::ssl_use_private_key(ssl, &cert.key))
+            .and_then(|_| {
+                cert.chain
+                    .iter()
+                    .try_for_each(|ca| ext::ssl_add_chain_cert(ssl, ca))
+            });
+        if let Err(e) = result {
+            tracing::error!(error = %e, "failed to install vault certificate");
+        }
+    }
+}
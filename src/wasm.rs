@@ -0,0 +1,675 @@
+use crate::configuration::WasmPluginConfig;
+use pingora::http::{RequestHeader, ResponseHeader};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use wasmi::{Caller, Config, Engine, Extern, Func, Instance, Linker, Module, Store, Val};
+
+// Subset of the proxy-wasm ABI (v0.2.1) that FlashProxy implements. Hostcalls outside this
+// subset are linked as stubs returning `STATUS_UNIMPLEMENTED`, so SDK-built plugins still load.
+const STATUS_OK: i32 = 0;
+const STATUS_NOT_FOUND: i32 = 1;
+const STATUS_BAD_ARGUMENT: i32 = 2;
+const STATUS_UNIMPLEMENTED: i32 = 12;
+
+const MAP_REQUEST_HEADERS: i32 = 0;
+const MAP_RESPONSE_HEADERS: i32 = 2;
+const BUFFER_PLUGIN_CONFIGURATION: i32 = 7;
+const ACTION_CONTINUE: i32 = 0;
+const ROOT_CONTEXT_ID: i32 = 1;
+
+/// Instruction budget per guest call, so a runaway plugin cannot wedge a worker.
+const FUEL_PER_CALL: u64 = 50_000_000;
+
+/// Host-side state visible to hostcalls while a guest callback runs.
+#[derive(Default)]
+struct HostState {
+    plugin_name: String,
+    plugin_config: Vec<u8>,
+    map_type: i32,
+    headers: Vec<(String, String)>,
+    edits: Vec<HeaderEdit>,
+    local_response: Option<LocalResponse>,
+}
+
+enum HeaderEdit {
+    Add(String, String),
+    Replace(String, String),
+    Remove(String),
+}
+
+/// Response generated by a plugin via `proxy_send_local_response`.
+pub struct LocalResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+struct PluginInstance {
+    store: Store<HostState>,
+    instance: Instance,
+    next_context_id: i32,
+}
+
+struct WasmPlugin {
+    name: String,
+    instances: Vec<Mutex<PluginInstance>>,
+    next: AtomicUsize,
+}
+
+/// Per-request binding of a plugin to the instance and context id that saw its request
+/// headers, so the response phase runs against the same guest state.
+#[derive(Clone, Copy)]
+pub struct PluginContext {
+    instance: usize,
+    context_id: i32,
+}
+
+/// Loaded WebAssembly filter plugins, invoked in config order at the request and response
+/// header phases.
+pub struct WasmPlugins {
+    plugins: Vec<WasmPlugin>,
+}
+
+impl WasmPlugins {
+    pub fn load(configs: &[WasmPluginConfig]) -> Result<Self, String> {
+        let mut engine_config = Config::default();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config);
+
+        let mut plugins = Vec::with_capacity(configs.len());
+        for config in configs {
+            let wasm = std::fs::read(&config.path)
+                .map_err(|e| format!("plugin {}: read {}: {}", config.name, config.path, e))?;
+            let module = Module::new(&engine, &wasm)
+                .map_err(|e| format!("plugin {}: compile: {}", config.name, e))?;
+            let linker = build_linker(&engine, &module)
+                .map_err(|e| format!("plugin {}: link: {}", config.name, e))?;
+
+            let mut instances = Vec::with_capacity(config.instances);
+            for _ in 0..config.instances.max(1) {
+                let instance = PluginInstance::new(&engine, &linker, &module, config)
+                    .map_err(|e| format!("plugin {}: start: {}", config.name, e))?;
+                instances.push(Mutex::new(instance));
+            }
+            plugins.push(WasmPlugin {
+                name: config.name.clone(),
+                instances,
+                next: AtomicUsize::new(0),
+            });
+        }
+        Ok(Self { plugins })
+    }
+
+    /// Runs `proxy_on_request_headers` for every plugin. Header edits are applied to `req`;
+    /// the first plugin that sends a local response short-circuits the chain.
+    pub fn on_request_headers(
+        &self,
+        req: &mut RequestHeader,
+        contexts: &mut Vec<PluginContext>,
+    ) -> Option<LocalResponse> {
+        for plugin in &self.plugins {
+            let idx = plugin.next.fetch_add(1, Ordering::Relaxed) % plugin.instances.len();
+            let mut guard = plugin.instances[idx]
+                .lock()
+                .unwrap_or_else(|p| p.into_inner());
+            let context_id = guard.create_context();
+            contexts.push(PluginContext {
+                instance: idx,
+                context_id,
+            });
+
+            let headers = request_header_pairs(req);
+            match guard.run_headers_phase(
+                "proxy_on_request_headers",
+                context_id,
+                MAP_REQUEST_HEADERS,
+                headers,
+            ) {
+                Ok((edits, local)) => {
+                    for edit in edits {
+                        apply_request_edit(req, edit);
+                    }
+                    if local.is_some() {
+                        return local;
+                    }
+                }
+                Err(e) => {
+                    tracing::error!(plugin = %plugin.name, "wasm request phase failed: {}", e)
+                }
+            }
+        }
+        None
+    }
+
+    /// Runs `proxy_on_response_headers` for every plugin that saw this request.
+    pub fn on_response_headers(&self, resp: &mut ResponseHeader, contexts: &[PluginContext]) {
+        for (plugin, pc) in self.plugins.iter().zip(contexts) {
+            let mut guard = plugin.instances[pc.instance]
+                .lock()
+                .unwrap_or_else(|p| p.into_inner());
+            let headers = response_header_pairs(resp);
+            match guard.run_headers_phase(
+                "proxy_on_response_headers",
+                pc.context_id,
+                MAP_RESPONSE_HEADERS,
+                headers,
+            ) {
+                Ok((edits, _)) => {
+                    for edit in edits {
+                        apply_response_edit(resp, edit);
+                    }
+                }
+                Err(e) => {
+                    tracing::error!(plugin = %plugin.name, "wasm response phase failed: {}", e)
+                }
+            }
+        }
+    }
+
+    /// Lets each plugin release per-request state.
+    pub fn on_done(&self, contexts: &[PluginContext]) {
+        for (plugin, pc) in self.plugins.iter().zip(contexts) {
+            let mut guard = plugin.instances[pc.instance]
+                .lock()
+                .unwrap_or_else(|p| p.into_inner());
+            guard.finish_context(pc.context_id);
+        }
+    }
+}
+
+impl PluginInstance {
+    fn new(
+        engine: &Engine,
+        linker: &Linker<HostState>,
+        module: &Module,
+        config: &WasmPluginConfig,
+    ) -> Result<Self, wasmi::Error> {
+        let state = HostState {
+            plugin_name: config.name.clone(),
+            plugin_config: config
+                .configuration
+                .clone()
+                .unwrap_or_default()
+                .into_bytes(),
+            ..Default::default()
+        };
+        let mut store = Store::new(engine, state);
+        store.set_fuel(FUEL_PER_CALL)?;
+        let instance = linker.instantiate_and_start(&mut store, module)?;
+
+        let mut plugin = Self {
+            store,
+            instance,
+            next_context_id: ROOT_CONTEXT_ID + 1,
+        };
+        for init in ["_initialize", "_start"] {
+            if plugin.func(init).is_some() {
+                plugin.call(init, &[])?;
+            }
+        }
+        plugin.call_optional("proxy_on_context_create", &[ROOT_CONTEXT_ID, 0])?;
+        plugin.call_optional("proxy_on_vm_start", &[ROOT_CONTEXT_ID, 0])?;
+        let config_len = plugin.store.data().plugin_config.len() as i32;
+        plugin.call_optional("proxy_on_configure", &[ROOT_CONTEXT_ID, config_len])?;
+        Ok(plugin)
+    }
+
+    fn func(&self, name: &str) -> Option<Func> {
+        self.instance.get_func(&self.store, name)
+    }
+
+    fn call(&mut self, name: &str, args: &[i32]) -> Result<Option<i32>, wasmi::Error> {
+        let Some(func) = self.func(name) else {
+            return Ok(None);
+        };
+        let params: Vec<Val> = args.iter().map(|a| Val::I32(*a)).collect();
+        let mut results: Vec<Val> = func
+            .ty(&self.store)
+            .results()
+            .iter()
+            .map(|ty| Val::default(*ty))
+            .collect();
+        self.store.set_fuel(FUEL_PER_CALL)?;
+        func.call(&mut self.store, &params, &mut results)?;
+        Ok(results.first().and_then(Val::i32))
+    }
+
+    fn call_optional(&mut self, name: &str, args: &[i32]) -> Result<(), wasmi::Error> {
+        self.call(name, args).map(|_| ())
+    }
+
+    fn create_context(&mut self) -> i32 {
+        let id = self.next_context_id;
+        self.next_context_id = self
+            .next_context_id
+            .wrapping_add(1)
+            .max(ROOT_CONTEXT_ID + 1);
+        if let Err(e) = self.call_optional("proxy_on_context_create", &[id, ROOT_CONTEXT_ID]) {
+            tracing::error!(plugin = %self.store.data().plugin_name, "wasm context create failed: {}", e);
+        }
+        id
+    }
+
+    fn finish_context(&mut self, context_id: i32) {
+        let result = self
+            .call_optional("proxy_on_done", &[context_id])
+            .and_then(|_| self.call_optional("proxy_on_delete", &[context_id]));
+        if let Err(e) = result {
+            tracing::error!(plugin = %self.store.data().plugin_name, "wasm context cleanup failed: {}", e);
+        }
+    }
+
+    fn run_headers_phase(
+        &mut self,
+        callback: &str,
+        context_id: i32,
+        map_type: i32,
+        headers: Vec<(String, String)>,
+    ) -> Result<(Vec<HeaderEdit>, Option<LocalResponse>), wasmi::Error> {
+        let num_headers = headers.len() as i32;
+        {
+            let state = self.store.data_mut();
+            state.map_type = map_type;
+            state.headers = headers;
+            state.edits.clear();
+            state.local_response = None;
+        }
+        let action = self.call(callback, &[context_id, num_headers, 1])?;
+        let state = self.store.data_mut();
+        let edits = std::mem::take(&mut state.edits);
+        let local = state.local_response.take();
+        if local.is_none() && action.unwrap_or(ACTION_CONTINUE) != ACTION_CONTINUE {
+            // We have no way to resume a paused request; treat pause as continue.
+            tracing::debug!(plugin = %state.plugin_name, "wasm plugin paused; continuing");
+        }
+        Ok((edits, local))
+    }
+}
+
+fn build_linker(engine: &Engine, module: &Module) -> Result<Linker<HostState>, wasmi::Error> {
+    let mut linker = Linker::new(engine);
+
+    linker.func_wrap(
+        "env",
+        "proxy_log",
+        |caller: Caller<'_, HostState>, level: i32, ptr: i32, len: i32| -> i32 {
+            let Some(msg) = read_string(&caller, ptr, len) else {
+                return STATUS_BAD_ARGUMENT;
+            };
+            let plugin = &caller.data().plugin_name;
+            match level {
+                0 | 1 => tracing::debug!(plugin = %plugin, "{}", msg),
+                2 => tracing::info!(plugin = %plugin, "{}", msg),
+                3 => tracing::warn!(plugin = %plugin, "{}", msg),
+                _ => tracing::error!(plugin = %plugin, "{}", msg),
+            }
+            STATUS_OK
+        },
+    )?;
+
+    linker.func_wrap(
+        "env",
+        "proxy_get_header_map_value",
+        |mut caller: Caller<'_, HostState>,
+         map: i32,
+         key_ptr: i32,
+         key_len: i32,
+         ret_ptr: i32,
+         ret_len: i32|
+         -> i32 {
+            if map != caller.data().map_type {
+                return STATUS_NOT_FOUND;
+            }
+            let Some(key) = read_string(&caller, key_ptr, key_len) else {
+                return STATUS_BAD_ARGUMENT;
+            };
+            let value = caller
+                .data()
+                .headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(&key))
+                .map(|(_, v)| v.clone());
+            match value {
+                Some(v) => return_bytes(&mut caller, v.as_bytes(), ret_ptr, ret_len),
+                None => STATUS_NOT_FOUND,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        "env",
+        "proxy_get_header_map_pairs",
+        |mut caller: Caller<'_, HostState>, map: i32, ret_ptr: i32, ret_len: i32| -> i32 {
+            if map != caller.data().map_type {
+                return STATUS_NOT_FOUND;
+            }
+            let encoded = encode_pairs(&caller.data().headers);
+            return_bytes(&mut caller, &encoded, ret_ptr, ret_len)
+        },
+    )?;
+
+    for (name, kind) in [
+        ("proxy_add_header_map_value", 0),
+        ("proxy_replace_header_map_value", 1),
+    ] {
+        linker.func_wrap(
+            "env",
+            name,
+            move |mut caller: Caller<'_, HostState>,
+                  map: i32,
+                  key_ptr: i32,
+                  key_len: i32,
+                  val_ptr: i32,
+                  val_len: i32|
+                  -> i32 {
+                if map != caller.data().map_type {
+                    return STATUS_NOT_FOUND;
+                }
+                let (Some(key), Some(value)) = (
+                    read_string(&caller, key_ptr, key_len),
+                    read_string(&caller, val_ptr, val_len),
+                ) else {
+                    return STATUS_BAD_ARGUMENT;
+                };
+                let state = caller.data_mut();
+                if kind == 0 {
+                    state.headers.push((key.clone(), value.clone()));
+                    state.edits.push(HeaderEdit::Add(key, value));
+                } else {
+                    state.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(&key));
+                    state.headers.push((key.clone(), value.clone()));
+                    state.edits.push(HeaderEdit::Replace(key, value));
+                }
+                STATUS_OK
+            },
+        )?;
+    }
+
+    linker.func_wrap(
+        "env",
+        "proxy_remove_header_map_value",
+        |mut caller: Caller<'_, HostState>, map: i32, key_ptr: i32, key_len: i32| -> i32 {
+            if map != caller.data().map_type {
+                return STATUS_NOT_FOUND;
+            }
+            let Some(key) = read_string(&caller, key_ptr, key_len) else {
+                return STATUS_BAD_ARGUMENT;
+            };
+            let state = caller.data_mut();
+            state.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(&key));
+            state.edits.push(HeaderEdit::Remove(key));
+            STATUS_OK
+        },
+    )?;
+
+    linker.func_wrap(
+        "env",
+        "proxy_get_buffer_bytes",
+        |mut caller: Caller<'_, HostState>,
+         buffer: i32,
+         start: i32,
+         max: i32,
+         ret_ptr: i32,
+         ret_len: i32|
+         -> i32 {
+            if buffer != BUFFER_PLUGIN_CONFIGURATION {
+                return STATUS_UNIMPLEMENTED;
+            }
+            let config = caller.data().plugin_config.clone();
+            let start = (start.max(0) as usize).min(config.len());
+            let end = start.saturating_add(max.max(0) as usize).min(config.len());
+            return_bytes(&mut caller, &config[start..end], ret_ptr, ret_len)
+        },
+    )?;
+
+    linker.func_wrap(
+        "env",
+        "proxy_send_local_response",
+        |mut caller: Caller<'_, HostState>,
+         status: i32,
+         _details_ptr: i32,
+         _details_len: i32,
+         body_ptr: i32,
+         body_len: i32,
+         headers_ptr: i32,
+         headers_len: i32,
+         _grpc_status: i32|
+         -> i32 {
+            let (Some(body), Some(raw_headers)) = (
+                read_bytes(&caller, body_ptr, body_len),
+                read_bytes(&caller, headers_ptr, headers_len),
+            ) else {
+                return STATUS_BAD_ARGUMENT;
+            };
+            let Ok(status) = u16::try_from(status) else {
+                return STATUS_BAD_ARGUMENT;
+            };
+            caller.data_mut().local_response = Some(LocalResponse {
+                status,
+                headers: decode_pairs(&raw_headers).unwrap_or_default(),
+                body,
+            });
+            STATUS_OK
+        },
+    )?;
+
+    // Everything else the plugin imports from the host gets a stub so instantiation succeeds.
+    for import in module.imports() {
+        if let wasmi::ExternType::Func(ty) = import.ty() {
+            if import.module() == "env" && is_defined(import.name()) {
+                continue;
+            }
+            let name = import.name().to_string();
+            linker.func_new(
+                import.module(),
+                import.name(),
+                ty.clone(),
+                move |_, _, results| {
+                    tracing::debug!(hostcall = %name, "unimplemented proxy-wasm hostcall");
+                    for r in results.iter_mut() {
+                        *r = Val::I32(STATUS_UNIMPLEMENTED);
+                    }
+                    Ok(())
+                },
+            )?;
+        }
+    }
+    Ok(linker)
+}
+
+fn is_defined(name: &str) -> bool {
+    matches!(
+        name,
+        "proxy_log"
+            | "proxy_get_header_map_value"
+            | "proxy_get_header_map_pairs"
+            | "proxy_add_header_map_value"
+            | "proxy_replace_header_map_value"
+            | "proxy_remove_header_map_value"
+            | "proxy_get_buffer_bytes"
+            | "proxy_send_local_response"
+    )
+}
+
+fn memory(caller: &Caller<'_, HostState>) -> Option<wasmi::Memory> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(m)) => Some(m),
+        _ => None,
+    }
+}
+
+/// `None` for a range outside guest memory, checked before anything is allocated for it.
+fn read_bytes(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Option<Vec<u8>> {
+    let mem = memory(caller)?;
+    let ptr = usize::try_from(ptr).ok()?;
+    let len = usize::try_from(len).ok()?;
+    if ptr.checked_add(len)? > mem.data_size(caller) {
+        return None;
+    }
+    let mut buf = vec![0u8; len];
+    mem.read(caller, ptr, &mut buf).ok()?;
+    Some(buf)
+}
+
+fn read_string(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Option<String> {
+    read_bytes(caller, ptr, len).and_then(|b| String::from_utf8(b).ok())
+}
+
+/// Copies `data` into guest memory allocated by the plugin and writes the pointer and length
+/// into the guest-provided return slots.
+fn return_bytes(
+    caller: &mut Caller<'_, HostState>,
+    data: &[u8],
+    ret_ptr: i32,
+    ret_len: i32,
+) -> i32 {
+    let alloc = ["proxy_on_memory_allocate", "malloc"]
+        .iter()
+        .find_map(|name| caller.get_export(name).and_then(Extern::into_func));
+    let (Some(alloc), Some(mem)) = (alloc, memory(caller)) else {
+        return STATUS_UNIMPLEMENTED;
+    };
+    let mut result = [Val::I32(0)];
+    if alloc
+        .call(&mut *caller, &[Val::I32(data.len() as i32)], &mut result)
+        .is_err()
+    {
+        return STATUS_BAD_ARGUMENT;
+    }
+    let Some(addr) = result[0].i32() else {
+        return STATUS_BAD_ARGUMENT;
+    };
+    let writes = [
+        (addr, data.to_vec()),
+        (ret_ptr, addr.to_le_bytes().to_vec()),
+        (ret_len, (data.len() as i32).to_le_bytes().to_vec()),
+    ];
+    for (offset, bytes) in writes {
+        if mem.write(&mut *caller, offset as usize, &bytes).is_err() {
+            return STATUS_BAD_ARGUMENT;
+        }
+    }
+    STATUS_OK
+}
+
+/// proxy-wasm map serialization: count, then (key_len, value_len) pairs, then NUL-terminated data.
+fn encode_pairs(pairs: &[(String, String)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+    for (k, v) in pairs {
+        out.extend_from_slice(&(k.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(v.len() as u32).to_le_bytes());
+    }
+    for (k, v) in pairs {
+        out.extend_from_slice(k.as_bytes());
+        out.push(0);
+        out.extend_from_slice(v.as_bytes());
+        out.push(0);
+    }
+    out
+}
+
+fn decode_pairs(raw: &[u8]) -> Option<Vec<(String, String)>> {
+    if raw.is_empty() {
+        return Some(Vec::new());
+    }
+    let read_u32 = |at: usize| -> Option<usize> {
+        raw.get(at..at + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    };
+    let count = read_u32(0)?;
+    // Each pair takes 8 bytes of sizes, so a larger count can't be genuine
+    if count.checked_mul(8)? > raw.len() - 4 {
+        return None;
+    }
+    let mut sizes = Vec::with_capacity(count);
+    for i in 0..count {
+        sizes.push((read_u32(4 + i * 8)?, read_u32(8 + i * 8)?));
+    }
+    let mut pos = 4 + count * 8;
+    let mut pairs = Vec::with_capacity(count);
+    for (klen, vlen) in sizes {
+        let key = std::str::from_utf8(raw.get(pos..pos + klen)?)
+            .ok()?
+            .to_string();
+        pos += klen + 1;
+        let value = std::str::from_utf8(raw.get(pos..pos + vlen)?)
+            .ok()?
+            .to_string();
+        pos += vlen + 1;
+        pairs.push((key, value));
+    }
+    Some(pairs)
+}
+
+fn request_header_pairs(req: &RequestHeader) -> Vec<(String, String)> {
+    let mut pairs = vec![
+        (":method".to_string(), req.method.as_str().to_string()),
+        (
+            ":path".to_string(),
+            req.uri
+                .path_and_query()
+                .map(|p| p.as_str().to_string())
+                .unwrap_or_default(),
+        ),
+    ];
+    pairs.extend(header_pairs(&req.headers));
+    pairs
+}
+
+fn response_header_pairs(resp: &ResponseHeader) -> Vec<(String, String)> {
+    let mut pairs = vec![(":status".to_string(), resp.status.as_u16().to_string())];
+    pairs.extend(header_pairs(&resp.headers));
+    pairs
+}
+
+fn header_pairs(headers: &http::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(k, v)| {
+            (
+                k.to_string(),
+                String::from_utf8_lossy(v.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+// Pseudo-headers are exposed read-only; edits to them are ignored.
+fn apply_request_edit(req: &mut RequestHeader, edit: HeaderEdit) {
+    let result = match edit {
+        HeaderEdit::Add(k, _) | HeaderEdit::Replace(k, _) | HeaderEdit::Remove(k)
+            if k.starts_with(':') =>
+        {
+            Ok(())
+        }
+        HeaderEdit::Add(k, v) => req.append_header(k, v).map(|_| ()),
+        HeaderEdit::Replace(k, v) => req.insert_header(k, v),
+        HeaderEdit::Remove(k) => {
+            req.remove_header(&k);
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        tracing::warn!("wasm plugin header edit rejected: {}", e);
+    }
+}
+
+fn apply_response_edit(resp: &mut ResponseHeader, edit: HeaderEdit) {
+    let result = match edit {
+        HeaderEdit::Add(k, _) | HeaderEdit::Replace(k, _) | HeaderEdit::Remove(k)
+            if k.starts_with(':') =>
+        {
+            Ok(())
+        }
+        HeaderEdit::Add(k, v) => resp.append_header(k, v).map(|_| ()),
+        HeaderEdit::Replace(k, v) => resp.insert_header(k, v),
+        HeaderEdit::Remove(k) => {
+            resp.remove_header(&k);
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        tracing::warn!("wasm plugin header edit rejected: {}", e);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::configuration::AlertWebhookConfig;
+use serde_json::Value;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::time::Duration;
+
+const QUEUE_DEPTH: usize = 256;
+
+/// Posts upstream health alerts as JSON to an on-call webhook. Deliveries run on a background
+/// thread and are retried a few times; if the endpoint is down long enough for the queue to
+/// fill, further alerts are dropped rather than held up.
+pub struct AlertWebhook {
+    tx: SyncSender<Value>,
+    hostname: String,
+}
+
+impl AlertWebhook {
+    pub fn new(config: &AlertWebhookConfig) -> Self {
+        let (tx, rx) = sync_channel(QUEUE_DEPTH);
+        let config = config.clone();
+        std::thread::spawn(move || run_sender(config, rx));
+
+        let hostname = std::fs::read_to_string("/etc/hostname")
+            .map(|h| h.trim().to_string())
+            .ok()
+            .filter(|h| !h.is_empty())
+            .unwrap_or_else(|| "-".to_string());
+        Self { tx, hostname }
+    }
+
+    /// Queues `event` (e.g. `upstream_unhealthy`) about `upstream`, with any extra fields.
+    pub fn send(&self, event: &str, upstream: &str, mut fields: Value) {
+        if !fields.is_object() {
+            fields = Value::Object(Default::default());
+        }
+        fields["event"] = event.into();
+        fields["upstream"] = upstream.into();
+        fields["host"] = self.hostname.as_str().into();
+        fields["timestamp"] = chrono::Utc::now()
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+            .into();
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(fields) {
+            tracing::warn!(event, "alert webhook queue full, dropping alert");
+        }
+    }
+}
+
+fn run_sender(config: AlertWebhookConfig, rx: Receiver<Value>) {
+    let client = match reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("alert webhook client: {}", e);
+            return;
+        }
+    };
+    for alert in rx {
+        for attempt in 1..=config.max_attempts {
+            let mut request = client.post(&config.url).json(&alert);
+            for (name, value) in &config.headers {
+                request = request.header(name, value);
+            }
+            match request.send().and_then(|r| r.error_for_status()) {
+                Ok(_) => break,
+                Err(e) => {
+                    tracing::warn!(url = %config.url, attempt, error = %e, "alert webhook delivery failed");
+                    if attempt < config.max_attempts {
+                        std::thread::sleep(Duration::from_secs(1 << attempt.min(5)));
+                    }
+                }
+            }
+        }
+    }
+}
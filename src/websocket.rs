@@ -0,0 +1,154 @@
+use crate::configuration::WebSocketConfig;
+use std::time::Instant;
+
+/// Frame and message limits for a proxied WebSocket connection, checked by following the frame
+/// headers in both directions of the upgraded stream. Payloads are skipped, never buffered.
+pub struct WebSocketLimits {
+    max_frame_bytes: u64,
+    max_message_bytes: u64,
+    messages_per_sec: Option<f64>,
+    burst: f64,
+}
+
+/// Why a connection was cut, as exported in `websocket_terminations_total`
+#[derive(Debug, Clone, Copy)]
+pub enum Violation {
+    FrameTooLarge,
+    MessageTooLarge,
+    RateLimited,
+}
+
+impl Violation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Violation::FrameTooLarge => "frame_too_large",
+            Violation::MessageTooLarge => "message_too_large",
+            Violation::RateLimited => "rate_limited",
+        }
+    }
+}
+
+/// State of one upgraded connection.
+pub struct WebSocketConn {
+    client: FrameReader,
+    upstream: FrameReader,
+    /// Client message budget: tokens left and when it was last topped up
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl WebSocketLimits {
+    pub fn new(config: &WebSocketConfig) -> Self {
+        Self {
+            max_frame_bytes: config.max_frame_kb * 1024,
+            max_message_bytes: config.max_message_kb * 1024,
+            messages_per_sec: config.max_messages_per_sec.map(f64::from),
+            burst: config
+                .message_burst
+                .or(config.max_messages_per_sec)
+                .map(f64::from)
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn connection(&self) -> WebSocketConn {
+        WebSocketConn {
+            client: FrameReader::default(),
+            upstream: FrameReader::default(),
+            tokens: self.burst,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Follows client bytes; returns the number of messages they completed. Only client
+    /// messages are rate limited.
+    pub fn client_bytes(&self, conn: &mut WebSocketConn, chunk: &[u8]) -> Result<u32, Violation> {
+        let messages = conn.client.read(chunk, self)?;
+        if let (Some(rate), true) = (self.messages_per_sec, messages > 0) {
+            let now = Instant::now();
+            let elapsed = now.duration_since(conn.refilled).as_secs_f64();
+            conn.tokens = (conn.tokens + elapsed * rate).min(self.burst);
+            conn.refilled = now;
+            conn.tokens -= f64::from(messages);
+            if conn.tokens < 0.0 {
+                return Err(Violation::RateLimited);
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Follows upstream bytes; returns the number of messages they completed.
+    pub fn upstream_bytes(&self, conn: &mut WebSocketConn, chunk: &[u8]) -> Result<u32, Violation> {
+        conn.upstream.read(chunk, self)
+    }
+}
+
+/// Tracks frame boundaries in one direction (RFC 6455 §5.2).
+#[derive(Default)]
+struct FrameReader {
+    /// Bytes of an incomplete frame header
+    header: Vec<u8>,
+    /// Payload bytes of the current frame still to pass
+    remaining: u64,
+    /// Payload bytes of the data message in progress
+    message: u64,
+}
+
+impl FrameReader {
+    fn read(&mut self, mut chunk: &[u8], limits: &WebSocketLimits) -> Result<u32, Violation> {
+        let mut messages = 0;
+        while !chunk.is_empty() {
+            if self.remaining > 0 {
+                let skip = self.remaining.min(chunk.len() as u64);
+                self.remaining -= skip;
+                chunk = &chunk[skip as usize..];
+                continue;
+            }
+            self.header.push(chunk[0]);
+            chunk = &chunk[1..];
+            let Some((fin, opcode, len)) = parse_header(&self.header) else {
+                continue;
+            };
+            self.header.clear();
+            if len > limits.max_frame_bytes {
+                return Err(Violation::FrameTooLarge);
+            }
+            self.remaining = len;
+            // Control frames may interleave with a fragmented message and don't count toward it
+            if opcode & 0x8 != 0 {
+                continue;
+            }
+            self.message += len;
+            if self.message > limits.max_message_bytes {
+                return Err(Violation::MessageTooLarge);
+            }
+            if fin {
+                self.message = 0;
+                messages += 1;
+            }
+        }
+        Ok(messages)
+    }
+}
+
+/// FIN bit, opcode and payload length once `header` holds a whole frame header.
+fn parse_header(header: &[u8]) -> Option<(bool, u8, u64)> {
+    let [first, second, ..] = *header else {
+        return None;
+    };
+    let (len, extended) = match second & 0x7f {
+        126 => (None, 2),
+        127 => (None, 8),
+        len => (Some(u64::from(len)), 0),
+    };
+    let mask = if second & 0x80 != 0 { 4 } else { 0 };
+    if header.len() < 2 + extended + mask {
+        return None;
+    }
+    let len = len.unwrap_or_else(|| {
+        header[2..2 + extended]
+            .iter()
+            .fold(0, |len, b| len << 8 | u64::from(*b))
+    });
+    Some((first & 0x80 != 0, first & 0x0f, len))
+}